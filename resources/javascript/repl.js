@@ -0,0 +1,50 @@
+// Runs cells sent on fd 3, each as its length in bytes on a line followed
+// by the code, in one context. The cells get an empty stdin, so they can't
+// read the ones after them.
+// After each cell "\0ok\n" or "\0error\n" is written to stdout and "\0\n"
+// to stderr, so the executor knows where its output ends.
+const fs = require("fs");
+const util = require("util");
+const vm = require("vm");
+
+const context = vm.createContext({
+  console,
+  require,
+  process,
+  Buffer,
+  setTimeout,
+  clearTimeout,
+  setInterval,
+  clearInterval,
+});
+let buffer = Buffer.alloc(0);
+
+fs.createReadStream(null, { fd: 3 }).on("data", (chunk) => {
+  buffer = Buffer.concat([buffer, chunk]);
+  for (;;) {
+    const newline = buffer.indexOf(10);
+    if (newline < 0) {
+      return;
+    }
+    const length = parseInt(buffer.subarray(0, newline).toString(), 10);
+    const end = newline + 1 + length;
+    if (buffer.length < end) {
+      return;
+    }
+    const code = buffer.subarray(newline + 1, end).toString();
+    buffer = buffer.subarray(end);
+    let status = "ok";
+    try {
+      // the value of the cell is printed, like in the REPL
+      const value = vm.runInContext(code, context, { filename: "cell" });
+      if (value !== undefined) {
+        process.stdout.write(util.inspect(value) + "\n");
+      }
+    } catch (e) {
+      process.stderr.write((e && e.stack ? e.stack : String(e)) + "\n");
+      status = "error";
+    }
+    process.stderr.write("\0\n");
+    process.stdout.write("\0" + status + "\n");
+  }
+});
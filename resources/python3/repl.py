@@ -0,0 +1,42 @@
+# Runs cells sent on fd 3, each as its length in bytes on a line followed by
+# the code, in one scope. Runs on Python 2.7 as well. The cells get an empty
+# stdin, so they can't read the ones after them.
+# After each cell "\0ok\n" or "\0error\n" is written to stdout and "\0\n"
+# to stderr, so the executor knows where its output ends.
+import ast
+import os
+import sys
+import traceback
+
+scope = {"__name__": "__main__"}
+cells = os.fdopen(3, "rb")
+
+while True:
+    line = cells.readline()
+    if not line:
+        break
+    code = cells.read(int(line)).decode("utf-8")
+    status = "ok"
+    try:
+        module = ast.parse(code, "<cell>")
+        # the value of a last expression is printed, like in the REPL
+        last = None
+        if module.body and isinstance(module.body[-1], ast.Expr):
+            last = ast.Expression(module.body.pop().value)
+        exec(compile(module, "<cell>", "exec"), scope)
+        if last is not None:
+            value = eval(compile(last, "<cell>", "eval"), scope)
+            if value is not None:
+                print(repr(value))
+    except SystemExit:
+        raise
+    except BaseException:
+        # without the frame of the driver
+        kind, error, trace = sys.exc_info()
+        traceback.print_exception(kind, error, trace.tb_next)
+        status = "error"
+    sys.stdout.flush()
+    sys.stderr.write("\0\n")
+    sys.stderr.flush()
+    sys.stdout.write("\0%s\n" % status)
+    sys.stdout.flush()
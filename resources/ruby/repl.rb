@@ -0,0 +1,26 @@
+# Runs cells sent on fd 3, each as its length in bytes on a line followed by
+# the code, in one binding. The cells get an empty stdin, so they can't read
+# the ones after them.
+# After each cell "\0ok\n" or "\0error\n" is written to stdout and "\0\n"
+# to stderr, so the executor knows where its output ends.
+$stdout.sync = true
+$stderr.sync = true
+scope = binding
+cells = IO.new(3, "rb")
+
+while (line = cells.gets)
+  code = cells.read(line.to_i).force_encoding("UTF-8")
+  status = "ok"
+  begin
+    # the value of the cell is printed, like in irb
+    value = scope.eval(code, "cell")
+    puts value.inspect unless value.nil?
+  rescue SystemExit
+    raise
+  rescue Exception => e
+    $stderr.puts e.full_message(highlight: false)
+    status = "error"
+  end
+  $stderr.write("\0\n")
+  $stdout.write("\0#{status}\n")
+end
@@ -0,0 +1,328 @@
+use hyper::header::{Authorization, Bearer, Headers};
+use hyper::server::Request;
+use digest;
+use json;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DAY: u64 = 86400;
+
+lazy_static! {
+    /// Seconds the programs of jobs ran for, by the token of their permit.
+    /// Programs may run on other threads
+    static ref CHARGES: Mutex<HashMap<u64, f64>> = Mutex::new(HashMap::new());
+}
+
+/// The token of the next permit of a job. They are made here rather than
+/// taken from the request, so that clients can't charge each other's jobs
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// Charges the job let through by the permit with `token` for `time`
+/// of running programs
+pub fn charge(token: Option<u64>, time: Duration) {
+    let token = match token {
+        Some(token) => token,
+        None => return,
+    };
+    let mut charges = CHARGES.lock().expect("no panics while charging");
+    if let Some(charged) = charges.get_mut(&token) {
+        *charged += time.as_secs_f64();
+    }
+}
+
+/// What a key may use
+#[derive(Deserialize, Debug, Copy, Clone)]
+#[serde(default)]
+pub struct Quota {
+    /// Most jobs running at once
+    pub concurrent: usize,
+    /// Most jobs started in a minute
+    pub per_minute: u32,
+    /// Most seconds jobs run for in a day (UTC). Jobs are charged for the
+    /// wall time their programs or cells run, which bounds their CPU time
+    /// as a container runs on one CPU
+    #[serde(alias = "cpu_seconds_per_day")]
+    pub run_seconds_per_day: f64,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Quota {
+            concurrent: 4,
+            per_minute: 60,
+            run_seconds_per_day: 3600.0,
+        }
+    }
+}
+
+/// An API key, of which only the hash is kept
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    /// Who the key belongs to
+    pub name: String,
+    /// Hex encoded SHA-256 of the key
+    pub sha256: String,
+    #[serde(flatten)]
+    pub quota: Quota,
+}
+
+/// Returns the hex encoded SHA-256 of `key`
+pub fn hash(key: &str) -> String {
+    digest::sha256(key.as_bytes())
+}
+
+/// What a key used lately
+#[derive(Debug)]
+struct Usage {
+    running: usize,
+    /// Start of the current minute and the jobs started in it
+    minute: (Instant, u32),
+    /// The current day since the epoch and the seconds run in it
+    day: (u64, f64),
+}
+
+/// Where a key stands against its quota, sent in the headers of responses
+#[derive(Debug, Copy, Clone)]
+pub struct QuotaStatus {
+    quota: Quota,
+    /// Jobs left in the current minute
+    remaining: u32,
+    /// Seconds until the current minute is over
+    reset: u64,
+    /// Seconds of running left today
+    run_remaining: f64,
+}
+
+impl QuotaStatus {
+    /// Sets the `X-RateLimit-*` and `X-Run-Quota-*` headers
+    pub fn set_headers(&self, headers: &mut Headers) {
+        headers.set_raw("X-RateLimit-Limit", self.quota.per_minute.to_string());
+        headers.set_raw("X-RateLimit-Remaining", self.remaining.to_string());
+        headers.set_raw("X-RateLimit-Reset", self.reset.to_string());
+        headers.set_raw("X-Run-Quota-Limit", self.quota.run_seconds_per_day.to_string());
+        headers.set_raw("X-Run-Quota-Remaining", format!("{:.3}", self.run_remaining.max(0.0)));
+    }
+}
+
+/// Why a request is turned away
+#[derive(Debug)]
+pub enum AuthError {
+    /// It has no key, or one which isn't known
+    Unauthorized,
+    /// The key used up its quota
+    Exceeded {
+        /// Which part of the quota
+        reason: &'static str,
+        /// Seconds until it may try again
+        retry_after: u64,
+        status: QuotaStatus,
+    },
+}
+
+/// The API keys along with what they used
+pub struct Keys {
+    /// By hash
+    keys: HashMap<String, ApiKey>,
+    /// By hash
+    usage: RefCell<HashMap<String, Usage>>,
+}
+
+/// Returns the current day since the epoch and the seconds left in it
+fn today() -> (u64, u64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (now / DAY, DAY - now % DAY)
+}
+
+/// Returns the key sent as `Authorization: Bearer <key>` or `X-API-Key: <key>`
+fn presented(req: &Request) -> Option<String> {
+    if let Some(bearer) = req.headers().get::<Authorization<Bearer>>() {
+        return Some(bearer.token.clone());
+    }
+    req.headers()
+        .get_raw("X-API-Key")
+        .and_then(|raw| raw.one())
+        .and_then(|key| String::from_utf8(key.to_vec()).ok())
+}
+
+impl Keys {
+    /// Creates the set of `keys`
+    pub fn new(keys: Vec<ApiKey>) -> Self {
+        Keys {
+            keys: keys
+                .into_iter()
+                .map(|key| (key.sha256.to_lowercase(), key))
+                .collect(),
+            usage: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the keys from a JSON file with a list of keys, e.g.
+    /// `[{"name": "tutoring", "sha256": "...", "per_minute": 120}]`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let keys = json::from_slice(&fs::read(path)?)?;
+        Ok(Keys::new(keys))
+    }
+
+    /// Lets `req` through if it has a known key with some quota left. Jobs
+    /// count against the quota until the returned `Permit` is dropped,
+    /// other requests only need the key
+    pub fn admit(keys: &Rc<Keys>, req: &Request, job: bool) -> Result<Permit, AuthError> {
+        let hash = presented(req).map(|key| hash(&key)).ok_or(AuthError::Unauthorized)?;
+        let key = keys.keys.get(&hash).ok_or(AuthError::Unauthorized)?;
+        let quota = key.quota;
+        let mut usage = keys.usage.borrow_mut();
+        let (day, day_left) = today();
+        let usage = usage.entry(hash.clone()).or_insert_with(|| Usage {
+            running: 0,
+            minute: (Instant::now(), 0),
+            day: (day, 0.0),
+        });
+        if usage.minute.0.elapsed() >= Duration::from_secs(60) {
+            usage.minute = (Instant::now(), 0);
+        }
+        if usage.day.0 != day {
+            usage.day = (day, 0.0);
+        }
+        let minute_left = 60u64.saturating_sub(usage.minute.0.elapsed().as_secs());
+        let mut status = QuotaStatus {
+            quota: quota,
+            remaining: quota.per_minute.saturating_sub(usage.minute.1),
+            reset: minute_left,
+            run_remaining: quota.run_seconds_per_day - usage.day.1,
+        };
+        let mut token = None;
+        if job {
+            let exceeded = |reason, retry_after| AuthError::Exceeded {
+                reason: reason,
+                retry_after: retry_after,
+                status: status,
+            };
+            if usage.running >= quota.concurrent {
+                return Err(exceeded("too many jobs running", 1));
+            }
+            if usage.minute.1 >= quota.per_minute {
+                return Err(exceeded("too many jobs this minute", minute_left));
+            }
+            if usage.day.1 >= quota.run_seconds_per_day {
+                return Err(exceeded("run time used up for today", day_left));
+            }
+            usage.running += 1;
+            usage.minute.1 += 1;
+            status.remaining -= 1;
+            let issued = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+            CHARGES.lock().expect("no panics while charging").insert(issued, 0.0);
+            token = Some(issued);
+        }
+        trace!("request of {}", key.name);
+        Ok(Permit {
+            keys: keys.clone(),
+            hash: hash,
+            name: key.name.clone(),
+            token: token,
+            status: status,
+        })
+    }
+}
+
+/// A request let through, jobs are charged once it is dropped
+pub struct Permit {
+    keys: Rc<Keys>,
+    hash: String,
+    /// Name of the key
+    name: String,
+    /// What the time of its job is `charge`d to, if it is one
+    token: Option<u64>,
+    status: QuotaStatus,
+}
+
+impl Permit {
+    /// Where the key stood when the request was let through
+    pub fn status(&self) -> &QuotaStatus {
+        &self.status
+    }
+
+    /// Who the key belongs to
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// What the programs of its job `charge` their time to, if it is one
+    pub fn token(&self) -> Option<u64> {
+        self.token
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let token = match self.token {
+            Some(token) => token,
+            None => return,
+        };
+        let charged = CHARGES
+            .lock()
+            .expect("no panics while charging")
+            .remove(&token)
+            .unwrap_or(0.0);
+        let mut usage = self.keys.usage.borrow_mut();
+        if let Some(usage) = usage.get_mut(&self.hash) {
+            usage.running -= 1;
+            // jobs running over midnight are charged to the new day
+            let (day, _) = today();
+            if usage.day.0 != day {
+                usage.day = (day, 0.0);
+            }
+            usage.day.1 += charged;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_keys() {
+        assert_eq!(
+            hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    /// A job with `key`, sent with the same request id as any other
+    fn job(key: &str) -> Request {
+        let mut req = Request::new(::hyper::Method::Post, "/execute".parse().unwrap());
+        req.headers_mut().set_raw("X-API-Key", key.to_owned());
+        req.headers_mut().set_raw("X-Request-Id", "same");
+        req
+    }
+
+    #[test]
+    fn charges_each_permit_its_own_time() {
+        let key = |name: &str| ApiKey {
+            name: name.to_owned(),
+            sha256: hash(name),
+            quota: Quota::default(),
+        };
+        let keys = Rc::new(Keys::new(vec![key("a"), key("b")]));
+        let a = Keys::admit(&keys, &job("a"), true).unwrap();
+        let b = Keys::admit(&keys, &job("b"), true).unwrap();
+        assert_ne!(a.token(), b.token());
+        charge(b.token(), Duration::from_secs(10));
+        drop(a);
+        let remaining = |key| Keys::admit(&keys, &job(key), true).unwrap().status().run_remaining;
+        assert_eq!(remaining("a"), 3600.0);
+        drop(b);
+        assert_eq!(remaining("b"), 3590.0);
+    }
+}
@@ -0,0 +1,227 @@
+use futures::{future, Future};
+use hyper::server::Service;
+use json;
+
+use digest;
+use executor::LanguageConfig;
+use history;
+use ExecutionError;
+use Output;
+use Submission;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How long outputs are kept and how much room they take up
+#[derive(Debug, Copy, Clone)]
+pub struct CacheLimits {
+    /// Outputs are run again once they are older than this
+    pub ttl: Duration,
+    /// Most bytes of outputs, as JSON, kept at once. The oldest
+    /// ones are dropped to make room for new ones
+    pub size: usize,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        CacheLimits {
+            ttl: Duration::from_secs(600),
+            size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// An output along with when it was kept
+struct Cached {
+    output: Output,
+    stored: Instant,
+    size: usize,
+}
+
+/// Outputs of submissions by the hash of everything they depend on
+pub struct ResultCache {
+    limits: CacheLimits,
+    outputs: RefCell<HashMap<String, Cached>>,
+    /// Keys in the order they were kept, the ones of outputs
+    /// which were dropped or kept again are left behind
+    order: RefCell<VecDeque<(String, Instant)>>,
+    size: RefCell<usize>,
+}
+
+impl ResultCache {
+    /// Creates an empty cache keeping outputs within `limits`
+    pub fn new(limits: CacheLimits) -> Self {
+        ResultCache {
+            limits: limits,
+            outputs: RefCell::new(HashMap::new()),
+            order: RefCell::new(VecDeque::new()),
+            size: RefCell::new(0),
+        }
+    }
+
+    /// Returns the output kept for `key`, if it isn't too old
+    fn get(&self, key: &str) -> Option<Output> {
+        let mut outputs = self.outputs.borrow_mut();
+        let fresh = match outputs.get(key) {
+            Some(cached) => cached.stored.elapsed() < self.limits.ttl,
+            None => return None,
+        };
+        if fresh {
+            return outputs.get(key).map(|cached| cached.output.clone());
+        }
+        let stale = outputs.remove(key).expect("is kept");
+        *self.size.borrow_mut() -= stale.size;
+        None
+    }
+
+    /// Keeps `output` for `key`, dropping the oldest outputs if there isn't
+    /// room. Outputs taking up more than the whole cache aren't kept
+    fn put(&self, key: String, output: &Output) {
+        let size = json::to_string(output).expect("can't error").len() + key.len();
+        if size > self.limits.size {
+            return;
+        }
+        let mut outputs = self.outputs.borrow_mut();
+        let mut order = self.order.borrow_mut();
+        let mut total = self.size.borrow_mut();
+        let stored = Instant::now();
+        if let Some(replaced) = outputs.insert(
+            key.clone(),
+            Cached {
+                output: output.clone(),
+                stored: stored,
+                size: size,
+            },
+        ) {
+            *total -= replaced.size;
+        }
+        *total += size;
+        order.push_back((key, stored));
+        // expired outputs go along with the ones there's no room for
+        while let Some((oldest, stored)) = order.pop_front() {
+            // the output may have been dropped, or kept again later on
+            let kept = outputs.get(&oldest).map(|cached| cached.stored) == Some(stored);
+            if kept && *total <= self.limits.size && stored.elapsed() < self.limits.ttl {
+                order.push_front((oldest, stored));
+                break;
+            }
+            if kept {
+                *total -= outputs.remove(&oldest).expect("is kept").size;
+            }
+        }
+    }
+}
+
+/// Returns the key `sub` is kept by: the hash of the image of its version,
+/// what it is built and run from, and the limits it runs with. `None` if
+/// it isn't to be kept
+fn key(sub: &Submission) -> Option<String> {
+    if sub.no_cache || !sub.lang.is_deterministic() {
+        return None;
+    }
+    let image = sub.version().ok()?.image;
+    let keyed = json::to_string(&(image, sub, history::limits(sub))).expect("can't error");
+    Some(digest::sha256(keyed.as_bytes()))
+}
+
+/// Answers submissions which were executed before with the output they
+/// got, without calling the service it wraps, if there is a cache
+pub struct Caching<S> {
+    service: S,
+    cache: Option<Rc<ResultCache>>,
+}
+
+impl<S> Caching<S> {
+    /// Keeps the outputs `service` returns in `cache`
+    pub fn new(service: S, cache: Option<Rc<ResultCache>>) -> Self {
+        Caching {
+            service: service,
+            cache: cache,
+        }
+    }
+}
+
+impl<S> Service for Caching<S>
+where
+    S: Service<Request = Submission, Response = Output, Error = ExecutionError>,
+    S::Future: 'static,
+{
+    type Request = Submission;
+    type Response = Output;
+    type Error = ExecutionError;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, sub: Self::Request) -> Self::Future {
+        let (cache, key) = match (self.cache.as_ref(), key(&sub)) {
+            (Some(cache), Some(key)) => (cache.clone(), key),
+            _ => return Box::new(self.service.call(sub)),
+        };
+        if let Some(output) = cache.get(&key) {
+            trace!("output of {} is cached", key);
+            return Box::new(future::ok(output));
+        }
+        // failures to build or run are up to the engine, not the submission,
+        // and so are runs stopped or cut short for a limit
+        let cached = self.service.call(sub).map(move |output| {
+            if output.is_complete() {
+                cache.put(key, &output);
+            }
+            output
+        });
+        Box::new(cached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(stdout: &str) -> Output {
+        Output::Output {
+            stdout: stdout.to_owned(),
+            stderr: String::new(),
+            transcript: None,
+            output_files: None,
+            complete: true,
+        }
+    }
+
+    fn stdout(output: Option<Output>) -> Option<String> {
+        match output {
+            Some(Output::Output { stdout, .. }) => Some(stdout),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn drops_oldest_outputs_for_room() {
+        let size = json::to_string(&output("1")).unwrap().len() + 1;
+        let cache = ResultCache::new(CacheLimits {
+            size: size * 2,
+            ..CacheLimits::default()
+        });
+        cache.put("a".to_owned(), &output("1"));
+        cache.put("b".to_owned(), &output("2"));
+        cache.put("a".to_owned(), &output("3"));
+        cache.put("c".to_owned(), &output("4"));
+        assert_eq!(stdout(cache.get("a")), Some("3".to_owned()));
+        assert_eq!(stdout(cache.get("b")), None);
+        assert_eq!(stdout(cache.get("c")), Some("4".to_owned()));
+        cache.put("d".to_owned(), &output(&"x".repeat(size * 2)));
+        assert_eq!(stdout(cache.get("d")), None);
+        assert_eq!(*cache.size.borrow(), size * 2);
+    }
+
+    #[test]
+    fn expires_outputs() {
+        let cache = ResultCache::new(CacheLimits {
+            ttl: Duration::from_secs(0),
+            ..CacheLimits::default()
+        });
+        cache.put("a".to_owned(), &output("1"));
+        assert_eq!(stdout(cache.get("a")), None);
+        assert_eq!(*cache.size.borrow(), 0);
+    }
+}
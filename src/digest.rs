@@ -0,0 +1,37 @@
+use sha2::{Digest, Sha256};
+
+use std::fmt::Write;
+
+/// Returns `bytes` hex encoded
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// Returns the hex encoded SHA-256 of `bytes`
+pub fn sha256(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+/// Returns the hex encoded SHA-256 of `parts`. Each part is preceded by
+/// its length, so that moving bytes between them changes the hash
+pub fn parts_sha256<'a, I: IntoIterator<Item = &'a [u8]>>(parts: I) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hex(&hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_parts_apart() {
+        assert_ne!(parts_sha256(vec![&b"ab"[..], b"c"]), parts_sha256(vec![&b"a"[..], b"bc"]));
+    }
+}
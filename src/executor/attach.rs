@@ -0,0 +1,452 @@
+use futures::{Async, Future, Poll};
+use tokio_core::reactor::Handle;
+use tokio_io::io::write_all;
+use tokio_uds::UnixStream;
+
+use executor::error::DockerError;
+use executor::log::{Decoder, Format, Message};
+use judge::{Exchange, Party};
+use session::CellStatus;
+use OutputStream;
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+
+/// Most bytes kept of the stderr of each side of a relay, of what each
+/// side says in the transcript and of each stream of a cell. What is
+/// written past it is dropped
+const MAX_KEPT: usize = 1048576;
+
+/// Most bytes of one side's stdout waiting for the other side to read
+/// them, it isn't read from until the other side catches up
+const MAX_PENDING: usize = 65536;
+
+/// Appends as much of `bytes` to `kept` as fits in `MAX_KEPT`
+fn keep(kept: &mut Vec<u8>, bytes: &[u8]) {
+    let room = MAX_KEPT.saturating_sub(kept.len());
+    kept.extend_from_slice(&bytes[..cmp::min(room, bytes.len())]);
+}
+
+/// A connection hijacked from Docker, carrying the stdin of a
+/// container one way and its multiplexed stdout and stderr the other
+pub struct Attached {
+    stream: UnixStream,
+    decoder: Decoder,
+}
+
+/// Reads the response to an attach request, up to where the streams start
+struct Handshake {
+    stream: Option<UnixStream>,
+    buf: Vec<u8>,
+}
+
+impl Future for Handshake {
+    type Item = Attached;
+    type Error = DockerError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+                trace!("attach response: {}", head);
+                let status = head.split_whitespace().nth(1);
+                match status {
+                    Some("101") | Some("200") => (),
+                    Some("404") => return Err(DockerError::NotFound),
+                    _ => return Err(DockerError::InternalServerError),
+                }
+                let mut decoder = Decoder::new(Format::Multiplexed);
+                // the container may have written something already
+                decoder.extend(&self.buf[end + 4..]);
+                return Ok(Async::Ready(Attached {
+                    stream: self.stream.take().expect("polled after completion"),
+                    decoder: decoder,
+                }));
+            }
+            let mut chunk = [0; 1024];
+            let read = self.stream
+                .as_mut()
+                .expect("polled after completion")
+                .read(&mut chunk);
+            match read {
+                Ok(0) => return Err(DockerError::InvalidMessage("attach response ended".to_owned())),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(DockerError::IoError(e)),
+            }
+        }
+    }
+}
+
+/// Attaches to the stdin, stdout and stderr of the container specified by `id`.
+/// Should be done before the container is started, so no output is missed
+/// # Arguments
+/// * `socket` - Path of the unix socket Docker listens on
+/// * `handle` - A `Handle` to the event loop the connection is driven on
+pub fn attach(socket: &str, id: &str, handle: &Handle) -> Box<Future<Item = Attached, Error = DockerError>> {
+    let stream = match UnixStream::connect(socket, handle) {
+        Ok(stream) => stream,
+        Err(e) => return Box::new(::futures::future::err(DockerError::IoError(e))),
+    };
+    // hyper's client can't hand over upgraded connections, so we talk HTTP ourselves
+    let request = format!(
+        "POST /v1.30/containers/{id}/attach?stream=1&stdin=1&stdout=1&stderr=1 HTTP/1.1\r\n\
+         Host: docker\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: tcp\r\n\
+         Content-Length: 0\r\n\r\n",
+        id = id
+    );
+    let attached = write_all(stream, request.into_bytes())
+        .map_err(DockerError::IoError)
+        .and_then(|(stream, _)| Handshake {
+            stream: Some(stream),
+            buf: Vec::new(),
+        });
+    Box::new(attached)
+}
+
+impl Attached {
+    /// Sends `code` to the REPL driver running in the container and reads
+    /// its output until the driver says the cell is done
+    pub fn eval(self, code: &str) -> Evaluate {
+        let mut input = format!("{}\n", code.len()).into_bytes();
+        input.extend_from_slice(code.as_bytes());
+        Evaluate {
+            attached: Some(self),
+            input: input,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            status: None,
+            stderr_done: false,
+        }
+    }
+}
+
+/// What the REPL driver printed for a cell
+#[derive(Debug)]
+pub struct Evaluated {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: CellStatus,
+}
+
+/// Marks the end of a cell on stderr, on stdout it is followed by the status
+const CELL_END: u8 = b'\0';
+
+/// Bytes kept past `MAX_KEPT` at the end of the output of a cell,
+/// enough for the longest end of a cell
+const CELL_END_LEN: usize = 8;
+
+/// Appends `bytes` to the output of a cell, dropping what is past `MAX_KEPT`
+/// but for the last `CELL_END_LEN` bytes, which may end the cell
+fn keep_cell_output(output: &mut Vec<u8>, bytes: &[u8]) {
+    output.extend_from_slice(bytes);
+    if output.len() > MAX_KEPT + CELL_END_LEN {
+        let end = output.len() - CELL_END_LEN;
+        output.drain(MAX_KEPT..end);
+    }
+}
+
+/// Strips the status line the driver writes to stdout after a cell
+fn cell_status(stdout: &mut Vec<u8>) -> Option<CellStatus> {
+    let status = if stdout.ends_with(b"\0ok\n") {
+        CellStatus::Ok
+    } else if stdout.ends_with(b"\0error\n") {
+        CellStatus::Error
+    } else {
+        return None;
+    };
+    let end = stdout.iter().rposition(|&byte| byte == CELL_END).expect("ends with a status");
+    stdout.truncate(end);
+    Some(status)
+}
+
+/// Evaluates a cell in a REPL, giving back the connection
+/// along with the output once the cell is done
+pub struct Evaluate {
+    attached: Option<Attached>,
+    /// The cell, after its length on a line
+    input: Vec<u8>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    /// Set once the status got to stdout
+    status: Option<CellStatus>,
+    /// Streams are copied separately, so stderr may end after stdout
+    stderr_done: bool,
+}
+
+impl Evaluate {
+    fn finish(&mut self, status: CellStatus) -> (Attached, Evaluated) {
+        let attached = self.attached.take().expect("polled after completion");
+        self.stdout.truncate(MAX_KEPT);
+        self.stderr.truncate(MAX_KEPT);
+        let evaluated = Evaluated {
+            stdout: ::std::mem::take(&mut self.stdout),
+            stderr: ::std::mem::take(&mut self.stderr),
+            status: status,
+        };
+        (attached, evaluated)
+    }
+
+    /// The output so far of a cell which took too long, hanging up on the interpreter
+    pub fn timed_out(mut self) -> Evaluated {
+        self.finish(CellStatus::Timeout).1
+    }
+}
+
+impl Future for Evaluate {
+    type Item = (Attached, Evaluated);
+    type Error = DockerError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let mut progress = false;
+            {
+                let attached = self.attached.as_mut().expect("polled after completion");
+                while !self.input.is_empty() {
+                    match attached.stream.write(&self.input) {
+                        Ok(n) => {
+                            self.input.drain(..n);
+                            progress = true;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(DockerError::IoError(e)),
+                    }
+                }
+                while let Some(message) = attached.decoder.decode()? {
+                    progress = true;
+                    match message {
+                        Message::Stdout(bytes) => {
+                            keep_cell_output(&mut self.stdout, &bytes);
+                            if self.status.is_none() {
+                                self.status = cell_status(&mut self.stdout);
+                            }
+                        }
+                        Message::Stderr(bytes) => {
+                            keep_cell_output(&mut self.stderr, &bytes);
+                            if self.stderr.ends_with(&[CELL_END, b'\n']) {
+                                let end = self.stderr.len() - 2;
+                                self.stderr.truncate(end);
+                                self.stderr_done = true;
+                            }
+                        }
+                        Message::Stdin(_) => (),
+                    }
+                }
+            }
+            if self.stderr_done {
+                if let Some(status) = self.status.take() {
+                    return Ok(Async::Ready(self.finish(status)));
+                }
+            }
+            let mut chunk = [0; 4096];
+            let read = self.attached
+                .as_mut()
+                .expect("polled after completion")
+                .stream
+                .read(&mut chunk);
+            match read {
+                Ok(0) => {
+                    trace!("interpreter exited");
+                    return Ok(Async::Ready(self.finish(CellStatus::Exited)));
+                }
+                Ok(n) => {
+                    self.attached.as_mut().unwrap().decoder.extend(&chunk[..n]);
+                    progress = true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(DockerError::IoError(e)),
+            }
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+/// One of the two containers being relayed between
+struct Side {
+    party: Party,
+    attached: Attached,
+    /// Its stdout and stderr ended
+    done: bool,
+    /// Output of the other side waiting to be written to its stdin
+    pending: Vec<u8>,
+    /// Its stdin was closed
+    closed: bool,
+    stderr: Vec<u8>,
+    /// Bytes of its output kept in the transcript
+    said: usize,
+}
+
+impl Side {
+    fn new(party: Party, attached: Attached) -> Self {
+        Side {
+            party: party,
+            attached: attached,
+            done: false,
+            pending: Vec::new(),
+            closed: false,
+            stderr: Vec::new(),
+            said: 0,
+        }
+    }
+
+    /// Reads what is available, passing stdout on to `other`.
+    /// Returns whether anything happened
+    fn read(&mut self, other: &mut Side, transcript: &mut Vec<Exchange>) -> Result<bool, DockerError> {
+        if self.done {
+            return Ok(false);
+        }
+        let mut progress = false;
+        // the handshake may have read some messages already
+        while let Some(message) = self.attached.decoder.decode()? {
+            progress = true;
+            let (stream, bytes) = match message {
+                // nobody reads it once the other side's stdin is closed
+                Message::Stdout(bytes) => {
+                    if !other.closed {
+                        other.pending.extend_from_slice(&bytes);
+                    }
+                    (OutputStream::Stdout, bytes)
+                }
+                Message::Stderr(bytes) => {
+                    keep(&mut self.stderr, &bytes);
+                    (OutputStream::Stderr, bytes)
+                }
+                Message::Stdin(_) => continue,
+            };
+            let kept = cmp::min(MAX_KEPT - self.said, bytes.len());
+            if kept == 0 {
+                continue;
+            }
+            self.said += kept;
+            transcript.push(Exchange {
+                from: self.party,
+                stream: stream,
+                data: String::from_utf8_lossy(&bytes[..kept]).into_owned(),
+            });
+        }
+        // writing to the other side wakes us up once it reads
+        if other.pending.len() >= MAX_PENDING {
+            return Ok(progress);
+        }
+        let mut chunk = [0; 4096];
+        match self.attached.stream.read(&mut chunk) {
+            Ok(0) => {
+                trace!("{:?} finished", self.party);
+                self.attached.decoder.finish()?;
+                self.done = true;
+            }
+            Ok(n) => self.attached.decoder.extend(&chunk[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(progress),
+            Err(e) => return Err(DockerError::IoError(e)),
+        }
+        Ok(true)
+    }
+
+    /// Writes pending input, closes stdin once the other side is
+    /// done and everything was written. Returns whether anything happened
+    fn write(&mut self, other_done: bool) -> bool {
+        if self.closed {
+            return false;
+        }
+        let mut progress = false;
+        while !self.pending.is_empty() {
+            match self.attached.stream.write(&self.pending) {
+                Ok(n) => {
+                    self.pending.drain(..n);
+                    progress = true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return progress,
+                Err(e) => {
+                    // it stopped reading, e.g. because it exited
+                    debug!("can't write to {:?}: {:?}", self.party, e);
+                    self.pending.clear();
+                    self.closed = true;
+                    return true;
+                }
+            }
+        }
+        if other_done {
+            trace!("closing stdin of {:?}", self.party);
+            if let Err(e) = self.attached.stream.shutdown(Shutdown::Write) {
+                debug!("can't close stdin of {:?}: {:?}", self.party, e);
+            }
+            self.closed = true;
+            progress = true;
+        }
+        progress
+    }
+}
+
+/// What was said between the contestant and the interactor
+#[derive(Debug)]
+pub struct Conversation {
+    pub transcript: Vec<Exchange>,
+    pub contestant_stderr: Vec<u8>,
+    pub interactor_stderr: Vec<u8>,
+}
+
+/// Relays the stdout of each container to the stdin of the
+/// other until both of them are finished
+pub struct Relay {
+    contestant: Side,
+    interactor: Side,
+    transcript: Vec<Exchange>,
+}
+
+impl Relay {
+    pub fn new(contestant: Attached, interactor: Attached) -> Self {
+        Relay {
+            contestant: Side::new(Party::Contestant, contestant),
+            interactor: Side::new(Party::Interactor, interactor),
+            transcript: Vec::new(),
+        }
+    }
+
+    fn conversation(&mut self) -> Conversation {
+        Conversation {
+            transcript: ::std::mem::take(&mut self.transcript),
+            contestant_stderr: ::std::mem::take(&mut self.contestant.stderr),
+            interactor_stderr: ::std::mem::take(&mut self.interactor.stderr),
+        }
+    }
+
+    /// What was said so far by a pair which took too long, hanging up on them
+    pub fn timed_out(mut self) -> Conversation {
+        self.conversation()
+    }
+}
+
+impl Future for Relay {
+    type Item = Conversation;
+    type Error = DockerError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let progress = {
+                let Relay {
+                    ref mut contestant,
+                    ref mut interactor,
+                    ref mut transcript,
+                } = *self;
+                // keep going until nothing is left to do, so the
+                // streams are registered for wake ups
+                let mut progress = contestant.read(interactor, transcript)?;
+                progress |= interactor.read(contestant, transcript)?;
+                progress |= contestant.write(interactor.done);
+                progress |= interactor.write(contestant.done);
+                progress
+            };
+            if self.contestant.done && self.interactor.done {
+                return Ok(Async::Ready(self.conversation()));
+            }
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
@@ -1,32 +1,260 @@
+use executor::attach::{self, Attached};
 use executor::error::DockerError;
-use executor::log::Logs;
+use executor::log::{Format, Logs};
 use hyper::Client;
 use hyper::client::{Connect, Request};
 use hyper::header::{Connection, ConnectionOption};
 use hyper::{self, Method, StatusCode};
 use hyperlocal::Uri;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use unicase::Ascii;
 
-use std::collections::HashMap;
+use json;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 use url::form_urlencoded::Serializer as FormEncoder;
 
-use futures::{future, Future};
+use futures::{future, Future, Stream};
+
+/// The engines which can serve the Docker API
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Flavor {
+    Docker,
+    /// Podman's Docker-compatible API, usually running rootless
+    Podman,
+}
+
+/// A component of the engine as reported by `/version`
+#[derive(Deserialize, Debug)]
+pub struct Component {
+    #[serde(rename = "Name")]
+    pub name: String,
+}
+
+/// Response of the `/version` endpoint
+#[derive(Deserialize, Debug)]
+pub struct Version {
+    #[serde(rename = "Version")]
+    pub version: String,
+    #[serde(rename = "Components", default)]
+    pub components: Vec<Component>,
+}
+
+impl Version {
+    /// Podman lists itself as the "Podman Engine" component
+    pub fn flavor(&self) -> Flavor {
+        if self.components
+            .iter()
+            .any(|component| component.name.contains("Podman"))
+        {
+            Flavor::Podman
+        } else {
+            Flavor::Docker
+        }
+    }
+}
 
 /// Docker Client
 pub struct Docker<C> {
     client: Client<C>,
+    /// Path of the unix socket the engine listens on
+    socket: String,
+    flavor: Rc<Cell<Flavor>>,
+    /// Used for connections which can't go through `client`
+    handle: Handle,
+    /// Containers created by us which may still be around
+    containers: Rc<RefCell<HashSet<String>>>,
 }
 
 type DockerResponse = Box<Future<Item = hyper::Response, Error = DockerError>>;
 
+/// The exit code of a container, once it stops
+pub type ExitCode = Box<Future<Item = i64, Error = DockerError>>;
+
 impl<C: Connect> Docker<C> {
     /// Creates a new Docker Client connected over the `connector`
+    /// to the engine listening on `socket`
     /// It is tied to an event loop by the `Handle`
-    pub fn new(connector: C, handle: Handle) -> Docker<C> {
+    pub fn new(connector: C, socket: &str, handle: Handle) -> Docker<C> {
         let client = Client::configure().connector(connector).build(&handle);
 
-        Docker { client: client }
+        Docker {
+            client: client,
+            socket: socket.to_owned(),
+            flavor: Rc::new(Cell::new(Flavor::Docker)),
+            handle: handle,
+            containers: Rc::new(RefCell::new(HashSet::new())),
+        }
+    }
+
+    /// Runs `future` on the event loop, for requests whose results nobody waits for
+    pub fn spawn<F: Future<Item = (), Error = ()> + 'static>(&self, future: F) {
+        self.handle.spawn(future)
+    }
+
+    /// Resolves once `duration` passed
+    pub fn timeout(&self, duration: Duration) -> Box<Future<Item = (), Error = DockerError>> {
+        let timeout = future::result(Timeout::new(duration, &self.handle))
+            .flatten()
+            .map_err(DockerError::IoError);
+        Box::new(timeout)
+    }
+
+    /// Path of the unix socket the engine listens on
+    pub fn socket(&self) -> &str {
+        &self.socket
+    }
+
+    /// The engine behind the socket, `Flavor::Docker`
+    /// until `detect_flavor` says otherwise
+    pub fn flavor(&self) -> Flavor {
+        self.flavor.get()
+    }
+
+    /// Returns version information of the engine
+    pub fn version(&self) -> Box<Future<Item = Version, Error = DockerError>> {
+        let uri = Uri::new(&self.socket, "/v1.30/version");
+        let request = Request::new(Method::Get, uri.into());
+        let response = self.request(request).and_then(|resp| {
+            let status = resp.status();
+            resp.body()
+                .map_err(|e| DockerError::HyperError(e))
+                .fold(Vec::new(), |mut body, chunk| {
+                    body.extend(&*chunk);
+                    Ok(body)
+                })
+                .and_then(move |body| match status {
+                    StatusCode::Ok => json::from_slice(&body).map_err(|e| {
+                        debug!("invalid version: {:?}", e);
+                        DockerError::UnknownError
+                    }),
+                    _ => Err(DockerError::InternalServerError),
+                })
+        });
+        Box::new(response)
+    }
+
+    /// Succeeds if the engine answers
+    pub fn ping(&self) -> Box<Future<Item = (), Error = DockerError>> {
+        let uri = Uri::new(&self.socket, "/v1.30/_ping");
+        let request = Request::new(Method::Get, uri.into());
+        let response = self.request(request).and_then(|resp| match resp.status() {
+            StatusCode::Ok => future::ok(()),
+            _ => future::err(DockerError::InternalServerError),
+        });
+        Box::new(response)
+    }
+
+    /// Whether the image `name` is present, without pulling it
+    pub fn has_image(&self, name: &str) -> Box<Future<Item = bool, Error = DockerError>> {
+        let uri = format!("/v1.30/images/{name}/json", name = name);
+        let uri = Uri::new(&self.socket, &uri);
+        let request = Request::new(Method::Get, uri.into());
+        let response = self.request(request).and_then(|resp| match resp.status() {
+            StatusCode::Ok => future::ok(true),
+            StatusCode::NotFound => future::ok(false),
+            _ => future::err(DockerError::InternalServerError),
+        });
+        Box::new(response)
+    }
+
+    /// Returns the bytes the layers of the image `name` on top of the tagged
+    /// image it is built from take up on disk, which removing it frees
+    pub fn image_size(&self, name: &str) -> Box<Future<Item = u64, Error = DockerError>> {
+        /// A layer of an image's history
+        #[derive(Deserialize)]
+        struct Layer {
+            #[serde(rename = "Size", default)]
+            size: u64,
+            #[serde(rename = "Tags", default)]
+            tags: Option<Vec<String>>,
+        }
+        let uri = format!("/v1.30/images/{name}/history", name = name);
+        let uri = Uri::new(&self.socket, &uri);
+        let request = Request::new(Method::Get, uri.into());
+        let response = self.request(request).and_then(|resp| {
+            let status = resp.status();
+            resp.body()
+                .map_err(DockerError::HyperError)
+                .concat2()
+                .and_then(move |body| match status {
+                    // the newest layer is listed first
+                    StatusCode::Ok => json::from_slice::<Vec<Layer>>(&body)
+                        .map(|layers| {
+                            layers
+                                .into_iter()
+                                .take_while(|layer| layer.tags.as_ref().is_none_or(|tags| tags.is_empty()))
+                                .map(|layer| layer.size)
+                                .sum()
+                        })
+                        .map_err(|e| {
+                            debug!("invalid image: {:?}", e);
+                            DockerError::InvalidMessage(String::from_utf8_lossy(&body).into_owned())
+                        }),
+                    StatusCode::NotFound => Err(DockerError::NotFound),
+                    _ => Err(DockerError::InternalServerError),
+                })
+        });
+        Box::new(response)
+    }
+
+    /// Returns the ids of the images which have the label `label`
+    pub fn labelled_images(&self, label: &str) -> Box<Future<Item = Vec<String>, Error = DockerError>> {
+        /// An image as it is listed
+        #[derive(Deserialize)]
+        struct Image {
+            #[serde(rename = "Id")]
+            id: String,
+        }
+        let params = FormEncoder::new(String::new())
+            .append_pair("filters", &json!({ "label": [label] }).to_string())
+            .finish();
+        let uri = format!("/v1.30/images/json?{params}", params = params);
+        let uri = Uri::new(&self.socket, &uri);
+        let request = Request::new(Method::Get, uri.into());
+        let response = self.request(request).and_then(|resp| {
+            let status = resp.status();
+            resp.body()
+                .map_err(DockerError::HyperError)
+                .concat2()
+                .and_then(move |body| match status {
+                    StatusCode::Ok => json::from_slice::<Vec<Image>>(&body)
+                        .map(|images| images.into_iter().map(|image| image.id).collect())
+                        .map_err(|e| {
+                            debug!("invalid images: {:?}", e);
+                            DockerError::InvalidMessage(String::from_utf8_lossy(&body).into_owned())
+                        }),
+                    _ => Err(DockerError::InternalServerError),
+                })
+        });
+        Box::new(response)
+    }
+
+    /// Removes the image `name`, along with its tags
+    pub fn remove_image(&self, name: &str) -> Box<Future<Item = (), Error = DockerError>> {
+        let uri = format!("/v1.30/images/{name}?force=true", name = name);
+        let uri = Uri::new(&self.socket, &uri);
+        let request = Request::new(Method::Delete, uri.into());
+        let response = self.request(request).and_then(|resp| match resp.status() {
+            StatusCode::Ok => future::ok(()),
+            StatusCode::NotFound => future::err(DockerError::NotFound),
+            _ => future::err(DockerError::InternalServerError),
+        });
+        Box::new(response)
+    }
+
+    /// Asks the engine for its version to find out whether it is Docker
+    /// or Podman, the result is used for all later requests
+    pub fn detect_flavor(&self) -> Box<Future<Item = Flavor, Error = DockerError>> {
+        let flavor = self.flavor.clone();
+        let response = self.version().map(move |version| {
+            debug!("engine: {:?} {}", version.flavor(), version.version);
+            flavor.set(version.flavor());
+            version.flavor()
+        });
+        Box::new(response)
     }
 
     /// Helper method for sending requests which don't
@@ -41,7 +269,7 @@ impl<C: Connect> Docker<C> {
     /// Starts a container specified by the `id`
     pub fn start_container(&self, id: &str) -> Box<Future<Item = (), Error = DockerError>> {
         let uri = format!("v1.30/containers/{id}/start", id = id);
-        let uri = Uri::new("/var/run/docker.sock", &uri);
+        let uri = Uri::new(&self.socket, &uri);
         let request = Request::new(Method::Post, uri.into());
         let resp = self.client
             .request(request)
@@ -54,32 +282,198 @@ impl<C: Connect> Docker<C> {
         Box::new(resp)
     }
 
-    /// Returns logs from the container specified by `container_id`
-    pub fn logs(&self, container_id: &str) -> Box<Future<Item = Logs, Error = DockerError>> {
+    /// Extracts the `tar` archive into `path` in the container specified by `id`
+    pub fn upload_archive(
+        &self,
+        id: &str,
+        path: &str,
+        tar: Vec<u8>,
+    ) -> Box<Future<Item = (), Error = DockerError>> {
+        let params = FormEncoder::new(String::new())
+            .append_pair("path", path)
+            .finish();
+        let uri = format!("v1.30/containers/{id}/archive?{params}", id = id, params = params);
+        let uri = Uri::new(&self.socket, &uri);
+        let mut request = Request::new(Method::Put, uri.into());
+        request.set_body(tar);
+        let resp = self.request(request)
+            .and_then(|resp| match resp.status() {
+                StatusCode::Ok => future::ok(()),
+                StatusCode::NotFound => future::err(DockerError::NotFound),
+                StatusCode::BadRequest | StatusCode::Forbidden => {
+                    future::err(DockerError::BadRequest)
+                }
+                _ => future::err(DockerError::InternalServerError),
+            });
+        Box::new(resp)
+    }
+
+    /// Returns a tar of `path` in the container specified by `id`
+    /// # Arguments
+    /// * `limit` - Most bytes kept, the rest of the archive is dropped
+    pub fn download_archive(
+        &self,
+        id: &str,
+        path: &str,
+        limit: usize,
+    ) -> Box<Future<Item = Vec<u8>, Error = DockerError>> {
+        let params = FormEncoder::new(String::new())
+            .append_pair("path", path)
+            .finish();
+        let uri = format!("v1.30/containers/{id}/archive?{params}", id = id, params = params);
+        let uri = Uri::new(&self.socket, &uri);
+        let request = Request::new(Method::Get, uri.into());
+        let resp = self.request(request).and_then(move |resp| {
+            let status = resp.status();
+            resp.body()
+                .map_err(DockerError::HyperError)
+                .fold(Vec::new(), move |mut tar, chunk| {
+                    let room = limit.saturating_sub(tar.len()).min(chunk.len());
+                    tar.extend_from_slice(&chunk[..room]);
+                    future::ok::<_, DockerError>(tar)
+                })
+                .and_then(move |tar| match status {
+                    StatusCode::Ok => future::ok(tar),
+                    StatusCode::NotFound => future::err(DockerError::NotFound),
+                    StatusCode::BadRequest => future::err(DockerError::BadRequest),
+                    _ => future::err(DockerError::InternalServerError),
+                })
+        });
+        Box::new(resp)
+    }
+
+    /// Waits for the container specified by `id` to stop, returns its exit code
+    pub fn wait_container(&self, id: &str) -> Box<Future<Item = i64, Error = DockerError>> {
+        Box::new(self.waiting(id, None).flatten())
+    }
+
+    /// Waits for the container specified by `id` to exit next, so that it can
+    /// be called before starting it. Resolves once Docker is waiting, to the
+    /// exit code it will have. Containers removed once they stop still have one
+    pub fn wait_next_exit(&self, id: &str) -> Box<Future<Item = ExitCode, Error = DockerError>> {
+        self.waiting(id, Some("next-exit"))
+    }
+
+    /// Sends the wait for the container specified by `id` until `condition`
+    fn waiting(&self, id: &str, condition: Option<&str>) -> Box<Future<Item = ExitCode, Error = DockerError>> {
+        let mut uri = format!("v1.30/containers/{id}/wait", id = id);
+        if let Some(condition) = condition {
+            uri.push_str("?condition=");
+            uri.push_str(condition);
+        }
+        let uri = Uri::new(&self.socket, &uri);
+        let request = Request::new(Method::Post, uri.into());
+        // Docker sends the headers as soon as it waits, the body once it is over
+        let resp = self.request(request).map(|resp| {
+            let status = resp.status();
+            let exit_code = resp.body()
+                .map_err(DockerError::HyperError)
+                .concat2()
+                .and_then(move |body| match status {
+                    StatusCode::Ok => json::from_slice::<json::Value>(&body)
+                        .ok()
+                        .and_then(|body| body["StatusCode"].as_i64())
+                        .ok_or_else(|| {
+                            DockerError::InvalidMessage(String::from_utf8_lossy(&body).into_owned())
+                        }),
+                    StatusCode::NotFound => Err(DockerError::NotFound),
+                    _ => Err(DockerError::InternalServerError),
+                });
+            Box::new(exit_code) as ExitCode
+        });
+        Box::new(resp)
+    }
+
+    /// Removes the container specified by `id`, killing it if it is still running
+    pub fn remove_container(&self, id: &str) -> Box<Future<Item = (), Error = DockerError>> {
+        let uri = format!("v1.30/containers/{id}?force=true", id = id);
+        let uri = Uri::new(&self.socket, &uri);
+        let request = Request::new(Method::Delete, uri.into());
+        let containers = self.containers.clone();
+        let id = id.to_owned();
+        let resp = self.request(request)
+            .and_then(move |resp| match resp.status() {
+                StatusCode::NoContent => {
+                    containers.borrow_mut().remove(&id);
+                    future::ok(())
+                }
+                StatusCode::NotFound => {
+                    containers.borrow_mut().remove(&id);
+                    future::err(DockerError::NotFound)
+                }
+                _ => future::err(DockerError::InternalServerError),
+            });
+        Box::new(resp)
+    }
+
+    /// Keeps the container specified by `id` to be removed by
+    /// `remove_all`, until it is removed
+    pub fn track(&self, id: &str) {
+        self.containers.borrow_mut().insert(id.to_owned());
+    }
+
+    /// Forgets the container specified by `id`, which Docker removes by itself
+    pub fn forget(&self, id: &str) {
+        self.containers.borrow_mut().remove(id);
+    }
+
+    /// Removes the containers created by us which are still around, killing
+    /// them if they are running, returns how many were removed
+    pub fn remove_all(&self) -> Box<Future<Item = usize, Error = ()>> {
+        let ids = self.containers.borrow().iter().cloned().collect::<Vec<_>>();
+        let removals = ids.into_iter().map(|id| {
+            self.remove_container(&id).then(move |removed| match removed {
+                Ok(()) => Ok(true),
+                Err(e) => {
+                    debug!("can't remove container {}: {:?}", id, e);
+                    Ok(false)
+                }
+            })
+        });
+        let removed = future::join_all(removals.collect::<Vec<_>>())
+            .map(|removed| removed.into_iter().filter(|&removed| removed).count());
+        Box::new(removed)
+    }
+
+    /// Attaches to the streams of the container specified by `id`
+    pub fn attach(&self, id: &str) -> Box<Future<Item = Attached, Error = DockerError>> {
+        attach::attach(&self.socket, id, &self.handle)
+    }
+
+    /// Returns logs from the container specified by `container_id`,
+    /// every message starts with the time it was written at
+    /// # Arguments
+    /// * `format` - `Format::Raw` if the container was created with a TTY
+    pub fn logs(
+        &self,
+        container_id: &str,
+        format: Format,
+    ) -> Box<Future<Item = Logs, Error = DockerError>> {
         let mut params = HashMap::new();
         params.insert("follow", "true");
         params.insert("stdout", "true");
         params.insert("stderr", "true");
+        params.insert("timestamps", "true");
         let params = FormEncoder::new(String::new())
             .extend_pairs(params)
             .finish();
         let mut uri = format!("v1.30/containers/{id}/logs?", id = container_id);
         uri.push_str(&params);
         trace!("{}", uri);
-        let uri = Uri::new("/var/run/docker.sock", &uri);
+        let uri = Uri::new(&self.socket, &uri);
         let mut request = Request::new(Method::Get, uri.into());
         let upgrade = Connection(vec![
             ConnectionOption::ConnectionHeader(Ascii::new("upgrade".to_owned())),
         ]);
         request.headers_mut().set(upgrade);
-        let response = self.request(request).and_then(|resp| {
+        let response = self.request(request).and_then(move |resp| {
             trace!("logs status: {}", resp.status());
             match resp.status() {
                 StatusCode::Ok | StatusCode::SwitchingProtocols => (),
                 StatusCode::NotFound => return future::err(DockerError::NotFound),
                 _ => return future::err(DockerError::InternalServerError),
             }
-            future::ok(Logs::new(resp.body()))
+            future::ok(Logs::new(resp.body(), format))
         });
 
         Box::new(response)
@@ -0,0 +1,231 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+
+/// Label of the images kept in the cache, its value is the key they are
+/// kept by. It has nothing to do with the request, so that building the
+/// same submission twice at once gives the same image where Docker can
+pub const LABEL: &str = "code-executor.image";
+
+/// A compiled image along with when it was last used
+#[derive(Debug)]
+struct Image {
+    id: String,
+    /// Bytes it takes up on disk
+    size: u64,
+    /// Value of the clock when it was last used
+    used: u64,
+}
+
+/// Images built from submissions, by the hash of what they are built
+/// from except for the input, so that they can be run with any input
+#[derive(Debug)]
+pub struct ImageCache {
+    /// Most bytes the images take up on disk together
+    size: u64,
+    images: RefCell<HashMap<String, Image>>,
+    /// Bytes the images take up, each one counted once however many keys keep it
+    total: Cell<u64>,
+    /// Counts uses, tells which image was used last
+    clock: Cell<u64>,
+    /// Requests using each image, by its id
+    pins: RefCell<HashMap<String, usize>>,
+    /// Images no longer kept which are removed once they aren't used
+    dropped: RefCell<HashSet<String>>,
+}
+
+/// Whether a key of `images` keeps the image `id`
+fn kept(images: &HashMap<String, Image>, id: &str) -> bool {
+    images.values().any(|image| image.id == id)
+}
+
+impl ImageCache {
+    /// Creates an empty cache of images taking up at most `size` bytes
+    pub fn new(size: u64) -> Self {
+        ImageCache {
+            size: size,
+            images: RefCell::new(HashMap::new()),
+            total: Cell::new(0),
+            clock: Cell::new(0),
+            pins: RefCell::new(HashMap::new()),
+            dropped: RefCell::new(HashSet::new()),
+        }
+    }
+
+    fn pin(&self, id: &str) {
+        *self.pins.borrow_mut().entry(id.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Adds the image `id` no longer kept by a key of `images` to `removed`
+    /// if it isn't used, otherwise it is removed once released
+    fn drop_image(&self, images: &HashMap<String, Image>, id: String, removed: &mut Vec<String>) {
+        if kept(images, &id) {
+            return;
+        }
+        if self.pins.borrow().contains_key(&id) {
+            self.dropped.borrow_mut().insert(id);
+        } else {
+            removed.push(id);
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.set(self.clock.get() + 1);
+        self.clock.get()
+    }
+
+    /// Returns the id of the image built from `key`, if it is kept. It
+    /// isn't removed until it is released
+    pub fn get(&self, key: &str) -> Option<String> {
+        let used = self.tick();
+        let id = self.images.borrow_mut().get_mut(key).map(|image| {
+            image.used = used;
+            image.id.clone()
+        })?;
+        self.pin(&id);
+        Some(id)
+    }
+
+    /// Keeps the image `id` built from `key`, which isn't removed until it
+    /// is released. Returns the ids of the least recently used images which
+    /// are dropped to make room for it and aren't used, they are to be
+    /// removed. Images larger than the whole cache are removed once released
+    pub fn insert(&self, key: String, id: String, size: u64) -> Vec<String> {
+        self.pin(&id);
+        self.dropped.borrow_mut().remove(&id);
+        if size > self.size {
+            self.dropped.borrow_mut().insert(id);
+            return Vec::new();
+        }
+        let mut images = self.images.borrow_mut();
+        let mut dropped = Vec::new();
+        let image = Image {
+            id: id.clone(),
+            size: size,
+            used: self.tick(),
+        };
+        // the same image may be kept by another key already
+        if !kept(&images, &id) {
+            self.total.set(self.total.get() + size);
+        }
+        if let Some(replaced) = images.insert(key, image) {
+            // the same image may have been built again
+            if !kept(&images, &replaced.id) {
+                self.total.set(self.total.get() - replaced.size);
+                self.drop_image(&images, replaced.id, &mut dropped);
+            }
+        }
+        while self.total.get() > self.size {
+            let oldest = images
+                .iter()
+                .min_by_key(|&(_, image)| image.used)
+                .map(|(key, _)| key.clone())
+                .expect("images take up the room");
+            let image = images.remove(&oldest).expect("is kept");
+            if !kept(&images, &image.id) {
+                self.total.set(self.total.get() - image.size);
+                self.drop_image(&images, image.id, &mut dropped);
+            }
+        }
+        dropped
+    }
+
+    /// Stops using the image `id` got or inserted, returns whether
+    /// it is to be removed now, as it was dropped meanwhile
+    pub fn release(&self, id: &str) -> bool {
+        let mut pins = self.pins.borrow_mut();
+        let unused = match pins.get_mut(id) {
+            Some(pins) => {
+                *pins -= 1;
+                *pins == 0
+            }
+            None => return false,
+        };
+        if !unused {
+            return false;
+        }
+        pins.remove(id);
+        self.dropped.borrow_mut().remove(id)
+    }
+
+    /// Stops keeping the image `id` built from `key`, e.g. as it was removed
+    /// by someone else. Returns whether it was kept, it isn't if `key` has
+    /// been built again since
+    pub fn forget(&self, key: &str, id: &str) -> bool {
+        let mut images = self.images.borrow_mut();
+        if images.get(key).is_none_or(|image| image.id != id) {
+            return false;
+        }
+        let image = images.remove(key).expect("is kept");
+        if !kept(&images, &image.id) {
+            self.total.set(self.total.get() - image.size);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_least_recently_used_images() {
+        let cache = ImageCache::new(250);
+        // each image is released once the request using it is done
+        let insert = |key: &str, id: &str, size| {
+            let removed = cache.insert(key.to_owned(), id.to_owned(), size);
+            assert!(!cache.release(id));
+            removed
+        };
+        assert!(insert("a", "1", 100).is_empty());
+        assert!(insert("b", "2", 100).is_empty());
+        assert_eq!(cache.get("a"), Some("1".to_owned()));
+        assert!(!cache.release("1"));
+        assert_eq!(insert("c", "3", 100), vec!["2"]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(insert("d", "4", 200), vec!["1", "3"]);
+        assert_eq!(cache.insert("e".to_owned(), "5".to_owned(), 300), Vec::<String>::new());
+        assert!(cache.release("5"));
+        assert_eq!(cache.get("e"), None);
+        assert_eq!(insert("d", "4", 200), Vec::<String>::new());
+        assert_eq!(insert("d", "6", 200), vec!["4"]);
+        assert!(!cache.forget("d", "4"));
+        assert!(cache.forget("d", "6"));
+        assert_eq!(cache.total.get(), 0);
+    }
+
+    #[test]
+    fn keeps_images_in_use() {
+        let cache = ImageCache::new(250);
+        assert!(cache.insert("a".to_owned(), "1".to_owned(), 100).is_empty());
+        assert!(cache.insert("b".to_owned(), "2".to_owned(), 100).is_empty());
+        assert_eq!(cache.get("a"), Some("1".to_owned()));
+        // the second is dropped, but still used by the request which built it
+        assert!(cache.insert("c".to_owned(), "3".to_owned(), 100).is_empty());
+        assert!(!cache.release("1"));
+        assert!(!cache.release("1"));
+        assert!(cache.release("2"));
+        assert!(!cache.release("2"));
+        assert_eq!(cache.insert("d".to_owned(), "4".to_owned(), 100), vec!["1"]);
+        assert!(cache.insert("e".to_owned(), "5".to_owned(), 100).is_empty());
+        // built again while used, so it is kept after all
+        assert!(cache.insert("c".to_owned(), "3".to_owned(), 100).is_empty());
+        assert!(!cache.release("3"));
+        assert!(!cache.release("3"));
+        assert!(cache.release("4"));
+    }
+
+    #[test]
+    fn counts_shared_images_once() {
+        let cache = ImageCache::new(250);
+        // Docker gives the same id to images built from the same context
+        assert!(cache.insert("a".to_owned(), "1".to_owned(), 100).is_empty());
+        assert!(cache.insert("b".to_owned(), "1".to_owned(), 100).is_empty());
+        assert_eq!(cache.total.get(), 100);
+        assert!(cache.insert("c".to_owned(), "2".to_owned(), 100).is_empty());
+        assert_eq!(cache.get("a"), Some("1".to_owned()));
+        assert!(cache.forget("b", "1"));
+        assert_eq!(cache.total.get(), 200);
+        assert!(cache.forget("a", "1"));
+        assert_eq!(cache.total.get(), 100);
+    }
+}
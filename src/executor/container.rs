@@ -57,7 +57,7 @@ impl ContainerBuilder {
         self
     }
 
-    pub fn build(self) -> Result<Request, hyper::Error> {
+    pub fn build(self, socket: &str) -> Result<Request, hyper::Error> {
         let params = FormEncoder::new(String::new())
             .extend_pairs(self.params)
             .finish();
@@ -66,7 +66,7 @@ impl ContainerBuilder {
             uri.push_str(&"?");
             uri.push_str(&params);
         }
-        let uri = Uri::new("/var/run/docker.sock", &uri);
+        let uri = Uri::new(socket, &uri);
         let mut req = Request::new(Method::Post, uri.into());
 
         *req.headers_mut() = self.headers;
@@ -80,7 +80,7 @@ impl ContainerBuilder {
         self,
         client: &Docker<C>,
     ) -> Box<Future<Item = String, Error = DockerError>> {
-        let request = match self.build() {
+        let request = match self.build(client.socket()) {
             Ok(request) => request,
             _ => return Box::new(future::err(DockerError::BadRequest)),
         };
@@ -1,4 +1,5 @@
 use hyper;
+use std::io;
 
 #[derive(Debug)]
 pub enum DockerError {
@@ -8,4 +9,40 @@ pub enum DockerError {
     CantAttach,
     UnknownError,
     NotFound,
+    /// A message in a response body couldn't be parsed
+    InvalidMessage(String),
+    /// Error on a connection hijacked from Docker
+    IoError(io::Error),
+}
+
+impl DockerError {
+    /// Name of the variant, errors are counted by it
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DockerError::HyperError(_) => "hyper_error",
+            DockerError::BadRequest => "bad_request",
+            DockerError::InternalServerError => "internal_server_error",
+            DockerError::CantAttach => "cant_attach",
+            DockerError::UnknownError => "unknown_error",
+            DockerError::NotFound => "not_found",
+            DockerError::InvalidMessage(_) => "invalid_message",
+            DockerError::IoError(_) => "io_error",
+        }
+    }
+}
+
+/// Errors while building an image
+#[derive(Debug)]
+pub enum BuildError {
+    /// A `RUN` step, which compiles or checks the code, exited non-zero.
+    /// Holds its output, e.g. compiler errors, or the error message if
+    /// there was none
+    Failed(String),
+    /// The daemon failed the build otherwise, e.g. pulling the base
+    /// image or writing to a full disk. Holds its error message
+    Daemon(String),
+    /// The build finished without reporting the Id of the image
+    MissingId,
+    /// Error communicating with Docker
+    DockerError(DockerError),
 }
@@ -0,0 +1,133 @@
+use base64;
+use glob::{MatchOptions, Pattern};
+use tar;
+
+use OutputFiles;
+
+use std::cmp;
+use std::io::Read;
+
+/// Most bytes sent of a single file
+const MAX_FILE_SIZE: u64 = 1048576;
+/// Most bytes sent of all the files
+pub const MAX_TOTAL_SIZE: u64 = 8388608;
+
+/// Returns the files in `tar` which match any of `patterns`
+/// # Arguments
+/// * `root` - Name of the archived directory, paths are relative to it
+pub fn collect(tar: &[u8], root: &str, patterns: &[Pattern]) -> OutputFiles {
+    // `*` doesn't go into directories, `**` does
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    let prefix = format!("{}/", root);
+    let mut files = OutputFiles::default();
+    let mut total = 0;
+    let mut archive = tar::Archive::new(tar);
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("can't read archive: {:?}", e);
+            return files;
+        }
+    };
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            // it may have been cut short
+            Err(e) => {
+                debug!("archive ended early: {:?}", e);
+                break;
+            }
+        };
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let path = match path.strip_prefix(&prefix) {
+            Some(path) => path.to_owned(),
+            None => continue,
+        };
+        if !patterns.iter().any(|pattern| pattern.matches_with(&path, options)) {
+            continue;
+        }
+        let room = cmp::min(MAX_FILE_SIZE, MAX_TOTAL_SIZE - total);
+        if room == 0 {
+            files.skipped.push(path);
+            continue;
+        }
+        let size = entry.header().size().unwrap_or(0);
+        let mut data = Vec::new();
+        if let Err(e) = (&mut entry).take(room).read_to_end(&mut data) {
+            debug!("can't read {}: {:?}", path, e);
+            files.skipped.push(path);
+            break;
+        }
+        total += data.len() as u64;
+        if size > data.len() as u64 {
+            files.truncated.push(path.clone());
+        }
+        files.files.insert(path, base64::encode(&data));
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::{Builder, Header};
+
+    fn archive(files: &[(&str, usize)]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for &(path, size) in files {
+            let mut header = Header::new_gnu();
+            header.set_path(path).unwrap();
+            header.set_size(size as u64);
+            header.set_cksum();
+            builder.append(&header, &vec![b'x'; size][..]).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn patterns(globs: &[&str]) -> Vec<Pattern> {
+        globs.iter().map(|glob| Pattern::new(glob).unwrap()).collect()
+    }
+
+    #[test]
+    fn matches_globs() {
+        let tar = archive(&[
+            ("src/code.c", 3),
+            ("src/out.csv", 2),
+            ("src/plots/a.png", 1),
+            ("src/plots/deep/b.png", 1),
+        ]);
+        let files = collect(&tar, "src", &patterns(&["*.csv", "plots/**/*.png"]));
+        let paths = files.files.keys().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["out.csv", "plots/a.png", "plots/deep/b.png"]);
+        assert_eq!(files.files["out.csv"], "eHg=");
+        let files = collect(&tar, "src", &patterns(&["*.png"]));
+        assert!(files.files.is_empty());
+    }
+
+    #[test]
+    fn truncates_and_skips() {
+        let big = MAX_FILE_SIZE as usize + 1;
+        let mut entries = (0..8).map(|i| (format!("src/{}", i), big)).collect::<Vec<_>>();
+        entries.push(("src/8".to_owned(), 10));
+        let entries = entries.iter().map(|&(ref path, size)| (path.as_str(), size)).collect::<Vec<_>>();
+        let files = collect(&archive(&entries), "src", &patterns(&["*"]));
+        assert_eq!(files.files.len(), 8);
+        assert_eq!(files.truncated, vec!["0", "1", "2", "3", "4", "5", "6", "7"]);
+        assert_eq!(files.skipped, vec!["8"]);
+        assert_eq!(files.files["0"].len(), base64::encode(&vec![b'x'; MAX_FILE_SIZE as usize]).len());
+    }
+
+    #[test]
+    fn keeps_files_of_cut_archives() {
+        let tar = archive(&[("src/a", 10), ("src/b", 1000)]);
+        let files = collect(&tar[..1024], "src", &patterns(&["*"]));
+        assert_eq!(files.files.keys().collect::<Vec<_>>(), vec!["a"]);
+    }
+}
@@ -11,7 +11,8 @@ use futures::future;
 use std::collections::HashMap;
 
 use executor::client::Docker;
-use executor::error::DockerError;
+use executor::error::{BuildError, DockerError};
+use executor::trace::Trace;
 
 pub struct BuildMessages {
     body: hyper::Body,
@@ -21,6 +22,8 @@ pub struct BuildMessages {
 
 #[derive(Deserialize, Debug)]
 pub struct Detail {
+    /// Podman leaves out the code
+    #[serde(default)]
     code: i32,
     message: String,
 }
@@ -38,6 +41,21 @@ pub enum Message {
         error_detail: Detail,
         error: String,
     },
+    /// Auxiliary data: `{"ID": ..}` of the built image, or a base64
+    /// encoded BuildKit trace when `id` is `moby.buildkit.trace`
+    Aux {
+        #[serde(default)]
+        id: Option<String>,
+        aux: json::Value,
+    },
+    /// Progress of pulling the base image
+    Status {
+        status: String,
+        #[serde(default)]
+        progress: Option<String>,
+    },
+    /// Anything newer daemons may send which we don't know about
+    Other(json::Value),
 }
 
 impl BuildMessages {
@@ -49,7 +67,7 @@ impl BuildMessages {
         }
     }
 
-    /// Returns the next message from `buf` if it contains one
+    /// Returns the next message from `buf` if it contains a complete one
     /// Also, removes the bytes used to construct the message from `buf`
     pub fn next_message(&mut self) -> Result<Option<Message>, json::Error> {
         let (next, byte_offset) = {
@@ -60,9 +78,11 @@ impl BuildMessages {
 
         match next {
             Some(Ok(value)) => {
-                self.buf = self.buf.split_off(byte_offset);
+                self.buf.advance(byte_offset);
                 Ok(Some(value))
             }
+            // the rest of the message hasn't arrived yet
+            Some(Err(ref e)) if e.is_eof() => Ok(None),
             Some(Err(e)) => {
                 debug!("invalid stream: {:?}", self.buf);
                 Err(e)
@@ -70,55 +90,185 @@ impl BuildMessages {
             None => Ok(None),
         }
     }
+
+    /// Reads all messages, returns the Id of the built image
+    pub fn image_id(self) -> Box<Future<Item = String, Error = BuildError>> {
+        let id = self.map_err(BuildError::DockerError)
+            .fold(BuildOutput::default(), |mut output, msg| {
+                output.push(msg);
+                Ok::<_, BuildError>(output)
+            })
+            .and_then(|output| output.finish());
+        Box::new(id)
+    }
 }
 
 impl Stream for BuildMessages {
     type Item = Message;
-    type Error = hyper::Error;
+    type Error = DockerError;
 
     /// We are trying to read JSON Objects delimited by `\r\n`
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        if self.finished {
-            // last line has been parsed, we're done
-            if self.buf == r"\r\n" {
-                Ok(Async::Ready(None))
-            } else {
-                // read the list of messages next
-                let next_message = self.next_message();
-                if let Ok(Some(value)) = next_message {
-                    Ok(Async::Ready(Some(value)))
-                } else if let Err(_) = next_message {
-                    Err(hyper::Error::Incomplete)
-                } else {
+        loop {
+            match self.next_message() {
+                Ok(Some(message)) => return Ok(Async::Ready(Some(message))),
+                Ok(None) => (),
+                Err(e) => return Err(DockerError::InvalidMessage(e.to_string())),
+            }
+            if self.finished {
+                // only the trailing delimiter may be left
+                return if self.buf.iter().all(u8::is_ascii_whitespace) {
                     Ok(Async::Ready(None))
-                }
+                } else {
+                    let rest = String::from_utf8_lossy(&self.buf).into_owned();
+                    Err(DockerError::InvalidMessage(rest))
+                };
             }
-        } else {
             match self.body.poll() {
                 Ok(Async::NotReady) => return Ok(Async::NotReady),
-                Ok(Async::Ready(Some(chunk))) => {
-                    self.buf.extend(chunk);
-                }
-                Ok(Async::Ready(None)) => {
-                    // inner stream exhausted
-                    self.finished = true;
+                Ok(Async::Ready(Some(chunk))) => self.buf.extend(chunk),
+                // inner stream exhausted
+                Ok(Async::Ready(None)) => self.finished = true,
+                Err(e) => return Err(DockerError::HyperError(e)),
+            }
+        }
+    }
+}
+
+/// Removes terminal coloring sequences like `\x1b[31m`
+fn strip_colors(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip up to and including the final letter of the sequence
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
                 }
-                Err(e) => return Err(e),
             }
-            let next_message = self.next_message();
-            if let Ok(Some(value)) = next_message {
-                Ok(Async::Ready(Some(value)))
-            } else {
-                // TODO: use task::notify() instead
-                // not enough bytes available for a complete message
-                if self.buf == "\r\n" {
-                    Ok(Async::Ready(None))
-                } else {
-                    Ok(Async::NotReady)
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Accumulates build messages to find either the Id of the built
+/// image or the output of the step which failed.
+///
+/// The classic builder sends steps as `stream` messages: with `q=true` only
+/// the output of a failing step and `sha256:<id>` are sent, otherwise every
+/// step is announced with `Step n/m` (`STEP n/m:` on Podman) followed by its
+/// output. The Id also comes in an `aux` message. BuildKit sends step output
+/// in `moby.buildkit.trace` messages instead.
+#[derive(Debug, Default)]
+pub struct BuildOutput {
+    id: Option<String>,
+    /// Output of the current step
+    step: String,
+    /// Output of BuildKit steps by vertex digest
+    vertex_logs: HashMap<String, Vec<u8>>,
+    /// Digest of the BuildKit step which failed
+    failed_vertex: Option<String>,
+    error: Option<String>,
+}
+
+impl BuildOutput {
+    /// Updates the state with the next message
+    pub fn push(&mut self, msg: Message) {
+        match msg {
+            Message::Stream { stream } => self.push_stream(&stream),
+            Message::ErrorDetail { error_detail, error } => {
+                debug!("build error {}: {}", error_detail.code, error);
+                self.error = Some(error_detail.message);
+            }
+            Message::Aux { id, aux } => match (id, aux) {
+                (Some(ref id), json::Value::String(ref aux)) if id == "moby.buildkit.trace" => {
+                    match Trace::decode(aux) {
+                        Some(trace) => self.push_trace(trace),
+                        None => debug!("can't decode trace: {}", aux),
+                    }
                 }
+                (_, json::Value::Object(aux)) => {
+                    if let Some(id) = aux.get("ID").and_then(json::Value::as_str) {
+                        self.id = Some(id.trim_start_matches("sha256:").to_owned());
+                    }
+                }
+                _ => (),
+            },
+            Message::Status { status, progress } => {
+                trace!("{} {}", status, progress.unwrap_or_default());
             }
+            Message::Other(value) => debug!("unknown build message: {}", value),
+        }
+    }
+
+    fn push_stream(&mut self, stream: &str) {
+        let line = stream.trim();
+        if line.starts_with("Step ") || line.starts_with("STEP ") {
+            self.step.clear();
+        } else if line.starts_with("sha256:") && !line.contains(char::is_whitespace) {
+            // the only line sent on success with q=true
+            self.id = Some(line.trim_start_matches("sha256:").to_owned());
+        } else if let Some(id) = line.strip_prefix("Successfully built ") {
+            if self.id.is_none() {
+                self.id = Some(id.to_owned());
+            }
+        } else if line.starts_with("-->")
+            || line.starts_with("--->")
+            || line.starts_with("Removing intermediate container")
+            || line.starts_with("Successfully tagged")
+            || line == "COMMIT"
+        {
+            // cache messages and other chatter - no thank you :|
+        } else {
+            self.step.push_str(&strip_colors(stream));
+        }
+    }
+
+    fn push_trace(&mut self, trace: Trace) {
+        for (vertex, msg) in trace.logs {
+            self.vertex_logs
+                .entry(vertex)
+                .or_default()
+                .extend(msg);
+        }
+        if let Some((vertex, error)) = trace.errors.into_iter().next() {
+            debug!("step {} failed: {}", vertex, error);
+            self.failed_vertex = Some(vertex);
         }
     }
+
+    /// Returns the Id of the built image, or an error holding
+    /// the output of the step which failed
+    pub fn finish(self) -> Result<String, BuildError> {
+        let error = match self.error {
+            Some(error) => error,
+            None => return self.id.ok_or(BuildError::MissingId),
+        };
+        if !exited_non_zero(&error) {
+            return Err(BuildError::Daemon(error));
+        }
+        let vertex_logs = self.vertex_logs;
+        let output = match self.failed_vertex.and_then(|vertex| vertex_logs.get(&vertex)) {
+            Some(logs) => strip_colors(&String::from_utf8_lossy(logs)),
+            None => self.step,
+        };
+        if output.trim().is_empty() {
+            Err(BuildError::Failed(error))
+        } else {
+            Err(BuildError::Failed(output))
+        }
+    }
+}
+
+/// Whether the build error `message` is about a `RUN` step exiting non-zero,
+/// as the classic builder, BuildKit and Podman put it
+fn exited_non_zero(message: &str) -> bool {
+    message.contains("returned a non-zero code")
+        || message.contains("exit code:")
+        || message.contains("exit status")
 }
 
 /// Builder for construction Docker Images
@@ -175,8 +325,8 @@ where
         self
     }
 
-    /// Builds a HTTP Request to be sent to Docker
-    pub fn build(self) -> Result<Request, DockerError> {
+    /// Builds a HTTP Request to be sent to Docker listening on `socket`
+    pub fn build(self, socket: &str) -> Result<Request, DockerError> {
         let params = FormEncoder::new(String::new())
             .extend_pairs(self.params)
             .finish();
@@ -185,7 +335,7 @@ where
             uri.push_str(&"?");
             uri.push_str(&params);
         }
-        let uri = Uri::new("/var/run/docker.sock", &uri);
+        let uri = Uri::new(socket, &uri);
         trace!("build params: {:?}", &uri);
         let mut request = Request::new(Method::Post, uri.into());
         if let Some(body) = self.body {
@@ -198,7 +348,7 @@ where
         self,
         client: &Docker<C>,
     ) -> Box<Future<Item = BuildMessages, Error = DockerError>> {
-        let request = match self.build() {
+        let request = match self.build(client.socket()) {
             Ok(request) => request,
             Err(_) => return Box::new(future::err(DockerError::BadRequest)),
         };
@@ -1,16 +1,24 @@
-use bytes::BytesMut;
 use bytes::{BigEndian, ByteOrder};
+use bytes::{Bytes, BytesMut};
 use hyper::Body;
 
 use executor::DockerError;
 
-use futures::stream::Fuse;
 use futures::{Async, Poll, Stream};
 
+/// How the log stream of a container is encoded
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Format {
+    /// Frames with an 8-byte header, used when the container has no TTY
+    Multiplexed,
+    /// The raw output of the container's TTY, stdout and stderr combined
+    Raw,
+}
+
 pub struct Logs {
-    body: Fuse<Body>,
-    state: State,
-    buf: BytesMut,
+    body: Body,
+    decoder: Decoder,
+    finished: bool,
 }
 
 #[derive(Debug)]
@@ -34,112 +42,268 @@ struct Header {
 }
 
 impl Header {
-    /// Create a header
+    /// Parses a header
     /// # Arguments:
     /// * `bytes` - Should be atleast 8 bytes long
-    fn new(bytes: &[u8]) -> Header {
+    fn parse(bytes: &[u8]) -> Result<Header, DockerError> {
         let log_type = match bytes[0] {
             0u8 => LogType::Stdin,
             1u8 => LogType::Stdout,
             2u8 => LogType::Stderr,
-            _ => unreachable!(),
+            other => {
+                return Err(DockerError::InvalidMessage(format!(
+                    "unknown log stream type: {}",
+                    other
+                )))
+            }
         };
         let size = BigEndian::read_u32(&bytes[4..]);
-        Header {
+        Ok(Header {
             log_type: log_type,
             size: size,
-        }
+        })
     }
 }
 
-impl Logs {
-    /// Create a new `Logs` instance from a body
-    pub fn new(body: Body) -> Self {
-        Logs {
-            body: body.fuse(),
+/// Body of the log frame
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Stdout(Bytes),
+    Stderr(Bytes),
+    Stdin(Bytes),
+}
+
+/// Splits a log stream into messages, regardless
+/// of how the stream is split into chunks
+pub struct Decoder {
+    format: Format,
+    state: State,
+    buf: BytesMut,
+}
+
+impl Decoder {
+    pub fn new(format: Format) -> Self {
+        Decoder {
+            format: format,
             state: State::Head,
             buf: BytesMut::with_capacity(64),
         }
     }
+
+    /// Adds the next chunk of the stream
+    pub fn extend(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Returns the next message if enough bytes are available
+    ///
+    /// Frames have an 8-byte header. The first byte of the header denotes
+    /// the type of the body. The next three bytes are unused, the remaining
+    /// four bytes encoded in big-endian format consist of a u32 which is
+    /// the size of the body
+    pub fn decode(&mut self) -> Result<Option<Message>, DockerError> {
+        if self.format == Format::Raw {
+            if self.buf.is_empty() {
+                return Ok(None);
+            }
+            let bytes = self.buf.split_to(self.buf.len()).freeze();
+            return Ok(Some(Message::Stdout(bytes)));
+        }
+        loop {
+            trace!("state: {:?}, buf len: {}", self.state, self.buf.len());
+            match self.state {
+                State::Head => {
+                    if self.buf.len() < 8 {
+                        return Ok(None);
+                    }
+                    let header = Header::parse(&self.buf.split_to(8))?;
+                    self.state = State::Body(header);
+                }
+                State::Body(Header { log_type, size }) => {
+                    if self.buf.len() < size as usize {
+                        return Ok(None);
+                    }
+                    let bytes = self.buf.split_to(size as usize).freeze();
+                    self.state = State::Head;
+                    // nothing to say, read the next frame
+                    if bytes.is_empty() {
+                        continue;
+                    }
+                    let message = match log_type {
+                        LogType::Stdout => Message::Stdout(bytes),
+                        LogType::Stdin => Message::Stdin(bytes),
+                        LogType::Stderr => Message::Stderr(bytes),
+                    };
+                    return Ok(Some(message));
+                }
+            }
+        }
+    }
+
+    /// Checks that the stream didn't end in the middle of a frame,
+    /// should be called once `decode` returns `None` at the end of the stream
+    pub fn finish(&self) -> Result<(), DockerError> {
+        match self.state {
+            State::Head if self.buf.is_empty() => Ok(()),
+            _ => {
+                debug!("truncated log frame, state: {:?}, buf: {:?}", self.state, self.buf);
+                Err(DockerError::InvalidMessage("truncated log frame".to_owned()))
+            }
+        }
+    }
 }
 
-/// Body of the log frame
-#[derive(Debug)]
-pub enum Message {
-    Stdout(String),
-    Stderr(String),
-    Stdin(String),
+/// Splits off the RFC 3339 timestamp Docker puts in front of every
+/// message when logs are requested with `timestamps=true`
+pub fn split_timestamp(mut bytes: Bytes) -> Result<(String, Bytes), DockerError> {
+    let end = match bytes.iter().position(|&b| b == b' ') {
+        Some(end) => end,
+        None => return Err(DockerError::InvalidMessage("log message without timestamp".to_owned())),
+    };
+    let timestamp = bytes.split_to(end);
+    bytes.advance(1);
+    match String::from_utf8(timestamp.to_vec()) {
+        Ok(timestamp) => Ok((timestamp, bytes)),
+        Err(_) => Err(DockerError::InvalidMessage("invalid log timestamp".to_owned())),
+    }
+}
+
+impl Logs {
+    /// Create a new `Logs` instance from a body encoded in `format`
+    pub fn new(body: Body, format: Format) -> Self {
+        Logs {
+            body: body,
+            decoder: Decoder::new(format),
+            finished: false,
+        }
+    }
 }
 
 impl Stream for Logs {
     type Item = Message;
     type Error = DockerError;
 
-    /// The inner stream has frames with an 8-byte header
-    /// The first byte of the header denotes the type of the body
-    /// The next three bytes are unused, the remaining four bytes
-    /// encoded in big-endian format consist of a u32 which is the
-    /// size of the body
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         loop {
-            trace!("state: {:?}", self.state);
-
-            let mut finished = false;
-            let mut not_ready = false;
+            if let Some(message) = self.decoder.decode()? {
+                return Ok(Async::Ready(Some(message)));
+            }
+            if self.finished {
+                self.decoder.finish()?;
+                return Ok(Async::Ready(None));
+            }
             match self.body.poll() {
-                Ok(Async::NotReady) => not_ready = true,
-                Ok(Async::Ready(Some(chunk))) => {
-                    self.buf.extend(chunk);
-                }
-                Ok(Async::Ready(None)) => finished = true,
-                Err(_) => return Err(DockerError::UnknownError),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Some(chunk))) => self.decoder.extend(&chunk),
+                Ok(Async::Ready(None)) => self.finished = true,
+                Err(e) => return Err(DockerError::HyperError(e)),
             }
+        }
+    }
+}
 
-            trace!("buf len: {}, finished: {}", self.buf.len(), finished);
-            match self.state {
-                State::Head => {
-                    if finished {
-                        let len = self.buf.len();
-                        if len == 0 {
-                            return Ok(Async::Ready(None));
-                        } else if len > 8 {
-                            let buf = self.buf.split_to(8);
-                            let header = Header::new(&buf);
-                            self.state = State::Body(header);
-                            continue;
-                        } else {
-                            debug!("self.buf {:?}", self.buf);
-                            return Err(DockerError::UnknownError);
-                        }
-                    }
-                    if not_ready {
-                        return Ok(Async::NotReady);
-                    }
-                    if self.buf.len() < 8 {
-                        continue;
-                    }
-                    let buf = self.buf.split_to(8);
-                    let header = Header::new(&buf);
-                    self.state = State::Body(header);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn frame(stream: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![stream, 0, 0, 0, 0, 0, 0, 0];
+        BigEndian::write_u32(&mut frame[4..], data.len() as u32);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    /// Everything written to (stdin, stdout, stderr)
+    type Streams = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+    /// Feeds `input` to a decoder split at `splits`
+    fn decode(format: Format, input: &[u8], splits: &[usize]) -> Result<Streams, DockerError> {
+        let mut decoder = Decoder::new(format);
+        let mut streams = (Vec::new(), Vec::new(), Vec::new());
+        let mut splits = splits.iter().map(|split| split % (input.len() + 1)).collect::<Vec<_>>();
+        splits.push(0);
+        splits.push(input.len());
+        splits.sort();
+        for chunk in splits.windows(2) {
+            decoder.extend(&input[chunk[0]..chunk[1]]);
+            while let Some(message) = decoder.decode()? {
+                match message {
+                    Message::Stdin(bytes) => streams.0.extend(bytes),
+                    Message::Stdout(bytes) => streams.1.extend(bytes),
+                    Message::Stderr(bytes) => streams.2.extend(bytes),
                 }
-                State::Body(Header { log_type, size }) => {
-                    if self.buf.len() >= size as usize {
-                        let bytes = self.buf.split_to(size as usize);
-                        // FIXME: not necessarily valid string
-                        let string = String::from_utf8(bytes.to_vec()).expect("Bad bytes");
-                        let message = match log_type {
-                            LogType::Stdout => Message::Stdout(string),
-                            LogType::Stdin => Message::Stdin(string),
-                            LogType::Stderr => Message::Stderr(string),
-                        };
-                        self.state = State::Head;
-                        return Ok(Async::Ready(Some(message)));
-                    }
-                    if not_ready {
-                        return Ok(Async::NotReady);
-                    }
+            }
+        }
+        decoder.finish()?;
+        Ok(streams)
+    }
+
+    proptest! {
+        #[test]
+        fn multiplexed_any_chunking(
+            frames in prop::collection::vec((0u8..3, prop::collection::vec(any::<u8>(), 0..64)), 0..16),
+            splits in prop::collection::vec(any::<usize>(), 0..32),
+        ) {
+            let mut input = Vec::new();
+            let mut expected = (Vec::new(), Vec::new(), Vec::new());
+            for &(stream, ref data) in &frames {
+                input.extend(frame(stream, data));
+                match stream {
+                    0 => expected.0.extend(data),
+                    1 => expected.1.extend(data),
+                    _ => expected.2.extend(data),
                 }
             }
+            let streams = decode(Format::Multiplexed, &input, &splits).unwrap();
+            prop_assert_eq!(streams, expected);
+        }
+
+        #[test]
+        fn raw_any_chunking(
+            input in prop::collection::vec(any::<u8>(), 0..256),
+            splits in prop::collection::vec(any::<usize>(), 0..32),
+        ) {
+            let streams = decode(Format::Raw, &input, &splits).unwrap();
+            prop_assert_eq!(streams, (Vec::new(), input, Vec::new()));
+        }
+
+        #[test]
+        fn truncated_frame_is_an_error(
+            data in prop::collection::vec(any::<u8>(), 1..64),
+            cut in any::<usize>(),
+            splits in prop::collection::vec(any::<usize>(), 0..8),
+        ) {
+            let mut input = frame(1, &data);
+            let len = input.len();
+            input.truncate(1 + cut % (len - 1));
+            prop_assert!(decode(Format::Multiplexed, &input, &splits).is_err());
+        }
+    }
+
+    #[test]
+    fn zero_length_final_frame() {
+        let mut input = frame(1, b"out");
+        input.extend(frame(2, b""));
+        let streams = decode(Format::Multiplexed, &input, &[]).unwrap();
+        assert_eq!(streams, (Vec::new(), b"out".to_vec(), Vec::new()));
+    }
+
+    #[test]
+    fn timestamps() {
+        let bytes = Bytes::from(&b"2018-02-14T10:00:00.123456789Z out 1\n"[..]);
+        let (timestamp, data) = split_timestamp(bytes).unwrap();
+        assert_eq!(timestamp, "2018-02-14T10:00:00.123456789Z");
+        assert_eq!(&data[..], b"out 1\n");
+        assert!(split_timestamp(Bytes::from(&b"out"[..])).is_err());
+    }
+
+    #[test]
+    fn unknown_stream_type() {
+        let input = frame(3, b"out");
+        match decode(Format::Multiplexed, &input, &[]) {
+            Err(DockerError::InvalidMessage(_)) => (),
+            other => panic!("expected an error, got {:?}", other),
         }
     }
 }
@@ -1,65 +1,255 @@
+mod attach;
 mod client;
+mod compiled;
 mod container;
 mod error;
+mod files;
 mod image;
 mod log;
+mod trace;
 
-use self::client::Docker;
+pub use self::attach::{Attached, Evaluated};
+
+use self::client::{Docker, Flavor};
+use self::compiled::ImageCache;
 use self::container::ContainerBuilder;
-use self::error::DockerError;
-use self::image::{ImageBuilder, Message};
+pub use self::error::DockerError;
+use self::error::BuildError;
+use self::image::ImageBuilder;
+use self::log::Format;
 use hyper::client::Connect;
 use hyper::header::ContentType;
 use hyper::server::Service;
+use json;
 use tokio_core::reactor::Handle;
 
 use futures::{Future, Stream};
 
 use tar::{Builder, Header};
 
+use self::attach::Relay;
+use auth;
+use digest;
+use health::Probe;
+use judge::{Check, CheckerFiles, CheckerRun, Interaction};
+use metrics::{self, Tracked};
+use options::OptionError;
+use session::{Interpreter, Repl};
+use source::{SourceError, Sources};
+use span::{self, span};
 use Language;
 use Output;
+use OutputFiles;
+use OutputStream;
 use Submission;
+use TranscriptChunk;
 
 use cpupool::CpuPool;
-use futures::future;
+use futures::future::{self, Either};
+use glob::Pattern;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::Path;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+use std::str;
 
-/// Builds a tar with files necessary for building a docker image for submission
-fn build_tar(sub: Submission) -> Result<Vec<u8>, ::std::io::Error> {
+/// Builds a tar with files necessary for building a docker image for submission,
+/// the files of the submission go in `src`
+/// # Arguments
+/// * `input` - What is saved as the input the program reads
+fn build_tar(sub: &Submission, sources: &Sources, input: &str) -> Result<Vec<u8>, ::std::io::Error> {
     let mut builder = Builder::new(Vec::new());
     let mut dockerfile = File::open(sub.lang.get_docker_file())?;
-    let mut header = Header::new_gnu();
-    header.set_path(sub.lang.get_file_name())?;
-    header.set_size(sub.code.bytes().len() as u64);
-    header.set_cksum();
     builder.append_file(Path::new("Dockerfile"), &mut dockerfile)?;
-    builder.append(&header, sub.code.as_bytes())?;
+    for (path, contents) in &sources.files {
+        append_bytes(&mut builder, &format!("src/{}", path), contents)?;
+    }
+    append_bytes(&mut builder, "input", input.as_bytes())?;
     builder.into_inner()
 }
 
-trait LanguageConfig {
+/// Returns the hex encoded SHA-256 of what an image is built from besides
+/// the input: the Dockerfile, which has the compiler, the sources and the
+/// build arguments, which have the version and flags
+fn context_sha256(sub: &Submission, sources: &Sources, args: &json::Map<String, json::Value>) -> Result<String, ::std::io::Error> {
+    let dockerfile = fs::read(sub.lang.get_docker_file())?;
+    let args = json::Value::Object(args.clone()).to_string();
+    let mut parts = vec![&dockerfile[..]];
+    for (path, contents) in &sources.files {
+        parts.push(path.as_bytes());
+        parts.push(contents);
+    }
+    parts.push(args.as_bytes());
+    Ok(digest::parts_sha256(parts))
+}
+
+/// Builds a tar with the files given to a checker or interactor
+fn files_tar(files: Vec<(&'static str, String)>) -> Result<Vec<u8>, ::std::io::Error> {
+    let mut builder = Builder::new(Vec::new());
+    for (path, contents) in files {
+        append_bytes(&mut builder, path, contents.as_bytes())?;
+    }
+    builder.into_inner()
+}
+
+/// Appends a file at `path` containing `bytes` to the tar
+fn append_bytes(
+    builder: &mut Builder<Vec<u8>>,
+    path: &str,
+    bytes: &[u8],
+) -> Result<(), ::std::io::Error> {
+    let mut header = Header::new_gnu();
+    header.set_path(path)?;
+    header.set_size(bytes.len() as u64);
+    header.set_cksum();
+    builder.append(&header, bytes)
+}
+
+/// A version of a language, built on its own image
+#[derive(Debug, PartialEq)]
+pub struct Version {
+    pub name: &'static str,
+    /// Base image of the Dockerfile, given to it as the `IMAGE` build argument
+    pub image: &'static str,
+}
+
+pub trait LanguageConfig {
     /// Should the filename where the code is to be saved
     fn get_file_name(&self) -> &'static str;
     /// Should return the docker file to be used for this container
     fn get_docker_file(&self) -> &'static str;
+    /// Should return the command running the code built from `entry` in the
+    /// container, used when it is given arguments instead of the input
+    fn get_run_command(&self, entry: &str) -> Vec<String>;
+    /// Should return the compiler flags submissions may use
+    fn get_compiler_flags(&self) -> &'static [&'static str];
+    /// Should return the versions of the language, the default first.
+    /// The default image is also the one in the Dockerfile
+    fn get_versions(&self) -> &'static [Version];
+    /// Should return the driver running cells of a REPL session,
+    /// `None` if the language has no interpreter
+    fn get_repl_driver(&self) -> Option<&'static str>;
+    /// Should return whether a program always writes the same output for
+    /// the same input, unless it asks for randomness or the time
+    fn is_deterministic(&self) -> bool;
 }
 
 impl LanguageConfig for Language {
     fn get_file_name(&self) -> &'static str {
         match *self {
             Language::C => "code.c",
-            Language::Python27 => "code.py",
+            Language::Cpp => "code.cpp",
+            Language::Rust => "main.rs",
+            Language::Go => "main.go",
+            Language::Java => "Main.java",
+            Language::Python27 | Language::Python3 => "code.py",
+            Language::JavaScript => "code.js",
+            Language::Ruby => "code.rb",
         }
     }
 
     fn get_docker_file(&self) -> &'static str {
         match *self {
             Language::C => "resources/c/Dockerfile",
+            Language::Cpp => "resources/cpp/Dockerfile",
+            Language::Rust => "resources/rust/Dockerfile",
+            Language::Go => "resources/go/Dockerfile",
+            Language::Java => "resources/java/Dockerfile",
             Language::Python27 => "resources/python2/Dockerfile",
+            Language::Python3 => "resources/python3/Dockerfile",
+            Language::JavaScript => "resources/javascript/Dockerfile",
+            Language::Ruby => "resources/ruby/Dockerfile",
+        }
+    }
+
+    fn get_run_command(&self, entry: &str) -> Vec<String> {
+        let interpreted = |interpreter: &str| vec![interpreter.to_owned(), format!("/src/{}", entry)];
+        match *self {
+            Language::C | Language::Cpp | Language::Rust | Language::Go => vec!["/code".to_owned()],
+            // `com/example/Main.java` has the class `com.example.Main`
+            Language::Java => vec![
+                "java".to_owned(),
+                "-cp".to_owned(),
+                "/classes".to_owned(),
+                entry.trim_end_matches(".java").replace('/', "."),
+            ],
+            Language::Python27 | Language::Python3 => interpreted("python"),
+            Language::JavaScript => interpreted("node"),
+            Language::Ruby => interpreted("ruby"),
+        }
+    }
+
+    fn get_compiler_flags(&self) -> &'static [&'static str] {
+        match *self {
+            Language::C => &[
+                "-O0", "-O1", "-O2", "-O3", "-Os", "-std=c89", "-std=c99", "-std=c11", "-std=c17",
+                "-std=gnu89", "-std=gnu99", "-std=gnu11", "-std=gnu17", "-lm", "-Wall", "-Wextra",
+                "-pedantic", "-DONLINE_JUDGE",
+            ],
+            Language::Cpp => &[
+                "-O0", "-O1", "-O2", "-O3", "-Os", "-std=c++11", "-std=c++14", "-std=c++17",
+                "-std=c++20", "-std=gnu++17", "-std=gnu++20", "-lm", "-Wall", "-Wextra", "-pedantic",
+                "-DONLINE_JUDGE",
+            ],
+            Language::Rust => &["-O", "-Copt-level=0", "-Copt-level=1", "-Copt-level=2", "-Copt-level=3"],
+            Language::Go => &["-trimpath"],
+            Language::Java => &["-g", "-nowarn", "-Xlint:all"],
+            Language::Python27 | Language::Python3 | Language::JavaScript | Language::Ruby => &[],
+        }
+    }
+
+    fn get_versions(&self) -> &'static [Version] {
+        match *self {
+            Language::C | Language::Cpp => &[
+                Version { name: "13", image: "gcc:13" },
+                Version { name: "9", image: "gcc:9" },
+            ],
+            Language::Rust => &[
+                Version { name: "1", image: "rust:1-alpine" },
+                Version { name: "1.75", image: "rust:1.75-alpine" },
+            ],
+            Language::Go => &[
+                Version { name: "1.22", image: "golang:1.22-alpine" },
+                Version { name: "1.21", image: "golang:1.21-alpine" },
+            ],
+            Language::Java => &[
+                Version { name: "21", image: "eclipse-temurin:21-jdk-alpine" },
+                Version { name: "17", image: "eclipse-temurin:17-jdk-alpine" },
+            ],
+            Language::Python27 => &[Version { name: "2.7", image: "frolvlad/alpine-python2" }],
+            Language::Python3 => &[
+                Version { name: "3.12", image: "python:3.12-alpine" },
+                Version { name: "3.8", image: "python:3.8-alpine" },
+            ],
+            Language::JavaScript => &[
+                Version { name: "20", image: "node:20-alpine" },
+                Version { name: "18", image: "node:18-alpine" },
+            ],
+            Language::Ruby => &[
+                Version { name: "3.3", image: "ruby:3.3-alpine" },
+                Version { name: "3.2", image: "ruby:3.2-alpine" },
+            ],
+        }
+    }
+
+    fn get_repl_driver(&self) -> Option<&'static str> {
+        match *self {
+            // the driver runs on Python 2.7 as well
+            Language::Python27 | Language::Python3 => Some("resources/python3/repl.py"),
+            Language::JavaScript => Some("resources/javascript/repl.js"),
+            Language::Ruby => Some("resources/ruby/repl.rb"),
+            Language::C | Language::Cpp | Language::Rust | Language::Go | Language::Java => None,
+        }
+    }
+
+    fn is_deterministic(&self) -> bool {
+        match *self {
+            // maps are iterated in a random order
+            Language::Go => false,
+            // strings are hashed with a random seed, which orders sets
+            Language::Python3 => false,
+            _ => true,
         }
     }
 }
@@ -72,40 +262,603 @@ pub enum ExecutionError {
     DockerError(DockerError),
     /// Holds the Compilation error message
     CompileError(String),
+    /// The compiler was stopped for taking too long, holds the message
+    /// sent as its compile error
+    CompileTimeout(String),
+    /// The image couldn't be built for reasons other than compile errors
+    BuildError(BuildError),
+    /// The language can't be run by the requested backend
+    UnsupportedLanguage,
+    /// The files of the submission were rejected
+    InvalidSource(SourceError),
+    /// The arguments, environment or compiler flags of the submission were rejected
+    InvalidOption(OptionError),
     UnknownError,
 }
 
+/// What an image is built from
+struct Context {
+    tar: Vec<u8>,
+    args: json::Map<String, json::Value>,
+    /// Hash of everything but the input
+    key: String,
+}
+
 /// Executor implementation which uses the Docker backend
-#[derive(Clone)]
 pub struct Executor<C> {
     /// Singleton Docker client instance
     docker: Rc<Docker<C>>,
     /// Thread pool used for doing blocking operations
     pool: CpuPool,
+    /// Compiled images of submissions, each one is built if not set
+    images: Option<Rc<ImageCache>>,
+    /// Longest an interactive run may take
+    wall_time: Duration,
+}
+
+// derived Clone would require `C: Clone`
+impl<C> Clone for Executor<C> {
+    fn clone(&self) -> Self {
+        Executor {
+            docker: self.docker.clone(),
+            pool: self.pool.clone(),
+            images: self.images.clone(),
+            wall_time: self.wall_time,
+        }
+    }
 }
 
 impl<C: Connect> Executor<C> {
     /// Create a new Executor
     /// # Arguments
     /// * `connector` - Provides connection to where Docker is running
+    /// * `socket` - Path of the unix socket Docker (or Podman) listens on
     /// * `handle` - A `Handle` to event loop on which this executor is to be run
-    pub fn new(connector: C, handle: Handle) -> Self {
+    pub fn new(connector: C, socket: &str, handle: Handle) -> Self {
         Executor {
-            docker: Rc::new(Docker::new(connector, handle)),
+            docker: Rc::new(Docker::new(connector, socket, handle)),
             pool: CpuPool::new(1),
+            images: None,
+            wall_time: Duration::from_secs(WALL_SECONDS),
         }
     }
+
+    /// Gives interactive runs `wall_time` before both programs are killed,
+    /// rather than `WALL_SECONDS`
+    pub fn with_wall_time(mut self, wall_time: Duration) -> Self {
+        self.wall_time = wall_time;
+        self
+    }
+
+    /// Keeps the images submissions are compiled in, taking up at most `size`
+    /// bytes on disk, so that submissions of the same code with the same
+    /// version and flags are only compiled once, whatever their input
+    pub fn with_image_cache(mut self, size: u64) -> Self {
+        self.images = Some(Rc::new(ImageCache::new(size)));
+        self
+    }
+
+    /// Removes the containers still around, for shutting down once the
+    /// submissions are done or given up on. Returns how many were removed
+    pub fn remove_containers(&self) -> Box<Future<Item = usize, Error = ()>> {
+        self.docker.remove_all()
+    }
+
+    /// Removes the images compiled before the cache was created, e.g. by an
+    /// earlier run, as it doesn't know how recently they were used. Returns
+    /// how many were removed
+    pub fn remove_images(&self) -> Box<Future<Item = usize, Error = ()>> {
+        let client = self.docker.clone();
+        let removed = self.docker
+            .labelled_images(compiled::LABEL)
+            .map_err(|e| {
+                debug!("can't list images: {:?}", e);
+                metrics::docker_error(&e);
+            })
+            .and_then(move |ids| {
+                let removals = ids.into_iter().map(|id| {
+                    client.remove_image(&id).then(move |removed| match removed {
+                        Ok(()) => Ok(true),
+                        Err(e) => {
+                            debug!("can't remove image {}: {:?}", id, e);
+                            Ok(false)
+                        }
+                    })
+                });
+                future::join_all(removals.collect::<Vec<_>>())
+                    .map(|removed| removed.into_iter().filter(|&removed| removed).count())
+            });
+        Box::new(removed)
+    }
+
+    /// Finds out whether Docker or Podman is serving the API,
+    /// should be done before any submissions are executed
+    pub fn detect_flavor(&self) -> Box<Future<Item = Flavor, Error = ExecutionError>> {
+        let flavor = self.docker.detect_flavor().map_err(|e| {
+            debug!("can't detect flavor: {:?}", e);
+            metrics::docker_error(&e);
+            ExecutionError::DockerError(e)
+        });
+        Box::new(flavor)
+    }
+}
+
+/// Bytes of memory, along with swap, a submission's container may use
+pub const MEMORY: u64 = 1073741824;
+
+/// Seconds of CPU time a submission's container may use
+pub const CPU_SECONDS: u64 = 1;
+
+/// Exit codes of containers the kernel killed for a limit: SIGKILL, sent on
+/// running out of memory or past the hard CPU limit, and SIGXCPU
+const KILLED: [i64; 2] = [128 + 9, 128 + 24];
+
+/// Seconds an interactive run may take. Programs waiting on each other
+/// don't use up their CPU time, so they would never be stopped by it
+pub const WALL_SECONDS: u64 = 10 * CPU_SECONDS;
+
+/// Returns the configuration of the container running the submission
+/// # Arguments
+/// * `image` - Id of the image built from the submission
+/// * `flavor` - The engine the container is created on
+/// * `program` - The submission, checker or interactor run in it
+fn container_config(image: &str, flavor: Flavor, program: &Submission) -> json::Value {
+    let mut config = json!({
+        "NetworkDisabled": true,
+        "Image": image,
+        "HostConfig": {
+            "PidsLimit": 1024,
+            "Ulimits": [{
+                "Name": "cpu",
+                "Hard": CPU_SECONDS,
+                "Soft": CPU_SECONDS
+             }],
+             "AutoRemove": true,
+             "Memory": MEMORY,
+             "MemorySwap": MEMORY
+         }
+    });
+    // Rootless Podman can't pin cpus and doesn't know about disk quotas
+    if flavor == Flavor::Docker {
+        let host_config = config["HostConfig"].as_object_mut().unwrap();
+        host_config.insert("CpusetCpus".to_owned(), json!("2-3"));
+        host_config.insert("DiskQuota".to_owned(), json!(10737418240usize));
+    }
+    if !program.env.is_empty() {
+        config["Env"] = json!(program.env_pairs());
+    }
+    if let Some(request_id) = program.request_id() {
+        config["Labels"] = json!({ span::LABEL: request_id });
+    }
+    config
+}
+
+/// Length of the longest prefix of `bytes` which doesn't
+/// end in the middle of a character
+fn complete_utf8(bytes: &[u8]) -> usize {
+    match str::from_utf8(bytes) {
+        Err(ref e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => bytes.len(),
+    }
+}
+
+/// Builds the `Output` of a container from its log messages
+#[derive(Debug, Default)]
+struct Collector {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    transcript: Vec<TranscriptChunk>,
+    /// Bytes of a character split across messages, with the
+    /// timestamp of the last message, by stream
+    partial: [(Vec<u8>, String); 2],
+    /// Exit code of the container, once it stopped
+    exit_code: i64,
 }
 
-/// Intermediate structure used when trying
-/// to extract the image id out of build messages
-enum Transform {
-    /// The extracted id
-    Id(String),
-    /// Holds the message where an unexpected error happened
-    Error(String),
-    /// Initial State
-    Empty,
+impl Collector {
+    fn push(&mut self, msg: log::Message) -> Result<(), DockerError> {
+        let (stream, bytes) = match msg {
+            log::Message::Stdout(bytes) => (OutputStream::Stdout, bytes),
+            log::Message::Stderr(bytes) => (OutputStream::Stderr, bytes),
+            log::Message::Stdin(_) => return Ok(()),
+        };
+        let (timestamp, bytes) = log::split_timestamp(bytes)?;
+        // FIXME: Huge outputs may cause out of memory
+        let (output, &mut (ref mut partial, ref mut last)) = match stream {
+            OutputStream::Stdout => (&mut self.stdout, &mut self.partial[0]),
+            OutputStream::Stderr => (&mut self.stderr, &mut self.partial[1]),
+        };
+        output.extend_from_slice(&bytes);
+        partial.extend_from_slice(&bytes);
+        let complete = complete_utf8(partial);
+        if complete > 0 {
+            let data = partial.drain(..complete).collect::<Vec<_>>();
+            self.transcript.push(TranscriptChunk {
+                stream: stream,
+                timestamp: timestamp.clone(),
+                data: String::from_utf8_lossy(&data).into_owned(),
+            });
+        }
+        *last = timestamp;
+        Ok(())
+    }
+
+    /// Frames may split characters, so output is decoded only once
+    /// all bytes are in
+    /// # Arguments
+    /// * `transcript` - Whether the transcript was asked for
+    /// * `output_files` - Files written by the program, if asked for
+    fn finish(mut self, transcript: bool, output_files: Option<OutputFiles>) -> Output {
+        let files_complete = output_files
+            .as_ref()
+            .is_none_or(|files| files.truncated.is_empty() && files.skipped.is_empty());
+        let streams = [OutputStream::Stdout, OutputStream::Stderr];
+        for (&stream, (partial, timestamp)) in streams.iter().zip(self.partial) {
+            // the program stopped in the middle of a character
+            if !partial.is_empty() {
+                self.transcript.push(TranscriptChunk {
+                    stream: stream,
+                    timestamp: timestamp,
+                    data: String::from_utf8_lossy(&partial).into_owned(),
+                });
+            }
+        }
+        Output::Output {
+            stdout: String::from_utf8_lossy(&self.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&self.stderr).into_owned(),
+            transcript: if transcript {
+                Some(self.transcript)
+            } else {
+                None
+            },
+            complete: !KILLED.contains(&self.exit_code) && files_complete,
+            output_files: output_files,
+        }
+    }
+}
+
+impl<C: Connect> Executor<C> {
+    /// Builds an image from the submission, which also compiles the code.
+    /// Fails with `ExecutionError::CompileError` if the code doesn't compile
+    fn build_image(&self, sub: Submission) -> Box<Future<Item = String, Error = ExecutionError>> {
+        let language = sub.lang.name();
+        let request_id = sub.request_id().map(String::from);
+        let executor = self.clone();
+        let id = self.context(sub, true).and_then(move |context| {
+            let mut labels = json::Map::new();
+            if let Some(ref request_id) = request_id {
+                labels.insert(span::LABEL.to_owned(), json!(request_id));
+            }
+            executor.build_context(context, labels, language, request_id)
+        });
+        Box::new(id)
+    }
+
+    /// Returns an image compiled from the submission, kept in `images`, which
+    /// runs with the input uploaded to its container. It is only built if it
+    /// isn't kept already, the least recently used images are removed to
+    /// make room for it once they aren't used
+    fn compiled_image(&self, sub: Submission, images: Rc<ImageCache>) -> Box<Future<Item = Pinned<C>, Error = ExecutionError>> {
+        let language = sub.lang.name();
+        let request_id = sub.request_id().map(String::from);
+        let executor = self.clone();
+        let image = self.context(sub, false).and_then(move |context| -> Box<Future<Item = _, Error = _>> {
+            let key = context.key.clone();
+            let client = executor.docker.clone();
+            if let Some(id) = images.get(&key) {
+                trace!("image of {} is compiled already", key);
+                return Box::new(future::ok(Pinned::new(client, images, id, key)));
+            }
+            let mut labels = json::Map::new();
+            labels.insert(compiled::LABEL.to_owned(), json!(key));
+            let built = executor.build_context(context, labels, language, request_id).and_then(move |id| {
+                client.image_size(&id).then(move |size| {
+                    let size = size.unwrap_or_else(|e| {
+                        // it is still run, then removed as it isn't accounted for
+                        debug!("can't inspect image {}: {:?}", id, e);
+                        metrics::docker_error(&e);
+                        u64::MAX
+                    });
+                    for old in images.insert(key.clone(), id.clone(), size) {
+                        trace!("removing image {}", old);
+                        remove_image(&client, old);
+                    }
+                    Ok(Pinned::new(client, images, id, key))
+                })
+            });
+            Box::new(built)
+        });
+        Box::new(image)
+    }
+
+    /// Returns the context an image is built from for the submission,
+    /// along with its build arguments
+    /// # Arguments
+    /// * `with_input` - Whether the input is saved in the image, otherwise
+    ///   it is empty and has to be uploaded to the container
+    fn context(&self, sub: Submission, with_input: bool) -> Box<Future<Item = Context, Error = ExecutionError>> {
+        let request_id = sub.request_id().map(String::from);
+        let context = self.pool.spawn_fn(move || {
+            sub.check_options().map_err(ExecutionError::InvalidOption)?;
+            // archives are unpacked here as well
+            let sources = sub.sources().map_err(ExecutionError::InvalidSource)?;
+            let input = if with_input { &*sub.stdin } else { "" };
+            let tar = build_tar(&sub, &sources, input).map_err(|e| {
+                debug!("can't create tar: {:?}", e);
+                ExecutionError::BadConfig
+            })?;
+            // the Dockerfiles default to the file `code` is saved to, no flags
+            // and the default version
+            let mut args = json::Map::new();
+            let version = sub.version().map_err(ExecutionError::InvalidOption)?;
+            if version != &sub.lang.get_versions()[0] {
+                args.insert("IMAGE".to_owned(), json!(version.image));
+            }
+            if sources.entry_point != sub.lang.get_file_name() {
+                args.insert("ENTRY".to_owned(), json!(sources.entry_point));
+            }
+            if !sub.compiler_flags.is_empty() {
+                args.insert("FLAGS".to_owned(), json!(sub.compiler_flags.join(" ")));
+            }
+            let key = context_sha256(&sub, &sources, &args).map_err(|e| {
+                debug!("can't hash context: {:?}", e);
+                ExecutionError::BadConfig
+            })?;
+            Ok(Context {
+                tar: tar,
+                args: args,
+                key: key,
+            })
+        });
+        span(request_id.as_deref(), "tar", context)
+    }
+
+    /// Builds an image with `labels` from `context` for a submission in
+    /// `language` made in the request `request_id`, returns its id
+    fn build_context(
+        &self,
+        context: Context,
+        labels: json::Map<String, json::Value>,
+        language: &'static str,
+        request_id: Option<String>,
+    ) -> Box<Future<Item = String, Error = ExecutionError>> {
+        trace!("building image");
+        let started = Instant::now();
+        // Podman only sends the image Id when not quiet
+        let quiet = match self.docker.flavor() {
+            Flavor::Docker => "true",
+            Flavor::Podman => "false",
+        };
+        let mut builder = ImageBuilder::new().with_body(context.tar).with_param("q", quiet);
+        if !context.args.is_empty() {
+            builder.set_param("buildargs", &json::Value::Object(context.args).to_string());
+        }
+        if !labels.is_empty() {
+            builder.set_param("labels", &json::Value::Object(labels).to_string());
+        }
+        let built = builder
+            .build_on(&self.docker)
+            .map_err(|e| {
+                debug!("error: {:?}", e);
+                metrics::docker_error(&e);
+                ExecutionError::BuildError(BuildError::DockerError(e))
+            })
+            .map(move |messages| {
+                metrics::BUILD_SECONDS
+                    .with_label_values(&[language])
+                    .observe(started.elapsed().as_secs_f64());
+                messages
+            });
+        let id = span(request_id.as_deref(), "build", built).and_then(|messages| {
+            // We also compile the code when we build the Docker Image, so
+            // compile errors are also extracted in that case. For interpreted
+            // languages errors are extracted when the container is actually run.
+            // Builds failing for any other reason are on the engine, not the code
+            messages.image_id().map_err(|e| match e {
+                BuildError::Failed(output) => ExecutionError::CompileError(output),
+                e => {
+                    debug!("can't build image: {:?}", e);
+                    if let BuildError::DockerError(ref e) = e {
+                        metrics::docker_error(e);
+                    }
+                    ExecutionError::BuildError(e)
+                }
+            })
+        });
+        Box::new(id)
+    }
+
+    /// Creates a container with `config` for the request `request_id`, returns its Id
+    fn create_container(&self, config: json::Value, request_id: Option<&str>) -> Box<Future<Item = String, Error = ExecutionError>> {
+        let id = ContainerBuilder::new()
+            .with_body(config.as_object().unwrap().clone())
+            .with_header(ContentType::json())
+            .build_on(&self.docker)
+            .map_err(|e| {
+                debug!("can't build container: {:?}", e);
+                metrics::docker_error(&e);
+                ExecutionError::UnknownError
+            });
+        let client = self.docker.clone();
+        let id = id.map(move |id| {
+            client.track(&id);
+            id
+        });
+        span(request_id, "create", id)
+    }
+
+    /// Creates a container with `config` from the compiled image kept by `key`,
+    /// for the request `request_id`, and uploads `input` to it. Returns its Id
+    fn create_with_input(
+        &self,
+        config: json::Value,
+        request_id: Option<&str>,
+        image: &Pinned<C>,
+        input: String,
+    ) -> Box<Future<Item = String, Error = ExecutionError>> {
+        let images = image.images.clone();
+        let (id, key) = (image.id.clone(), image.key.clone());
+        let created = self.create_container(config, request_id).map_err(move |e| {
+            // the image may have been removed by someone else
+            images.forget(&key, &id);
+            e
+        });
+        let client = self.docker.clone();
+        let request_id = request_id.map(String::from);
+        let uploaded = created.and_then(move |id| {
+            let tar = match files_tar(vec![("input", input)]) {
+                Ok(tar) => tar,
+                Err(e) => {
+                    debug!("can't create tar: {:?}", e);
+                    return Box::new(future::err(ExecutionError::BadConfig)) as Box<Future<Item = _, Error = _>>;
+                }
+            };
+            let upload = client.upload_archive(&id, "/", tar);
+            let uploaded = span(request_id.as_deref(), "input", upload).then(move |uploaded| match uploaded {
+                Ok(()) => Ok(id),
+                Err(e) => {
+                    debug!("can't upload input: {:?}", e);
+                    metrics::docker_error(&e);
+                    // it never starts, so Docker doesn't remove it
+                    client.spawn(client.remove_container(&id).map_err(move |e| {
+                        debug!("can't remove container {}: {:?}", id, e);
+                    }));
+                    Err(ExecutionError::UnknownError)
+                }
+            });
+            Box::new(uploaded)
+        });
+        Box::new(uploaded)
+    }
+
+    /// Removes the container specified by `id` once `future` is done, whatever its result
+    fn removing<T: 'static>(
+        &self,
+        id: String,
+        request_id: Option<&str>,
+        future: Box<Future<Item = T, Error = ExecutionError>>,
+    ) -> Box<Future<Item = T, Error = ExecutionError>> {
+        let client = self.docker.clone();
+        let request_id = request_id.map(String::from);
+        let removed = future.then(move |result| {
+            let removed = client.remove_container(&id);
+            span(request_id.as_deref(), "cleanup", removed).then(move |removed| {
+                if let Err(e) = removed {
+                    debug!("can't remove container {}: {:?}", id, e);
+                    metrics::docker_error(&e);
+                }
+                result
+            })
+        });
+        Box::new(removed)
+    }
+
+    /// Returns the files in the working directory of the stopped container
+    /// specified by `id` which match any of `patterns`
+    fn output_files(&self, id: &str, patterns: Vec<Pattern>) -> Box<Future<Item = OutputFiles, Error = ExecutionError>> {
+        let pool = self.pool.clone();
+        // the sources and files which aren't asked for are in there as well
+        let limit = (files::MAX_TOTAL_SIZE * 4) as usize;
+        let files = self.docker
+            .download_archive(id, "/src", limit)
+            .map_err(|e| {
+                debug!("can't get output files: {:?}", e);
+                metrics::docker_error(&e);
+                ExecutionError::UnknownError
+            })
+            .and_then(move |tar| pool.spawn_fn(move || Ok(files::collect(&tar, "src", &patterns))));
+        Box::new(files)
+    }
+
+    /// Starts the container and collects its output and exit code until it
+    /// exits, charging the time it runs to the permit token `charged_to`
+    fn run_container(
+        &self,
+        id: String,
+        request_id: Option<&str>,
+        charged_to: Option<u64>,
+    ) -> Box<Future<Item = Collector, Error = ExecutionError>> {
+        let client = self.docker.clone();
+        let client2 = self.docker.clone();
+        let id2 = id.clone();
+        let request_id2 = request_id.map(String::from);
+        let started = Instant::now();
+        // it may be removed as soon as it exits, so the wait comes first
+        let start = self.docker
+            .wait_next_exit(&id)
+            .and_then(move |exited| client2.start_container(&id2).map(|_| exited))
+            .map_err(|e| {
+                debug!("cant start container: {:?}", e);
+                metrics::docker_error(&e);
+                ExecutionError::UnknownError
+            });
+        let collector = span(request_id, "start", start).and_then(move |exited| {
+            metrics::START_SECONDS.observe(started.elapsed().as_secs_f64());
+            let running = Instant::now();
+            let active = Tracked::new(&metrics::ACTIVE_CONTAINERS);
+            trace!("getting logs from container: {}", id);
+            let logs = client
+                // our containers don't have a TTY
+                .logs(&id, Format::Multiplexed)
+                .map_err(|e| {
+                    debug!("can't get logs: {:?}", e);
+                    metrics::docker_error(&e);
+                    ExecutionError::UnknownError
+                })
+                .and_then(|logs| {
+                    logs.fold(Collector::default(), |mut collector, msg| {
+                        collector.push(msg)?;
+                        Ok::<_, DockerError>(collector)
+                    }).map_err(|e| {
+                        debug!("logging error: {:?}", e);
+                        metrics::docker_error(&e);
+                        ExecutionError::UnknownError
+                    })
+                });
+            let exited = exited.map_err(|e| {
+                debug!("can't wait for container: {:?}", e);
+                metrics::docker_error(&e);
+                ExecutionError::UnknownError
+            });
+            let ran = logs.join(exited).map(|(mut collector, exit_code)| {
+                collector.exit_code = exit_code;
+                collector
+            });
+            span(request_id2.as_deref(), "logs", ran).then(move |collector| {
+                drop(active);
+                auth::charge(charged_to, running.elapsed());
+                if collector.is_ok() {
+                    metrics::RUN_SECONDS.observe(started.elapsed().as_secs_f64());
+                }
+                collector
+            })
+        });
+        Box::new(collector)
+    }
+}
+
+/// Returns the command running `program` with `args`, followed by its own arguments
+fn run_command(program: &Submission, args: &[&str]) -> Result<Vec<String>, ExecutionError> {
+    let entry = program.entry_point().map_err(ExecutionError::InvalidSource)?;
+    let mut cmd = program.lang.get_run_command(&entry);
+    cmd.extend(args.iter().map(|arg| arg.to_string()));
+    cmd.extend(program.args.iter().cloned());
+    Ok(cmd)
+}
+
+/// Returns the configuration of a container whose streams are attached to,
+/// running `cmd` instead of reading the input from a file
+fn attached_config(image: &str, flavor: Flavor, program: &Submission, cmd: Vec<String>) -> json::Value {
+    let mut config = container_config(image, flavor, program);
+    config["Cmd"] = json!(cmd);
+    config["OpenStdin"] = json!(true);
+    config["StdinOnce"] = json!(true);
+    config["AttachStdin"] = json!(true);
+    config["AttachStdout"] = json!(true);
+    config["AttachStderr"] = json!(true);
+    // the exit code is read after it stops
+    config["HostConfig"]["AutoRemove"] = json!(false);
+    config
 }
 
 impl<C: Connect> Service for Executor<C> {
@@ -122,164 +875,502 @@ impl<C: Connect> Service for Executor<C> {
     /// * Read the `Container` logs which contains the program output
     fn call(&self, sub: Self::Request) -> Self::Future {
         trace!("executor called: {:?}", sub);
-        let tar = self.pool.spawn_fn(move || build_tar(sub));
-        let client = self.docker.clone();
-        let client2 = client.clone();
-        let image = tar.map_err(|e| {
-            debug!("can't create tar: {:?}", e);
-            ExecutionError::BadConfig
-        }).and_then(move |tar| {
-            trace!("building image");
-            ImageBuilder::new()
-                .with_body(tar)
-                .with_param("q", "true")
-                .build_on(&client)
-                .map_err(|e| {
-                    debug!("error: {:?}", e);
-                    ExecutionError::DockerError(e)
-                })
-        });
-        let logs = image.and_then(move |messages| {
-            // This is a huge mess.
-            // We are trying to extract the Id of the Docker Image we built.
-            // The format of the docker response is not really suitable for
-            // parsing and I barely managed to do so.
-            //
-            // We also compile the code when we build the Docker Image, so
-            // compile errors are also extracted in that case. For interpreted
-            // languages errors are extracted when the container is actually run
-            messages
-                .map_err(|e| {
-                    debug!("error: {:?}", e);
-                    ExecutionError::DockerError(DockerError::HyperError(e))
-                })
-                .fold(Transform::Empty, |last_step, mut msg| {
-                    debug!("build message: {:?}", msg);
-                    // TODO: remove terminal coloring sequences
-                    match msg {
-                        Message::Stream { ref mut stream } if stream.starts_with("sha256") => {
-                            let id = stream
-                                .split(":")
-                                .skip(1)
-                                .next()
-                                .unwrap()
-                                .trim_right()
-                                .to_owned();
-                            future::ok(Transform::Id(id))
-                        }
-                        Message::Stream { ref mut stream } if stream.contains("Step") => {
-                            match last_step {
-                                Transform::Id(msg) => future::ok(Transform::Id(msg)),
-                                _ => future::ok(Transform::Empty),
-                            }
-                        }
-                        // cache messages - no thank you :|
-                        Message::Stream { ref stream } if stream.contains("---") => {
-                            future::ok(last_step)
+        let queued = Tracked::new(&metrics::QUEUE_DEPTH);
+        let transcript = sub.transcript;
+        let patterns = match sub.output_patterns() {
+            Ok(patterns) => patterns,
+            Err(e) => return Box::new(future::err(ExecutionError::InvalidOption(e))),
+        };
+        let flavor = self.docker.flavor();
+        let request_id = sub.request_id().map(String::from);
+        let request_id2 = request_id.clone();
+        let charged_to = sub.charged_to();
+        let executor = self.clone();
+        let executor2 = self.clone();
+        let stdin = sub.stdin.clone();
+        let image: Box<Future<Item = _, Error = _>> = match self.images {
+            Some(ref images) => Box::new(self.compiled_image(sub.clone(), images.clone()).map(|image| (image.id.clone(), Some(image)))),
+            None => Box::new(self.build_image(sub.clone()).map(|id| (id, None))),
+        };
+        let output = image
+            .and_then(move |(id, pinned)| {
+                trace!("building container from: {}", id);
+                let mut config = container_config(&id, flavor, &sub);
+                // the image's command doesn't take arguments
+                if !sub.args.is_empty() {
+                    let mut cmd = vec!["sh", "-c", "exec \"$@\" < /input", "sh"]
+                        .into_iter()
+                        .map(String::from)
+                        .collect::<Vec<_>>();
+                    cmd.extend(run_command(&sub, &[])?);
+                    config["Cmd"] = json!(cmd);
+                }
+                // the files are read after it stops
+                if !sub.output_files.is_empty() {
+                    config["HostConfig"]["AutoRemove"] = json!(false);
+                }
+                Ok((config, pinned))
+            })
+            .and_then(move |(config, pinned)| -> Box<Future<Item = _, Error = _>> {
+                match pinned {
+                    Some(pinned) => {
+                        let created = executor.create_with_input(config, request_id.as_deref(), &pinned, stdin);
+                        Box::new(created.map(move |id| (id, Some(pinned))))
+                    }
+                    None => Box::new(executor.create_container(config, request_id.as_deref()).map(|id| (id, None))),
+                }
+            })
+            .and_then(move |(id, pinned)| {
+                let request_id = request_id2.as_deref();
+                let run = executor2.run_container(id.clone(), request_id, charged_to);
+                let ran: Box<Future<Item = _, Error = _>> = if patterns.is_empty() {
+                    let client = executor2.docker.clone();
+                    Box::new(run.then(move |result| match result {
+                        // it is removed by Docker once it stops
+                        Ok(collector) => {
+                            client.forget(&id);
+                            Either::A(future::ok((collector, None)))
                         }
-                        // Not a step/id/cache, append all messages in between
-                        Message::Stream { mut stream } => match last_step {
-                            Transform::Id(msg) => future::ok(Transform::Id(msg)),
-                            Transform::Empty => future::ok(Transform::Error(stream)),
-                            Transform::Error(msg) => {
-                                stream.push_str(&msg);
-                                future::ok(Transform::Error(stream))
+                        // it may not have started or stopped
+                        Err(e) => Either::B(client.remove_container(&id).then(move |removed| {
+                            if let Err(removed) = removed {
+                                debug!("can't remove container {}: {:?}", id, removed);
                             }
-                        },
-                        // compilation error. last step is supposed to have the compile error
-                        Message::ErrorDetail { .. } => future::ok(last_step),
-                    }
-                })
-                .and_then(|msg| match msg {
-                    Transform::Error(msg) => future::err(ExecutionError::CompileError(msg)),
-                    Transform::Empty => unreachable!(),
-                    Transform::Id(id) => future::ok(id),
-                })
-                .and_then(move |id| {
-                    trace!("building container from: {}", id);
-                    let config = json!({
-                        "NetworkDisabled": true,
-                        "Image": id,
-                        "HostConfig": {
-                            "CpusetCpus": "2-3",
-                            "PidsLimit": 1024,
-                            "Ulimits": [{
-                                "Name": "cpu",
-                                "Hard": 1,
-                                "Soft": 1
-                             }],
-                             "AutoRemove": true,
-                             "Memory": 1073741824usize,
-                             "MemorySwap": 1073741824usize,
-                             "DiskQuota": 10737418240usize
-                         }
+                            Err(e)
+                        })),
+                    }))
+                } else {
+                    let executor3 = executor2.clone();
+                    let id2 = id.clone();
+                    let collected = run.and_then(move |collector| {
+                        executor3
+                            .output_files(&id2, patterns)
+                            .map(|files| (collector, Some(files)))
                     });
-                    ContainerBuilder::new()
-                        .with_body(config.as_object().unwrap().clone())
-                        .with_header(ContentType::json())
-                        .build_on(&client2)
-                        .map_err(|e| {
-                            debug!("can't build container: {:?}", e);
-                            ExecutionError::UnknownError
-                        })
-                        .map(|id| (client2, id))
+                    executor2.removing(id, request_id, Box::new(collected))
+                };
+                // the image isn't removed while its container runs
+                ran.then(move |result| {
+                    drop(pinned);
+                    result
                 })
-                .and_then(move |(client, id)| {
-                    client
-                        .start_container(&id)
-                        .map_err(|e| {
-                            debug!("cant start container: {:?}", e);
-                            ExecutionError::UnknownError
-                        })
-                        .and_then(|_| Ok((client, id)))
+            })
+            .map(move |(collector, files)| collector.finish(transcript, files))
+            .then(move |result| {
+                drop(queued);
+                result
+            })
+            .then(|result| match result {
+                Ok(output) => future::ok(output),
+                Err(ExecutionError::CompileError(msg)) => {
+                    future::ok(Output::CompileError {
+                        error: msg,
+                        complete: true,
+                    })
+                }
+                Err(e) => {
+                    debug!("error in executor: {:?}", e);
+                    future::err(e)
+                }
+            });
+        Box::new(output)
+    }
+}
+
+impl<C: Connect> Check for Executor<C> {
+    fn build(&self, program: &Submission) -> Box<Future<Item = String, Error = ExecutionError>> {
+        trace!("building program: {:?}", program);
+        self.build_image(program.clone())
+    }
+
+    /// Runs the checker like a submission, except that its files are
+    /// copied into the container before it starts and its exit code is
+    /// read after it stops, so it is removed by us rather than by Docker
+    fn run_checker(
+        &self,
+        checker: &Submission,
+        image: String,
+        files: CheckerFiles,
+    ) -> Box<Future<Item = CheckerRun, Error = ExecutionError>> {
+        let cmd = match run_command(checker, &["/input", "/output", "/expected"]) {
+            Ok(cmd) => cmd,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let mut config = container_config(&image, self.docker.flavor(), checker);
+        config["Cmd"] = json!(cmd);
+        config["HostConfig"]["AutoRemove"] = json!(false);
+        let files = vec![
+            ("input", files.input),
+            ("output", files.output),
+            ("expected", files.expected),
+        ];
+        let tar = self.pool.spawn_fn(move || files_tar(files)).map_err(|e| {
+            debug!("can't create checker tar: {:?}", e);
+            ExecutionError::BadConfig
+        });
+        let executor = self.clone();
+        let request_id = checker.request_id().map(String::from);
+        let charged_to = checker.charged_to();
+        let run = self.create_container(config, checker.request_id())
+            .join(tar)
+            .and_then(move |(id, tar)| {
+                let request_id = request_id.as_deref();
+                let request_id2 = request_id.map(String::from);
+                let executor2 = executor.clone();
+                let id2 = id.clone();
+                let run = executor
+                    .docker
+                    .upload_archive(&id, "/", tar)
+                    .map_err(|e| {
+                        debug!("can't copy files to checker: {:?}", e);
+                        metrics::docker_error(&e);
+                        ExecutionError::UnknownError
+                    })
+                    .and_then(move |_| executor.run_container(id, request_id2.as_deref(), charged_to))
+                    .map(|collector| {
+                        let exit_code = collector.exit_code;
+                        (collector, exit_code)
+                    });
+                executor2.removing(id2, request_id, Box::new(run))
+            })
+            .map(|(collector, exit_code)| {
+                trace!("checker exited with {}", exit_code);
+                CheckerRun {
+                    exit_code: exit_code,
+                    stdout: String::from_utf8_lossy(&collector.stdout).into_owned(),
+                    stderr: String::from_utf8_lossy(&collector.stderr).into_owned(),
+                }
+            });
+        Box::new(run)
+    }
+
+    /// Both containers are attached to before they are started and removed
+    /// by us once they stop, so the interactor's exit code can be read
+    fn interact(
+        &self,
+        submission: (&Submission, String),
+        interactor: (&Submission, String),
+        input: String,
+        expected: String,
+    ) -> Box<Future<Item = Interaction, Error = ExecutionError>> {
+        let cmds = run_command(submission.0, &[]).and_then(|contestant| {
+            run_command(interactor.0, &["/input", "/expected"]).map(|interactor| (contestant, interactor))
+        });
+        let (contestant_cmd, interactor_cmd) = match cmds {
+            Ok(cmds) => cmds,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let flavor = self.docker.flavor();
+        let contestant_config = attached_config(&submission.1, flavor, submission.0, contestant_cmd);
+        let interactor_config = attached_config(&interactor.1, flavor, interactor.0, interactor_cmd);
+        let files = vec![("input", input), ("expected", expected)];
+        let tar = self.pool.spawn_fn(move || files_tar(files)).map_err(|e| {
+            debug!("can't create interactor tar: {:?}", e);
+            ExecutionError::BadConfig
+        });
+        let docker = self.docker.clone();
+        let executor = self.clone();
+        let wall_time = self.wall_time;
+        let request_id = submission.0.request_id().map(String::from);
+        let charged_to = submission.0.charged_to();
+        let created = self.create_container(contestant_config, submission.0.request_id())
+            .join(self.create_container(interactor_config, interactor.0.request_id()))
+            .join(tar);
+        let interaction = created.and_then(move |((contestant, interactor), tar)| {
+            let client = executor.docker.clone();
+            let client2 = executor.docker.clone();
+            let client3 = executor.docker.clone();
+            let (contestant2, interactor2) = (contestant.clone(), interactor.clone());
+            let (contestant3, interactor3) = (contestant.clone(), interactor.clone());
+            let relay = docker
+                .upload_archive(&interactor, "/", tar)
+                .and_then(move |_| {
+                    trace!("attaching to {} and {}", contestant, interactor);
+                    client.attach(&contestant).join(client.attach(&interactor))
                 })
-                .and_then(|(client, id)| {
-                    trace!("getting logs from container: {}", id);
-                    client
-                        .logs(&id)
-                        .map_err(|e| {
-                            debug!("can't get logs: {:?}", e);
-                            ExecutionError::UnknownError
+                .and_then(move |(contestant, interactor)| {
+                    let timer = client2.clone();
+                    client2
+                        .start_container(&interactor2)
+                        .join(client2.start_container(&contestant2))
+                        .and_then(move |_| {
+                            let active = (
+                                Tracked::new(&metrics::ACTIVE_CONTAINERS),
+                                Tracked::new(&metrics::ACTIVE_CONTAINERS),
+                            );
+                            let timeout = timer.timeout(wall_time);
+                            let running = Instant::now();
+                            Relay::new(contestant, interactor).select2(timeout).then(move |result| {
+                                drop(active);
+                                auth::charge(charged_to, running.elapsed());
+                                match result {
+                                    Ok(Either::A((conversation, _))) => Ok((conversation, false)),
+                                    Ok(Either::B((_, relay))) => {
+                                        debug!("interaction took longer than {:?}", wall_time);
+                                        Ok((relay.timed_out(), true))
+                                    }
+                                    Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+                                }
+                            })
                         })
-                        .and_then(|logs| {
-                            logs.map_err(|e| {
-                                debug!("logging error: {:?}", e);
-                                ExecutionError::UnknownError
-                            }).fold(
-                                    (String::from(""), String::from("")),
-                                    |(mut stdout, mut stderr), msg| {
-                                        // FIXME: Huge outputs may cause out of memory
-                                        match msg {
-                                            log::Message::Stdout(msg) => {
-                                                stdout.push_str(&msg);
-                                            }
-                                            log::Message::Stderr(msg) => {
-                                                stderr.push_str(&msg);
-                                            }
-                                            _ => (),
-                                        }
-                                        Ok((stdout, stderr))
-                                    },
-                                )
-                                .and_then(|(stdout, stderr)| {
-                                    Ok(Output::Output {
-                                        stdout: stdout,
-                                        stderr: stderr,
-                                    })
-                                })
+                        .and_then(move |(conversation, timed_out)| {
+                            // both are killed once they are removed
+                            if timed_out {
+                                return Either::A(future::ok((conversation, None)));
+                            }
+                            let exit_code = client2
+                                .wait_container(&interactor2)
+                                .map(|exit_code| (conversation, Some(exit_code)));
+                            Either::B(exit_code)
                         })
                 })
-                .then(|result| match result {
-                    Ok(output) => future::ok(output),
-                    Err(ExecutionError::CompileError(msg)) => {
-                        future::ok(Output::CompileError { error: msg })
+                .map_err(|e| {
+                    debug!("interaction failed: {:?}", e);
+                    metrics::docker_error(&e);
+                    ExecutionError::UnknownError
+                });
+            relay.then(move |result| {
+                let removed = client3
+                    .remove_container(&contestant3)
+                    .join(client3.remove_container(&interactor3));
+                span(request_id.as_deref(), "cleanup", removed).then(move |removed| {
+                    if let Err(e) = removed {
+                        debug!("can't remove interactive containers: {:?}", e);
+                        metrics::docker_error(&e);
                     }
-                    Err(e) => {
-                        debug!("error in executor: {:?}", e);
-                        future::err(e)
+                    result
+                })
+            })
+        });
+        let interaction = interaction.map(|(conversation, exit_code)| {
+            trace!("interactor exited with {:?}", exit_code);
+            debug!(
+                "contestant stderr: {}",
+                String::from_utf8_lossy(&conversation.contestant_stderr)
+            );
+            let stderr = String::from_utf8_lossy(&conversation.interactor_stderr).into_owned();
+            Interaction {
+                interactor: exit_code.map(|exit_code| CheckerRun {
+                    exit_code: exit_code,
+                    // its stdout went to the contestant
+                    stdout: String::new(),
+                    stderr: stderr,
+                }),
+                transcript: conversation.transcript,
+            }
+        });
+        Box::new(interaction)
+    }
+}
+
+/// Runs the REPL driver given as arguments with its stdin moved to fd 3,
+/// where it reads the cells from, so the code of the cells can't read them
+const REPL_STDIN: &str = "exec 3<&0 </dev/null; exec \"$@\"";
+
+/// Removes the image `id` in the background
+fn remove_image<C: Connect>(docker: &Docker<C>, id: String) {
+    docker.spawn(docker.remove_image(&id).map_err(move |e| {
+        debug!("can't remove image {}: {:?}", id, e);
+        metrics::docker_error(&e);
+    }));
+}
+
+/// An image kept in the cache which a request is using, released once it
+/// is done. It is removed then if it was dropped from the cache meanwhile
+struct Pinned<C: Connect> {
+    docker: Rc<Docker<C>>,
+    images: Rc<ImageCache>,
+    id: String,
+    /// What the image is kept by
+    key: String,
+}
+
+impl<C: Connect> Pinned<C> {
+    fn new(docker: Rc<Docker<C>>, images: Rc<ImageCache>, id: String, key: String) -> Self {
+        Pinned {
+            docker: docker,
+            images: images,
+            id: id,
+            key: key,
+        }
+    }
+}
+
+impl<C: Connect> Drop for Pinned<C> {
+    fn drop(&mut self) {
+        if self.images.release(&self.id) {
+            trace!("removing image {} which is no longer used", self.id);
+            remove_image(&self.docker, self.id.clone());
+        }
+    }
+}
+
+/// The container of an interpreter being started, removed in the
+/// background if the start is given up on before it is handed over
+struct Starting<C: Connect> {
+    docker: Rc<Docker<C>>,
+    id: Option<String>,
+}
+
+impl<C: Connect> Starting<C> {
+    /// Leaves the container to whoever handles the result of starting it
+    fn done(mut self) {
+        self.id = None;
+    }
+}
+
+impl<C: Connect> Drop for Starting<C> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            debug!("removing interpreter {} which didn't start", id);
+            self.docker.spawn(self.docker.remove_container(&id).map_err(move |e| {
+                debug!("can't remove container {}: {:?}", id, e);
+            }));
+        }
+    }
+}
+
+impl<C: Connect> Repl for Executor<C> {
+    /// The driver is added to the files of `program`, which is built like a
+    /// submission. Its container is attached to before it starts and is
+    /// removed by us, as it lives across requests
+    fn start(&self, program: &Submission, memory: u64, cpu: u64) -> Box<Future<Item = Interpreter, Error = ExecutionError>> {
+        let driver = match program.lang.get_repl_driver() {
+            Some(driver) => driver,
+            None => return Box::new(future::err(ExecutionError::UnsupportedLanguage)),
+        };
+        let name = Path::new(driver)
+            .file_name()
+            .expect("driver is a file")
+            .to_string_lossy()
+            .into_owned();
+        let mut program = program.clone();
+        let flavor = self.docker.flavor();
+        let executor = self.clone();
+        let executor2 = self.clone();
+        let executor3 = self.clone();
+        let image = self.pool
+            .spawn_fn(move || fs::read_to_string(driver))
+            .map_err(|e| {
+                debug!("can't read REPL driver: {:?}", e);
+                ExecutionError::BadConfig
+            })
+            .and_then(move |contents| {
+                program.files.insert(name.clone(), contents);
+                // the Dockerfile checks and runs the driver rather than `code`
+                program.entry_point = Some(name.clone());
+                executor
+                    .build_image(program.clone())
+                    .map(move |image| (program, image, name))
+            });
+        let id = image.and_then(move |(program, image, name)| {
+            let mut cmd = vec!["sh".to_owned(), "-c".to_owned(), REPL_STDIN.to_owned(), "sh".to_owned()];
+            cmd.extend(program.lang.get_run_command(&name));
+            let mut config = attached_config(&image, flavor, &program, cmd);
+            config["HostConfig"]["Memory"] = json!(memory);
+            config["HostConfig"]["MemorySwap"] = json!(memory);
+            config["HostConfig"]["Ulimits"][0]["Hard"] = json!(cpu);
+            config["HostConfig"]["Ulimits"][0]["Soft"] = json!(cpu);
+            executor2.create_container(config, program.request_id())
+        });
+        let interpreter = id.and_then(move |id| {
+            let client = executor3.docker.clone();
+            let client2 = executor3.docker.clone();
+            let id2 = id.clone();
+            let id3 = id.clone();
+            let starting = Starting {
+                docker: executor3.docker.clone(),
+                id: Some(id.clone()),
+            };
+            executor3
+                .docker
+                .attach(&id)
+                .and_then(move |attached| client.start_container(&id2).map(|_| attached))
+                .then(move |result| -> Box<Future<Item = _, Error = _>> {
+                    starting.done();
+                    match result {
+                        Ok(attached) => {
+                            metrics::ACTIVE_CONTAINERS.inc();
+                            Box::new(future::ok(Interpreter {
+                                container: id3,
+                                attached: attached,
+                            }))
+                        }
+                        Err(e) => {
+                            debug!("can't start interpreter: {:?}", e);
+                            metrics::docker_error(&e);
+                            let removed = client2.remove_container(&id3).then(|_| Err(ExecutionError::DockerError(e)));
+                            Box::new(removed)
+                        }
                     }
                 })
         });
-        Box::new(logs)
+        Box::new(interpreter)
+    }
+
+    fn stop(&self, container: &str) -> Box<Future<Item = (), Error = ExecutionError>> {
+        metrics::ACTIVE_CONTAINERS.dec();
+        let removed = self.docker.remove_container(container).map_err(|e| {
+            debug!("can't remove interpreter: {:?}", e);
+            metrics::docker_error(&e);
+            ExecutionError::DockerError(e)
+        });
+        Box::new(removed)
+    }
+}
+
+impl<C: Connect> Probe for Executor<C> {
+    /// Images are only looked for once the engine answers the ping
+    fn probe(&self) -> Box<Future<Item = Vec<String>, Error = ExecutionError>> {
+        let client = self.docker.clone();
+        let missing = self.docker
+            .ping()
+            .and_then(move |_| {
+                let images = Language::ALL
+                    .iter()
+                    .flat_map(|lang| lang.get_versions())
+                    .map(|version| client.has_image(version.image).map(move |present| (version.image, present)))
+                    .collect::<Vec<_>>();
+                future::join_all(images)
+            })
+            .map(|images| {
+                images
+                    .into_iter()
+                    .filter(|&(_, present)| !present)
+                    .map(|(image, _)| image.to_owned())
+                    .collect()
+            })
+            .map_err(|e| {
+                debug!("can't probe engine: {:?}", e);
+                metrics::docker_error(&e);
+                ExecutionError::DockerError(e)
+            });
+        Box::new(missing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn dockerfiles_default_to_first_version() {
+        for lang in &Language::ALL {
+            let dockerfile = fs::read_to_string(lang.get_docker_file()).unwrap();
+            let default = dockerfile
+                .lines()
+                .find(|line| line.starts_with("ARG IMAGE="))
+                .map(|line| line.trim_start_matches("ARG IMAGE="));
+            assert_eq!(default, Some(lang.get_versions()[0].image), "{:?}", lang);
+        }
+    }
+
+    #[test]
+    fn runs_java_classes_by_name() {
+        assert_eq!(
+            Language::Java.get_run_command("com/example/Main.java"),
+            vec!["java", "-cp", "/classes", "com.example.Main"]
+        );
     }
 }
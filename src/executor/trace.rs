@@ -0,0 +1,106 @@
+use base64;
+
+/// A decoded field of a protobuf message
+enum Field<'a> {
+    /// Length-delimited: strings, bytes and embedded messages
+    Bytes(&'a [u8]),
+    /// Varints and fixed size numbers, which we don't need
+    Scalar,
+}
+
+/// Reads a varint from the start of `buf`, returns it with the bytes read
+fn varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in buf.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Splits a protobuf message into its fields, `None` if it is malformed
+fn fields(mut buf: &[u8]) -> Option<Vec<(u64, Field<'_>)>> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let (key, read) = varint(buf)?;
+        buf = &buf[read..];
+        let field = match key & 0x7 {
+            0 => {
+                let (_, read) = varint(buf)?;
+                buf = &buf[read..];
+                Field::Scalar
+            }
+            1 | 5 => {
+                let len = if key & 0x7 == 1 { 8 } else { 4 };
+                buf = buf.get(len..)?;
+                Field::Scalar
+            }
+            2 => {
+                let (len, read) = varint(buf)?;
+                let end = read.checked_add(len as usize)?;
+                let bytes = buf.get(read..end)?;
+                buf = &buf[end..];
+                Field::Bytes(bytes)
+            }
+            _ => return None,
+        };
+        fields.push((key >> 3, field));
+    }
+    Some(fields)
+}
+
+fn string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// The parts of a BuildKit `StatusResponse` we care about
+#[derive(Debug, Default)]
+pub struct Trace {
+    /// Output of build steps as `(vertex digest, bytes)`
+    pub logs: Vec<(String, Vec<u8>)>,
+    /// Failed build steps as `(vertex digest, error)`
+    pub errors: Vec<(String, String)>,
+}
+
+impl Trace {
+    /// Decodes the base64 encoded protobuf sent as
+    /// the `aux` of a `moby.buildkit.trace` message
+    pub fn decode(aux: &str) -> Option<Trace> {
+        let bytes = base64::decode(aux).ok()?;
+        let mut trace = Trace::default();
+        for (number, field) in fields(&bytes)? {
+            match (number, field) {
+                // repeated Vertex vertexes = 1
+                (1, Field::Bytes(vertex)) => {
+                    let (mut digest, mut error) = (String::new(), String::new());
+                    for field in fields(vertex)? {
+                        match field {
+                            (1, Field::Bytes(bytes)) => digest = string(bytes),
+                            (7, Field::Bytes(bytes)) => error = string(bytes),
+                            _ => (),
+                        }
+                    }
+                    if !error.is_empty() {
+                        trace.errors.push((digest, error));
+                    }
+                }
+                // repeated VertexLog logs = 3
+                (3, Field::Bytes(log)) => {
+                    let (mut digest, mut msg) = (String::new(), Vec::new());
+                    for field in fields(log)? {
+                        match field {
+                            (1, Field::Bytes(bytes)) => digest = string(bytes),
+                            (4, Field::Bytes(bytes)) => msg = bytes.to_vec(),
+                            _ => (),
+                        }
+                    }
+                    trace.logs.push((digest, msg));
+                }
+                _ => (),
+            }
+        }
+        Some(trace)
+    }
+}
@@ -0,0 +1,68 @@
+use futures::{future, Future};
+use hyper::server::Response;
+use hyper::{self, Body, StatusCode};
+use json;
+
+use metrics;
+use ExecutionError;
+
+use std::rc::Rc;
+
+/// Executors whose engine can be checked on
+pub trait Probe {
+    /// Pings the engine, returns the images of the language versions which are missing
+    fn probe(&self) -> Box<Future<Item = Vec<String>, Error = ExecutionError>>;
+}
+
+impl<E: Probe> Probe for Rc<E> {
+    fn probe(&self) -> Box<Future<Item = Vec<String>, Error = ExecutionError>> {
+        (**self).probe()
+    }
+}
+
+/// The response of the /readyz endpoint
+#[derive(Serialize, Debug)]
+struct Readiness {
+    ready: bool,
+    /// Whether the engine answered the ping
+    docker: bool,
+    /// Images submissions are built on which have to be pulled first
+    missing_images: Vec<String>,
+    /// Submissions taken by the executor which aren't done
+    queue_depth: i64,
+    /// Not ready from this many on
+    max_queue: usize,
+}
+
+/// Responds whether `executor` can take submissions: the engine has to
+/// answer, have the images of every language and not be too busy
+pub fn readiness<E: Probe>(executor: &E, max_queue: usize) -> Box<Future<Item = Response, Error = hyper::Error>> {
+    let response = executor.probe().then(move |probed| {
+        let (docker, missing_images) = match probed {
+            Ok(missing) => (true, missing),
+            Err(e) => {
+                debug!("engine isn't ready: {:?}", e);
+                (false, Vec::new())
+            }
+        };
+        let queue_depth = metrics::QUEUE_DEPTH.get();
+        let readiness = Readiness {
+            ready: docker && missing_images.is_empty() && queue_depth < max_queue as i64,
+            docker: docker,
+            missing_images: missing_images,
+            queue_depth: queue_depth,
+            max_queue: max_queue,
+        };
+        let status = if readiness.ready {
+            StatusCode::Ok
+        } else {
+            StatusCode::ServiceUnavailable
+        };
+        future::ok(
+            Response::new()
+                .with_body(Body::from(json::to_string(&readiness).expect("can't error")))
+                .with_status(status),
+        )
+    });
+    Box::new(response)
+}
@@ -0,0 +1,500 @@
+use futures::Future;
+use hyper::server::Service;
+use json;
+use rusqlite::types::ToSql;
+use rusqlite::{self, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use url::form_urlencoded;
+
+use cpupool::CpuPool;
+use digest;
+use executor::{self, LanguageConfig};
+use Backend;
+use Output;
+use Submission;
+
+use std::cell::Cell;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Entries listed at once if the query doesn't say
+const PAGE: usize = 50;
+
+/// Most entries listed at once
+const MAX_PAGE: usize = 500;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS submissions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        request_id TEXT,
+        endpoint TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        requester TEXT,
+        language TEXT NOT NULL,
+        version TEXT NOT NULL,
+        code_sha256 TEXT NOT NULL,
+        limits TEXT NOT NULL,
+        verdict TEXT NOT NULL,
+        duration_ms REAL NOT NULL,
+        result TEXT
+    );
+    CREATE INDEX IF NOT EXISTS submissions_created_at ON submissions (created_at);
+";
+
+/// The columns of an `Entry`, in order
+const COLUMNS: &str = "id, request_id, endpoint, created_at, requester, language, version, \
+                       code_sha256, limits, verdict, duration_ms";
+
+/// How long entries are kept
+#[derive(Debug, Copy, Clone)]
+pub struct Retention {
+    /// Entries older than this are removed
+    pub max_age: Duration,
+    /// The oldest entries are removed once there are more
+    pub max_entries: usize,
+    /// Entries are only removed every this many entries recorded
+    pub prune_every: usize,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            max_age: Duration::from_secs(30 * 86400),
+            max_entries: 100_000,
+            prune_every: 100,
+        }
+    }
+}
+
+/// The submissions executed or judged, kept in a SQLite database
+pub struct History {
+    connection: Arc<Mutex<Connection>>,
+    retention: Retention,
+    /// Entries are written on it one at a time, rather than on the event loop
+    pool: CpuPool,
+    /// Entries recorded since the database was opened
+    recorded: Cell<usize>,
+}
+
+/// A submission as it is listed
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    id: i64,
+    /// Id of the request it came in
+    request_id: Option<String>,
+    /// `execute` or `judge`
+    endpoint: String,
+    /// Seconds since the epoch
+    created_at: i64,
+    /// Name of the API key it was made with
+    requester: Option<String>,
+    language: String,
+    version: String,
+    /// Hex encoded SHA-256 of its code, files and archive
+    code_sha256: String,
+    /// What it was allowed to use
+    limits: json::Value,
+    /// `ok`, `compile_error` or `error` when executed, the verdict when judged
+    verdict: String,
+    duration_ms: f64,
+}
+
+/// A submission along with the response it got, `null` if it failed
+#[derive(Serialize, Debug)]
+pub struct Replay {
+    #[serde(flatten)]
+    entry: Entry,
+    result: Option<json::Value>,
+}
+
+/// A page of entries, the newest first
+#[derive(Serialize, Debug)]
+pub struct Page {
+    submissions: Vec<Entry>,
+    /// What to send as `before` for the next page, if there is one
+    next: Option<i64>,
+}
+
+/// Which entries to list, from the query of /submissions
+#[derive(Debug, Default)]
+pub struct Filter {
+    language: Option<String>,
+    verdict: Option<String>,
+    endpoint: Option<String>,
+    requester: Option<String>,
+    code_sha256: Option<String>,
+    request_id: Option<String>,
+    /// Seconds since the epoch the entries are created at or after
+    since: Option<i64>,
+    /// Seconds since the epoch the entries are created before
+    until: Option<i64>,
+    /// Only entries with lower ids
+    before: Option<i64>,
+    limit: Option<usize>,
+}
+
+impl Filter {
+    /// Parses the query of a request, e.g. `language=c&verdict=ok&limit=10`
+    pub fn parse(query: Option<&str>) -> Result<Self, String> {
+        fn number<T: ::std::str::FromStr>(key: &str, value: &str) -> Result<Option<T>, String> {
+            value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("{} isn't a number", key))
+        }
+        let mut filter = Filter::default();
+        for (key, value) in form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+            let value = value.into_owned();
+            match &*key {
+                "language" => filter.language = Some(value),
+                "verdict" => filter.verdict = Some(value),
+                "endpoint" => filter.endpoint = Some(value),
+                "requester" => filter.requester = Some(value),
+                "code_sha256" => filter.code_sha256 = Some(value.to_lowercase()),
+                "request_id" => filter.request_id = Some(value),
+                "since" => filter.since = number(&key, &value)?,
+                "until" => filter.until = number(&key, &value)?,
+                "before" => filter.before = number(&key, &value)?,
+                "limit" => filter.limit = number(&key, &value)?,
+                _ => return Err(format!("unknown parameter {}", key)),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Only lists the entries of `requester`
+    pub fn of(mut self, requester: &str) -> Self {
+        self.requester = Some(requester.to_owned());
+        self
+    }
+}
+
+/// Returns the seconds since the epoch
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn entry(row: &Row) -> rusqlite::Result<Entry> {
+    let limits: String = row.get(8)?;
+    Ok(Entry {
+        id: row.get(0)?,
+        request_id: row.get(1)?,
+        endpoint: row.get(2)?,
+        created_at: row.get(3)?,
+        requester: row.get(4)?,
+        language: row.get(5)?,
+        version: row.get(6)?,
+        code_sha256: row.get(7)?,
+        limits: json::from_str(&limits).unwrap_or(json::Value::Null),
+        verdict: row.get(9)?,
+        duration_ms: row.get(10)?,
+    })
+}
+
+/// Writes `entry` along with the response it got
+fn insert(connection: &Connection, entry: &Entry, result: Option<&str>) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO submissions (request_id, endpoint, created_at, requester, language, \
+         version, code_sha256, limits, verdict, duration_ms, result) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        rusqlite::params![
+            entry.request_id,
+            entry.endpoint,
+            entry.created_at,
+            entry.requester,
+            entry.language,
+            entry.version,
+            entry.code_sha256,
+            entry.limits.to_string(),
+            entry.verdict,
+            entry.duration_ms,
+            result,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Removes the entries which are no longer to be kept by `retention`
+fn remove_old(connection: &Connection, retention: &Retention) -> rusqlite::Result<()> {
+    let oldest = now() - retention.max_age.as_secs() as i64;
+    connection.execute("DELETE FROM submissions WHERE created_at < ?1", [oldest])?;
+    connection.execute(
+        "DELETE FROM submissions WHERE id <= \
+         (SELECT id FROM submissions ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+        [retention.max_entries as i64],
+    )?;
+    Ok(())
+}
+
+/// Returns the entries matching `filter`, the newest first
+fn select(connection: &Connection, filter: &Filter) -> rusqlite::Result<Page> {
+    let mut conditions = Vec::new();
+    let mut params: Vec<Box<ToSql>> = Vec::new();
+    {
+        let mut matching = |condition: &'static str, param: Box<ToSql>| {
+            params.push(param);
+            conditions.push(condition.replace('?', &format!("?{}", params.len())));
+        };
+        let texts = [
+            ("language = ?", &filter.language),
+            ("verdict = ?", &filter.verdict),
+            ("endpoint = ?", &filter.endpoint),
+            ("requester = ?", &filter.requester),
+            ("code_sha256 = ?", &filter.code_sha256),
+            ("request_id = ?", &filter.request_id),
+        ];
+        for &(condition, value) in &texts {
+            if let Some(ref value) = *value {
+                matching(condition, Box::new(value.clone()));
+            }
+        }
+        let numbers = [
+            ("created_at >= ?", filter.since),
+            ("created_at < ?", filter.until),
+            ("id < ?", filter.before),
+        ];
+        for &(condition, value) in &numbers {
+            if let Some(value) = value {
+                matching(condition, Box::new(value));
+            }
+        }
+    }
+    let limit = filter.limit.unwrap_or(PAGE).min(MAX_PAGE);
+    let mut sql = format!("SELECT {} FROM submissions", COLUMNS);
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    // one more tells whether there is a next page
+    sql.push_str(&format!(" ORDER BY id DESC LIMIT {}", limit + 1));
+    let mut statement = connection.prepare(&sql)?;
+    let mut submissions = statement
+        .query_map(rusqlite::params_from_iter(params.iter()), entry)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let next = if submissions.len() > limit {
+        submissions.truncate(limit);
+        submissions.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    Ok(Page {
+        submissions: submissions,
+        next: next,
+    })
+}
+
+/// Returns the entry specified by `id` with the response it got, if it is still kept
+fn select_one(connection: &Connection, id: i64) -> rusqlite::Result<Option<Replay>> {
+    let sql = format!("SELECT {}, result FROM submissions WHERE id = ?1", COLUMNS);
+    connection
+        .query_row(&sql, [id], |row| {
+            let result: Option<String> = row.get(11)?;
+            Ok(Replay {
+                entry: entry(row)?,
+                result: result.and_then(|result| json::from_str(&result).ok()),
+            })
+        })
+        .optional()
+}
+
+impl History {
+    /// Opens the database at `path`, creating it if there is none
+    pub fn open<P: AsRef<Path>>(path: P, retention: Retention) -> rusqlite::Result<Self> {
+        History::new(Connection::open(path)?, retention)
+    }
+
+    /// Keeps the history in memory, it is gone once dropped
+    pub fn in_memory(retention: Retention) -> rusqlite::Result<Self> {
+        History::new(Connection::open_in_memory()?, retention)
+    }
+
+    fn new(connection: Connection, retention: Retention) -> rusqlite::Result<Self> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(History {
+            connection: Arc::new(Mutex::new(connection)),
+            retention: retention,
+            pool: CpuPool::new(1),
+            recorded: Cell::new(0),
+        })
+    }
+
+    /// Keeps `entry` along with the response it got. The entries which are
+    /// no longer to be kept are removed then, every `prune_every` entries
+    fn record(&self, entry: Entry, result: Option<String>) -> Box<Future<Item = (), Error = rusqlite::Error>> {
+        let recorded = self.recorded.get();
+        self.recorded.set(recorded + 1);
+        let prune = recorded.is_multiple_of(self.retention.prune_every.max(1));
+        let connection = self.connection.clone();
+        let retention = self.retention;
+        let written = self.pool.spawn_fn(move || {
+            let connection = connection.lock().expect("no panics while using the history");
+            insert(&connection, &entry, result.as_deref())?;
+            if prune {
+                remove_old(&connection, &retention)?;
+            }
+            Ok(())
+        });
+        Box::new(written)
+    }
+
+    /// Lists the entries matching `filter`, the newest first
+    pub fn list(&self, filter: Filter) -> Box<Future<Item = Page, Error = rusqlite::Error>> {
+        let connection = self.connection.clone();
+        let page = self.pool.spawn_fn(move || {
+            let connection = connection.lock().expect("no panics while using the history");
+            select(&connection, &filter)
+        });
+        Box::new(page)
+    }
+
+    /// Returns the entry specified by `id` with the response it got, if
+    /// it is still kept. Only the entries of `requester` if one is given
+    pub fn get(&self, id: i64, requester: Option<&str>) -> Box<Future<Item = Option<Replay>, Error = rusqlite::Error>> {
+        let connection = self.connection.clone();
+        let requester = requester.map(String::from);
+        let replay = self.pool.spawn_fn(move || {
+            let connection = connection.lock().expect("no panics while using the history");
+            let replay = select_one(&connection, id)?;
+            Ok(replay.filter(|replay| requester.is_none() || replay.entry.requester == requester))
+        });
+        Box::new(replay)
+    }
+}
+
+/// Requests whose program is kept in the history
+pub trait Recordable {
+    /// The program which is run for the request
+    fn program(&self) -> &Submission;
+}
+
+impl Recordable for Submission {
+    fn program(&self) -> &Submission {
+        self
+    }
+}
+
+/// Responses kept in the history
+pub trait Outcome: Serialize {
+    /// Name of what came out of the run, entries are filtered by it
+    fn verdict(&self) -> &'static str;
+}
+
+impl Outcome for Output {
+    fn verdict(&self) -> &'static str {
+        match *self {
+            Output::Output { .. } => "ok",
+            Output::CompileError { .. } => "compile_error",
+        }
+    }
+}
+
+/// Returns the hex encoded SHA-256 of the code, files and archive of `program`
+fn code_sha256(program: &Submission) -> String {
+    let mut parts = vec![program.code.as_bytes()];
+    for (path, contents) in &program.files {
+        parts.push(path.as_bytes());
+        parts.push(contents.as_bytes());
+    }
+    parts.push(program.archive.as_deref().unwrap_or("").as_bytes());
+    digest::parts_sha256(parts)
+}
+
+/// Returns what the backend of `program` lets it use
+pub fn limits(program: &Submission) -> json::Value {
+    match program.backend {
+        Backend::Docker => json!({
+            "backend": "docker",
+            "memory": executor::MEMORY,
+            "cpu_seconds": executor::CPU_SECONDS,
+        }),
+        // the limits of the WebAssembly executor are up to who runs it
+        Backend::Wasm => json!({"backend": "wasm"}),
+    }
+}
+
+/// Keeps the submissions handled by the service it wraps in the history,
+/// if there is one
+pub struct Recorded<S> {
+    service: S,
+    history: Option<Rc<History>>,
+    endpoint: &'static str,
+    requester: Option<String>,
+}
+
+impl<S> Recorded<S> {
+    /// Records the submissions `service` handles for `endpoint`, made by `requester`
+    pub fn new(service: S, history: Option<Rc<History>>, endpoint: &'static str, requester: Option<&str>) -> Self {
+        Recorded {
+            service: service,
+            history: history,
+            endpoint: endpoint,
+            requester: requester.map(String::from),
+        }
+    }
+}
+
+impl<S> Service for Recorded<S>
+where
+    S: Service,
+    S::Request: Recordable,
+    S::Response: Outcome + 'static,
+    S::Error: 'static,
+    S::Future: 'static,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, request: Self::Request) -> Self::Future {
+        let history = match self.history {
+            Some(ref history) => history.clone(),
+            None => return Box::new(self.service.call(request)),
+        };
+        let mut entry = {
+            let program = request.program();
+            Entry {
+                id: 0,
+                request_id: program.request_id().map(String::from),
+                endpoint: self.endpoint.to_owned(),
+                created_at: now(),
+                requester: self.requester.clone(),
+                language: program.lang.name().to_owned(),
+                version: program
+                    .version
+                    .clone()
+                    .unwrap_or_else(|| program.lang.get_versions()[0].name.to_owned()),
+                code_sha256: code_sha256(program),
+                limits: limits(program),
+                verdict: String::new(),
+                duration_ms: 0.0,
+            }
+        };
+        let started = Instant::now();
+        let recorded = self.service.call(request).then(move |result| {
+            entry.duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+            let response = match result {
+                Ok(ref response) => {
+                    entry.verdict = response.verdict().to_owned();
+                    Some(json::to_string(response).expect("can't error"))
+                }
+                Err(_) => {
+                    entry.verdict = "error".to_owned();
+                    None
+                }
+            };
+            history.record(entry, response).then(move |recorded| {
+                if let Err(e) = recorded {
+                    warn!("can't record submission: {:?}", e);
+                }
+                result
+            })
+        });
+        Box::new(recorded)
+    }
+}
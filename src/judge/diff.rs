@@ -0,0 +1,180 @@
+/// Lines of context around each change
+const CONTEXT: usize = 3;
+
+/// Above this many (expected, actual) line pairs the differing middle
+/// is shown as a single replacement instead of being diffed
+const MAX_CELLS: usize = 1 << 22;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Kind {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// An edit, with the positions in both texts it applies at
+#[derive(Debug, Copy, Clone)]
+struct Op {
+    kind: Kind,
+    old: usize,
+    new: usize,
+}
+
+/// Returns the edits turning `old` into `new`
+fn ops(old: &[&str], new: &[&str]) -> Vec<Op> {
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let (a, b) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+    let mut ops = (0..prefix)
+        .map(|i| Op { kind: Kind::Equal, old: i, new: i })
+        .collect::<Vec<_>>();
+    let (mut i, mut j) = (0, 0);
+    if (a.len() + 1) * (b.len() + 1) <= MAX_CELLS {
+        // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..]
+        let width = b.len() + 1;
+        let mut lcs = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * width + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+        while i < a.len() && j < b.len() {
+            let kind = if a[i] == b[j] {
+                Kind::Equal
+            } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+                Kind::Delete
+            } else {
+                Kind::Insert
+            };
+            ops.push(Op { kind: kind, old: prefix + i, new: prefix + j });
+            match kind {
+                Kind::Equal => {
+                    i += 1;
+                    j += 1;
+                }
+                Kind::Delete => i += 1,
+                Kind::Insert => j += 1,
+            }
+        }
+    }
+    for i in i..a.len() {
+        ops.push(Op { kind: Kind::Delete, old: prefix + i, new: prefix + j });
+    }
+    for j in j..b.len() {
+        ops.push(Op { kind: Kind::Insert, old: prefix + a.len(), new: prefix + j });
+    }
+    for k in 0..suffix {
+        ops.push(Op {
+            kind: Kind::Equal,
+            old: old.len() - suffix + k,
+            new: new.len() - suffix + k,
+        });
+    }
+    ops
+}
+
+/// Formats the start and length of a hunk, 0 lines start before the hunk
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+/// Returns a unified diff from `expected` to `actual`, empty if the
+/// lines are the same
+pub fn unified(expected: &str, actual: &str) -> String {
+    let old = expected.lines().collect::<Vec<_>>();
+    let new = actual.lines().collect::<Vec<_>>();
+    let ops = ops(&old, &new);
+    let changes = ops.iter()
+        .enumerate()
+        .filter(|&(_, op)| op.kind != Kind::Equal)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return String::new();
+    }
+    // group changes close enough to share context into hunks
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &change in &changes {
+        let start = change.saturating_sub(CONTEXT);
+        let end = (change + CONTEXT + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    let mut diff = String::from("--- expected\n+++ actual\n");
+    for (start, end) in hunks {
+        let ops = &ops[start..end];
+        let old_len = ops.iter().filter(|op| op.kind != Kind::Insert).count();
+        let new_len = ops.iter().filter(|op| op.kind != Kind::Delete).count();
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(ops[0].old, old_len),
+            range(ops[0].new, new_len)
+        ));
+        for op in ops {
+            let (sign, line) = match op.kind {
+                Kind::Equal => (' ', old[op.old]),
+                Kind::Delete => ('-', old[op.old]),
+                Kind::Insert => ('+', new[op.new]),
+            };
+            diff.push(sign);
+            diff.push_str(line);
+            diff.push('\n');
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same() {
+        assert_eq!(unified("1\n2\n", "1\n2"), "");
+    }
+
+    #[test]
+    fn changed_line() {
+        let expected = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let actual = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n";
+        assert_eq!(
+            unified(expected, actual),
+            "--- expected\n+++ actual\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+        );
+    }
+
+    #[test]
+    fn separate_hunks() {
+        let expected = (1..20).map(|i| format!("{}\n", i)).collect::<String>();
+        let actual = (1..20)
+            .filter(|&i| i != 18)
+            .map(|i| if i == 2 { "two\n".to_owned() } else { format!("{}\n", i) })
+            .collect::<String>();
+        assert_eq!(
+            unified(&expected, &actual),
+            "--- expected\n+++ actual\n\
+             @@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+             @@ -15,5 +15,4 @@\n 15\n 16\n 17\n-18\n 19\n"
+        );
+    }
+
+    #[test]
+    fn missing_output() {
+        assert_eq!(unified("1\n", ""), "--- expected\n+++ actual\n@@ -1 +0,0 @@\n-1\n");
+    }
+}
@@ -0,0 +1,686 @@
+mod diff;
+
+use futures::{future, Future};
+use hyper::server::Service;
+
+use history::{Outcome, Recordable};
+use limits::{self, Bounded, RequestLimits, SizeError};
+use metrics;
+use span::Tagged;
+use ExecutionError;
+use Output;
+use OutputStream;
+use Submission;
+
+use std::rc::Rc;
+
+/// How the output of a run is compared to the expected output
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum Comparator {
+    /// Byte for byte
+    #[default]
+    #[serde(rename = "exact")]
+    Exact,
+    /// Line by line, ignoring the amount of whitespace between
+    /// words and trailing blank lines
+    #[serde(rename = "whitespace")]
+    Whitespace,
+    /// Whitespace separated tokens, ignoring how they are split into lines
+    #[serde(rename = "tokens")]
+    Tokens,
+    /// Tokens, numbers may differ by `epsilon`, absolute or relative
+    #[serde(rename = "float")]
+    Float,
+    /// Line by line, ignoring case
+    #[serde(rename = "case_insensitive")]
+    CaseInsensitive,
+}
+
+fn default_epsilon() -> f64 {
+    1e-6
+}
+
+/// The input JSON format for the /judge endpoint
+#[derive(Deserialize, Debug)]
+pub struct Problem {
+    #[serde(flatten)]
+    submission: Submission,
+    /// Expected stdout of the submission
+    expected: String,
+    #[serde(default)]
+    comparator: Comparator,
+    #[serde(default = "default_epsilon")]
+    epsilon: f64,
+    /// Program deciding whether the output is right, used
+    /// instead of the comparator. Only its code and lang are used
+    #[serde(default)]
+    checker: Option<Submission>,
+    /// Program talking to the submission over its stdin and stdout for
+    /// interactive problems, it decides whether the submission is right
+    /// like a checker. Takes precedence over the checker
+    #[serde(default)]
+    interactor: Option<Submission>,
+}
+
+impl Bounded for Problem {
+    fn check_size(&self, limits: &RequestLimits) -> Result<(), SizeError> {
+        self.submission.check_size(limits)?;
+        limits::check("expected", &self.expected, limits.stdin)?;
+        for program in self.checker.iter().chain(self.interactor.iter()) {
+            program.check_size(limits)?;
+        }
+        Ok(())
+    }
+}
+
+impl Tagged for Problem {
+    fn tag(&mut self, request_id: &str, charged_to: Option<u64>) {
+        self.submission.tag(request_id, charged_to);
+        for program in self.checker.iter_mut().chain(self.interactor.iter_mut()) {
+            program.tag(request_id, charged_to);
+        }
+    }
+}
+
+impl Recordable for Problem {
+    /// Only the submission, not the checker or interactor
+    fn program(&self) -> &Submission {
+        &self.submission
+    }
+}
+
+/// The programs taking part in an interactive run
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+pub enum Party {
+    #[serde(rename = "contestant")]
+    Contestant,
+    #[serde(rename = "interactor")]
+    Interactor,
+}
+
+/// A piece of output of an interactive run, the stdout
+/// of each party is what the other party reads
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Exchange {
+    pub from: Party,
+    pub stream: OutputStream,
+    pub data: String,
+}
+
+/// The response of the /judge endpoint
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Judgement {
+    #[serde(flatten)]
+    verdict: Verdict,
+    /// Everything said during an interactive run, in order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transcript: Option<Vec<Exchange>>,
+}
+
+impl From<Verdict> for Judgement {
+    fn from(verdict: Verdict) -> Self {
+        Judgement {
+            verdict: verdict,
+            transcript: None,
+        }
+    }
+}
+
+impl Outcome for Judgement {
+    fn verdict(&self) -> &'static str {
+        self.verdict.name()
+    }
+}
+
+/// What a checker said about an answer
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Checked {
+    /// From 0 to 1
+    score: f64,
+    message: String,
+}
+
+/// Why an answer is wrong
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Reason {
+    /// The output differs from the expected output
+    Mismatch {
+        /// 1-based number of the first differing line of the output
+        line: usize,
+        /// The expected line, or token for token-wise comparators
+        expected: String,
+        /// The line, or token, found in the output instead
+        actual: String,
+        /// Unified diff from the expected output to the actual one
+        diff: String,
+    },
+    /// The checker rejected it
+    Checker(Checked),
+}
+
+/// The result of judging a submission
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "verdict")]
+pub enum Verdict {
+    #[serde(rename = "accepted")]
+    Accepted {
+        /// Only set when a checker was used
+        #[serde(flatten)]
+        checked: Option<Checked>,
+    },
+    #[serde(rename = "wrong_answer")]
+    WrongAnswer(Reason),
+    /// The checker says the answer is right but badly formatted
+    #[serde(rename = "presentation_error")]
+    PresentationError(Checked),
+    /// The checker gave some points for the answer
+    #[serde(rename = "partially_accepted")]
+    PartiallyAccepted(Checked),
+    #[serde(rename = "compile_error")]
+    CompileError { error: String },
+    /// The submission and the interactor didn't finish talking in time
+    #[serde(rename = "time_limit_exceeded")]
+    TimeLimitExceeded,
+    /// The checker didn't compile or failed, which isn't the submission's fault
+    #[serde(rename = "checker_error")]
+    CheckerError { error: String },
+}
+
+impl Verdict {
+    /// Name of the verdict as it is sent, submissions are counted by it
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Verdict::Accepted { .. } => "accepted",
+            Verdict::WrongAnswer(_) => "wrong_answer",
+            Verdict::PresentationError(_) => "presentation_error",
+            Verdict::PartiallyAccepted(_) => "partially_accepted",
+            Verdict::CompileError { .. } => "compile_error",
+            Verdict::TimeLimitExceeded => "time_limit_exceeded",
+            Verdict::CheckerError { .. } => "checker_error",
+        }
+    }
+}
+
+/// Where the output first differs from the expected output
+#[derive(Debug, PartialEq)]
+struct Mismatch {
+    /// 0-based line index in the output
+    line: usize,
+    expected: String,
+    actual: String,
+}
+
+/// Compares two texts line by line, lines are equal if `eq` says so
+fn compare_lines<F>(expected: &[&str], actual: &[&str], eq: F) -> Option<Mismatch>
+where
+    F: Fn(&str, &str) -> bool,
+{
+    let len = expected.len().max(actual.len());
+    (0..len)
+        .find(|&i| match (expected.get(i), actual.get(i)) {
+            (Some(a), Some(b)) => !eq(a, b),
+            _ => true,
+        })
+        .map(|i| Mismatch {
+            line: i,
+            expected: expected.get(i).cloned().unwrap_or_default().to_owned(),
+            actual: actual.get(i).cloned().unwrap_or_default().to_owned(),
+        })
+}
+
+/// Splits `text` into tokens along with the index of their line
+fn tokens(text: &str) -> Vec<(usize, &str)> {
+    text.split('\n')
+        .enumerate()
+        .flat_map(|(i, line)| line.split_whitespace().map(move |token| (i, token)))
+        .collect()
+}
+
+/// Compares two texts token by token, tokens are equal if `eq` says so
+fn compare_tokens<F>(expected: &str, actual: &str, eq: F) -> Option<Mismatch>
+where
+    F: Fn(&str, &str) -> bool,
+{
+    let (expected, actual) = (tokens(expected), tokens(actual));
+    let len = expected.len().max(actual.len());
+    let i = (0..len).find(|&i| match (expected.get(i), actual.get(i)) {
+        (Some(&(_, a)), Some(&(_, b))) => !eq(a, b),
+        _ => true,
+    })?;
+    let line = match actual.get(i).or_else(|| actual.last()) {
+        Some(&(line, _)) if i < actual.len() => line,
+        // the output ended early, point past its last token
+        Some(&(line, _)) => line + 1,
+        None => 0,
+    };
+    Some(Mismatch {
+        line: line,
+        expected: expected.get(i).map(|&(_, token)| token).unwrap_or("").to_owned(),
+        actual: actual.get(i).map(|&(_, token)| token).unwrap_or("").to_owned(),
+    })
+}
+
+/// Numbers are equal if they differ by at most `epsilon`,
+/// either absolutely or relative to the expected one
+fn float_eq(expected: &str, actual: &str, epsilon: f64) -> bool {
+    if expected == actual {
+        return true;
+    }
+    match (expected.parse::<f64>(), actual.parse::<f64>()) {
+        (Ok(a), Ok(b)) => {
+            let diff = (a - b).abs();
+            diff <= epsilon || diff <= epsilon * a.abs()
+        }
+        _ => false,
+    }
+}
+
+/// Lines of `text` without the trailing blank ones
+fn trimmed_lines(text: &str) -> Vec<&str> {
+    let mut lines = text.split('\n').collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+/// Returns where `actual` first differs from `expected`, `None` if
+/// they are the same according to `comparator`
+fn compare(comparator: Comparator, epsilon: f64, expected: &str, actual: &str) -> Option<Mismatch> {
+    match comparator {
+        Comparator::Exact => {
+            if expected == actual {
+                return None;
+            }
+            let expected = expected.split('\n').collect::<Vec<_>>();
+            let actual = actual.split('\n').collect::<Vec<_>>();
+            compare_lines(&expected, &actual, |a, b| a == b)
+        }
+        Comparator::CaseInsensitive => {
+            let expected = expected.split('\n').collect::<Vec<_>>();
+            let actual = actual.split('\n').collect::<Vec<_>>();
+            compare_lines(&expected, &actual, |a, b| a.to_lowercase() == b.to_lowercase())
+        }
+        Comparator::Whitespace => {
+            compare_lines(&trimmed_lines(expected), &trimmed_lines(actual), |a, b| {
+                a.split_whitespace().eq(b.split_whitespace())
+            })
+        }
+        Comparator::Tokens => compare_tokens(expected, actual, |a, b| a == b),
+        Comparator::Float => compare_tokens(expected, actual, |a, b| float_eq(a, b, epsilon)),
+    }
+}
+
+/// Judges the output of a run against the expected output
+pub fn judge(comparator: Comparator, epsilon: f64, expected: &str, output: Output) -> Verdict {
+    let stdout = match output {
+        Output::CompileError { error, .. } => return Verdict::CompileError { error: error },
+        Output::Output { stdout, .. } => stdout,
+    };
+    match compare(comparator, epsilon, expected, &stdout) {
+        None => Verdict::Accepted { checked: None },
+        Some(mismatch) => Verdict::WrongAnswer(Reason::Mismatch {
+            line: mismatch.line + 1,
+            expected: mismatch.expected,
+            actual: mismatch.actual,
+            diff: diff::unified(expected, &stdout),
+        }),
+    }
+}
+
+/// The files given to a checker, as paths in its arguments in this order
+#[derive(Debug)]
+pub struct CheckerFiles {
+    /// Stdin of the submission
+    pub input: String,
+    /// Stdout of the submission
+    pub output: String,
+    pub expected: String,
+}
+
+/// The result of running a checker
+#[derive(Debug)]
+pub struct CheckerRun {
+    pub exit_code: i64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// The result of running an interactor with a submission
+#[derive(Debug)]
+pub struct Interaction {
+    /// The exit code and stderr of the interactor, `None` if both were
+    /// killed for taking too long
+    pub interactor: Option<CheckerRun>,
+    pub transcript: Vec<Exchange>,
+}
+
+/// Executors which can run checker and interactor programs
+pub trait Check {
+    /// Builds the image of `program`, compiling it. Fails with
+    /// `ExecutionError::CompileError` if it doesn't compile
+    fn build(&self, program: &Submission) -> Box<Future<Item = String, Error = ExecutionError>>;
+
+    /// Runs `checker` from its built `image`, with the paths of `files` as arguments
+    fn run_checker(
+        &self,
+        checker: &Submission,
+        image: String,
+        files: CheckerFiles,
+    ) -> Box<Future<Item = CheckerRun, Error = ExecutionError>>;
+
+    /// Runs `submission` and `interactor` from their built images, with
+    /// the stdout of each piped to the stdin of the other. The interactor
+    /// gets the paths of the input and the expected output as arguments
+    fn interact(
+        &self,
+        submission: (&Submission, String),
+        interactor: (&Submission, String),
+        input: String,
+        expected: String,
+    ) -> Box<Future<Item = Interaction, Error = ExecutionError>>;
+}
+
+impl<E: Check> Check for Rc<E> {
+    fn build(&self, program: &Submission) -> Box<Future<Item = String, Error = ExecutionError>> {
+        (**self).build(program)
+    }
+
+    fn run_checker(
+        &self,
+        checker: &Submission,
+        image: String,
+        files: CheckerFiles,
+    ) -> Box<Future<Item = CheckerRun, Error = ExecutionError>> {
+        (**self).run_checker(checker, image, files)
+    }
+
+    fn interact(
+        &self,
+        submission: (&Submission, String),
+        interactor: (&Submission, String),
+        input: String,
+        expected: String,
+    ) -> Box<Future<Item = Interaction, Error = ExecutionError>> {
+        (**self).interact(submission, interactor, input, expected)
+    }
+}
+
+/// Maps the result of a checker to a verdict. Checkers follow the exit
+/// codes of testlib: 0 accepted, 1 wrong answer, 2 presentation error,
+/// 3 checker failure and 7 partially accepted, in which case the
+/// message starts with the score from 0 to 1
+fn checked(run: CheckerRun) -> Verdict {
+    let message = if run.stderr.trim().is_empty() {
+        run.stdout.trim().to_owned()
+    } else {
+        run.stderr.trim().to_owned()
+    };
+    let checked = |score| Checked {
+        score: score,
+        message: message.clone(),
+    };
+    match run.exit_code {
+        0 => Verdict::Accepted {
+            checked: Some(checked(1.0)),
+        },
+        1 => Verdict::WrongAnswer(Reason::Checker(checked(0.0))),
+        2 => Verdict::PresentationError(checked(0.0)),
+        7 => {
+            let mut parts = message.splitn(2, char::is_whitespace);
+            let score = parts.next().and_then(|score| score.parse::<f64>().ok());
+            match score {
+                Some(score) if (0.0..=1.0).contains(&score) => {
+                    Verdict::PartiallyAccepted(Checked {
+                        score: score,
+                        message: parts.next().unwrap_or("").trim().to_owned(),
+                    })
+                }
+                _ => Verdict::CheckerError {
+                    error: format!("invalid score: {}", message),
+                },
+            }
+        }
+        code => Verdict::CheckerError {
+            error: format!("checker exited with {}: {}", code, message),
+        },
+    }
+}
+
+/// Runs submissions on an executor and judges their output
+pub struct Judge<E> {
+    executor: Rc<E>,
+}
+
+impl<E> Judge<E> {
+    /// Creates a judge running submissions on `executor`
+    pub fn new(executor: Rc<E>) -> Self {
+        Judge { executor: executor }
+    }
+}
+
+type Judging = Box<Future<Item = Judgement, Error = ExecutionError>>;
+
+/// The image of a submission, or the verdict if it doesn't compile
+fn submission_image(image: Result<String, ExecutionError>) -> Result<String, Judging> {
+    match image {
+        Ok(image) => Ok(image),
+        Err(ExecutionError::CompileError(error)) => {
+            Err(Box::new(future::ok(Verdict::CompileError { error: error }.into())))
+        }
+        Err(e) => Err(Box::new(future::err(e))),
+    }
+}
+
+/// The image of a checker or interactor, or the verdict if it doesn't compile
+fn judge_image(image: Result<String, ExecutionError>) -> Result<String, Judging> {
+    match image {
+        Ok(image) => Ok(image),
+        // a checker which doesn't compile isn't an error of the submission
+        Err(ExecutionError::CompileError(error)) => {
+            Err(Box::new(future::ok(Verdict::CheckerError { error: error }.into())))
+        }
+        Err(e) => Err(Box::new(future::err(e))),
+    }
+}
+
+impl<E> Judge<E>
+where
+    E: Service<Request = Submission, Response = Output, Error = ExecutionError> + Check + 'static,
+    E::Future: 'static,
+{
+    /// Compares the output of `submission` to `expected` with `comparator`
+    fn compare(&self, submission: Submission, expected: String, comparator: Comparator, epsilon: f64) -> Judging {
+        trace!("judging with {:?}", comparator);
+        let judgement = self.executor
+            .call(submission)
+            .map(move |output| judge(comparator, epsilon, &expected, output).into());
+        Box::new(judgement)
+    }
+
+    /// Runs `checker` on the output of `submission`, it is
+    /// compiled while the submission runs
+    fn check(&self, submission: Submission, expected: String, checker: Submission) -> Judging {
+        trace!("judging with a checker");
+        let input = submission.stdin.clone();
+        let executor = self.executor.clone();
+        let image = self.executor.build(&checker).then(Ok);
+        let judgement = self.executor
+            .call(submission)
+            .join(image)
+            .and_then(move |(output, image)| {
+                let output = match output {
+                    Output::CompileError { error, .. } => {
+                        return Box::new(future::ok(Verdict::CompileError { error: error }.into()))
+                            as Judging;
+                    }
+                    Output::Output { stdout, .. } => stdout,
+                };
+                let image = match judge_image(image) {
+                    Ok(image) => image,
+                    Err(judgement) => return judgement,
+                };
+                let files = CheckerFiles {
+                    input: input,
+                    output: output,
+                    expected: expected,
+                };
+                Box::new(executor
+                    .run_checker(&checker, image, files)
+                    .map(|run| checked(run).into()))
+            });
+        Box::new(judgement)
+    }
+
+    /// Runs `submission` talking to `interactor`, both are compiled at once
+    fn interact(&self, submission: Submission, expected: String, interactor: Submission) -> Judging {
+        trace!("judging with an interactor");
+        let executor = self.executor.clone();
+        let images = self.executor
+            .build(&submission)
+            .then(Ok)
+            .join(self.executor.build(&interactor).then(Ok));
+        let judgement = images.and_then(move |(image, interactor_image)| {
+            let image = match submission_image(image) {
+                Ok(image) => image,
+                Err(judgement) => return judgement,
+            };
+            let interactor_image = match judge_image(interactor_image) {
+                Ok(image) => image,
+                Err(judgement) => return judgement,
+            };
+            let input = submission.stdin.clone();
+            let interaction = executor.interact(
+                (&submission, image),
+                (&interactor, interactor_image),
+                input,
+                expected,
+            );
+            Box::new(interaction.map(|interaction| Judgement {
+                verdict: interaction.interactor.map_or(Verdict::TimeLimitExceeded, checked),
+                transcript: Some(interaction.transcript),
+            }))
+        });
+        Box::new(judgement)
+    }
+}
+
+impl<E> Service for Judge<E>
+where
+    E: Service<Request = Submission, Response = Output, Error = ExecutionError> + Check + 'static,
+    E::Future: 'static,
+{
+    type Request = Problem;
+    type Response = Judgement;
+    type Error = ExecutionError;
+    type Future = Judging;
+
+    /// Interactive problems are judged by the interactor, others by the
+    /// checker if there is one or else by comparing the output
+    fn call(&self, problem: Self::Request) -> Self::Future {
+        let Problem {
+            submission,
+            expected,
+            comparator,
+            epsilon,
+            checker,
+            interactor,
+        } = problem;
+        let language = submission.lang.name();
+        let judging = match (interactor, checker) {
+            (Some(interactor), _) => self.interact(submission, expected, interactor),
+            (None, Some(checker)) => self.check(submission, expected, checker),
+            (None, None) => self.compare(submission, expected, comparator, epsilon),
+        };
+        let counted = judging.then(move |result| {
+            let verdict = match result {
+                Ok(ref judgement) => judgement.verdict.name(),
+                Err(_) => "error",
+            };
+            metrics::submitted(language, verdict);
+            result
+        });
+        Box::new(counted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mismatch(line: usize, expected: &str, actual: &str) -> Option<Mismatch> {
+        Some(Mismatch {
+            line: line,
+            expected: expected.to_owned(),
+            actual: actual.to_owned(),
+        })
+    }
+
+    #[test]
+    fn exact() {
+        assert_eq!(compare(Comparator::Exact, 0.0, "1\n2\n", "1\n2\n"), None);
+        assert_eq!(compare(Comparator::Exact, 0.0, "1\n2\n", "1\n3\n"), mismatch(1, "2", "3"));
+        assert_eq!(compare(Comparator::Exact, 0.0, "1\n2\n", "1\n2"), mismatch(2, "", ""));
+    }
+
+    #[test]
+    fn whitespace() {
+        let compare = |a, b| compare(Comparator::Whitespace, 0.0, a, b);
+        assert_eq!(compare("1 2\n3\n", "1   2 \n3\n\n\n"), None);
+        assert_eq!(compare("1 2\n3\n", "1 2 3\n"), mismatch(0, "1 2", "1 2 3"));
+        assert_eq!(compare("1\n2\n", "1\n"), mismatch(1, "2", ""));
+    }
+
+    #[test]
+    fn tokens() {
+        let compare = |a, b| compare(Comparator::Tokens, 0.0, a, b);
+        assert_eq!(compare("1 2\n3\n", "1\n2 3"), None);
+        assert_eq!(compare("1 2 3", "1\n2\n4"), mismatch(2, "3", "4"));
+        assert_eq!(compare("1 2 3", "1\n2\n"), mismatch(2, "3", ""));
+        assert_eq!(compare("1", "1 2"), mismatch(0, "", "2"));
+    }
+
+    #[test]
+    fn float() {
+        let compare = |a, b| compare(Comparator::Float, 1e-6, a, b);
+        assert_eq!(compare("0.5 1000000", "0.5000001 1000000.5\n"), None);
+        assert_eq!(compare("yes 0.5", "yes 0.51"), mismatch(0, "0.5", "0.51"));
+        assert_eq!(compare("yes", "no"), mismatch(0, "yes", "no"));
+    }
+
+    fn run(exit_code: i64, stderr: &str) -> Verdict {
+        checked(CheckerRun {
+            exit_code: exit_code,
+            stdout: String::new(),
+            stderr: stderr.to_owned(),
+        })
+    }
+
+    #[test]
+    fn checker_exit_codes() {
+        let checked = |score, message: &str| Checked {
+            score: score,
+            message: message.to_owned(),
+        };
+        assert_eq!(run(0, "ok\n"), Verdict::Accepted { checked: Some(checked(1.0, "ok")) });
+        assert_eq!(run(1, "no"), Verdict::WrongAnswer(Reason::Checker(checked(0.0, "no"))));
+        assert_eq!(run(2, "spaces"), Verdict::PresentationError(checked(0.0, "spaces")));
+        assert_eq!(run(7, "0.5 half right"), Verdict::PartiallyAccepted(checked(0.5, "half right")));
+        match run(7, "2 too much") {
+            Verdict::CheckerError { .. } => (),
+            other => panic!("expected a checker error, got {:?}", other),
+        }
+        match run(3, "bad input") {
+            Verdict::CheckerError { error } => assert_eq!(error, "checker exited with 3: bad input"),
+            other => panic!("expected a checker error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let compare = |a, b| compare(Comparator::CaseInsensitive, 0.0, a, b);
+        assert_eq!(compare("YES\nNo", "yes\nno"), None);
+        assert_eq!(compare("YES\nNo", "yes\nnope"), mismatch(1, "No", "nope"));
+    }
+}
@@ -0,0 +1,828 @@
+extern crate base64;
+extern crate bytes;
+extern crate futures;
+extern crate futures_cpupool as cpupool;
+extern crate glob;
+#[cfg(test)]
+extern crate proptest;
+#[cfg(test)]
+extern crate wat;
+extern crate hyper;
+extern crate hyperlocal;
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+#[macro_use]
+extern crate log as logger;
+extern crate prometheus;
+extern crate rand;
+extern crate rusqlite;
+extern crate serde;
+extern crate sha2;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json as json;
+extern crate tar;
+extern crate tempdir;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_uds;
+extern crate unicase;
+extern crate url;
+extern crate wasmi;
+extern crate zip;
+
+mod auth;
+mod cache;
+mod digest;
+mod executor;
+mod health;
+mod history;
+mod judge;
+mod limits;
+mod metrics;
+mod options;
+mod session;
+mod source;
+mod span;
+mod wasm;
+
+use hyper::server::Response;
+use hyper::server::Service;
+use hyper::header::ContentLength;
+use hyper::{Body, Method, StatusCode};
+
+use futures::Stream;
+use futures::{future, Future};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use auth::{AuthError, Permit};
+use cache::Caching;
+use executor::LanguageConfig;
+use health::readiness;
+use history::{Filter, Recorded};
+use limits::{Bounded, SizeError};
+use metrics::Counted;
+use session::{Eval, Interpreter, NotebookRunner, Open, SessionError};
+use span::{span, Tagged};
+
+use std::cell::Cell;
+use std::clone::Clone;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+pub use auth::{ApiKey, Keys, Quota};
+pub use cache::{CacheLimits, ResultCache};
+pub use executor::ExecutionError;
+pub use executor::Executor;
+pub use health::Probe;
+pub use history::{History, Retention};
+pub use limits::RequestLimits;
+pub use judge::{Check, CheckerFiles, CheckerRun, Exchange, Interaction, Judge, Judgement, Party,
+                Verdict};
+pub use session::{Repl, SessionLimits, Sessions};
+pub use span::format as format_log;
+pub use wasm::WasmExecutor;
+
+/// The input JSON format for the /execute endpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Submission {
+    #[serde(default)]
+    code: String,
+    lang: Language,
+    /// More files by path relative to the project
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+    /// A base64 encoded tar or zip of more files
+    archive: Option<String>,
+    /// The file which is compiled or run, if not the one `code` is saved to
+    entry_point: Option<String>,
+    /// Arguments given to the program
+    #[serde(default)]
+    args: Vec<String>,
+    /// Environment variables the program runs with
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// Flags given to the compiler, from the ones the language allows
+    #[serde(default)]
+    compiler_flags: Vec<String>,
+    /// Globs of files, relative to the working directory, sent back after the run
+    #[serde(default)]
+    output_files: Vec<String>,
+    /// Version of the language, the default one if not set
+    version: Option<String>,
+    #[serde(default)]
+    stdin: String,
+    #[serde(default)]
+    backend: Backend,
+    /// Whether to send the output in the order it was written as well
+    #[serde(default)]
+    transcript: bool,
+    /// Whether to run it even if the same submission was run before
+    #[serde(default, skip_serializing)]
+    no_cache: bool,
+    /// Id of the request it came in, its containers and images are labelled with it
+    #[serde(skip)]
+    request_id: Option<String>,
+    /// Token of the permit of its request, which the time it runs is charged to
+    #[serde(skip)]
+    charged_to: Option<u64>,
+}
+
+/// The backends a submission can be executed on
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+enum Backend {
+    /// Builds an image and runs the code in a Docker container
+    #[default]
+    #[serde(rename = "docker")]
+    Docker,
+    /// Compiles the code to WebAssembly and runs it in-process
+    #[serde(rename = "wasm")]
+    Wasm,
+}
+
+/// The languages supported
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+enum Language {
+    #[serde(rename = "c")]
+    C,
+    #[serde(rename = "cpp", alias = "c++")]
+    Cpp,
+    #[serde(rename = "rust")]
+    Rust,
+    #[serde(rename = "go")]
+    Go,
+    /// The class with `main` is `Main`, saved as `Main.java`
+    #[serde(rename = "java")]
+    Java,
+    #[serde(rename = "python2.7")]
+    Python27,
+    #[serde(rename = "python3")]
+    Python3,
+    /// Runs on Node.js
+    #[serde(rename = "javascript", alias = "node")]
+    JavaScript,
+    #[serde(rename = "ruby")]
+    Ruby,
+}
+
+impl Language {
+    const ALL: [Language; 9] = [
+        Language::C,
+        Language::Cpp,
+        Language::Rust,
+        Language::Go,
+        Language::Java,
+        Language::Python27,
+        Language::Python3,
+        Language::JavaScript,
+        Language::Ruby,
+    ];
+
+    /// Name of the language as it is sent, submissions are counted by it
+    fn name(&self) -> &'static str {
+        match *self {
+            Language::C => "c",
+            Language::Cpp => "cpp",
+            Language::Rust => "rust",
+            Language::Go => "go",
+            Language::Java => "java",
+            Language::Python27 => "python2.7",
+            Language::Python3 => "python3",
+            Language::JavaScript => "javascript",
+            Language::Ruby => "ruby",
+        }
+    }
+}
+
+/// An entry of the /languages listing
+#[derive(Serialize)]
+struct LanguageInfo {
+    lang: Language,
+    /// The versions, the default first
+    versions: Vec<&'static str>,
+    default_version: &'static str,
+    compiler_flags: &'static [&'static str],
+}
+
+/// Lists the languages with their versions and compiler flags
+fn languages() -> Vec<LanguageInfo> {
+    Language::ALL
+        .iter()
+        .map(|&lang| {
+            let versions = lang.get_versions();
+            LanguageInfo {
+                lang: lang,
+                versions: versions.iter().map(|version| version.name).collect(),
+                default_version: versions[0].name,
+                compiler_flags: lang.get_compiler_flags(),
+            }
+        })
+        .collect()
+}
+
+type Stdout = String;
+type Stderr = String;
+
+/// The streams a program writes its output to
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+pub enum OutputStream {
+    #[serde(rename = "stdout")]
+    Stdout,
+    #[serde(rename = "stderr")]
+    Stderr,
+}
+
+/// A piece of output, transcripts list them in the order they were written
+#[derive(Serialize, Debug, Clone)]
+pub struct TranscriptChunk {
+    stream: OutputStream,
+    /// RFC 3339 time the chunk was written at
+    timestamp: String,
+    data: String,
+}
+
+/// Files written by the program, as asked for with `output_files`
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct OutputFiles {
+    /// Base64 encoded contents by path, relative to the working directory
+    files: BTreeMap<String, String>,
+    /// Files of which only the first bytes are sent, as they are too big
+    truncated: Vec<String>,
+    /// Files which are left out, as the others take up all the room
+    skipped: Vec<String>,
+}
+
+/// The output of submission
+#[derive(Serialize, Clone)]
+pub enum Output {
+    #[serde(rename = "compile_error")]
+    CompileError {
+        error: String,
+        /// Whether the compiler finished, rather than being stopped for
+        /// taking too long
+        #[serde(skip)]
+        complete: bool,
+    },
+    #[serde(rename = "output")]
+    Output {
+        stdout: Stdout,
+        stderr: Stderr,
+        /// Only sent when asked for, by backends which can tell the order
+        #[serde(skip_serializing_if = "Option::is_none")]
+        transcript: Option<Vec<TranscriptChunk>>,
+        /// Only sent when asked for
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output_files: Option<OutputFiles>,
+        /// Whether the program finished with all of its output kept, rather
+        /// than being killed for a limit or having its output cut short
+        #[serde(skip)]
+        complete: bool,
+    },
+}
+
+impl Output {
+    /// Whether nothing was stopped or cut short for a limit, so that the
+    /// output doesn't depend on how the run went
+    pub fn is_complete(&self) -> bool {
+        match *self {
+            Output::CompileError { complete, .. } | Output::Output { complete, .. } => complete,
+        }
+    }
+}
+
+/// The APIService which manages the REST API endpoints
+#[derive(Clone)]
+pub struct APIService<E> {
+    executor: Rc<E>,
+    /// REPL sessions, the endpoints are only served if set
+    sessions: Option<Rc<Sessions>>,
+    /// Keys requests are made with, anyone may make them if not set
+    keys: Option<Rc<Keys>>,
+    /// Submissions handled, the endpoints are only served if set
+    history: Option<Rc<History>>,
+    /// Outputs of executed submissions, each one is run if not set
+    cache: Option<Rc<ResultCache>>,
+    limits: RequestLimits,
+    /// Not ready once this many submissions are queued
+    max_queue: usize,
+    /// Requests which weren't answered yet, shared by the clones
+    in_flight: Rc<Cell<usize>>,
+}
+
+impl<E> APIService<E> {
+    /// Creates a new instance of the API server using the `executor`
+    pub fn new(executor: E) -> Self {
+        APIService {
+            executor: Rc::new(executor),
+            sessions: None,
+            keys: None,
+            history: None,
+            cache: None,
+            limits: RequestLimits::default(),
+            max_queue: 64,
+            in_flight: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// How many requests weren't answered yet, by this service and its clones
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.get()
+    }
+
+    /// Reports not being ready once `max_queue` submissions are queued
+    pub fn with_max_queue(mut self, max_queue: usize) -> Self {
+        self.max_queue = max_queue;
+        self
+    }
+
+    /// Turns away requests larger than `limits`
+    pub fn with_limits(mut self, limits: RequestLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Only serves requests made with one of `keys`, within their quotas
+    pub fn with_keys(mut self, keys: Keys) -> Self {
+        self.keys = Some(Rc::new(keys));
+        self
+    }
+
+    /// Keeps the submissions executed and judged in `history`
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = Some(Rc::new(history));
+        self
+    }
+
+    /// Answers submissions which were executed before from `cache`
+    pub fn with_cache(mut self, cache: ResultCache) -> Self {
+        self.cache = Some(Rc::new(cache));
+        self
+    }
+
+    /// Serves REPL sessions kept in `sessions`
+    pub fn with_sessions(mut self, sessions: Sessions) -> Self {
+        self.sessions = Some(Rc::new(sessions));
+        self
+    }
+}
+
+/// Counts a request as in flight until it is dropped, along with
+/// the response if the client hangs up
+struct InFlight(Rc<Cell<usize>>);
+
+impl InFlight {
+    fn new(count: &Rc<Cell<usize>>) -> Self {
+        count.set(count.get() + 1);
+        InFlight(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+/// Routes each submission to the executor of the backend it asks for
+#[derive(Clone)]
+pub struct Backends<D, W> {
+    docker: D,
+    wasm: W,
+}
+
+impl<D, W> Backends<D, W> {
+    /// Creates a router over the Docker and WebAssembly executors
+    pub fn new(docker: D, wasm: W) -> Self {
+        Backends {
+            docker: docker,
+            wasm: wasm,
+        }
+    }
+}
+
+impl<D, W> Service for Backends<D, W>
+where
+    D: Service<Request = Submission, Response = Output, Error = ExecutionError>,
+    W: Service<Request = Submission, Response = Output, Error = ExecutionError>,
+    D::Future: 'static,
+    W::Future: 'static,
+{
+    type Request = Submission;
+    type Response = Output;
+    type Error = ExecutionError;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, sub: Self::Request) -> Self::Future {
+        match sub.backend {
+            Backend::Docker => Box::new(self.docker.call(sub)),
+            Backend::Wasm => Box::new(self.wasm.call(sub)),
+        }
+    }
+}
+
+impl<D: Check, W> Check for Backends<D, W> {
+    /// Checkers always run on Docker
+    fn build(&self, program: &Submission) -> Box<Future<Item = String, Error = ExecutionError>> {
+        self.docker.build(program)
+    }
+
+    fn run_checker(
+        &self,
+        checker: &Submission,
+        image: String,
+        files: CheckerFiles,
+    ) -> Box<Future<Item = CheckerRun, Error = ExecutionError>> {
+        self.docker.run_checker(checker, image, files)
+    }
+
+    /// Interactive problems always run on Docker
+    fn interact(
+        &self,
+        submission: (&Submission, String),
+        interactor: (&Submission, String),
+        input: String,
+        expected: String,
+    ) -> Box<Future<Item = Interaction, Error = ExecutionError>> {
+        self.docker.interact(submission, interactor, input, expected)
+    }
+}
+
+impl<D: Probe, W> Probe for Backends<D, W> {
+    /// Only Docker needs anything to be ready
+    fn probe(&self) -> Box<Future<Item = Vec<String>, Error = ExecutionError>> {
+        self.docker.probe()
+    }
+}
+
+impl<D: Repl, W> Repl for Backends<D, W> {
+    /// Sessions always run on Docker
+    fn start(&self, program: &Submission, memory: u64, cpu: u64) -> Box<Future<Item = Interpreter, Error = ExecutionError>> {
+        self.docker.start(program, memory, cpu)
+    }
+
+    fn stop(&self, container: &str) -> Box<Future<Item = (), Error = ExecutionError>> {
+        self.docker.stop(container)
+    }
+}
+
+#[derive(Debug)]
+enum APIError {
+    BadRequest,
+    UnsupportedLanguage,
+    /// The files or options of the submission were rejected
+    InvalidSubmission(String),
+    HyperError,
+    ExecutionError,
+    /// The image couldn't be built for reasons other than the code
+    BuildError,
+    SessionNotFound,
+    /// A cell of the session is still running
+    SessionBusy,
+    TooManySessions,
+    /// The API key is missing or used up its quota
+    Auth(AuthError),
+    /// The body or one of its fields is too large
+    TooLarge(SizeError),
+    /// The query of the URL is invalid
+    InvalidQuery(String),
+    SubmissionNotFound,
+    /// The history can't be read
+    HistoryError,
+}
+
+impl From<ExecutionError> for APIError {
+    fn from(e: ExecutionError) -> Self {
+        debug!("executor error: {:?}", e);
+        match e {
+            ExecutionError::UnsupportedLanguage => APIError::UnsupportedLanguage,
+            ExecutionError::InvalidSource(e) => APIError::InvalidSubmission(e.to_string()),
+            ExecutionError::InvalidOption(e) => APIError::InvalidSubmission(e.to_string()),
+            ExecutionError::BuildError(_) => APIError::BuildError,
+            _ => APIError::ExecutionError,
+        }
+    }
+}
+
+impl From<SessionError> for APIError {
+    fn from(e: SessionError) -> Self {
+        match e {
+            SessionError::NotFound => APIError::SessionNotFound,
+            SessionError::Busy => APIError::SessionBusy,
+            SessionError::TooMany => APIError::TooManySessions,
+            SessionError::Memory(memory) => {
+                APIError::InvalidSubmission(format!("memory {} isn't allowed", memory))
+            }
+            // the files of a session are built like a submission's
+            SessionError::ExecutionError(ExecutionError::CompileError(error)) => {
+                APIError::InvalidSubmission(format!("files don't compile: {}", error))
+            }
+            SessionError::ExecutionError(e) => e.into(),
+        }
+    }
+}
+
+/// The response telling what went wrong
+fn error_response(e: APIError) -> Response {
+    match e {
+        APIError::BadRequest => Response::new()
+            .with_body(Body::from("Invalid json"))
+            .with_status(StatusCode::BadRequest),
+        APIError::UnsupportedLanguage => Response::new()
+            .with_body(Body::from("Language not supported by backend"))
+            .with_status(StatusCode::BadRequest),
+        APIError::InvalidSubmission(error) => Response::new()
+            .with_body(Body::from(format!("Invalid submission: {}", error)))
+            .with_status(StatusCode::BadRequest),
+        APIError::SessionNotFound => Response::new()
+            .with_body(Body::from("Session not found"))
+            .with_status(StatusCode::NotFound),
+        APIError::SessionBusy => Response::new()
+            .with_body(Body::from("Session is busy"))
+            .with_status(StatusCode::Conflict),
+        APIError::TooManySessions => Response::new()
+            .with_body(Body::from("Too many sessions"))
+            .with_status(StatusCode::ServiceUnavailable),
+        APIError::Auth(AuthError::Unauthorized) => {
+            let mut response = Response::new()
+                .with_body(Body::from("Invalid API key"))
+                .with_status(StatusCode::Unauthorized);
+            response.headers_mut().set_raw("WWW-Authenticate", "Bearer");
+            response
+        }
+        APIError::Auth(AuthError::Exceeded {
+            reason,
+            retry_after,
+            status,
+        }) => {
+            let mut response = Response::new()
+                .with_body(Body::from(format!("Quota exceeded: {}", reason)))
+                .with_status(StatusCode::TooManyRequests);
+            status.set_headers(response.headers_mut());
+            response.headers_mut().set_raw("Retry-After", retry_after.to_string());
+            response
+        }
+        APIError::TooLarge(error) => Response::new()
+            .with_body(Body::from(format!("Request too large: {}", error)))
+            .with_status(StatusCode::PayloadTooLarge),
+        APIError::InvalidQuery(error) => Response::new()
+            .with_body(Body::from(format!("Invalid query: {}", error)))
+            .with_status(StatusCode::BadRequest),
+        APIError::SubmissionNotFound => Response::new()
+            .with_body(Body::from("Submission not found"))
+            .with_status(StatusCode::NotFound),
+        APIError::HistoryError => Response::new()
+            .with_body(Body::from("Can't read history"))
+            .with_status(StatusCode::InternalServerError),
+        APIError::BuildError => Response::new()
+            .with_body(Body::from("Can't build image"))
+            .with_status(StatusCode::InternalServerError),
+        _ => Response::new().with_body(Body::from("Unknown error")),
+    }
+}
+
+/// Responds with `result` as JSON, or with what went wrong
+fn json_response<T: Serialize>(result: Result<T, APIError>) -> Response {
+    match result {
+        Ok(body) => Response::new().with_body(Body::from(json::to_string(&body).expect("can't error"))),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Reads `body`, failing as soon as more than `limit` bytes arrived
+fn read_body(body: Body, limit: usize) -> Box<Future<Item = Vec<u8>, Error = APIError>> {
+    let body = body.map_err(|e| {
+        debug!("can't read body: {:?}", e);
+        APIError::HyperError
+    }).fold(Vec::new(), move |mut body, chunk| {
+        if body.len() + chunk.len() > limit {
+            return Err(APIError::TooLarge(SizeError::Body(limit)));
+        }
+        body.extend(chunk);
+        Ok(body)
+    });
+    Box::new(body)
+}
+
+/// Calls `service` with the JSON body of `req`, responds with the JSON it returns.
+/// The programs in the body are tagged with `request_id` and the permit
+/// token `charged_to`.
+/// Bodies over the limit are refused by their Content-Length, or once as much
+/// was read of them, and requests with fields over the limits never reach `service`
+fn handle<S>(
+    req: hyper::server::Request,
+    service: S,
+    limits: RequestLimits,
+    request_id: &str,
+    charged_to: Option<u64>,
+) -> Box<Future<Item = Response, Error = hyper::Error>>
+where
+    S: Service + 'static,
+    S::Request: DeserializeOwned + Bounded + Tagged,
+    S::Response: Serialize,
+    APIError: From<S::Error>,
+{
+    let request_id = request_id.to_owned();
+    if let Some(&ContentLength(length)) = req.headers().get::<ContentLength>() {
+        if length > limits.body as u64 {
+            return Box::new(future::ok(error_response(APIError::TooLarge(
+                SizeError::Body(limits.body),
+            ))));
+        }
+    }
+    let response = read_body(req.body(), limits.body)
+        .and_then(|json| match json::from_slice::<S::Request>(&json) {
+            Ok(request) => future::ok(request),
+            _ => future::err(APIError::BadRequest),
+        })
+        .and_then(move |request| match request.check_size(&limits) {
+            Ok(()) => future::ok(request),
+            Err(e) => future::err(APIError::TooLarge(e)),
+        })
+        .map(move |mut request| {
+            request.tag(&request_id, charged_to);
+            request
+        })
+        .and_then(move |request| {
+            service
+                .call(request)
+                .map_err(APIError::from)
+                .and_then(|resp| {
+                    future::ok(Response::new().with_body(Body::from(
+                        json::to_string(&resp).expect("can't error"),
+                    )))
+                })
+        })
+        .then(|result| {
+            let response = match result {
+                Ok(response) => response,
+                Err(e) => error_response(e),
+            };
+            future::ok(response)
+        });
+    Box::new(response)
+}
+
+impl<E> APIService<E>
+where
+    E: Service<Request = Submission, Response = Output, Error = ExecutionError> + Check + Repl + Probe + 'static,
+{
+    /// Routes `req` if it needs no key or its key may make it. Requests
+    /// need a key if there are any, POST requests start jobs which count
+    /// against its quota. The probes of the orchestrator don't need one
+    fn admitted(&self, req: hyper::server::Request, request_id: &str) -> Box<Future<Item = Response, Error = hyper::Error>> {
+        let probe = req.method() == &Method::Get && (req.path() == "/healthz" || req.path() == "/readyz");
+        let permit = match self.keys {
+            Some(ref keys) if !probe => match Keys::admit(keys, &req, req.method() == &Method::Post) {
+                Ok(permit) => Some(permit),
+                Err(e) => {
+                    debug!("request turned away: {:?}", e);
+                    return Box::new(future::ok(error_response(APIError::Auth(e))));
+                }
+            },
+            _ => None,
+        };
+        let requester = permit.as_ref().map(|permit| permit.name().to_owned());
+        let charged_to = permit.as_ref().and_then(Permit::token);
+        let response = self.route(req, request_id, charged_to, requester.as_deref());
+        match permit {
+            Some(permit) => Box::new(response.map(move |mut response| {
+                permit.status().set_headers(response.headers_mut());
+                response
+            })),
+            None => response,
+        }
+    }
+
+    /// Routes `req`, known as `request_id`, to the handler of its endpoint.
+    /// The time its programs run is charged to the permit token `charged_to`.
+    /// Submissions are recorded as made by `requester`, the name of the key
+    /// they are made with, who only sees their own in the history
+    fn route(
+        &self,
+        req: hyper::server::Request,
+        request_id: &str,
+        charged_to: Option<u64>,
+        requester: Option<&str>,
+    ) -> Box<Future<Item = Response, Error = hyper::Error>> {
+        let path = req.path().to_owned();
+        let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+        match (req.method(), &segments[..], self.sessions.as_ref(), self.history.as_ref()) {
+            (&Method::Post, &["execute"], _, _) => {
+                trace!("execute request");
+                let executor = Counted(Caching::new(self.executor.clone(), self.cache.clone()));
+                let recorded = Recorded::new(executor, self.history.clone(), "execute", requester);
+                handle(req, recorded, self.limits, request_id, charged_to)
+            }
+            (&Method::Post, &["judge"], _, _) => {
+                trace!("judge request");
+                let judge = Judge::new(self.executor.clone());
+                let recorded = Recorded::new(judge, self.history.clone(), "judge", requester);
+                handle(req, recorded, self.limits, request_id, charged_to)
+            }
+            (&Method::Get, &["languages"], _, _) => Box::new(future::ok(Response::new().with_body(
+                Body::from(json::to_string(&languages()).expect("can't error")),
+            ))),
+            (&Method::Get, &["healthz"], _, _) => Box::new(future::ok(Response::new().with_body(Body::from("OK")))),
+            (&Method::Get, &["readyz"], _, _) => readiness(&self.executor, self.max_queue),
+            (&Method::Get, &["metrics"], _, _) => {
+                let mut response = Response::new().with_body(Body::from(metrics::render()));
+                response.headers_mut().set_raw("Content-Type", metrics::CONTENT_TYPE);
+                Box::new(future::ok(response))
+            }
+            (&Method::Post, &["sessions"], Some(sessions), _) => {
+                trace!("new session request");
+                handle(req, Open::new(self.executor.clone(), sessions.clone(), requester), self.limits, request_id, charged_to)
+            }
+            (&Method::Post, &["sessions", id, "eval"], Some(sessions), _) => {
+                trace!("eval request");
+                handle(req, Eval::new(self.executor.clone(), sessions.clone(), id, requester), self.limits, request_id, charged_to)
+            }
+            (&Method::Post, &["notebook"], Some(sessions), _) => {
+                trace!("notebook request");
+                handle(req, NotebookRunner::new(self.executor.clone(), sessions.clone()), self.limits, request_id, charged_to)
+            }
+            (&Method::Delete, &["sessions", id], Some(sessions), _) => {
+                trace!("close session request");
+                let response = sessions.close(&self.executor, id, requester).then(|result| {
+                    let response = match result {
+                        Ok(()) => Response::new().with_status(StatusCode::NoContent),
+                        Err(e) => error_response(e.into()),
+                    };
+                    future::ok(response)
+                });
+                Box::new(response)
+            }
+            (&Method::Get, &["submissions"], _, Some(history)) => {
+                trace!("submissions request");
+                let filter = match Filter::parse(req.query()) {
+                    Ok(filter) => filter,
+                    Err(e) => return Box::new(future::ok(error_response(APIError::InvalidQuery(e)))),
+                };
+                let filter = match requester {
+                    Some(requester) => filter.of(requester),
+                    None => filter,
+                };
+                let page = history.list(filter).then(|page| {
+                    let page = page.map_err(|e| {
+                        warn!("can't list submissions: {:?}", e);
+                        APIError::HistoryError
+                    });
+                    future::ok(json_response(page))
+                });
+                Box::new(page)
+            }
+            (&Method::Get, &["submissions", id], _, Some(history)) => {
+                trace!("replay request");
+                let id = match id.parse() {
+                    Ok(id) => id,
+                    Err(_) => return Box::new(future::ok(error_response(APIError::SubmissionNotFound))),
+                };
+                let replay = history.get(id, requester).then(|replay| {
+                    let replay = replay
+                        .map_err(|e| {
+                            warn!("can't read submission: {:?}", e);
+                            APIError::HistoryError
+                        })
+                        .and_then(|replay| replay.ok_or(APIError::SubmissionNotFound));
+                    future::ok(json_response(replay))
+                });
+                Box::new(replay)
+            }
+            _ => Box::new(future::ok(
+                Response::new()
+                    .with_body(Body::from("Invalid URL"))
+                    .with_status(StatusCode::NotFound),
+            )),
+        }
+    }
+}
+
+impl<E> Service for APIService<E>
+where
+    E: Service<Request = Submission, Response = Output, Error = ExecutionError> + Check + Repl + Probe + 'static,
+{
+    type Request = hyper::server::Request;
+    type Response = hyper::server::Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    /// Each request gets an id, or keeps the one it is sent with, which is
+    /// sent back as `X-Request-Id` and logged along with its stages
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let request_id = span::request_id(&req);
+        debug!("request {}: {} {}", request_id, req.method(), req.path());
+        let in_flight = InFlight::new(&self.in_flight);
+        let response = span(Some(&request_id), "request", self.admitted(req, &request_id));
+        let response = response.then(move |response| {
+            drop(in_flight);
+            response.map(|mut response| {
+                response.headers_mut().set_raw("X-Request-Id", request_id);
+                response
+            })
+        });
+        Box::new(response)
+    }
+}
@@ -0,0 +1,82 @@
+use Submission;
+
+use std::fmt;
+
+/// Limits on the size of requests, checked before anything is executed
+#[derive(Debug, Copy, Clone)]
+pub struct RequestLimits {
+    /// Most bytes of a request body
+    pub body: usize,
+    /// Most bytes of the source of a program, its `code`, `files` and
+    /// `archive` together, or of the code of a cell
+    pub code: usize,
+    /// Most bytes of the input, and of the expected output
+    pub stdin: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            body: 16777216,
+            code: 262144,
+            stdin: 8388608,
+        }
+    }
+}
+
+/// A request which is too large
+#[derive(Debug)]
+pub enum SizeError {
+    /// The body, by its Content-Length or what was read of it
+    Body(usize),
+    /// A field of the request
+    Field {
+        name: &'static str,
+        size: usize,
+        limit: usize,
+    },
+}
+
+impl fmt::Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SizeError::Body(limit) => write!(f, "the body takes more than {} bytes", limit),
+            SizeError::Field { name, size, limit } => {
+                write!(f, "{} takes {} bytes, more than {}", name, size, limit)
+            }
+        }
+    }
+}
+
+/// Fails if `value` takes more than `limit` bytes
+pub fn check(name: &'static str, value: &str, limit: usize) -> Result<(), SizeError> {
+    if value.len() > limit {
+        return Err(SizeError::Field {
+            name: name,
+            size: value.len(),
+            limit: limit,
+        });
+    }
+    Ok(())
+}
+
+/// Requests with fields whose size is limited
+pub trait Bounded {
+    /// Checks the code and input of the request against `limits`
+    fn check_size(&self, limits: &RequestLimits) -> Result<(), SizeError>;
+}
+
+impl Bounded for Submission {
+    fn check_size(&self, limits: &RequestLimits) -> Result<(), SizeError> {
+        let files = self.files.iter().map(|(path, contents)| path.len() + contents.len());
+        let size = self.code.len() + files.sum::<usize>() + self.archive.as_ref().map_or(0, String::len);
+        if size > limits.code {
+            return Err(SizeError::Field {
+                name: "source",
+                size: size,
+                limit: limits.code,
+            });
+        }
+        check("stdin", &self.stdin, limits.stdin)
+    }
+}
@@ -1,162 +1,168 @@
-extern crate bytes;
+extern crate code_executor;
 extern crate env_logger;
 extern crate futures;
-extern crate futures_cpupool as cpupool;
 extern crate hyper;
 extern crate hyperlocal;
 #[macro_use]
-extern crate log as logger;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-#[macro_use]
-extern crate serde_json as json;
-extern crate tar;
+extern crate log;
+extern crate signal_hook;
 extern crate tokio_core;
-extern crate unicase;
-extern crate url;
 
-mod executor;
+use code_executor::{format_log, APIService, Backends, CacheLimits, Executor, History, Keys,
+                    ResultCache, Retention, SessionLimits, Sessions, WasmExecutor};
 
 use hyper::server::Http;
-use hyper::server::Response;
-use hyper::server::Service;
-use hyper::{Body, Method, StatusCode};
 use hyperlocal::UnixConnector;
-
-use futures::Stream;
-use futures::{future, Future};
-
-use std::clone::Clone;
-use std::rc::Rc;
-
-use tokio_core::reactor::Core;
-
-use executor::ExecutionError;
-use executor::Executor;
-
-/// The input JSON format for the /execute endpoint
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Submission {
-    code: String,
-    lang: Language,
-}
-
-/// The languages supported
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
-enum Language {
-    #[serde(rename = "c")]
-    C,
-    #[serde(rename = "python2.7")]
-    Python27,
-}
-
-type Stdout = String;
-type Stderr = String;
-
-/// The output of submission
-#[derive(Serialize)]
-pub enum Output {
-    #[serde(rename = "compile_error")]
-    CompileError { error: String },
-    #[serde(rename = "output")]
-    Output { stdout: Stdout, stderr: Stderr },
-}
-
-/// The APIService which manages the REST API endpoints
-#[derive(Clone)]
-struct APIService<E> {
-    executor: Rc<E>,
-}
-
-impl<E> APIService<E> {
-    /// Creates a new instance of the API server using the `executor`
-    fn new(executor: E) -> Self {
-        APIService {
-            executor: Rc::new(executor),
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use futures::future::{self, Either, Loop};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+/// Resolves once the process is asked to terminate. Asking again kills it
+fn terminated() -> Box<Future<Item = (), Error = ()>> {
+    let (tx, rx) = oneshot::channel();
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("can't handle signals");
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            let _ = tx.send(signal);
         }
-    }
-}
-
-#[derive(Debug)]
-enum APIError {
-    BadRequest,
-    HyperError,
-    ExecutionError,
+        // the signals are still caught rather than killing us
+        if let Some(signal) = signals.next() {
+            warn!("got signal {} again, exiting", signal);
+            process::exit(128 + signal);
+        }
+    });
+    let terminated = rx.map(|signal| info!("got signal {}, shutting down", signal))
+        .map_err(|_| ());
+    Box::new(terminated)
 }
 
-impl<E> Service for APIService<E>
+/// Resolves once `in_flight` says no requests are left or at the `deadline`
+fn drained<F>(in_flight: F, handle: Handle, deadline: Instant) -> Box<Future<Item = (), Error = ()>>
 where
-    E: Service<Request = Submission, Response = Output, Error = ExecutionError> + 'static,
+    F: Fn() -> usize + 'static,
 {
-    type Request = hyper::server::Request;
-    type Response = hyper::server::Response;
-    type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
-
-    fn call(&self, req: Self::Request) -> Self::Future {
-        match (req.method(), req.path()) {
-            (&Method::Post, "/execute") => {
-                trace!("execute request");
-                let executor = self.executor.clone();
-                let response = req.body()
-                    .fold(Vec::new(), |mut body, chunk| {
-                        // FIXME: huge body and out we go!
-                        body.extend(chunk.into_iter());
-                        future::ok::<_, hyper::Error>(body)
-                    })
-                    .map_err(|e| {
-                        debug!("can't read body: {:?}", e);
-                        APIError::HyperError
-                    })
-                    .and_then(|json| match json::from_slice::<Submission>(&json) {
-                        Ok(sub) => future::ok(sub),
-                        _ => future::err(APIError::BadRequest),
-                    })
-                    .and_then(move |sub: Submission| {
-                        executor
-                            .call(sub)
-                            .map_err(|e| {
-                                debug!("executor error: {:?}", e);
-                                APIError::ExecutionError
-                            })
-                            .and_then(|resp| {
-                                future::ok(Response::new().with_body(Body::from(
-                                    json::to_string(&resp).expect("can't error"),
-                                )))
-                            })
-                    })
-                    .then(|result| {
-                        let response = match result {
-                            Ok(response) => response,
-                            Err(APIError::BadRequest) => Response::new()
-                                .with_body(Body::from("Invalid json"))
-                                .with_status(StatusCode::BadRequest),
-                            _ => Response::new().with_body(Body::from("Unknown error")),
-                        };
-                        future::ok(response)
-                    });
-                Box::new(response)
-            }
-            _ => Box::new(future::ok(
-                Response::new()
-                    .with_body(Body::from("Invalid URL"))
-                    .with_status(StatusCode::NotFound),
-            )),
+    let drained = future::loop_fn((), move |_| {
+        let left = in_flight();
+        if left == 0 {
+            return Either::A(future::ok(Loop::Break(())));
         }
+        if Instant::now() >= deadline {
+            warn!("giving up on {} requests", left);
+            return Either::A(future::ok(Loop::Break(())));
+        }
+        let tick = Timeout::new(Duration::from_millis(100), &handle).expect("can't set timer");
+        Either::B(tick.map(|_| Loop::Continue(())).map_err(|e| debug!("timer error: {:?}", e)))
+    });
+    Box::new(drained)
+}
+
+/// Returns the path of the engine's socket, from `DOCKER_HOST` if it is set.
+/// It also points us to Podman's socket, e.g. for rootless Podman:
+/// unix://$XDG_RUNTIME_DIR/podman/podman.sock
+fn docker_socket() -> Result<String, String> {
+    match env::var("DOCKER_HOST") {
+        Ok(host) => match host.strip_prefix("unix://") {
+            Some(path) => Ok(path.to_owned()),
+            None => Err(format!("DOCKER_HOST {} isn't a unix:// socket, the only kind supported", host)),
+        },
+        Err(_) => Ok(String::from("/var/run/docker.sock")),
     }
 }
 
 fn main() {
-    env_logger::init();
+    // a JSON object per line, the stages of requests are logged with their ids
+    env_logger::Builder::from_env(env_logger::Env::default())
+        .format(|buf, record| format_log(buf, record))
+        .init();
     let mut core = Core::new().unwrap();
     let handle = &core.handle();
     let addr = "127.0.0.1:3000".parse().unwrap();
-    let executor = Executor::new(UnixConnector::new(handle.clone()), handle.clone());
-    let api_service = APIService::new(executor);
+    let socket = docker_socket().unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1)
+    });
+    let docker = Executor::new(UnixConnector::new(handle.clone()), &socket, handle.clone());
+    match core.run(docker.detect_flavor()) {
+        Ok(flavor) => info!("serving the API: {:?}", flavor),
+        Err(e) => warn!("can't detect engine, assuming Docker: {:?}", e),
+    }
+    // the sizes of the images compiled by an earlier run aren't known
+    match core.run(docker.remove_images()) {
+        Ok(0) => {}
+        Ok(removed) => info!("removed {} images compiled before", removed),
+        Err(()) => warn!("can't remove images compiled before"),
+    }
+    // bytes the images submissions are compiled in may take up on disk,
+    // 0 compiles every submission
+    let images = env::var("IMAGE_CACHE_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(5 * 1024 * 1024 * 1024);
+    let docker = if images > 0 {
+        docker.with_image_cache(images)
+    } else {
+        docker
+    };
+    let executor = Backends::new(docker.clone(), WasmExecutor::new(Default::default()));
+    let sessions = Sessions::new(handle.clone(), SessionLimits::default());
+    let mut api_service = APIService::new(executor).with_sessions(sessions);
+    // a JSON list of the hashes of the keys along with their quotas
+    match env::var("API_KEYS") {
+        Ok(path) => {
+            let keys = Keys::from_file(&path).expect("can't read API keys");
+            api_service = api_service.with_keys(keys);
+        }
+        Err(_) => warn!("API_KEYS isn't set, anyone can use the API"),
+    }
+    // a SQLite database the submissions are kept in, for as many days and
+    // up to as many entries as HISTORY_DAYS and HISTORY_ENTRIES say
+    if let Ok(path) = env::var("HISTORY_DB") {
+        let mut retention = Retention::default();
+        if let Some(days) = env::var("HISTORY_DAYS").ok().and_then(|days| days.parse::<u64>().ok()) {
+            let secs = days.checked_mul(86400).unwrap_or_else(|| {
+                error!("HISTORY_DAYS {} is too many days", days);
+                process::exit(1)
+            });
+            retention.max_age = Duration::from_secs(secs);
+        }
+        if let Some(entries) = env::var("HISTORY_ENTRIES").ok().and_then(|entries| entries.parse().ok()) {
+            retention.max_entries = entries;
+        }
+        let history = History::open(&path, retention).expect("can't open history");
+        api_service = api_service.with_history(history);
+    }
+    // outputs of submissions are kept for RESULT_CACHE_TTL seconds, up to
+    // RESULT_CACHE_SIZE bytes of them, a TTL of 0 turns the cache off
+    let mut cache = CacheLimits::default();
+    if let Some(ttl) = env::var("RESULT_CACHE_TTL").ok().and_then(|ttl| ttl.parse().ok()) {
+        cache.ttl = Duration::from_secs(ttl);
+    }
+    if let Some(size) = env::var("RESULT_CACHE_SIZE").ok().and_then(|size| size.parse().ok()) {
+        cache.size = size;
+    }
+    if cache.ttl > Duration::from_secs(0) {
+        api_service = api_service.with_cache(ResultCache::new(cache));
+    }
+    // seconds in-flight requests get to finish once we are asked to terminate
+    let grace = env::var("SHUTDOWN_TIMEOUT")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(30);
+    let service = api_service.clone();
     let handle2 = handle.clone();
     let server = Http::new()
-        .serve_addr_handle(&addr, handle, || Ok(api_service.clone()))
+        .serve_addr_handle(&addr, handle, move || Ok(api_service.clone()))
         .expect("can't start serve")
         .for_each(move |conn| {
             let handle = &handle2;
@@ -166,5 +172,14 @@ fn main() {
             Ok(())
         })
         .map_err(|e| debug!("error: {:?}", e));
-    core.run(server).unwrap();
+    // no connections are accepted once the server is dropped, the
+    // ones which were keep being served on the event loop
+    let _ = core.run(server.select(terminated()));
+    let deadline = Instant::now() + Duration::from_secs(grace);
+    let _ = core.run(drained(move || service.in_flight(), core.handle(), deadline));
+    match core.run(docker.remove_containers()) {
+        Ok(0) => {}
+        Ok(removed) => info!("removed {} containers", removed),
+        Err(()) => warn!("can't remove containers"),
+    }
 }
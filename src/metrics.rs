@@ -0,0 +1,131 @@
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts,
+                 Registry, TextEncoder};
+
+use futures::Future;
+use hyper::server::Service;
+
+use executor::DockerError;
+use ExecutionError;
+use Output;
+use Submission;
+
+/// Buckets in seconds, from pulling a cached image to compiling a project
+const BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+
+    /// By language and verdict, `ok`, `compile_error` or `error` for executed
+    /// submissions and the verdict of judged ones
+    pub static ref SUBMISSIONS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("code_executor_submissions_total", "Submissions by language and verdict"),
+        &["language", "verdict"],
+    ));
+
+    pub static ref BUILD_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("code_executor_image_build_seconds", "Time taken to build images")
+            .buckets(BUCKETS.to_vec()),
+        &["language"],
+    ));
+
+    pub static ref START_SECONDS: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("code_executor_container_start_seconds", "Time taken to start containers")
+            .buckets(BUCKETS.to_vec()),
+    ));
+
+    /// From the start of a container until its output is read
+    pub static ref RUN_SECONDS: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("code_executor_run_seconds", "Time taken by programs to run")
+            .buckets(BUCKETS.to_vec()),
+    ));
+
+    pub static ref QUEUE_DEPTH: IntGauge = register(IntGauge::new(
+        "code_executor_queue_depth",
+        "Submissions taken by the executor which aren't done",
+    ));
+
+    pub static ref ACTIVE_CONTAINERS: IntGauge = register(IntGauge::new(
+        "code_executor_active_containers",
+        "Containers started and not stopped",
+    ));
+
+    static ref DOCKER_ERRORS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("code_executor_docker_errors_total", "Errors talking to Docker by kind"),
+        &["error"],
+    ));
+}
+
+/// Registers `metric`, which only fails for duplicate names
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+    let metric = metric.expect("invalid metric");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("can't register metric");
+    metric
+}
+
+/// Counts `error` in the Docker API errors
+pub fn docker_error(error: &DockerError) {
+    DOCKER_ERRORS.with_label_values(&[error.name()]).inc();
+}
+
+/// Counts a submission in `language` which ended with `verdict`
+pub fn submitted(language: &str, verdict: &str) {
+    SUBMISSIONS.with_label_values(&[language, verdict]).inc();
+}
+
+/// Counts one in `gauge` as long as it is kept
+pub struct Tracked(&'static IntGauge);
+
+impl Tracked {
+    pub fn new(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        Tracked(gauge)
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Counts the submissions executed by the service it wraps
+pub struct Counted<S>(pub S);
+
+impl<S> Service for Counted<S>
+where
+    S: Service<Request = Submission, Response = Output, Error = ExecutionError>,
+    S::Future: 'static,
+{
+    type Request = Submission;
+    type Response = Output;
+    type Error = ExecutionError;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, sub: Self::Request) -> Self::Future {
+        let language = sub.lang.name();
+        let counted = self.0.call(sub).then(move |result| {
+            let verdict = match result {
+                Ok(Output::Output { .. }) => "ok",
+                Ok(Output::CompileError { .. }) => "compile_error",
+                Err(_) => "error",
+            };
+            submitted(language, verdict);
+            result
+        });
+        Box::new(counted)
+    }
+}
+
+/// Returns the metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .expect("can't encode metrics");
+    String::from_utf8(buffer).expect("metrics aren't UTF-8")
+}
+
+/// Content type of the text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
@@ -0,0 +1,146 @@
+use executor::{LanguageConfig, Version};
+use glob::Pattern;
+use Submission;
+
+use std::fmt;
+
+/// Reasons the arguments, environment, compiler flags or output files of a submission are rejected
+#[derive(Debug)]
+pub enum OptionError {
+    /// The flag isn't allowed for the language
+    CompilerFlag(String),
+    /// Names of variables are letters, digits and underscores, not starting with a digit
+    EnvName(String),
+    /// Arguments and variables can't have NUL bytes
+    NulByte,
+    /// The glob isn't valid or leaves the working directory
+    Pattern(String),
+    /// The option can't be used with the backend
+    Unsupported(&'static str),
+    /// The language has no such version
+    Version(String),
+}
+
+impl fmt::Display for OptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OptionError::CompilerFlag(ref flag) => write!(f, "compiler flag {:?} isn't allowed", flag),
+            OptionError::EnvName(ref name) => write!(f, "invalid variable name {:?}", name),
+            OptionError::NulByte => write!(f, "arguments and variables can't have NUL bytes"),
+            OptionError::Pattern(ref pattern) => write!(f, "invalid output file pattern {:?}", pattern),
+            OptionError::Unsupported(option) => write!(f, "{} can't be used with this backend", option),
+            OptionError::Version(ref version) => write!(f, "unknown version {:?}", version),
+        }
+    }
+}
+
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => (),
+        _ => return false,
+    }
+    chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+impl Submission {
+    /// Checks `args`, `env` and `compiler_flags` against what the language
+    /// allows, and that `output_files` are valid globs
+    pub fn check_options(&self) -> Result<(), OptionError> {
+        let allowed = self.lang.get_compiler_flags();
+        if let Some(flag) = self.compiler_flags
+            .iter()
+            .find(|flag| !allowed.contains(&flag.as_str()))
+        {
+            return Err(OptionError::CompilerFlag(flag.clone()));
+        }
+        if let Some(name) = self.env.keys().find(|name| !is_env_name(name)) {
+            return Err(OptionError::EnvName(name.clone()));
+        }
+        let mut strings = self.args.iter().chain(self.env.values());
+        if strings.any(|string| string.contains('\0')) {
+            return Err(OptionError::NulByte);
+        }
+        self.output_patterns()?;
+        self.version()?;
+        Ok(())
+    }
+
+    /// The version asked for, or the default one of the language
+    pub fn version(&self) -> Result<&'static Version, OptionError> {
+        let versions = self.lang.get_versions();
+        match self.version {
+            Some(ref name) => versions
+                .iter()
+                .find(|version| version.name == name)
+                .ok_or_else(|| OptionError::Version(name.clone())),
+            None => Ok(&versions[0]),
+        }
+    }
+
+    /// Parses `output_files`, which stay in the working directory
+    pub fn output_patterns(&self) -> Result<Vec<Pattern>, OptionError> {
+        self.output_files
+            .iter()
+            .map(|glob| {
+                let invalid = || OptionError::Pattern(glob.clone());
+                if glob.starts_with('/') || glob.split('/').any(|component| component == "..") {
+                    return Err(invalid());
+                }
+                Pattern::new(glob).map_err(|_| invalid())
+            })
+            .collect()
+    }
+
+    /// The environment as `NAME=value` pairs
+    pub fn env_pairs(&self) -> Vec<String> {
+        self.env
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json;
+    use Submission;
+
+    fn check(value: json::Value) -> Result<(), String> {
+        let submission: Submission = json::from_value(value).unwrap();
+        submission.check_options().map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn checks_options() {
+        assert!(
+            check(json!({
+                "code": "",
+                "lang": "c",
+                "args": ["-v", "two words"],
+                "env": {"MODE": "fast", "_x1": ""},
+                "compiler_flags": ["-O2", "-std=c11", "-lm"],
+            })).is_ok()
+        );
+        assert_eq!(
+            check(json!({"code": "", "lang": "c", "compiler_flags": ["-fplugin=evil.so"]})),
+            Err("compiler flag \"-fplugin=evil.so\" isn't allowed".to_owned())
+        );
+        assert!(check(json!({"code": "", "lang": "python2.7", "compiler_flags": ["-O2"]})).is_err());
+        for name in &["", "1A", "A-B", "A=B"] {
+            let mut env = json::Map::new();
+            env.insert(name.to_string(), json!("x"));
+            assert!(check(json!({"code": "", "lang": "c", "env": env})).is_err(), "{:?}", name);
+        }
+        assert!(check(json!({"code": "", "lang": "c", "args": ["a\u{0}b"]})).is_err());
+        assert!(check(json!({"code": "", "lang": "c", "output_files": ["*.csv", "plots/**/*.png"]})).is_ok());
+        assert!(check(json!({"code": "", "lang": "python3", "version": "3.8"})).is_ok());
+        assert_eq!(
+            check(json!({"code": "", "lang": "python3", "version": "2.7"})),
+            Err("unknown version \"2.7\"".to_owned())
+        );
+        for glob in &["/etc/*", "../*", "[", "a/../../b"] {
+            assert!(check(json!({"code": "", "lang": "c", "output_files": [glob]})).is_err(), "{:?}", glob);
+        }
+    }
+}
@@ -0,0 +1,557 @@
+mod notebook;
+
+pub use self::notebook::NotebookRunner;
+
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use hyper::server::Service;
+use rand::{OsRng, Rng};
+use tokio_core::reactor::{Handle, Timeout};
+
+use auth;
+use executor::{Attached, Evaluated};
+use limits::{self, Bounded, RequestLimits, SizeError};
+use span::Tagged;
+use ExecutionError;
+use Submission;
+
+use std::cell::{self, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Docker refuses containers with less memory
+const MIN_MEMORY: u64 = 6291456;
+
+/// Limits of the REPL sessions
+#[derive(Debug, Copy, Clone)]
+pub struct SessionLimits {
+    /// Most sessions alive at once
+    pub sessions: usize,
+    /// Sessions which aren't used for this long are closed
+    pub idle: Duration,
+    /// Longest a cell may run, the session is closed if it takes longer
+    pub eval: Duration,
+    /// Most memory of a session's container in bytes, also the default
+    pub memory: u64,
+    /// Seconds of CPU time a session may use in total
+    pub cpu: u64,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        SessionLimits {
+            sessions: 16,
+            idle: Duration::from_secs(600),
+            eval: Duration::from_secs(30),
+            memory: 268435456,
+            cpu: 300,
+        }
+    }
+}
+
+/// The input JSON format for the /sessions endpoint
+#[derive(Deserialize, Debug)]
+pub struct NewSession {
+    /// Only its lang, version, env and files are used, `code` is
+    /// saved like a submission's but not run
+    #[serde(flatten)]
+    program: Submission,
+    /// Memory of the container in bytes, up to the limit
+    memory: Option<u64>,
+}
+
+impl Bounded for NewSession {
+    fn check_size(&self, limits: &RequestLimits) -> Result<(), SizeError> {
+        self.program.check_size(limits)
+    }
+}
+
+impl Tagged for NewSession {
+    fn tag(&mut self, request_id: &str, charged_to: Option<u64>) {
+        self.program.tag(request_id, charged_to);
+    }
+}
+
+/// The response of the /sessions endpoint
+#[derive(Serialize, Debug)]
+pub struct SessionInfo {
+    id: String,
+    /// Seconds the session is kept without being used
+    idle_timeout: u64,
+}
+
+/// The input JSON format for the /sessions/{id}/eval endpoint
+#[derive(Deserialize, Debug)]
+pub struct Cell {
+    code: String,
+    /// Token of the permit of the request it came in, which its time is charged to
+    #[serde(skip)]
+    charged_to: Option<u64>,
+}
+
+impl Bounded for Cell {
+    fn check_size(&self, limits: &RequestLimits) -> Result<(), SizeError> {
+        limits::check("code", &self.code, limits.code)
+    }
+}
+
+/// Cells run in an interpreter which is already there, so only
+/// the time they take is charged to the request
+impl Tagged for Cell {
+    fn tag(&mut self, _request_id: &str, charged_to: Option<u64>) {
+        self.charged_to = charged_to;
+    }
+}
+
+/// How a cell ended
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+pub enum CellStatus {
+    #[serde(rename = "ok")]
+    Ok,
+    /// It raised an error, which is in its stderr
+    #[serde(rename = "error")]
+    Error,
+    /// The interpreter exited, e.g. as it ran out of memory.
+    /// The session is closed
+    #[serde(rename = "exited")]
+    Exited,
+    /// It took longer than allowed, the session is closed
+    #[serde(rename = "timeout")]
+    Timeout,
+}
+
+/// The response of the /sessions/{id}/eval endpoint
+#[derive(Serialize, Debug)]
+pub struct CellOutput {
+    stdout: String,
+    stderr: String,
+    status: CellStatus,
+}
+
+impl From<Evaluated> for CellOutput {
+    fn from(evaluated: Evaluated) -> Self {
+        CellOutput {
+            stdout: String::from_utf8_lossy(&evaluated.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&evaluated.stderr).into_owned(),
+            status: evaluated.status,
+        }
+    }
+}
+
+/// An interpreter running the REPL driver of its language
+pub struct Interpreter {
+    pub container: String,
+    pub attached: Attached,
+}
+
+/// Executors which can keep interpreters running across requests
+pub trait Repl {
+    /// Starts an interpreter for `program`, its files can be imported
+    /// # Arguments
+    /// * `memory` - Memory of the container in bytes
+    /// * `cpu` - Seconds of CPU time it may use in total
+    fn start(&self, program: &Submission, memory: u64, cpu: u64) -> Box<Future<Item = Interpreter, Error = ExecutionError>>;
+
+    /// Kills the interpreter running in `container` and removes it
+    fn stop(&self, container: &str) -> Box<Future<Item = (), Error = ExecutionError>>;
+}
+
+impl<E: Repl> Repl for Rc<E> {
+    fn start(&self, program: &Submission, memory: u64, cpu: u64) -> Box<Future<Item = Interpreter, Error = ExecutionError>> {
+        (**self).start(program, memory, cpu)
+    }
+
+    fn stop(&self, container: &str) -> Box<Future<Item = (), Error = ExecutionError>> {
+        (**self).stop(container)
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    /// There's no session with the id, or it was closed
+    NotFound,
+    /// A cell of the session is still running
+    Busy,
+    /// As many sessions as allowed are alive
+    TooMany,
+    /// The memory asked for is more than allowed or less than Docker takes
+    Memory(u64),
+    ExecutionError(ExecutionError),
+}
+
+struct Session {
+    /// Name of the key it was opened with, only that key may use it
+    owner: Option<String>,
+    container: String,
+    /// Taken while a cell runs
+    attached: Option<Attached>,
+    last_used: Instant,
+}
+
+/// The live REPL sessions, by id
+pub struct Sessions {
+    limits: SessionLimits,
+    /// Used for the timers
+    handle: Handle,
+    sessions: RefCell<HashMap<String, Session>>,
+    /// Sessions being started and notebooks being run,
+    /// which count against the limit
+    reserved: cell::Cell<usize>,
+}
+
+/// Returns a new session id of 128 random bits. Anyone who knows it can
+/// run code in the session, so it comes from the system's generator
+fn session_id() -> Result<String, SessionError> {
+    let mut bytes = [0u8; 16];
+    let mut rng = OsRng::new().map_err(|e| {
+        debug!("can't open the system's random generator: {:?}", e);
+        SessionError::ExecutionError(ExecutionError::UnknownError)
+    })?;
+    rng.fill_bytes(&mut bytes);
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Room made by `Sessions::reserve`, given back once it is dropped, e.g.
+/// when the client goes away. The interpreter it holds is stopped then
+struct Reservation<E: Repl> {
+    sessions: Rc<Sessions>,
+    executor: Rc<E>,
+    container: Option<String>,
+}
+
+impl<E: Repl> Reservation<E> {
+    /// Makes room for another interpreter, returns it with its memory
+    /// # Arguments
+    /// * `memory` - The memory asked for, if any
+    fn new(sessions: Rc<Sessions>, executor: Rc<E>, memory: Option<u64>) -> Result<(Self, u64), SessionError> {
+        let memory = sessions.reserve(memory)?;
+        let reservation = Reservation {
+            sessions: sessions,
+            executor: executor,
+            container: None,
+        };
+        Ok((reservation, memory))
+    }
+
+    /// Stops the interpreter running in `container` if dropped before `let_go`
+    fn hold(&mut self, container: &str) {
+        self.container = Some(container.to_owned());
+    }
+
+    /// Leaves the interpreter held running
+    fn let_go(&mut self) {
+        self.container = None;
+    }
+}
+
+impl<E: Repl> Drop for Reservation<E> {
+    fn drop(&mut self) {
+        self.sessions.release();
+        if let Some(container) = self.container.take() {
+            debug!("stopping abandoned interpreter {}", container);
+            let stopped = self.executor
+                .stop(&container)
+                .map_err(|e| debug!("can't stop interpreter: {:?}", e));
+            self.sessions.handle.spawn(stopped);
+        }
+    }
+}
+
+/// A cell running in a session, which is closed if this is dropped before
+/// it is `done`, e.g. when the client goes away, as the connection to its
+/// interpreter is dropped along with the cell
+struct Evaluating<E: Repl> {
+    sessions: Rc<Sessions>,
+    executor: Rc<E>,
+    id: Option<String>,
+}
+
+impl<E: Repl> Evaluating<E> {
+    /// Leaves the session to whoever handles the result of the cell
+    fn done(mut self) {
+        self.id = None;
+    }
+}
+
+impl<E: Repl> Drop for Evaluating<E> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            debug!("closing session {} whose cell was given up on", id);
+            self.sessions.discard(&*self.executor, &id);
+        }
+    }
+}
+
+impl Sessions {
+    /// Creates an empty set of sessions, with timers on the event loop behind `handle`
+    pub fn new(handle: Handle, limits: SessionLimits) -> Self {
+        Sessions {
+            limits: limits,
+            handle: handle,
+            sessions: RefCell::new(HashMap::new()),
+            reserved: cell::Cell::new(0),
+        }
+    }
+
+    /// Makes room for another interpreter, returns its memory.
+    /// Taken through a `Reservation`, which gives it back
+    /// # Arguments
+    /// * `memory` - The memory asked for, if any
+    fn reserve(&self, memory: Option<u64>) -> Result<u64, SessionError> {
+        let memory = memory.unwrap_or(self.limits.memory);
+        if memory > self.limits.memory || memory < MIN_MEMORY {
+            return Err(SessionError::Memory(memory));
+        }
+        let reserved = &self.reserved;
+        if self.sessions.borrow().len() + reserved.get() >= self.limits.sessions {
+            return Err(SessionError::TooMany);
+        }
+        reserved.set(reserved.get() + 1);
+        Ok(memory)
+    }
+
+    /// Gives back the room made by `reserve`, once its `Reservation` is dropped
+    fn release(&self) {
+        self.reserved.set(self.reserved.get() - 1);
+    }
+
+    /// Takes the connection of the session specified by `id` to run a cell,
+    /// if it was opened by `owner`
+    fn take(&self, id: &str, owner: Option<&str>) -> Result<Attached, SessionError> {
+        match self.sessions.borrow_mut().get_mut(id) {
+            Some(ref session) if session.owner.as_deref() != owner => Err(SessionError::NotFound),
+            Some(session) => session.attached.take().ok_or(SessionError::Busy),
+            None => Err(SessionError::NotFound),
+        }
+    }
+
+    /// Gives the connection back once the cell is done
+    fn put_back(&self, id: &str, attached: Attached) {
+        // it may have been closed while the cell ran
+        if let Some(session) = self.sessions.borrow_mut().get_mut(id) {
+            session.attached = Some(attached);
+            session.last_used = Instant::now();
+        }
+    }
+
+    /// Closes the session specified by `id` if it was opened by `owner`,
+    /// removing its container
+    pub fn close<E: Repl>(&self, executor: &E, id: &str, owner: Option<&str>) -> Box<Future<Item = (), Error = SessionError>> {
+        match self.sessions.borrow().get(id) {
+            Some(session) if session.owner.as_deref() == owner => (),
+            _ => return Box::new(future::err(SessionError::NotFound)),
+        }
+        self.remove(executor, id)
+    }
+
+    /// Closes the session specified by `id`, whoever opened it
+    fn remove<E: Repl>(&self, executor: &E, id: &str) -> Box<Future<Item = (), Error = SessionError>> {
+        let session = match self.sessions.borrow_mut().remove(id) {
+            Some(session) => session,
+            None => return Box::new(future::err(SessionError::NotFound)),
+        };
+        trace!("closing session {}", id);
+        Box::new(executor.stop(&session.container).map_err(SessionError::ExecutionError))
+    }
+
+    /// Closes the session specified by `id` in the background
+    fn discard<E: Repl>(&self, executor: &E, id: &str) {
+        let closed = self.remove(executor, id).map_err(|e| debug!("can't close session: {:?}", e));
+        self.handle.spawn(closed);
+    }
+
+    /// Closes the session specified by `id` once it isn't used for long enough
+    fn watch<E: Repl + 'static>(sessions: Rc<Sessions>, executor: Rc<E>, id: String) {
+        let idle = sessions.limits.idle;
+        let handle = sessions.handle.clone();
+        let handle2 = sessions.handle.clone();
+        let watcher = future::loop_fn(idle, move |wait| {
+            let sessions = sessions.clone();
+            let executor = executor.clone();
+            let id = id.clone();
+            future::result(Timeout::new(wait, &handle)).flatten().map(move |_| {
+                let unused = match sessions.sessions.borrow().get(&id) {
+                    // running cells are timed out on their own
+                    Some(session) if session.attached.is_none() => return Loop::Continue(idle),
+                    Some(session) => session.last_used.elapsed(),
+                    None => return Loop::Break(()),
+                };
+                if unused < idle {
+                    return Loop::Continue(idle - unused);
+                }
+                debug!("session {} is idle", id);
+                sessions.discard(&*executor, &id);
+                Loop::Break(())
+            })
+        });
+        handle2.spawn(watcher.map_err(|e| debug!("session timer failed: {:?}", e)));
+    }
+}
+
+/// Runs `code` on `attached` for as long as `sessions` allow cells to run,
+/// charging the time it takes to the permit token `charged_to`. The connection
+/// is given back unless the interpreter exited or the cell took too long
+fn evaluate(
+    attached: Attached,
+    code: &str,
+    sessions: &Sessions,
+    charged_to: Option<u64>,
+) -> Box<Future<Item = (Option<Attached>, Evaluated), Error = SessionError>> {
+    let timeout = match Timeout::new(sessions.limits.eval, &sessions.handle) {
+        Ok(timeout) => timeout,
+        Err(e) => {
+            debug!("can't set cell timeout: {:?}", e);
+            return Box::new(future::err(SessionError::ExecutionError(ExecutionError::UnknownError)));
+        }
+    };
+    let started = Instant::now();
+    let evaluated = attached.eval(code).select2(timeout).then(move |result| {
+        auth::charge(charged_to, started.elapsed());
+        match result {
+            Ok(Either::A(((_, evaluated), _))) if evaluated.status == CellStatus::Exited => Ok((None, evaluated)),
+            Ok(Either::A(((attached, evaluated), _))) => Ok((Some(attached), evaluated)),
+            Ok(Either::B((_, evaluate))) => Ok((None, evaluate.timed_out())),
+            Err(Either::A((e, _))) => {
+                debug!("can't evaluate cell: {:?}", e);
+                Err(SessionError::ExecutionError(ExecutionError::DockerError(e)))
+            }
+            Err(Either::B((e, _))) => {
+                debug!("cell timer failed: {:?}", e);
+                Err(SessionError::ExecutionError(ExecutionError::UnknownError))
+            }
+        }
+    });
+    Box::new(evaluated)
+}
+
+/// Opens sessions, the service behind the /sessions endpoint
+pub struct Open<E> {
+    executor: Rc<E>,
+    sessions: Rc<Sessions>,
+    /// Name of the key the sessions are opened with
+    owner: Option<String>,
+}
+
+impl<E> Open<E> {
+    /// Creates a service opening `sessions` on `executor` for `owner`
+    pub fn new(executor: Rc<E>, sessions: Rc<Sessions>, owner: Option<&str>) -> Self {
+        Open {
+            executor: executor,
+            sessions: sessions,
+            owner: owner.map(String::from),
+        }
+    }
+}
+
+impl<E: Repl + 'static> Service for Open<E> {
+    type Request = NewSession;
+    type Response = SessionInfo;
+    type Error = SessionError;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, request: Self::Request) -> Self::Future {
+        let limits = self.sessions.limits;
+        let reserved = Reservation::new(self.sessions.clone(), self.executor.clone(), request.memory);
+        let (mut reservation, memory) = match reserved {
+            Ok(reserved) => reserved,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let sessions = self.sessions.clone();
+        let executor = self.executor.clone();
+        let owner = self.owner.clone();
+        let session = self.executor
+            .start(&request.program, memory, limits.cpu)
+            .then(move |result| {
+                let interpreter = result.map_err(SessionError::ExecutionError)?;
+                reservation.hold(&interpreter.container);
+                let id = session_id()?;
+                // the session counts against the limit instead
+                reservation.let_go();
+                drop(reservation);
+                debug!("session {} runs in {}", id, interpreter.container);
+                sessions.sessions.borrow_mut().insert(
+                    id.clone(),
+                    Session {
+                        owner: owner,
+                        container: interpreter.container,
+                        attached: Some(interpreter.attached),
+                        last_used: Instant::now(),
+                    },
+                );
+                Sessions::watch(sessions, executor, id.clone());
+                Ok(SessionInfo {
+                    id: id,
+                    idle_timeout: limits.idle.as_secs(),
+                })
+            });
+        Box::new(session)
+    }
+}
+
+/// Runs cells of a session, the service behind the /sessions/{id}/eval endpoint
+pub struct Eval<E> {
+    executor: Rc<E>,
+    sessions: Rc<Sessions>,
+    id: String,
+    /// Name of the key the cells are sent with
+    owner: Option<String>,
+}
+
+impl<E> Eval<E> {
+    /// Creates a service running cells of the session specified by `id`,
+    /// if it was opened by `owner`
+    pub fn new(executor: Rc<E>, sessions: Rc<Sessions>, id: &str, owner: Option<&str>) -> Self {
+        Eval {
+            executor: executor,
+            sessions: sessions,
+            id: id.to_owned(),
+            owner: owner.map(String::from),
+        }
+    }
+}
+
+impl<E: Repl + 'static> Service for Eval<E> {
+    type Request = Cell;
+    type Response = CellOutput;
+    type Error = SessionError;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    /// Cells run one at a time, the session is closed if the
+    /// interpreter exits or a cell takes too long
+    fn call(&self, cell: Self::Request) -> Self::Future {
+        let attached = match self.sessions.take(&self.id, self.owner.as_deref()) {
+            Ok(attached) => attached,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let sessions = self.sessions.clone();
+        let executor = self.executor.clone();
+        let id = self.id.clone();
+        trace!("evaluating a cell in session {}", id);
+        let evaluating = Evaluating {
+            sessions: sessions.clone(),
+            executor: executor.clone(),
+            id: Some(id.clone()),
+        };
+        let evaluated = evaluate(attached, &cell.code, &self.sessions, cell.charged_to);
+        let output = evaluated.then(move |result| {
+            evaluating.done();
+            match result {
+                Ok((Some(attached), evaluated)) => {
+                    sessions.put_back(&id, attached);
+                    Ok(evaluated.into())
+                }
+                Ok((None, evaluated)) => {
+                    debug!("closing session {}: {:?}", id, evaluated.status);
+                    sessions.discard(&*executor, &id);
+                    Ok(evaluated.into())
+                }
+                Err(e) => {
+                    sessions.discard(&*executor, &id);
+                    Err(e)
+                }
+            }
+        });
+        Box::new(output)
+    }
+}
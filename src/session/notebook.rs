@@ -0,0 +1,142 @@
+use futures::future::{self, Loop};
+use futures::Future;
+use hyper::server::Service;
+
+use limits::{self, Bounded, RequestLimits, SizeError};
+use span::Tagged;
+
+use super::{evaluate, CellOutput, CellStatus, Interpreter, Repl, Reservation, SessionError, Sessions};
+use Submission;
+
+use std::rc::Rc;
+
+/// What happens to the cells after one which raised an error
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum OnError {
+    /// They aren't run
+    #[default]
+    #[serde(rename = "stop")]
+    Stop,
+    #[serde(rename = "continue")]
+    Continue,
+}
+
+/// The input JSON format for the /notebook endpoint
+#[derive(Deserialize, Debug)]
+pub struct Notebook {
+    /// Only its lang, version, env and files are used, like a session's
+    #[serde(flatten)]
+    program: Submission,
+    /// Code of the cells, run in order
+    cells: Vec<String>,
+    #[serde(default)]
+    on_error: OnError,
+    /// Memory of the container in bytes, up to the limit of sessions
+    memory: Option<u64>,
+}
+
+impl Bounded for Notebook {
+    fn check_size(&self, limits: &RequestLimits) -> Result<(), SizeError> {
+        self.program.check_size(limits)?;
+        for cell in &self.cells {
+            limits::check("code", cell, limits.code)?;
+        }
+        Ok(())
+    }
+}
+
+impl Tagged for Notebook {
+    fn tag(&mut self, request_id: &str, charged_to: Option<u64>) {
+        self.program.tag(request_id, charged_to);
+    }
+}
+
+/// The response of the /notebook endpoint
+#[derive(Serialize, Debug)]
+pub struct NotebookOutput {
+    /// Outputs of the cells which ran, in order
+    cells: Vec<CellOutput>,
+}
+
+/// Runs notebooks, the service behind the /notebook endpoint
+pub struct NotebookRunner<E> {
+    executor: Rc<E>,
+    sessions: Rc<Sessions>,
+}
+
+impl<E> NotebookRunner<E> {
+    /// Creates a service running notebooks on `executor`, within the limits of `sessions`
+    pub fn new(executor: Rc<E>, sessions: Rc<Sessions>) -> Self {
+        NotebookRunner {
+            executor: executor,
+            sessions: sessions,
+        }
+    }
+}
+
+impl<E: Repl + 'static> Service for NotebookRunner<E> {
+    type Request = Notebook;
+    type Response = NotebookOutput;
+    type Error = SessionError;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    /// The cells run in one interpreter, like a session which is closed
+    /// once they are done. A notebook counts against the limit of sessions
+    /// while it runs
+    fn call(&self, notebook: Self::Request) -> Self::Future {
+        let Notebook {
+            program,
+            cells,
+            on_error,
+            memory,
+        } = notebook;
+        if cells.is_empty() {
+            return Box::new(future::ok(NotebookOutput { cells: Vec::new() }));
+        }
+        let reserved = Reservation::new(self.sessions.clone(), self.executor.clone(), memory);
+        let (mut reservation, memory) = match reserved {
+            Ok(reserved) => reserved,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let sessions = self.sessions.clone();
+        let executor = self.executor.clone();
+        let charged_to = program.charged_to();
+        let outputs = self.executor
+            .start(&program, memory, self.sessions.limits.cpu)
+            .map_err(SessionError::ExecutionError)
+            .and_then(move |interpreter| {
+                let Interpreter { container, attached } = interpreter;
+                reservation.hold(&container);
+                let state = (attached, cells.into_iter(), Vec::new());
+                let run = future::loop_fn(state, move |(attached, mut cells, mut outputs)| {
+                    let code = match cells.next() {
+                        Some(code) => code,
+                        None => return Box::new(future::ok(Loop::Break(outputs))) as Box<Future<Item = _, Error = _>>,
+                    };
+                    let evaluated = evaluate(attached, &code, &sessions, charged_to).map(move |(attached, evaluated)| {
+                        let status = evaluated.status;
+                        outputs.push(evaluated.into());
+                        match attached {
+                            Some(attached) if status != CellStatus::Error || on_error == OnError::Continue => {
+                                Loop::Continue((attached, cells, outputs))
+                            }
+                            _ => Loop::Break(outputs),
+                        }
+                    });
+                    Box::new(evaluated)
+                });
+                run.then(move |result| {
+                    reservation.let_go();
+                    executor.stop(&container).then(move |stopped| {
+                        if let Err(e) = stopped {
+                            debug!("can't stop notebook interpreter: {:?}", e);
+                        }
+                        drop(reservation);
+                        result
+                    })
+                })
+            })
+            .map(|cells| NotebookOutput { cells: cells });
+        Box::new(outputs)
+    }
+}
@@ -0,0 +1,321 @@
+use base64;
+use tar;
+use zip;
+
+use executor::LanguageConfig;
+use Submission;
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{Cursor, Read};
+
+/// Most files a submission may have
+const MAX_FILES: usize = 256;
+/// Most bytes all the files of a submission may take
+const MAX_SIZE: u64 = 1048576;
+/// Most bytes the base64 encoded archive may take, leaving room for the
+/// headers of `MAX_FILES` tar entries
+const MAX_ARCHIVE: usize = 2 * MAX_SIZE as usize / 3 * 4;
+
+/// Mode bits of symbolic links
+const S_IFLNK: u32 = 0o120000;
+const S_IFMT: u32 = 0o170000;
+
+/// Reasons the files of a submission are rejected
+#[derive(Debug)]
+pub enum SourceError {
+    /// The path is absolute, empty or leaves the project
+    InvalidPath(String),
+    /// More than one file has this path
+    DuplicatePath(String),
+    /// An entry of the archive is neither a file nor a directory
+    UnsupportedEntry(String),
+    TooManyFiles,
+    TooLarge,
+    /// The archive isn't base64 encoded or can't be read
+    InvalidArchive(String),
+    /// The entry point isn't one of the files
+    MissingEntryPoint(String),
+    /// The submission has no files at all
+    Empty,
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourceError::InvalidPath(ref path) => write!(f, "invalid path {:?}", path),
+            SourceError::DuplicatePath(ref path) => write!(f, "more than one file at {:?}", path),
+            SourceError::UnsupportedEntry(ref path) => write!(f, "{:?} isn't a regular file", path),
+            SourceError::TooManyFiles => write!(f, "more than {} files", MAX_FILES),
+            SourceError::TooLarge => write!(f, "files take more than {} bytes", MAX_SIZE),
+            SourceError::InvalidArchive(ref error) => write!(f, "invalid archive: {}", error),
+            SourceError::MissingEntryPoint(ref path) => {
+                write!(f, "entry point {:?} isn't one of the files", path)
+            }
+            SourceError::Empty => write!(f, "no files"),
+        }
+    }
+}
+
+/// The files of a submission, with paths relative to the project
+#[derive(Debug, PartialEq)]
+pub struct Sources {
+    pub files: Vec<(String, Vec<u8>)>,
+    /// The file which is compiled or run
+    pub entry_point: String,
+}
+
+/// Checks a path is relative and stays in the project, returns it without
+/// empty and `.` components. Components can't start with `-`, so that
+/// compilers don't take paths for flags
+fn normalize(path: &str) -> Result<String, SourceError> {
+    let invalid = || SourceError::InvalidPath(path.to_owned());
+    if path.starts_with('/') || path.contains('\\') || path.contains('\0') {
+        return Err(invalid());
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => return Err(invalid()),
+            component if component.starts_with('-') => return Err(invalid()),
+            component => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        return Err(invalid());
+    }
+    Ok(components.join("/"))
+}
+
+/// Collects files, checking their paths and the limits
+#[derive(Default)]
+struct Collector {
+    files: Vec<(String, Vec<u8>)>,
+    paths: BTreeSet<String>,
+    size: u64,
+}
+
+impl Collector {
+    fn push(&mut self, path: &str, contents: Vec<u8>) -> Result<(), SourceError> {
+        let path = normalize(path)?;
+        if self.files.len() == MAX_FILES {
+            return Err(SourceError::TooManyFiles);
+        }
+        self.size += contents.len() as u64;
+        if self.size > MAX_SIZE {
+            return Err(SourceError::TooLarge);
+        }
+        if !self.paths.insert(path.clone()) {
+            return Err(SourceError::DuplicatePath(path));
+        }
+        self.files.push((path, contents));
+        Ok(())
+    }
+
+    /// Reads a file of `size` bytes, without trusting `size`
+    fn read<R: Read>(&mut self, path: &str, size: u64, reader: R) -> Result<(), SourceError> {
+        if self.size + size > MAX_SIZE {
+            return Err(SourceError::TooLarge);
+        }
+        let mut contents = Vec::new();
+        reader
+            .take(MAX_SIZE - self.size + 1)
+            .read_to_end(&mut contents)
+            .map_err(|e| SourceError::InvalidArchive(e.to_string()))?;
+        self.push(path, contents)
+    }
+
+    fn extend_tar(&mut self, archive: &[u8]) -> Result<(), SourceError> {
+        let invalid = |e: ::std::io::Error| SourceError::InvalidArchive(e.to_string());
+        let mut archive = tar::Archive::new(archive);
+        for entry in archive.entries().map_err(invalid)? {
+            let entry = entry.map_err(invalid)?;
+            let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let kind = entry.header().entry_type();
+            if kind.is_dir() {
+                continue;
+            }
+            // links could point out of the project
+            if !kind.is_file() {
+                return Err(SourceError::UnsupportedEntry(path));
+            }
+            let size = entry.header().size().map_err(invalid)?;
+            self.read(&path, size, entry)?;
+        }
+        Ok(())
+    }
+
+    fn extend_zip(&mut self, archive: &[u8]) -> Result<(), SourceError> {
+        let invalid = |e: zip::result::ZipError| SourceError::InvalidArchive(e.to_string());
+        let mut archive = zip::ZipArchive::new(Cursor::new(archive)).map_err(invalid)?;
+        for i in 0..archive.len() {
+            let file = archive.by_index(i).map_err(invalid)?;
+            let path = file.name().to_owned();
+            if file.is_dir() {
+                continue;
+            }
+            if file.unix_mode().is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+                return Err(SourceError::UnsupportedEntry(path));
+            }
+            let size = file.size();
+            self.read(&path, size, file)?;
+        }
+        Ok(())
+    }
+}
+
+impl Submission {
+    /// The file which is compiled or run, `get_file_name()` unless set
+    pub fn entry_point(&self) -> Result<String, SourceError> {
+        match self.entry_point {
+            Some(ref path) => normalize(path),
+            None => Ok(self.lang.get_file_name().to_owned()),
+        }
+    }
+
+    /// Returns the files of the submission: `code` saved as `get_file_name()`,
+    /// then `files` and the contents of the tar or zip `archive`
+    pub fn sources(&self) -> Result<Sources, SourceError> {
+        let mut collector = Collector::default();
+        if !self.code.is_empty() {
+            collector.push(self.lang.get_file_name(), self.code.clone().into_bytes())?;
+        }
+        for (path, contents) in &self.files {
+            collector.push(path, contents.clone().into_bytes())?;
+        }
+        if let Some(ref archive) = self.archive {
+            // checked before it is decoded into memory
+            if archive.len() > MAX_ARCHIVE {
+                return Err(SourceError::TooLarge);
+            }
+            let archive = base64::decode(archive).map_err(|e| SourceError::InvalidArchive(e.to_string()))?;
+            if archive.starts_with(b"PK\x03\x04") {
+                collector.extend_zip(&archive)?;
+            } else {
+                collector.extend_tar(&archive)?;
+            }
+        }
+        if collector.files.is_empty() {
+            return Err(SourceError::Empty);
+        }
+        let entry_point = self.entry_point()?;
+        if !collector.paths.contains(&entry_point) {
+            return Err(SourceError::MissingEntryPoint(entry_point));
+        }
+        Ok(Sources {
+            files: collector.files,
+            entry_point: entry_point,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use json;
+
+    fn submission(value: json::Value) -> Submission {
+        json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize("src/./main.c").unwrap(), "src/main.c");
+        assert_eq!(normalize("a//b").unwrap(), "a/b");
+        for path in &["", ".", "/etc/passwd", "../up", "a/../../up", "a\\b", "-fplugin=x.so", "src/-o"] {
+            assert!(normalize(path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn collects_code_and_files() {
+        let sources = submission(json!({
+            "code": "import util",
+            "lang": "python2.7",
+            "files": {"util.py": "x = 1", "pkg/./__init__.py": ""},
+        })).sources()
+            .unwrap();
+        let paths = sources.files.iter().map(|file| file.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["code.py", "pkg/__init__.py", "util.py"]);
+        assert_eq!(sources.entry_point, "code.py");
+    }
+
+    #[test]
+    fn reads_archives() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_path("lib/util.h").unwrap();
+        header.set_size(3);
+        header.set_cksum();
+        builder.append(&header, &b"int"[..]).unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("main.c", zip::write::FileOptions::default()).unwrap();
+        ::std::io::Write::write_all(&mut zip, b"int main;").unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+
+        for (archive, path) in [(tar, "lib/util.h"), (zip, "main.c")] {
+            let sources = submission(json!({
+                "lang": "c",
+                "archive": base64::encode(&archive),
+                "entry_point": path,
+            })).sources()
+                .unwrap();
+            assert_eq!(sources.files.len(), 1);
+            assert_eq!(sources.files[0].0, path);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_sources() {
+        let error = |value| submission(value).sources().unwrap_err();
+        match error(json!({"code": "", "lang": "c"})) {
+            SourceError::Empty => (),
+            e => panic!("{:?}", e),
+        }
+        match error(json!({"lang": "c", "files": {"../x.c": ""}})) {
+            SourceError::InvalidPath(ref path) if path == "../x.c" => (),
+            e => panic!("{:?}", e),
+        }
+        match error(json!({"code": "", "lang": "c", "files": {"code.c": "", "./code.c": ""}})) {
+            SourceError::DuplicatePath(ref path) if path == "code.c" => (),
+            e => panic!("{:?}", e),
+        }
+        match error(json!({"lang": "c", "files": {"main.c": ""}})) {
+            SourceError::MissingEntryPoint(ref path) if path == "code.c" => (),
+            e => panic!("{:?}", e),
+        }
+        let big = "x".repeat(MAX_SIZE as usize / 2 + 1);
+        match error(json!({"lang": "c", "files": {"a.c": big, "b.c": big}})) {
+            SourceError::TooLarge => (),
+            e => panic!("{:?}", e),
+        }
+        match error(json!({"lang": "c", "archive": "A".repeat(MAX_ARCHIVE + 4)})) {
+            SourceError::TooLarge => (),
+            e => panic!("{:?}", e),
+        }
+        match error(json!({"lang": "c", "archive": "not base64!"})) {
+            SourceError::InvalidArchive(_) => (),
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn rejects_links_in_archives() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_path("code.c").unwrap();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_link_name("/etc/passwd").unwrap();
+        header.set_size(0);
+        header.set_cksum();
+        builder.append(&header, &b""[..]).unwrap();
+        let archive = base64::encode(&builder.into_inner().unwrap());
+        match submission(json!({"lang": "c", "archive": archive})).sources() {
+            Err(SourceError::UnsupportedEntry(ref path)) if path == "code.c" => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
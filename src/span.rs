@@ -0,0 +1,141 @@
+use futures::Future;
+use hyper::server::Request;
+use json;
+use logger::Record;
+use Submission;
+
+use std::collections::hash_map::RandomState;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Target of the records logged when a span is done
+pub const TARGET: &str = "code_executor::span";
+
+/// Label of the containers and images built for a request
+pub const LABEL: &str = "code-executor.request-id";
+
+/// Longest `X-Request-Id` taken from a client
+const MAX_LEN: usize = 128;
+
+/// Returns a new random id
+pub fn random_id() -> String {
+    // each `RandomState` has its own random keys
+    let random = || RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", random(), random())
+}
+
+/// Returns the `X-Request-Id` of `req` if it is sensible, or else a new id
+pub fn request_id(req: &Request) -> String {
+    req.headers()
+        .get_raw("X-Request-Id")
+        .and_then(|raw| raw.one())
+        .and_then(|id| String::from_utf8(id.to_vec()).ok())
+        .filter(|id| {
+            !id.is_empty() && id.len() <= MAX_LEN
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+        })
+        .unwrap_or_else(random_id)
+}
+
+/// Requests whose programs are told the id of the request they came in,
+/// and the token of its permit which the time they run is charged to
+pub trait Tagged {
+    fn tag(&mut self, request_id: &str, charged_to: Option<u64>);
+}
+
+impl Tagged for Submission {
+    fn tag(&mut self, request_id: &str, charged_to: Option<u64>) {
+        self.request_id = Some(request_id.to_owned());
+        self.charged_to = charged_to;
+    }
+}
+
+impl Submission {
+    /// Id of the request the submission came in, if it came in one
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    /// Token of the permit the time it runs is charged to, if it has one
+    pub fn charged_to(&self) -> Option<u64> {
+        self.charged_to
+    }
+}
+
+/// Logs how long `future` takes as the `stage` of the request `request_id`,
+/// along with whether it failed. The stage starts once this is called
+pub fn span<F>(request_id: Option<&str>, stage: &'static str, future: F) -> Box<Future<Item = F::Item, Error = F::Error>>
+where
+    F: Future + 'static,
+    F::Error: Debug,
+{
+    let request_id = request_id.map(String::from);
+    let started = Instant::now();
+    let spanned = future.then(move |result| {
+        let mut fields = json!({
+            "request_id": request_id,
+            "span": stage,
+            "duration_ms": started.elapsed().as_secs_f64() * 1000.0,
+        });
+        if let Err(ref e) = result {
+            fields["error"] = json!(format!("{:?}", e));
+        }
+        info!(target: TARGET, "{}", fields);
+        result
+    });
+    Box::new(spanned)
+}
+
+/// Writes `record` as a line of JSON, the fields of spans are
+/// written as they are rather than as the message
+pub fn format<W: Write>(buf: &mut W, record: &Record) -> io::Result<()> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let mut line = json!({
+        "time": time,
+        "level": record.level().to_string(),
+        "target": record.target(),
+    });
+    let message = record.args().to_string();
+    match json::from_str::<json::Value>(&message) {
+        Ok(json::Value::Object(fields)) if record.target() == TARGET => {
+            line.as_object_mut().expect("is an object").extend(fields);
+        }
+        _ => line["message"] = json!(message),
+    }
+    writeln!(buf, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use logger::Level;
+
+    fn formatted(target: &str, message: &str) -> json::Value {
+        let mut buf = Vec::new();
+        format(
+            &mut buf,
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Info)
+                .target(target)
+                .build(),
+        ).unwrap();
+        json::from_slice(&buf).unwrap()
+    }
+
+    #[test]
+    fn formats_spans_as_fields() {
+        let span = formatted(TARGET, r#"{"request_id": "7", "span": "build"}"#);
+        assert_eq!(span["span"], "build");
+        assert_eq!(span["request_id"], "7");
+        assert!(span.get("message").is_none());
+        let other = formatted("code_executor", r#"{"span": "build"}"#);
+        assert_eq!(other["message"], r#"{"span": "build"}"#);
+        assert_eq!(other["level"], "INFO");
+    }
+}
@@ -0,0 +1,485 @@
+mod wasi;
+
+use self::wasi::WasiCtx;
+use hyper::server::Service;
+use tempdir::TempDir;
+use wasmi::core::TrapCode;
+use wasmi::{Config, Engine, Linker, Module, Store};
+
+use futures::Future;
+
+use auth;
+use executor::ExecutionError;
+use options::OptionError;
+use Language;
+use Output;
+use Submission;
+
+use cpupool::CpuPool;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Seconds a compiler may run for, of CPU time as well as wall time
+const COMPILE_SECONDS: u64 = 30;
+
+/// Bytes of memory a compiler may map
+const COMPILE_MEMORY: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Bytes a compiler may write to a single file
+const COMPILE_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Bytes of the compiler's errors which are kept
+const COMPILE_ERRORS: u64 = 65536;
+
+/// Directories of the host shared with the jail the compilers run in, if
+/// they exist, besides the toolchain's own
+const SYSTEM_DIRS: &[&str] = &["/usr", "/lib", "/lib64", "/bin", "/sbin"];
+
+/// Limits applied to a single WebAssembly run
+#[derive(Debug, Copy, Clone)]
+pub struct Limits {
+    /// Fuel given to the module, roughly one unit per executed instruction
+    pub fuel: u64,
+    /// Maximum size of the linear memory in bytes
+    pub memory: usize,
+    /// Maximum number of bytes kept for each of stdout and stderr
+    pub output: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: 1_000_000_000,
+            memory: 1073741824,
+            output: 16777216,
+        }
+    }
+}
+
+trait WasmConfig {
+    /// Should return the command which compiles the project at `root` to a
+    /// WASI module at `module`, or `None` if the language can't be compiled
+    /// to WebAssembly
+    /// # Arguments
+    /// * `entry` - Path of the entry point, relative to `root`
+    /// * `files` - Paths of all the files, relative to `root`
+    fn get_wasm_compiler(&self, root: &Path, entry: &str, files: &[&str], module: &Path) -> Option<Command>;
+}
+
+/// Returns the entry point followed by the other files with one of `extensions`
+fn compiled(root: &Path, entry: &str, files: &[&str], extensions: &[&str]) -> Vec<PathBuf> {
+    let others = files.iter().filter(|&&path| {
+        path != entry && extensions.iter().any(|extension| path.ends_with(extension))
+    });
+    ::std::iter::once(&entry)
+        .chain(others)
+        .map(|path| root.join(path))
+        .collect()
+}
+
+/// Returns a command compiling `sources` with a compiler of wasi-sdk,
+/// found through `WASI_SDK_PATH`
+fn wasi_sdk(compiler: &str, root: &Path, sources: Vec<PathBuf>, module: &Path) -> Command {
+    let sdk = env::var("WASI_SDK_PATH").unwrap_or_else(|_| "/opt/wasi-sdk".to_owned());
+    let mut command = Command::new(Path::new(&sdk).join("bin").join(compiler));
+    command
+        .arg(format!("--sysroot={}/share/wasi-sysroot", sdk))
+        .arg("--target=wasm32-wasi")
+        .arg("-O2")
+        .arg("-I")
+        .arg(root)
+        .arg("-o")
+        .arg(module)
+        .args(sources);
+    command
+}
+
+impl WasmConfig for Language {
+    fn get_wasm_compiler(&self, root: &Path, entry: &str, files: &[&str], module: &Path) -> Option<Command> {
+        match *self {
+            Language::C => Some(wasi_sdk("clang", root, compiled(root, entry, files, &[".c"]), module)),
+            Language::Cpp => {
+                let sources = compiled(root, entry, files, &[".cpp", ".cc", ".cxx"]);
+                Some(wasi_sdk("clang++", root, sources, module))
+            }
+            // modules are found from the entry point
+            Language::Rust => {
+                let mut command = Command::new(rustc());
+                command
+                    .arg("--edition=2021")
+                    .arg("--target=wasm32-wasip1")
+                    .arg("-O")
+                    .arg("-o")
+                    .arg(module)
+                    .arg(root.join(entry));
+                Some(command)
+            }
+            Language::Go
+            | Language::Java
+            | Language::Python27
+            | Language::Python3
+            | Language::JavaScript
+            | Language::Ruby => None,
+        }
+    }
+}
+
+/// Returns the path of rustc, from `RUSTC` if it is set. It is the one in
+/// the toolchain's sysroot rather than rustup's proxy, so that it runs in
+/// the jail
+fn rustc() -> PathBuf {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let sysroot = Command::new(&rustc)
+        .arg("--print")
+        .arg("sysroot")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned());
+    match sysroot {
+        Some(sysroot) => Path::new(&sysroot).join("bin").join("rustc"),
+        None => PathBuf::from(rustc),
+    }
+}
+
+/// Returns `command` run in a bubblewrap jail by `jail`. It has no network,
+/// and sees none of the host's files but the system's libraries, the
+/// toolchain the compiler is in and `dir`, the only one it can write to
+fn jailed(jail: &str, command: &Command, dir: &Path) -> Command {
+    let mut jailed = Command::new(jail);
+    jailed.args(["--unshare-all", "--die-with-parent", "--new-session", "--cap-drop", "ALL"]);
+    for system in SYSTEM_DIRS {
+        jailed.arg("--ro-bind-try").arg(system).arg(system);
+    }
+    // the compilers are in the toolchain's `bin`
+    let program = Path::new(command.get_program());
+    if let Some(toolchain) = program.parent().and_then(Path::parent).filter(|_| program.is_absolute()) {
+        jailed.arg("--ro-bind").arg(toolchain).arg(toolchain);
+    }
+    jailed
+        .arg("--bind")
+        .arg(dir)
+        .arg(dir)
+        .args(["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp", "--chdir"])
+        .arg(dir)
+        .arg("--")
+        .arg(program)
+        .args(command.get_args());
+    jailed
+}
+
+/// Runs the compiler `command` with its errors written to `errors`, limited
+/// by rlimits and killed after `timeout`. Returns whether it succeeded
+fn run_compiler(mut command: Command, errors: &Path, timeout: Duration) -> Result<bool, ExecutionError> {
+    let stderr = File::create(errors).map_err(|e| {
+        debug!("can't create compiler errors: {:?}", e);
+        ExecutionError::BadConfig
+    })?;
+    // the host's environment may have secrets, e.g. for `env!`
+    command.env_clear().env("PATH", "/usr/bin:/bin");
+    command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(stderr);
+    let limits = [
+        (libc::RLIMIT_CPU, COMPILE_SECONDS),
+        (libc::RLIMIT_AS, COMPILE_MEMORY),
+        (libc::RLIMIT_FSIZE, COMPILE_FILE_SIZE),
+    ];
+    // its own process group is killed along with everything it started
+    unsafe {
+        command.pre_exec(move || {
+            for &(resource, limit) in &limits {
+                let limit = libc::rlimit {
+                    rlim_cur: limit,
+                    rlim_max: limit,
+                };
+                if libc::setrlimit(resource, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    trace!("compiling: {:?}", command);
+    let mut child = command.spawn().map_err(|e| {
+        debug!("can't run compiler: {:?}", e);
+        ExecutionError::BadConfig
+    })?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status.success()),
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Ok(None) => break,
+            Err(e) => {
+                debug!("can't wait for compiler: {:?}", e);
+                break;
+            }
+        }
+    }
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.wait();
+    Err(ExecutionError::CompileTimeout(format!(
+        "compilation took longer than {} seconds",
+        timeout.as_secs()
+    )))
+}
+
+/// Compiles the submission to a WebAssembly module, returns the module's bytes
+fn compile(sub: &Submission) -> Result<Vec<u8>, ExecutionError> {
+    let dir = TempDir::new("code-executor").map_err(|e| {
+        debug!("can't create build directory: {:?}", e);
+        ExecutionError::BadConfig
+    })?;
+    sub.check_options().map_err(ExecutionError::InvalidOption)?;
+    // there is no filesystem to write them to
+    if !sub.output_files.is_empty() {
+        return Err(ExecutionError::InvalidOption(OptionError::Unsupported("output_files")));
+    }
+    // the pipes are read separately, so the order is lost
+    if sub.transcript {
+        return Err(ExecutionError::InvalidOption(OptionError::Unsupported("transcript")));
+    }
+    // the installed compilers are used
+    if sub.version.is_some() {
+        return Err(ExecutionError::InvalidOption(OptionError::Unsupported("version")));
+    }
+    let sources = sub.sources().map_err(ExecutionError::InvalidSource)?;
+    let root = dir.path().join("src");
+    let module = dir.path().join("code.wasm");
+    for (path, contents) in &sources.files {
+        let source = root.join(path);
+        source
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| File::create(&source))
+            .and_then(|mut file| file.write_all(contents))
+            .map_err(|e| {
+                debug!("can't write source: {:?}", e);
+                ExecutionError::BadConfig
+            })?;
+    }
+    let files = sources.files.iter().map(|file| file.0.as_str()).collect::<Vec<_>>();
+    let mut command = match sub.lang.get_wasm_compiler(&root, &sources.entry_point, &files, &module) {
+        Some(command) => command,
+        None => return Err(ExecutionError::UnsupportedLanguage),
+    };
+    command.args(&sub.compiler_flags);
+    // an empty `WASM_JAIL` runs the compilers as they are, e.g. where the
+    // server is sandboxed itself
+    let jail = env::var("WASM_JAIL").unwrap_or_else(|_| "bwrap".to_owned());
+    if !jail.is_empty() {
+        command = jailed(&jail, &command, dir.path());
+    }
+    let errors = dir.path().join("errors");
+    if !run_compiler(command, &errors, Duration::from_secs(COMPILE_SECONDS))? {
+        let mut error = Vec::new();
+        File::open(&errors)
+            .and_then(|file| file.take(COMPILE_ERRORS).read_to_end(&mut error))
+            .map_err(|e| {
+                debug!("can't read compiler errors: {:?}", e);
+                ExecutionError::BadConfig
+            })?;
+        return Err(ExecutionError::CompileError(String::from_utf8_lossy(&error).into_owned()));
+    }
+    let mut bytes = Vec::new();
+    File::open(&module)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|e| {
+            debug!("can't read module: {:?}", e);
+            ExecutionError::BadConfig
+        })?;
+    Ok(bytes)
+}
+
+/// Runs the `_start` function of a WASI module
+/// # Arguments:
+/// * `wasm` - The module's bytes
+/// * `sub` - The submission, giving the input, arguments and environment
+/// * `limits` - Fuel, memory and output limits for this run
+fn run(wasm: &[u8], sub: Submission, limits: &Limits) -> Result<Output, ExecutionError> {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, wasm).map_err(|e| {
+        debug!("invalid module: {:?}", e);
+        ExecutionError::UnknownError
+    })?;
+    let mut args = vec!["code".to_owned()];
+    args.extend(sub.args.iter().cloned());
+    let env = sub.env_pairs();
+    let ctx = WasiCtx::new(sub.stdin.into_bytes(), limits.memory, limits.output)
+        .with_args(args)
+        .with_env(env);
+    let mut store = Store::new(&engine, ctx);
+    store.limiter(|ctx| &mut ctx.limits);
+    store
+        .set_fuel(limits.fuel)
+        .expect("fuel metering is enabled");
+    let mut linker = Linker::new(&engine);
+    wasi::add_to_linker(&mut linker).expect("WASI functions are defined once");
+    wasi::stub_missing(&mut linker, &module);
+    let start = linker
+        .instantiate(&mut store, &module)
+        .and_then(|instance| instance.start(&mut store))
+        .and_then(|instance| instance.get_typed_func::<(), ()>(&store, "_start"))
+        .map_err(|e| {
+            debug!("can't instantiate module: {:?}", e);
+            ExecutionError::UnknownError
+        })?;
+    let result = start.call(&mut store, ());
+    let mut ctx = store.into_data();
+    let mut out_of_fuel = false;
+    match result {
+        Ok(()) => (),
+        Err(ref e) if e.i32_exit_status().is_some() => (),
+        // traps, e.g. running out of fuel, end up in stderr
+        Err(e) => {
+            debug!("wasm trap: {:?}", e);
+            out_of_fuel = e.as_trap_code() == Some(TrapCode::OutOfFuel);
+            ctx.stderr.write(format!("\n{}\n", e).as_bytes());
+        }
+    }
+    let truncated = ctx.stdout.is_truncated() || ctx.stderr.is_truncated();
+    Ok(Output::Output {
+        stdout: ctx.stdout.into_string(),
+        stderr: ctx.stderr.into_string(),
+        transcript: None,
+        output_files: None,
+        complete: !out_of_fuel && !truncated,
+    })
+}
+
+/// Executor implementation which compiles submissions to WebAssembly
+/// and runs them in-process, without starting any containers
+#[derive(Clone)]
+pub struct WasmExecutor {
+    /// Thread pool used for compiling and running modules
+    pool: CpuPool,
+    limits: Limits,
+}
+
+impl WasmExecutor {
+    /// Create a new WasmExecutor
+    /// # Arguments
+    /// * `limits` - Limits applied to every run
+    pub fn new(limits: Limits) -> Self {
+        WasmExecutor {
+            pool: CpuPool::new_num_cpus(),
+            limits: limits,
+        }
+    }
+}
+
+impl Service for WasmExecutor {
+    type Request = Submission;
+    type Response = Output;
+    type Error = ExecutionError;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    /// The steps that we do for a single execution are
+    /// * Compile the code to a WASI module with the language's toolchain
+    /// * Instantiate the module with virtualised stdin/stdout/stderr
+    /// * Run `_start` until it returns, exits or runs out of fuel
+    fn call(&self, sub: Self::Request) -> Self::Future {
+        trace!("wasm executor called: {:?}", sub);
+        let limits = self.limits;
+        let output = self.pool
+            .spawn_fn(move || {
+                let module = compile(&sub)?;
+                let charged_to = sub.charged_to();
+                let started = Instant::now();
+                let output = run(&module, sub, &limits);
+                auth::charge(charged_to, started.elapsed());
+                output
+            })
+            .then(|result| match result {
+                Ok(output) => Ok(output),
+                Err(ExecutionError::CompileError(msg)) => Ok(Output::CompileError {
+                    error: msg,
+                    complete: true,
+                }),
+                Err(ExecutionError::CompileTimeout(msg)) => Ok(Output::CompileError {
+                    error: msg,
+                    complete: false,
+                }),
+                Err(e) => {
+                    debug!("error in wasm executor: {:?}", e);
+                    Err(e)
+                }
+            });
+        Box::new(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn compiles_c_and_rust() {
+        let (root, module) = (Path::new("/src"), Path::new("/code.wasm"));
+        let files = ["main.c", "lib/add.c", "lib/add.h"];
+        let c = Language::C.get_wasm_compiler(root, "main.c", &files, module).unwrap();
+        assert!(c.get_program().to_string_lossy().ends_with("/bin/clang"));
+        assert!(args(&c).ends_with(&["/src/main.c".to_owned(), "/src/lib/add.c".to_owned()]));
+        let rust = Language::Rust
+            .get_wasm_compiler(root, "main.rs", &["main.rs", "util.rs"], module)
+            .unwrap();
+        let rust = args(&rust);
+        assert!(rust.contains(&"--target=wasm32-wasip1".to_owned()));
+        assert_eq!(rust.last().map(String::as_str), Some("/src/main.rs"));
+        assert!(Language::Python3.get_wasm_compiler(root, "code.py", &[], module).is_none());
+    }
+
+    #[test]
+    fn jails_compilers() {
+        let mut clang = Command::new("/opt/wasi-sdk/bin/clang");
+        clang.arg("/build/src/main.c");
+        let jailed = jailed("bwrap", &clang, Path::new("/build"));
+        assert_eq!(jailed.get_program(), "bwrap");
+        let jailed = args(&jailed);
+        assert!(jailed.windows(3).any(|bind| bind == ["--ro-bind", "/opt/wasi-sdk", "/opt/wasi-sdk"]));
+        assert!(jailed.windows(3).any(|bind| bind == ["--bind", "/build", "/build"]));
+        assert!(jailed.ends_with(&["--".to_owned(), "/opt/wasi-sdk/bin/clang".to_owned(), "/build/src/main.c".to_owned()]));
+        // no other files of the host are shared with it
+        let binds = jailed.iter().filter(|arg| arg.contains("bind")).count();
+        assert_eq!(binds, SYSTEM_DIRS.len() + 2);
+    }
+
+    #[test]
+    fn kills_slow_compilers() {
+        let dir = TempDir::new("compiler").unwrap();
+        let errors = dir.path().join("errors");
+        let mut failing = Command::new("sh");
+        failing.arg("-c").arg("echo \"$HOME\" >&2; exit 1");
+        assert!(!run_compiler(failing, &errors, Duration::from_secs(5)).unwrap());
+        // the host's environment isn't passed on
+        assert_eq!(fs::read_to_string(&errors).unwrap(), "\n");
+        let mut slow = Command::new("sh");
+        slow.arg("-c").arg("sleep 10");
+        let started = Instant::now();
+        match run_compiler(slow, &errors, Duration::from_millis(100)) {
+            Err(ExecutionError::CompileTimeout(error)) => assert!(error.contains("longer than")),
+            other => panic!("compiler wasn't killed: {:?}", other.map_err(|_| ())),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
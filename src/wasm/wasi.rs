@@ -0,0 +1,514 @@
+use wasmi::core::ValType;
+use wasmi::{Caller, Error, Extern, Linker, Memory, Module, StoreLimits, StoreLimitsBuilder, Val};
+
+use std::io::{Cursor, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Module name under which WASI preview1 functions are imported
+const MODULE: &str = "wasi_snapshot_preview1";
+
+const ERRNO_SUCCESS: i32 = 0;
+const ERRNO_BADF: i32 = 8;
+const ERRNO_FAULT: i32 = 21;
+const ERRNO_NOSYS: i32 = 52;
+const ERRNO_SPIPE: i32 = 70;
+
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+
+/// An in-memory sink for stdout/stderr which stops
+/// storing bytes once `limit` is reached
+pub struct Pipe {
+    buf: Vec<u8>,
+    limit: usize,
+    /// Whether any bytes were dropped
+    truncated: bool,
+}
+
+impl Pipe {
+    fn new(limit: usize) -> Self {
+        Pipe {
+            buf: Vec::new(),
+            limit: limit,
+            truncated: false,
+        }
+    }
+
+    /// Appends `bytes`, dropping whatever doesn't fit
+    pub fn write(&mut self, bytes: &[u8]) {
+        let room = self.limit.saturating_sub(self.buf.len());
+        let len = ::std::cmp::min(room, bytes.len());
+        self.buf.extend_from_slice(&bytes[..len]);
+        self.truncated |= len < bytes.len();
+    }
+
+    /// Whether the program wrote more than the limit
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Returns what the program wrote, replacing invalid UTF-8
+    pub fn into_string(self) -> String {
+        String::from_utf8_lossy(&self.buf).into_owned()
+    }
+}
+
+/// State of a single WASI process: its virtualised
+/// standard streams and the resource limits of its store
+pub struct WasiCtx {
+    stdin: Cursor<Vec<u8>>,
+    pub stdout: Pipe,
+    pub stderr: Pipe,
+    pub limits: StoreLimits,
+    /// NUL terminated arguments, starting with the program's name
+    args: Vec<Vec<u8>>,
+    /// NUL terminated `NAME=value` pairs
+    env: Vec<Vec<u8>>,
+    /// State of the generator `random_get` reads from, the same
+    /// for every run so that reruns of a submission behave the same
+    random: u64,
+}
+
+/// Returns `strings` as C strings
+fn nul_terminated(strings: Vec<String>) -> Vec<Vec<u8>> {
+    strings
+        .into_iter()
+        .map(|string| {
+            let mut bytes = string.into_bytes();
+            bytes.push(0);
+            bytes
+        })
+        .collect()
+}
+
+impl WasiCtx {
+    /// Create a context
+    /// # Arguments:
+    /// * `stdin` - Bytes the program reads from fd 0
+    /// * `memory` - Maximum size of linear memory in bytes
+    /// * `output` - Maximum number of bytes kept for each of stdout and stderr
+    pub fn new(stdin: Vec<u8>, memory: usize, output: usize) -> Self {
+        WasiCtx {
+            stdin: Cursor::new(stdin),
+            stdout: Pipe::new(output),
+            stderr: Pipe::new(output),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(memory)
+                .instances(1)
+                .build(),
+            args: Vec::new(),
+            env: Vec::new(),
+            random: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Sets the arguments of the program, the first being its name
+    pub fn with_args(mut self, args: Vec<String>) -> Self {
+        self.args = nul_terminated(args);
+        self
+    }
+
+    /// Sets the environment of the program as `NAME=value` pairs
+    pub fn with_env(mut self, env: Vec<String>) -> Self {
+        self.env = nul_terminated(env);
+        self
+    }
+
+    fn strings(&self, environ: bool) -> &[Vec<u8>] {
+        if environ {
+            &self.env
+        } else {
+            &self.args
+        }
+    }
+}
+
+fn memory(caller: &Caller<WasiCtx>) -> Option<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory)
+}
+
+fn read_u32(mem: &[u8], ptr: u32) -> Option<u32> {
+    let ptr = ptr as usize;
+    mem.get(ptr..ptr + 4).map(|bytes| {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(bytes);
+        u32::from_le_bytes(buf)
+    })
+}
+
+fn write_bytes(mem: &mut [u8], ptr: u32, bytes: &[u8]) -> i32 {
+    let ptr = ptr as usize;
+    match mem.get_mut(ptr..ptr + bytes.len()) {
+        Some(dest) => {
+            dest.copy_from_slice(bytes);
+            ERRNO_SUCCESS
+        }
+        None => ERRNO_FAULT,
+    }
+}
+
+/// Returns the `(buf, len)` pairs of an iovec array
+fn iovecs(mem: &[u8], iovs: u32, len: u32) -> Option<Vec<(u32, u32)>> {
+    (0..len)
+        .map(|i| {
+            let base = iovs.checked_add(i.checked_mul(8)?)?;
+            Some((read_u32(mem, base)?, read_u32(mem, base.checked_add(4)?)?))
+        })
+        .collect()
+}
+
+/// Defines the subset of WASI preview1 needed by wasi-libc and Rust's std
+/// on `linker`. There is no filesystem, only stdin, stdout and stderr.
+pub fn add_to_linker(linker: &mut Linker<WasiCtx>) -> Result<(), Error> {
+    linker.func_wrap(
+        MODULE,
+        "fd_write",
+        |mut caller: Caller<WasiCtx>, fd: i32, iovs: i32, iovs_len: i32, nwritten: i32| -> i32 {
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERRNO_FAULT,
+            };
+            let (mem, ctx) = memory.data_and_store_mut(&mut caller);
+            let iovs = match iovecs(mem, iovs as u32, iovs_len as u32) {
+                Some(iovs) => iovs,
+                None => return ERRNO_FAULT,
+            };
+            let pipe = match fd {
+                1 => &mut ctx.stdout,
+                2 => &mut ctx.stderr,
+                _ => return ERRNO_BADF,
+            };
+            let mut written = 0u32;
+            for (buf, len) in iovs {
+                let (start, end) = (buf as usize, buf as usize + len as usize);
+                match mem.get(start..end) {
+                    Some(bytes) => pipe.write(bytes),
+                    None => return ERRNO_FAULT,
+                }
+                written = written.wrapping_add(len);
+            }
+            write_bytes(mem, nwritten as u32, &written.to_le_bytes())
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        "fd_read",
+        |mut caller: Caller<WasiCtx>, fd: i32, iovs: i32, iovs_len: i32, nread: i32| -> i32 {
+            if fd != 0 {
+                return ERRNO_BADF;
+            }
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERRNO_FAULT,
+            };
+            let (mem, ctx) = memory.data_and_store_mut(&mut caller);
+            let iovs = match iovecs(mem, iovs as u32, iovs_len as u32) {
+                Some(iovs) => iovs,
+                None => return ERRNO_FAULT,
+            };
+            let mut read = 0u32;
+            for (buf, len) in iovs {
+                let (start, end) = (buf as usize, buf as usize + len as usize);
+                let dest = match mem.get_mut(start..end) {
+                    Some(dest) => dest,
+                    None => return ERRNO_FAULT,
+                };
+                // reading from an in-memory cursor can't fail
+                let n = ctx.stdin.read(dest).unwrap_or(0);
+                read += n as u32;
+                if n < dest.len() {
+                    break;
+                }
+            }
+            write_bytes(mem, nread as u32, &read.to_le_bytes())
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        "fd_fdstat_get",
+        |mut caller: Caller<WasiCtx>, fd: i32, buf: i32| -> i32 {
+            if !(0..=2).contains(&fd) {
+                return ERRNO_BADF;
+            }
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERRNO_FAULT,
+            };
+            // filetype: u8, flags: u16, rights_base: u64, rights_inheriting: u64
+            let mut stat = [0u8; 24];
+            stat[0] = FILETYPE_CHARACTER_DEVICE;
+            stat[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+            write_bytes(memory.data_mut(&mut caller), buf as u32, &stat)
+        },
+    )?;
+    linker.func_wrap(MODULE, "fd_close", |fd: i32| -> i32 {
+        if (0..=2).contains(&fd) {
+            ERRNO_SUCCESS
+        } else {
+            ERRNO_BADF
+        }
+    })?;
+    linker.func_wrap(
+        MODULE,
+        "fd_seek",
+        |fd: i32, _offset: i64, _whence: i32, _newoffset: i32| -> i32 {
+            if (0..=2).contains(&fd) {
+                ERRNO_SPIPE
+            } else {
+                ERRNO_BADF
+            }
+        },
+    )?;
+    // No directories are preopened, this ends wasi-libc's preopen scan
+    linker.func_wrap(MODULE, "fd_prestat_get", |_fd: i32, _buf: i32| -> i32 {
+        ERRNO_BADF
+    })?;
+    linker.func_wrap(
+        MODULE,
+        "fd_prestat_dir_name",
+        |_fd: i32, _path: i32, _len: i32| -> i32 { ERRNO_BADF },
+    )?;
+    for &(sizes, get, environ) in &[
+        ("args_sizes_get", "args_get", false),
+        ("environ_sizes_get", "environ_get", true),
+    ] {
+        linker.func_wrap(
+            MODULE,
+            sizes,
+            move |mut caller: Caller<WasiCtx>, count: i32, size: i32| -> i32 {
+                let memory = match memory(&caller) {
+                    Some(memory) => memory,
+                    None => return ERRNO_FAULT,
+                };
+                let (mem, ctx) = memory.data_and_store_mut(&mut caller);
+                let strings = ctx.strings(environ);
+                let total = strings.iter().map(Vec::len).sum::<usize>() as u32;
+                match write_bytes(mem, count as u32, &(strings.len() as u32).to_le_bytes()) {
+                    ERRNO_SUCCESS => write_bytes(mem, size as u32, &total.to_le_bytes()),
+                    errno => errno,
+                }
+            },
+        )?;
+        linker.func_wrap(
+            MODULE,
+            get,
+            move |mut caller: Caller<WasiCtx>, ptrs: i32, buf: i32| -> i32 {
+                let memory = match memory(&caller) {
+                    Some(memory) => memory,
+                    None => return ERRNO_FAULT,
+                };
+                let (mem, ctx) = memory.data_and_store_mut(&mut caller);
+                // pointers to the strings, which are laid out one after another
+                let mut offset = buf as u32;
+                for (i, string) in ctx.strings(environ).iter().enumerate() {
+                    let ptr = (ptrs as u32).wrapping_add(4 * i as u32);
+                    match write_bytes(mem, ptr, &offset.to_le_bytes()) {
+                        ERRNO_SUCCESS => (),
+                        errno => return errno,
+                    }
+                    match write_bytes(mem, offset, string) {
+                        ERRNO_SUCCESS => (),
+                        errno => return errno,
+                    }
+                    offset = offset.wrapping_add(string.len() as u32);
+                }
+                ERRNO_SUCCESS
+            },
+        )?;
+    }
+    linker.func_wrap(
+        MODULE,
+        "clock_time_get",
+        |mut caller: Caller<WasiCtx>, _id: i32, _precision: i64, time: i32| -> i32 {
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERRNO_FAULT,
+            };
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+                .unwrap_or(0);
+            write_bytes(memory.data_mut(&mut caller), time as u32, &now.to_le_bytes())
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        "random_get",
+        |mut caller: Caller<WasiCtx>, buf: i32, len: i32| -> i32 {
+            let memory = match memory(&caller) {
+                Some(memory) => memory,
+                None => return ERRNO_FAULT,
+            };
+            let (start, end) = (buf as u32 as usize, buf as u32 as usize + len as u32 as usize);
+            let (data, ctx) = memory.data_and_store_mut(&mut caller);
+            match data.get_mut(start..end) {
+                Some(dest) => {
+                    for byte in dest {
+                        ctx.random ^= ctx.random << 13;
+                        ctx.random ^= ctx.random >> 7;
+                        ctx.random ^= ctx.random << 17;
+                        *byte = ctx.random as u8;
+                    }
+                    ERRNO_SUCCESS
+                }
+                None => ERRNO_FAULT,
+            }
+        },
+    )?;
+    linker.func_wrap(MODULE, "sched_yield", || -> i32 { ERRNO_SUCCESS })?;
+    linker.func_wrap(MODULE, "proc_exit", |code: i32| -> Result<(), Error> {
+        Err(Error::i32_exit(code))
+    })?;
+    Ok(())
+}
+
+/// Defines every WASI function imported by `module` which isn't
+/// implemented by `add_to_linker` as a stub returning `ENOSYS`,
+/// so that modules importing them can still be instantiated
+pub fn stub_missing(linker: &mut Linker<WasiCtx>, module: &Module) {
+    for import in module.imports() {
+        if import.module() != MODULE {
+            continue;
+        }
+        let ty = match import.ty().func() {
+            Some(ty) => ty.clone(),
+            None => continue,
+        };
+        let returns_errno = ty.results() == [ValType::I32];
+        // fails only for functions which are already defined
+        let _ = linker.func_new(MODULE, import.name(), ty, move |_, _, results| {
+            if returns_errno {
+                results[0] = Val::I32(ERRNO_NOSYS);
+                Ok(())
+            } else {
+                Err(Error::new("unsupported WASI function"))
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmi::{Engine, Instance, Store};
+    use wat;
+
+    /// Exports functions calling the shims, which only find the
+    /// memory when they are called from within the module
+    const MODULE_WAT: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (import "wasi_snapshot_preview1" "random_get" (func $random_get (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "fd_write") (param i32 i32 i32 i32) (result i32)
+                (call $fd_write (local.get 0) (local.get 1) (local.get 2) (local.get 3)))
+            (func (export "fd_read") (param i32 i32 i32 i32) (result i32)
+                (call $fd_read (local.get 0) (local.get 1) (local.get 2) (local.get 3)))
+            (func (export "proc_exit") (param i32)
+                (call $proc_exit (local.get 0)))
+            (func (export "random_get") (param i32 i32) (result i32)
+                (call $random_get (local.get 0) (local.get 1))))
+    "#;
+
+    /// Instantiates the module reading `stdin`, keeping `output` bytes of each pipe
+    fn instance(stdin: &[u8], output: usize) -> (Store<WasiCtx>, Instance) {
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wat::parse_str(MODULE_WAT).unwrap()[..]).unwrap();
+        let mut store = Store::new(&engine, WasiCtx::new(stdin.to_vec(), 65536, output));
+        let mut linker = Linker::new(&engine);
+        add_to_linker(&mut linker).unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .unwrap();
+        (store, instance)
+    }
+
+    /// Writes the iovecs `(buf, len)` at 0, returns how many there are
+    fn iovecs(store: &mut Store<WasiCtx>, instance: &Instance, iovs: &[(u32, u32)]) -> i32 {
+        let memory = instance.get_memory(&*store, "memory").unwrap();
+        for (i, &(buf, len)) in iovs.iter().enumerate() {
+            let mut iov = buf.to_le_bytes().to_vec();
+            iov.extend_from_slice(&len.to_le_bytes());
+            memory.write(&mut *store, i * 8, &iov).unwrap();
+        }
+        iovs.len() as i32
+    }
+
+    fn read(store: &Store<WasiCtx>, instance: &Instance, ptr: usize, len: usize) -> Vec<u8> {
+        let memory = instance.get_memory(store, "memory").unwrap();
+        memory.data(store)[ptr..ptr + len].to_vec()
+    }
+
+    #[test]
+    fn writes_to_pipes() {
+        let (mut store, instance) = instance(b"", 8);
+        let memory = instance.get_memory(&store, "memory").unwrap();
+        memory.write(&mut store, 100, b"hello world").unwrap();
+        let fd_write = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&store, "fd_write")
+            .unwrap();
+        let len = iovecs(&mut store, &instance, &[(100, 5), (105, 6)]);
+        assert_eq!(fd_write.call(&mut store, (1, 0, len, 200)).unwrap(), ERRNO_SUCCESS);
+        assert_eq!(read(&store, &instance, 200, 4), 11u32.to_le_bytes());
+        let len = iovecs(&mut store, &instance, &[(100, 2)]);
+        assert_eq!(fd_write.call(&mut store, (2, 0, len, 200)).unwrap(), ERRNO_SUCCESS);
+        assert_eq!(fd_write.call(&mut store, (3, 0, len, 200)).unwrap(), ERRNO_BADF);
+        let len = iovecs(&mut store, &instance, &[(65530, 10)]);
+        assert_eq!(fd_write.call(&mut store, (1, 0, len, 200)).unwrap(), ERRNO_FAULT);
+        let ctx = store.into_data();
+        // only as much as the limit is kept
+        assert!(ctx.stdout.is_truncated());
+        assert!(!ctx.stderr.is_truncated());
+        assert_eq!(ctx.stdout.into_string(), "hello wo");
+        assert_eq!(ctx.stderr.into_string(), "he");
+    }
+
+    #[test]
+    fn reads_stdin() {
+        let (mut store, instance) = instance(b"1 2\n3", 0);
+        let fd_read = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&store, "fd_read")
+            .unwrap();
+        let len = iovecs(&mut store, &instance, &[(100, 2), (110, 2)]);
+        assert_eq!(fd_read.call(&mut store, (0, 0, len, 200)).unwrap(), ERRNO_SUCCESS);
+        assert_eq!(read(&store, &instance, 200, 4), 4u32.to_le_bytes());
+        assert_eq!(read(&store, &instance, 100, 2), b"1 ");
+        assert_eq!(read(&store, &instance, 110, 2), b"2\n");
+        // a short read stops at the end of the input
+        let len = iovecs(&mut store, &instance, &[(120, 4), (130, 4)]);
+        assert_eq!(fd_read.call(&mut store, (0, 0, len, 200)).unwrap(), ERRNO_SUCCESS);
+        assert_eq!(read(&store, &instance, 200, 4), 1u32.to_le_bytes());
+        assert_eq!(read(&store, &instance, 120, 1), b"3");
+        assert_eq!(fd_read.call(&mut store, (0, 0, len, 200)).unwrap(), ERRNO_SUCCESS);
+        assert_eq!(read(&store, &instance, 200, 4), 0u32.to_le_bytes());
+        assert_eq!(fd_read.call(&mut store, (1, 0, len, 200)).unwrap(), ERRNO_BADF);
+    }
+
+    /// Calls `random_get` twice for 8 bytes, returns what each call wrote
+    fn random_twice() -> (Vec<u8>, Vec<u8>) {
+        let (mut store, instance) = instance(b"", 0);
+        let random_get = instance.get_typed_func::<(i32, i32), i32>(&store, "random_get").unwrap();
+        assert_eq!(random_get.call(&mut store, (100, 8)).unwrap(), ERRNO_SUCCESS);
+        assert_eq!(random_get.call(&mut store, (108, 8)).unwrap(), ERRNO_SUCCESS);
+        assert_eq!(random_get.call(&mut store, (65530, 8)).unwrap(), ERRNO_FAULT);
+        (read(&store, &instance, 100, 8), read(&store, &instance, 108, 8))
+    }
+
+    #[test]
+    fn generates_new_random_bytes() {
+        let (first, second) = random_twice();
+        assert_ne!(first, second);
+        // every run starts from the same state
+        assert_eq!(random_twice(), (first, second));
+    }
+
+    #[test]
+    fn exits_with_code() {
+        let (mut store, instance) = instance(b"", 0);
+        let proc_exit = instance.get_typed_func::<i32, ()>(&store, "proc_exit").unwrap();
+        let error = proc_exit.call(&mut store, 3).unwrap_err();
+        assert_eq!(error.i32_exit_status(), Some(3));
+    }
+}
@@ -0,0 +1,2323 @@
+extern crate base64;
+extern crate code_executor;
+extern crate futures;
+extern crate hyper;
+extern crate hyperlocal;
+#[macro_use]
+extern crate serde_json as json;
+extern crate tar;
+extern crate tempdir;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_uds;
+extern crate wat;
+
+mod support;
+
+use code_executor::{APIService, ApiKey, Backends, CacheLimits, Executor, History, Keys, Quota,
+                    RequestLimits, ResultCache, Retention, SessionLimits, Sessions, WasmExecutor};
+use support::{build_message, buildkit_trace, log_frame, timestamped_frame, Action, FakeDocker,
+              Received, Reply, Script, CHECKER_ID};
+
+use futures::{future, Async, Future, Stream};
+use hyper::server::{Request, Service};
+use hyper::header::{Authorization, Bearer, ContentLength, Headers};
+use hyper::{Method, StatusCode};
+use hyperlocal::UnixConnector;
+use tokio_core::reactor::{Core, Timeout};
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+/// Sends `body` to `path` of an `APIService` backed by a fake daemon
+/// running `script`, returns the response along with the daemon
+fn call(script: Script, path: &str, body: &str) -> (StatusCode, String, FakeDocker) {
+    send(script, Method::Post, path, body)
+}
+
+/// Query parameter labelling the images built for the requests of `send`
+const LABELS: &str = "labels=%7B%22code-executor.request-id%22%3A%22test%22%7D";
+
+/// The query parameters of `received`, sorted as their order doesn't matter
+fn params(received: &Received) -> Vec<&str> {
+    let mut params = received.query.as_deref().unwrap_or("").split('&').collect::<Vec<_>>();
+    params.sort();
+    params
+}
+
+/// Like `call`, with any `method`. Requests are sent with `test` as their id
+fn send(script: Script, method: Method, path: &str, body: &str) -> (StatusCode, String, FakeDocker) {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let docker = FakeDocker::start(&handle, script);
+    let socket = docker.socket();
+    let executor = Executor::new(
+        UnixConnector::new(handle.clone()),
+        socket.to_str().unwrap(),
+        handle.clone(),
+    );
+    core.run(executor.detect_flavor()).unwrap();
+    let service = APIService::new(executor);
+    let mut request = Request::new(method, path.parse().unwrap());
+    request.headers_mut().set_raw("X-Request-Id", "test");
+    request.set_body(body.to_owned());
+    let response = service
+        .call(request)
+        .and_then(|response| {
+            let status = response.status();
+            response.body().concat2().map(move |body| (status, body))
+        });
+    let (status, body) = core.run(response).unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap(), docker)
+}
+
+/// Posts `request` to `path`, returns the parsed response and the daemon
+fn post(script: Script, path: &str, request: json::Value) -> (json::Value, FakeDocker) {
+    let (status, body, docker) = call(script, path, &request.to_string());
+    assert_eq!(status, StatusCode::Ok, "body: {}", body);
+    let output = json::from_str(&body).unwrap_or_else(|_| panic!("not json: {}", body));
+    (output, docker)
+}
+
+/// Executes `submission`, returns the parsed response and the daemon
+fn execute(script: Script, submission: json::Value) -> (json::Value, FakeDocker) {
+    post(script, "/execute", submission)
+}
+
+/// Judges `hello_c` printing `stdout` against `expected`
+/// # Arguments
+/// * `comparator` - Name of the comparator, if any
+fn judge(stdout: &[u8], expected: &str, comparator: Option<&str>) -> json::Value {
+    let script = Script {
+        logs: Reply::new(StatusCode::Ok).chunk(log_frame(1, stdout)),
+        ..Script::default()
+    };
+    let mut problem = hello_c();
+    problem["expected"] = json!(expected);
+    if let Some(comparator) = comparator {
+        problem["comparator"] = json!(comparator);
+    }
+    post(script, "/judge", problem).0
+}
+
+/// Returns the paths and contents of the files in a tar
+fn unpack(tar: &[u8]) -> Vec<(String, String)> {
+    let mut archive = tar::Archive::new(tar);
+    let mut files = Vec::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().to_string_lossy().into_owned();
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        files.push((path, contents));
+    }
+    files
+}
+
+fn hello_c() -> json::Value {
+    json!({
+        "code": "#include <stdio.h>\nint main(void) { printf(\"Hello world\"); return 0; }",
+        "lang": "c",
+    })
+}
+
+#[test]
+fn runs_submission() {
+    let (output, docker) = execute(Script::default(), hello_c());
+    assert_eq!(
+        output,
+        json!({"output": {"stdout": "Hello world", "stderr": ""}})
+    );
+    let paths = docker
+        .received()
+        .into_iter()
+        .map(|received| received.path)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        vec![
+            "/version",
+            "/build",
+            "/containers/create",
+            "/containers/e90e34656806/wait",
+            "/containers/e90e34656806/start",
+            "/containers/e90e34656806/logs",
+        ]
+    );
+    let wait = docker.last("/containers/e90e34656806/wait").unwrap();
+    assert_eq!(params(&wait), vec!["condition=next-exit"]);
+}
+
+#[test]
+fn sends_build_context() {
+    let mut submission = hello_c();
+    submission["stdin"] = json!("1 2\n");
+    let (_, docker) = execute(Script::default(), submission);
+    let build = docker.last("/build").unwrap();
+    assert_eq!(params(&build), vec![LABELS, "q=true"]);
+    let files = unpack(&build.body);
+    assert_eq!(files[0].0, "Dockerfile");
+    assert_eq!(files[1].0, "src/code.c");
+    assert_eq!(files[1].1, hello_c()["code"].as_str().unwrap());
+    assert_eq!(files[2], ("input".to_owned(), "1 2\n".to_owned()));
+}
+
+#[test]
+fn builds_multi_file_projects() {
+    let submission = json!({
+        "lang": "c",
+        "files": {
+            "main.c": "#include \"lib/add.h\"\nint main(void) { return add(1, 2); }",
+            "lib/add.h": "int add(int a, int b);",
+            "lib/add.c": "int add(int a, int b) { return a + b; }",
+        },
+        "entry_point": "./main.c",
+    });
+    let (_, docker) = execute(Script::default(), submission);
+    let build = docker.last("/build").unwrap();
+    let query = build.query.unwrap();
+    assert!(query.contains("q=true"), "{}", query);
+    assert!(
+        query.contains("buildargs=%7B%22ENTRY%22%3A%22main.c%22%7D"),
+        "{}",
+        query
+    );
+    let paths = unpack(&build.body)
+        .into_iter()
+        .map(|file| file.0)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        vec!["Dockerfile", "src/lib/add.c", "src/lib/add.h", "src/main.c", "input"]
+    );
+}
+
+#[test]
+fn rejects_invalid_files() {
+    let reject = |submission: json::Value| {
+        let (status, body, docker) = call(Script::default(), "/execute", &submission.to_string());
+        assert_eq!(status, StatusCode::BadRequest);
+        assert!(docker.last("/build").is_none());
+        body
+    };
+    assert_eq!(
+        reject(json!({"lang": "c", "files": {"../../etc/passwd": ""}})),
+        "Invalid submission: invalid path \"../../etc/passwd\""
+    );
+    assert_eq!(
+        reject(json!({"lang": "c", "files": {"main.c": ""}, "entry_point": "missing.c"})),
+        "Invalid submission: entry point \"missing.c\" isn't one of the files"
+    );
+    assert_eq!(
+        reject(json!({"lang": "c", "archive": "%%%"})).split(':').next(),
+        Some("Invalid submission")
+    );
+}
+
+#[test]
+fn passes_arguments_environment_and_flags() {
+    let mut submission = hello_c();
+    submission["args"] = json!(["--verbose", "two words"]);
+    submission["env"] = json!({"MODE": "fast"});
+    submission["compiler_flags"] = json!(["-O2", "-lm"]);
+    let (_, docker) = execute(Script::default(), submission);
+    let query = docker.last("/build").unwrap().query.unwrap();
+    assert!(
+        query.contains("buildargs=%7B%22FLAGS%22%3A%22-O2+-lm%22%7D"),
+        "{}",
+        query
+    );
+    let config = docker.last("/containers/create").unwrap().json();
+    assert_eq!(
+        config["Cmd"],
+        json!(["sh", "-c", "exec \"$@\" < /input", "sh", "/code", "--verbose", "two words"])
+    );
+    assert_eq!(config["Env"], json!(["MODE=fast"]));
+}
+
+#[test]
+fn rejects_disallowed_compiler_flags() {
+    let mut submission = hello_c();
+    submission["compiler_flags"] = json!(["-fplugin=/tmp/evil.so"]);
+    let (status, body, docker) = call(Script::default(), "/execute", &submission.to_string());
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(
+        body,
+        "Invalid submission: compiler flag \"-fplugin=/tmp/evil.so\" isn't allowed"
+    );
+    assert!(docker.last("/build").is_none());
+}
+
+#[test]
+fn creates_container_from_built_image() {
+    let (_, docker) = execute(Script::default(), hello_c());
+    let config = docker.last("/containers/create").unwrap().json();
+    assert_eq!(config["Image"], "4a1e8a4ab5ff");
+    assert_eq!(config["NetworkDisabled"], true);
+    assert_eq!(config["HostConfig"]["Memory"], 1073741824);
+    assert_eq!(config["HostConfig"]["DiskQuota"], 10737418240u64);
+}
+
+#[test]
+fn collects_stdout_and_stderr() {
+    let mut frames = log_frame(1, b"out 1\n");
+    frames.extend(log_frame(2, b"err 1\n"));
+    frames.extend(log_frame(1, b"out 2\n"));
+    // frames split at awkward places
+    let script = Script {
+        logs: Reply::new(StatusCode::Ok)
+            .chunk(&frames[..3])
+            .chunk(&frames[3..10])
+            .chunk(&frames[10..20])
+            .chunk(&frames[20..]),
+        ..Script::default()
+    };
+    let (output, _) = execute(script, hello_c());
+    assert_eq!(
+        output,
+        json!({"output": {"stdout": "out 1\nout 2\n", "stderr": "err 1\n"}})
+    );
+}
+
+#[test]
+fn requests_logs_with_timestamps() {
+    let (_, docker) = execute(Script::default(), hello_c());
+    let query = docker.last("/logs").unwrap().query.unwrap();
+    assert!(query.contains("timestamps=true"), "query: {}", query);
+}
+
+#[test]
+fn sends_transcript_in_order() {
+    let mut frames = timestamped_frame(1, "2018-02-14T10:00:00.000000001Z", b"out 1\n");
+    frames.extend(timestamped_frame(2, "2018-02-14T10:00:00.000000002Z", b"err 1\n"));
+    frames.extend(timestamped_frame(1, "2018-02-14T10:00:00.000000003Z", b"out 2\n"));
+    let script = Script {
+        logs: Reply::new(StatusCode::Ok).chunk(frames),
+        ..Script::default()
+    };
+    let mut submission = hello_c();
+    submission["transcript"] = json!(true);
+    let (output, _) = execute(script, submission);
+    assert_eq!(
+        output,
+        json!({"output": {
+            "stdout": "out 1\nout 2\n",
+            "stderr": "err 1\n",
+            "transcript": [
+                {"stream": "stdout", "timestamp": "2018-02-14T10:00:00.000000001Z", "data": "out 1\n"},
+                {"stream": "stderr", "timestamp": "2018-02-14T10:00:00.000000002Z", "data": "err 1\n"},
+                {"stream": "stdout", "timestamp": "2018-02-14T10:00:00.000000003Z", "data": "out 2\n"},
+            ],
+        }})
+    );
+}
+
+#[test]
+fn keeps_characters_whole_in_transcript() {
+    let smiley = "\u{1F600}".as_bytes();
+    let mut frames = timestamped_frame(1, "2018-02-14T10:00:00.000000001Z", &smiley[..2]);
+    frames.extend(timestamped_frame(1, "2018-02-14T10:00:00.000000002Z", &smiley[2..]));
+    let script = Script {
+        logs: Reply::new(StatusCode::Ok).chunk(frames),
+        ..Script::default()
+    };
+    let mut submission = hello_c();
+    submission["transcript"] = json!(true);
+    let (output, _) = execute(script, submission);
+    assert_eq!(
+        output["output"]["transcript"],
+        json!([{"stream": "stdout", "timestamp": "2018-02-14T10:00:00.000000002Z", "data": "\u{1F600}"}])
+    );
+}
+
+#[test]
+fn decodes_characters_split_across_frames() {
+    let smiley = "\u{1F600}".as_bytes();
+    let mut frames = log_frame(1, &smiley[..2]);
+    frames.extend(log_frame(1, &smiley[2..]));
+    frames.extend(log_frame(2, b""));
+    let script = Script {
+        logs: Reply::new(StatusCode::Ok).chunk(frames),
+        ..Script::default()
+    };
+    let (output, _) = execute(script, hello_c());
+    assert_eq!(
+        output,
+        json!({"output": {"stdout": "\u{1F600}", "stderr": ""}})
+    );
+}
+
+#[test]
+fn fails_on_malformed_logs() {
+    let script = Script {
+        logs: Reply::new(StatusCode::Ok).chunk(log_frame(7, b"Hello world")),
+        ..Script::default()
+    };
+    let (status, body, docker) = call(script, "/execute", &hello_c().to_string());
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "Unknown error");
+    let removed = docker.last("/containers/e90e34656806").unwrap();
+    assert_eq!(removed.method, Method::Delete);
+}
+
+#[test]
+fn reports_compile_error() {
+    let script = Script {
+        build: Reply::new(StatusCode::Ok)
+            .chunk(build_message(json!({"stream": "Step 1/4 : FROM frolvlad/alpine-gcc\n"})))
+            .chunk(build_message(json!({"stream": " ---> 4b19fa7d2ee6\n"})))
+            .chunk(build_message(json!({"stream": "Step 3/4 : RUN gcc code.c -o code\n"})))
+            .chunk(build_message(json!({"stream": "code.c:1:1: error: expected ';'\n"})))
+            .chunk(build_message(json!({
+                "errorDetail": {
+                    "code": 1,
+                    "message": "The command '/bin/sh -c gcc code.c -o code' returned a non-zero code: 1"
+                },
+                "error": "The command '/bin/sh -c gcc code.c -o code' returned a non-zero code: 1"
+            }))),
+        ..Script::default()
+    };
+    let (output, docker) = execute(script, hello_c());
+    assert_eq!(
+        output,
+        json!({"compile_error": {"error": "code.c:1:1: error: expected ';'\n"}})
+    );
+    assert!(docker.last("/containers/create").is_none());
+}
+
+/// The sample of every language with the file its code is saved as, the
+/// step compiling it and the first line of a compile error
+const SAMPLES: &[(&str, &str, &str, &str)] = &[
+    (
+        "resources/c/c.json",
+        "code.c",
+        "gcc -I. ./\"$ENTRY\" -o /code",
+        "./code.c:1:1: error: expected ';'",
+    ),
+    (
+        "resources/cpp/cpp.json",
+        "code.cpp",
+        "g++ -I. ./\"$ENTRY\" -o /code",
+        "./code.cpp:1:1: error: 'not' does not name a type",
+    ),
+    (
+        "resources/rust/rust.json",
+        "main.rs",
+        "rustc --edition=2021 -o /code ./\"$ENTRY\"",
+        "error: expected one of `!` or `::`, found `code`",
+    ),
+    (
+        "resources/go/go.json",
+        "main.go",
+        "go build -o /code .",
+        "./main.go:1:1: expected 'package', found not",
+    ),
+    (
+        "resources/java/java.json",
+        "Main.java",
+        "find . -name '*.java' -exec javac -d /classes {} +",
+        "./Main.java:1: error: class, interface, enum, or record expected",
+    ),
+    (
+        "resources/python2/python2.json",
+        "code.py",
+        "python -m compileall -q .",
+        "SyntaxError: invalid syntax",
+    ),
+    (
+        "resources/python3/python3.json",
+        "code.py",
+        "python -m compileall -q .",
+        "SyntaxError: invalid syntax",
+    ),
+    (
+        "resources/javascript/javascript.json",
+        "code.js",
+        "node --check \"$ENTRY\"",
+        "SyntaxError: Unexpected identifier 'code'",
+    ),
+    (
+        "resources/ruby/ruby.json",
+        "code.rb",
+        "ruby -c \"$ENTRY\"",
+        "code.rb:1: syntax error, unexpected local variable or method",
+    ),
+];
+
+fn sample(path: &str) -> json::Value {
+    json::from_reader(fs::File::open(path).unwrap()).unwrap()
+}
+
+#[test]
+fn runs_hello_world_in_every_language() {
+    for &(path, file, _, _) in SAMPLES {
+        let sample = sample(path);
+        let (output, docker) = execute(Script::default(), sample.clone());
+        assert_eq!(
+            output,
+            json!({"output": {"stdout": "Hello world", "stderr": ""}}),
+            "{}",
+            path
+        );
+        let files = unpack(&docker.last("/build").unwrap().body);
+        let dockerfile = fs::read_to_string(Path::new(path).with_file_name("Dockerfile")).unwrap();
+        assert_eq!(files[0], ("Dockerfile".to_owned(), dockerfile), "{}", path);
+        assert_eq!(
+            files[1],
+            (
+                format!("src/{}", file),
+                sample["code"].as_str().unwrap().to_owned()
+            ),
+            "{}",
+            path
+        );
+    }
+}
+
+#[test]
+fn reports_compile_errors_in_every_language() {
+    for &(path, _, step, error) in SAMPLES {
+        let failed = format!(
+            "The command '/bin/sh -c {}' returned a non-zero code: 1",
+            step
+        );
+        let script = Script {
+            build: Reply::new(StatusCode::Ok)
+                .chunk(build_message(json!({ "stream": format!("Step 7/8 : RUN {}\n", step) })))
+                .chunk(build_message(json!({ "stream": format!("{}\n", error) })))
+                .chunk(build_message(json!({
+                    "errorDetail": {"code": 1, "message": failed},
+                    "error": failed,
+                }))),
+            ..Script::default()
+        };
+        let mut submission = sample(path);
+        submission["code"] = json!("not code");
+        let (output, docker) = execute(script, submission);
+        assert_eq!(
+            output,
+            json!({ "compile_error": {"error": format!("{}\n", error)} }),
+            "{}",
+            path
+        );
+        assert!(docker.last("/containers/create").is_none(), "{}", path);
+    }
+}
+
+#[test]
+fn lists_languages() {
+    let (status, body, _) = send(Script::default(), Method::Get, "/languages", "");
+    assert_eq!(status, StatusCode::Ok);
+    let languages: json::Value = json::from_str(&body).unwrap();
+    let names = languages
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|language| language["lang"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec!["c", "cpp", "rust", "go", "java", "python2.7", "python3", "javascript", "ruby"]
+    );
+    assert_eq!(
+        languages[6],
+        json!({
+            "lang": "python3",
+            "versions": ["3.12", "3.8"],
+            "default_version": "3.12",
+            "compiler_flags": [],
+        })
+    );
+}
+
+#[test]
+fn builds_on_image_of_version() {
+    let mut submission = sample("resources/python3/python3.json");
+    submission["version"] = json!("3.8");
+    let (_, docker) = execute(Script::default(), submission);
+    let query = docker.last("/build").unwrap().query.unwrap();
+    assert!(
+        query.contains("buildargs=%7B%22IMAGE%22%3A%22python%3A3.8-alpine%22%7D"),
+        "{}",
+        query
+    );
+    // the default one is in the Dockerfile
+    let mut submission = sample("resources/python3/python3.json");
+    submission["version"] = json!("3.12");
+    let (_, docker) = execute(Script::default(), submission);
+    assert_eq!(params(&docker.last("/build").unwrap()), vec![LABELS, "q=true"]);
+}
+
+#[test]
+fn rejects_unknown_versions() {
+    let mut submission = hello_c();
+    submission["version"] = json!("2");
+    let (status, body, _) = call(Script::default(), "/execute", &submission.to_string());
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body, "Invalid submission: unknown version \"2\"");
+}
+
+#[test]
+fn reads_image_id_from_aux() {
+    let script = Script {
+        build: Reply::new(StatusCode::Ok)
+            .chunk(build_message(json!({"stream": "Step 1/4 : FROM frolvlad/alpine-gcc\n"})))
+            .chunk(build_message(json!({"status": "Pulling from frolvlad/alpine-gcc", "id": "latest"})))
+            .chunk(build_message(json!({
+                "status": "Downloading",
+                "progressDetail": {"current": 1024, "total": 2048},
+                "progress": "[=====>     ]",
+                "id": "ff3a5c916c92"
+            })))
+            .chunk(build_message(json!({"stream": " ---> Running in 0a8c1d5e2f3b\n"})))
+            .chunk(build_message(json!({"aux": {"ID": "sha256:7c3e1b6d2a8f"}})))
+            .chunk(build_message(json!({"stream": "Successfully built 7c3e1b6d2a8f\n"}))),
+        ..Script::default()
+    };
+    let (output, docker) = execute(script, hello_c());
+    assert_eq!(
+        output,
+        json!({"output": {"stdout": "Hello world", "stderr": ""}})
+    );
+    let config = docker.last("/containers/create").unwrap().json();
+    assert_eq!(config["Image"], "7c3e1b6d2a8f");
+}
+
+#[test]
+fn reads_messages_split_across_chunks() {
+    let message = build_message(json!({"stream": "sha256:4a1e8a4ab5ff\n"}));
+    let script = Script {
+        build: Reply::new(StatusCode::Ok)
+            .chunk(&message[..5])
+            .chunk(&message[5..20])
+            .chunk(&message[20..]),
+        ..Script::default()
+    };
+    let (_, docker) = execute(script, hello_c());
+    let config = docker.last("/containers/create").unwrap().json();
+    assert_eq!(config["Image"], "4a1e8a4ab5ff");
+}
+
+#[test]
+fn strips_colors_from_compile_error() {
+    let script = Script {
+        build: Reply::new(StatusCode::Ok)
+            .chunk(build_message(json!({
+                "stream": "\u{1b}[01;31merror:\u{1b}[0m expected ';'\n"
+            })))
+            .chunk(build_message(json!({
+                "errorDetail": {"code": 1, "message": "returned a non-zero code: 1"},
+                "error": "returned a non-zero code: 1"
+            }))),
+        ..Script::default()
+    };
+    let (output, _) = execute(script, hello_c());
+    assert_eq!(
+        output,
+        json!({"compile_error": {"error": "error: expected ';'\n"}})
+    );
+}
+
+#[test]
+fn reports_compile_error_from_buildkit() {
+    let script = Script {
+        build: Reply::new(StatusCode::Ok)
+            .chunk(buildkit_trace("sha256:aa", b"#7 0.3 ", None))
+            .chunk(buildkit_trace(
+                "sha256:aa",
+                b"code.c:1:1: error: expected ';'\n",
+                Some("exit code: 1"),
+            ))
+            .chunk(build_message(json!({
+                "errorDetail": {"message": "process did not complete successfully: exit code: 1"},
+                "error": "process did not complete successfully: exit code: 1"
+            }))),
+        ..Script::default()
+    };
+    let (output, _) = execute(script, hello_c());
+    assert_eq!(
+        output,
+        json!({"compile_error": {"error": "#7 0.3 code.c:1:1: error: expected ';'\n"}})
+    );
+}
+
+#[test]
+fn reads_image_id_from_buildkit() {
+    let script = Script {
+        build: Reply::new(StatusCode::Ok)
+            .chunk(buildkit_trace("sha256:aa", b"done\n", None))
+            .chunk(build_message(json!({
+                "id": "moby.image.id",
+                "aux": {"ID": "sha256:5d4c3b2a1f0e"}
+            }))),
+        ..Script::default()
+    };
+    let (_, docker) = execute(script, hello_c());
+    let config = docker.last("/containers/create").unwrap().json();
+    assert_eq!(config["Image"], "5d4c3b2a1f0e");
+}
+
+#[test]
+fn fails_without_image_id() {
+    let script = Script {
+        build: Reply::new(StatusCode::Ok)
+            .chunk(build_message(json!({"stream": "Step 1/4 : FROM frolvlad/alpine-gcc\n"}))),
+        ..Script::default()
+    };
+    let (status, body, docker) = call(script, "/execute", &hello_c().to_string());
+    assert_eq!(status, StatusCode::InternalServerError);
+    assert_eq!(body, "Can't build image");
+    assert!(docker.last("/containers/create").is_none());
+}
+
+#[test]
+fn fails_on_malformed_build_output() {
+    let script = Script {
+        build: Reply::new(StatusCode::Ok).chunk("{\"stream\": \"sha256:4a1e8a4ab5ff\"}\r\n]garbage"),
+        ..Script::default()
+    };
+    let (status, body, _) = call(script, "/execute", &hello_c().to_string());
+    assert_eq!(status, StatusCode::InternalServerError);
+    assert_eq!(body, "Can't build image");
+}
+
+/// A build whose base image can't be pulled
+fn failed_pull() -> Script {
+    let failed = "pull access denied for frolvlad/alpine-gcc, repository does not exist";
+    Script {
+        build: Reply::new(StatusCode::Ok)
+            .chunk(build_message(json!({"stream": "Step 1/4 : FROM frolvlad/alpine-gcc\n"})))
+            .chunk(build_message(json!({
+                "errorDetail": {"message": failed},
+                "error": failed,
+            }))),
+        ..Script::default()
+    }
+}
+
+#[test]
+fn reports_failed_pulls_as_server_errors() {
+    let (status, body, docker) = call(failed_pull(), "/execute", &hello_c().to_string());
+    assert_eq!(status, StatusCode::InternalServerError);
+    assert_eq!(body, "Can't build image");
+    assert!(docker.last("/containers/create").is_none());
+    let mut problem = hello_c();
+    problem["expected"] = json!("Hello world");
+    let (status, body, _) = call(failed_pull(), "/judge", &problem.to_string());
+    assert_eq!(status, StatusCode::InternalServerError);
+    assert_eq!(body, "Can't build image");
+}
+
+#[test]
+fn supports_podman() {
+    let script = Script {
+        version: Reply::json(
+            StatusCode::Ok,
+            json!({
+                "Version": "4.3.1",
+                "Components": [{"Name": "Podman Engine", "Version": "4.3.1"}],
+            }),
+        ),
+        build: Reply::new(StatusCode::Ok)
+            .chunk(build_message(json!({"stream": "STEP 1/4: FROM frolvlad/alpine-gcc\n"})))
+            .chunk(build_message(json!({"stream": "STEP 2/4: COPY code.c input /\n"})))
+            .chunk(build_message(json!({"stream": "--> 0d1b3f1b2c3a\n"})))
+            .chunk(build_message(json!({"stream": "COMMIT\n"})))
+            .chunk(build_message(json!({"aux": {"ID": "sha256:9f8e7d6c5b4a"}})))
+            .chunk(build_message(json!({"stream": "Successfully built 9f8e7d6c5b4a\n"}))),
+        ..Script::default()
+    };
+    let (output, docker) = execute(script, hello_c());
+    assert_eq!(
+        output,
+        json!({"output": {"stdout": "Hello world", "stderr": ""}})
+    );
+    let build = docker.last("/build").unwrap();
+    assert_eq!(params(&build), vec![LABELS, "q=false"]);
+    let config = docker.last("/containers/create").unwrap().json();
+    assert_eq!(config["Image"], "9f8e7d6c5b4a");
+    assert!(config["HostConfig"].get("DiskQuota").is_none());
+    assert!(config["HostConfig"].get("CpusetCpus").is_none());
+}
+
+#[test]
+fn fails_when_container_cant_be_created() {
+    let script = Script {
+        create: Reply::json(
+            StatusCode::InternalServerError,
+            json!({"message": "no space left on device"}),
+        ),
+        ..Script::default()
+    };
+    let (status, body, docker) = call(script, "/execute", &hello_c().to_string());
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "Unknown error");
+    assert!(docker.last("/start").is_none());
+}
+
+#[test]
+fn rejects_invalid_json() {
+    let (status, body, docker) = call(Script::default(), "/execute", "{\"code\": ");
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body, "Invalid json");
+    assert!(docker.last("/build").is_none());
+}
+
+#[test]
+fn rejects_unknown_url() {
+    let (status, _, _) = call(Script::default(), "/compile", "");
+    assert_eq!(status, StatusCode::NotFound);
+}
+
+#[test]
+fn accepts_matching_output() {
+    assert_eq!(judge(b"Hello world", "Hello world", None), json!({"verdict": "accepted"}));
+    assert_eq!(
+        judge(b"Hello   world\n\n", "Hello world", Some("whitespace")),
+        json!({"verdict": "accepted"})
+    );
+    assert_eq!(
+        judge(b"Hello\nworld", "Hello world", Some("tokens")),
+        json!({"verdict": "accepted"})
+    );
+    assert_eq!(
+        judge(b"HELLO WORLD", "Hello world", Some("case_insensitive")),
+        json!({"verdict": "accepted"})
+    );
+}
+
+#[test]
+fn compares_floats_with_epsilon() {
+    let script = Script {
+        logs: Reply::new(StatusCode::Ok).chunk(log_frame(1, b"3.1416\n")),
+        ..Script::default()
+    };
+    let mut problem = hello_c();
+    problem["expected"] = json!("3.14159");
+    problem["comparator"] = json!("float");
+    problem["epsilon"] = json!(1e-4);
+    let (verdict, _) = post(script.clone(), "/judge", problem.clone());
+    assert_eq!(verdict, json!({"verdict": "accepted"}));
+    problem["epsilon"] = json!(1e-6);
+    let (verdict, _) = post(script, "/judge", problem);
+    assert_eq!(verdict["verdict"], "wrong_answer");
+}
+
+#[test]
+fn reports_wrong_answer() {
+    assert_eq!(
+        judge(b"1\n2\n4\n", "1\n2\n3\n", None),
+        json!({
+            "verdict": "wrong_answer",
+            "line": 3,
+            "expected": "3",
+            "actual": "4",
+            "diff": "--- expected\n+++ actual\n@@ -1,3 +1,3 @@\n 1\n 2\n-3\n+4\n",
+        })
+    );
+}
+
+#[test]
+fn judges_compile_errors() {
+    let failed = "The command '/bin/sh -c gcc code.c -o code' returned a non-zero code: 1";
+    let script = Script {
+        build: Reply::new(StatusCode::Ok)
+            .chunk(build_message(json!({"stream": "code.c:1:1: error: expected ';'"})))
+            .chunk(build_message(json!({
+                "errorDetail": {"code": 1, "message": failed},
+                "error": failed,
+            }))),
+        ..Script::default()
+    };
+    let mut problem = hello_c();
+    problem["expected"] = json!("Hello world");
+    let (verdict, _) = post(script, "/judge", problem);
+    assert_eq!(
+        verdict,
+        json!({"verdict": "compile_error", "error": "code.c:1:1: error: expected ';'"})
+    );
+}
+
+#[test]
+fn rejects_unknown_comparator() {
+    let mut problem = hello_c();
+    problem["expected"] = json!("Hello world");
+    problem["comparator"] = json!("fuzzy");
+    let (status, body, _) = call(Script::default(), "/judge", &problem.to_string());
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body, "Invalid json");
+}
+
+#[test]
+fn sends_output_files() {
+    let mut builder = tar::Builder::new(Vec::new());
+    for &(path, contents) in &[
+        ("src/code.c", "int main;"),
+        ("src/out.csv", "a,b\n1,2\n"),
+        ("src/plots/a.png", "PNG"),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_path(path).unwrap();
+        header.set_size(contents.len() as u64);
+        header.set_cksum();
+        builder.append(&header, contents.as_bytes()).unwrap();
+    }
+    let script = Script {
+        download: Reply::new(StatusCode::Ok).chunk(builder.into_inner().unwrap()),
+        ..Script::default()
+    };
+    let mut submission = hello_c();
+    submission["output_files"] = json!(["*.csv", "plots/*.png", "missing.txt"]);
+    let (output, docker) = execute(script, submission);
+    assert_eq!(
+        output["output"]["output_files"],
+        json!({
+            "files": {
+                "out.csv": base64::encode("a,b\n1,2\n"),
+                "plots/a.png": base64::encode("PNG"),
+            },
+            "truncated": [],
+            "skipped": [],
+        })
+    );
+    let config = docker.last("/containers/create").unwrap().json();
+    assert_eq!(config["HostConfig"]["AutoRemove"], false);
+    let download = docker.last("/containers/e90e34656806/archive").unwrap();
+    assert_eq!(download.method, Method::Get);
+    assert_eq!(download.query.as_deref(), Some("path=%2Fsrc"));
+    let removed = docker.last("/containers/e90e34656806").unwrap();
+    assert_eq!(removed.method, Method::Delete);
+}
+
+#[test]
+fn removes_container_when_output_files_are_missing() {
+    let mut submission = hello_c();
+    submission["output_files"] = json!(["*.csv"]);
+    let (status, body, docker) = call(Script::default(), "/execute", &submission.to_string());
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "Unknown error");
+    assert_eq!(
+        docker.last("/containers/e90e34656806").unwrap().method,
+        Method::Delete
+    );
+}
+
+/// A problem judged by a checker written in Python
+fn checked_problem() -> json::Value {
+    let mut problem = hello_c();
+    problem["stdin"] = json!("world\n");
+    problem["expected"] = json!("Hello world");
+    problem["checker"] = json!({
+        "code": "import sys\nsys.exit(0)",
+        "lang": "python2.7",
+    });
+    problem
+}
+
+#[test]
+fn runs_checker_on_output() {
+    let (verdict, docker) = post(Script::default(), "/judge", checked_problem());
+    assert_eq!(
+        verdict,
+        json!({"verdict": "accepted", "score": 1.0, "message": "ok"})
+    );
+    let config = docker
+        .received()
+        .into_iter()
+        .filter(|received| received.path == "/containers/create")
+        .map(|received| received.json())
+        .find(|config| !config["Cmd"].is_null())
+        .unwrap();
+    assert_eq!(
+        config["Cmd"],
+        json!(["python", "/src/code.py", "/input", "/output", "/expected"])
+    );
+    assert_eq!(config["HostConfig"]["AutoRemove"], false);
+    let upload = docker.last("/archive").unwrap();
+    assert_eq!(upload.path, format!("/containers/{}/archive", CHECKER_ID));
+    assert_eq!(upload.query.as_deref(), Some("path=%2F"));
+    assert_eq!(
+        unpack(&upload.body),
+        vec![
+            ("input".to_owned(), "world\n".to_owned()),
+            ("output".to_owned(), "Hello world".to_owned()),
+            ("expected".to_owned(), "Hello world".to_owned()),
+        ]
+    );
+    assert!(docker.last(&format!("/containers/{}", CHECKER_ID)).is_some());
+}
+
+#[test]
+fn maps_checker_exit_codes() {
+    let verdict = |code: i64, message: &[u8]| {
+        let script = Script {
+            checker_logs: Reply::new(StatusCode::Ok).chunk(log_frame(2, message)),
+            checker_wait: Reply::json(StatusCode::Ok, json!({ "StatusCode": code })),
+            ..Script::default()
+        };
+        post(script, "/judge", checked_problem()).0
+    };
+    assert_eq!(
+        verdict(1, b"expected 42"),
+        json!({"verdict": "wrong_answer", "score": 0.0, "message": "expected 42"})
+    );
+    assert_eq!(
+        verdict(2, b"missing newline"),
+        json!({"verdict": "presentation_error", "score": 0.0, "message": "missing newline"})
+    );
+    assert_eq!(
+        verdict(7, b"0.25 one of four"),
+        json!({"verdict": "partially_accepted", "score": 0.25, "message": "one of four"})
+    );
+    assert_eq!(
+        verdict(3, b"no answer file"),
+        json!({"verdict": "checker_error", "error": "checker exited with 3: no answer file"})
+    );
+}
+
+#[test]
+fn removes_checker_container_on_failure() {
+    let script = Script {
+        checker_wait: Reply::new(StatusCode::InternalServerError),
+        ..Script::default()
+    };
+    let (status, body, docker) = call(script, "/judge", &checked_problem().to_string());
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "Unknown error");
+    let removed = docker.last(&format!("/containers/{}", CHECKER_ID)).unwrap();
+    assert_eq!(removed.query.as_deref(), Some("force=true"));
+}
+
+/// A problem judged by an interactor written in Python
+fn interactive_problem() -> json::Value {
+    let mut problem = hello_c();
+    problem["stdin"] = json!("5\n");
+    problem["expected"] = json!("25\n");
+    problem["interactor"] = json!({
+        "code": "import sys\nprint(5)\nsys.exit(0)",
+        "lang": "python2.7",
+    });
+    problem
+}
+
+/// A contestant squaring the number the interactor asks for
+fn squaring_script() -> Script {
+    Script {
+        attach: vec![
+            Action::Expect(b"5\n".to_vec()),
+            Action::Write(1, b"25\n".to_vec()),
+        ],
+        checker_attach: vec![
+            Action::Write(1, b"5\n".to_vec()),
+            Action::Expect(b"25\n".to_vec()),
+            Action::Write(2, b"ok\n".to_vec()),
+        ],
+        ..Script::default()
+    }
+}
+
+#[test]
+fn relays_between_contestant_and_interactor() {
+    let (verdict, docker) = post(squaring_script(), "/judge", interactive_problem());
+    assert_eq!(
+        verdict,
+        json!({
+            "verdict": "accepted",
+            "score": 1.0,
+            "message": "ok",
+            "transcript": [
+                {"from": "interactor", "stream": "stdout", "data": "5\n"},
+                {"from": "contestant", "stream": "stdout", "data": "25\n"},
+                {"from": "interactor", "stream": "stderr", "data": "ok\n"},
+            ],
+        })
+    );
+    let configs = docker
+        .received()
+        .into_iter()
+        .filter(|received| received.path == "/containers/create")
+        .map(|received| received.json())
+        .collect::<Vec<_>>();
+    assert_eq!(configs.len(), 2);
+    for config in &configs {
+        assert_eq!(config["OpenStdin"], true);
+        assert_eq!(config["StdinOnce"], true);
+        assert_eq!(config["HostConfig"]["AutoRemove"], false);
+    }
+    let cmds = configs.iter().map(|config| config["Cmd"].clone()).collect::<Vec<_>>();
+    assert!(cmds.contains(&json!(["/code"])));
+    assert!(cmds.contains(&json!(["python", "/src/code.py", "/input", "/expected"])));
+    let upload = docker.last("/archive").unwrap();
+    assert_eq!(upload.path, format!("/containers/{}/archive", CHECKER_ID));
+    assert_eq!(
+        unpack(&upload.body),
+        vec![
+            ("input".to_owned(), "5\n".to_owned()),
+            ("expected".to_owned(), "25\n".to_owned()),
+        ]
+    );
+    let attached = docker
+        .received()
+        .into_iter()
+        .filter(|received| received.path.ends_with("/attach"))
+        .count();
+    assert_eq!(attached, 2);
+    assert!(docker.last("/containers/e90e34656806").is_some());
+    assert!(docker.last(&format!("/containers/{}", CHECKER_ID)).is_some());
+}
+
+#[test]
+fn judges_with_interactor_exit_code() {
+    let script = Script {
+        checker_wait: Reply::json(StatusCode::Ok, json!({ "StatusCode": 1 })),
+        ..squaring_script()
+    };
+    let (verdict, _) = post(script, "/judge", interactive_problem());
+    assert_eq!(verdict["verdict"], "wrong_answer");
+    assert_eq!(verdict["message"], "ok");
+    assert_eq!(verdict["transcript"].as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn removes_interactive_containers_on_failure() {
+    let script = Script {
+        checker_wait: Reply::new(StatusCode::InternalServerError),
+        ..squaring_script()
+    };
+    let (status, body, docker) = call(script, "/judge", &interactive_problem().to_string());
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "Unknown error");
+    assert!(docker.last("/containers/e90e34656806").is_some());
+    assert!(docker.last(&format!("/containers/{}", CHECKER_ID)).is_some());
+}
+
+#[test]
+fn times_out_waiting_interactor() {
+    // neither program says anything, each waits for the other
+    let script = Script {
+        attach: vec![Action::Expect(b"1\n".to_vec())],
+        checker_attach: vec![Action::Expect(b"2\n".to_vec())],
+        ..Script::default()
+    };
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let docker = FakeDocker::start(&handle, script);
+    let executor = Executor::new(
+        UnixConnector::new(handle.clone()),
+        docker.socket().to_str().unwrap(),
+        handle.clone(),
+    ).with_wall_time(Duration::from_millis(200));
+    core.run(executor.detect_flavor()).unwrap();
+    let service = APIService::new(executor);
+    let mut request = Request::new(Method::Post, "/judge".parse().unwrap());
+    request.set_body(interactive_problem().to_string());
+    let response = service
+        .call(request)
+        .and_then(|response| response.body().concat2());
+    let body = core.run(response).unwrap();
+    let verdict: json::Value = json::from_slice(&body).unwrap();
+    assert_eq!(verdict, json!({"verdict": "time_limit_exceeded", "transcript": []}));
+    for id in &["e90e34656806", CHECKER_ID] {
+        let removed = docker.last(&format!("/containers/{}", id)).unwrap();
+        assert_eq!(removed.method, Method::Delete);
+        assert_eq!(removed.query.as_deref(), Some("force=true"));
+    }
+}
+
+/// An `APIService` with sessions, backed by a fake daemon,
+/// which keeps its state across requests
+struct Client {
+    core: Core,
+    service: APIService<Executor<UnixConnector>>,
+    /// Shares its containers with the one of `service`
+    executor: Executor<UnixConnector>,
+    docker: FakeDocker,
+}
+
+impl Client {
+    /// Starts a fake daemon running `script`
+    fn new(script: Script, limits: SessionLimits) -> Self {
+        Client::start(script, limits, None)
+    }
+
+    /// Starts a fake daemon running `script`, the executor keeps
+    /// compiled images taking up to `size` bytes
+    fn compiling(script: Script, size: u64) -> Self {
+        Client::start(script, SessionLimits::default(), Some(size))
+    }
+
+    fn start(script: Script, limits: SessionLimits, images: Option<u64>) -> Self {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let docker = FakeDocker::start(&handle, script);
+        let mut executor = Executor::new(
+            UnixConnector::new(handle.clone()),
+            docker.socket().to_str().unwrap(),
+            handle.clone(),
+        );
+        if let Some(size) = images {
+            executor = executor.with_image_cache(size);
+        }
+        core.run(executor.detect_flavor()).unwrap();
+        let service = APIService::new(executor.clone()).with_sessions(Sessions::new(handle, limits));
+        Client {
+            core: core,
+            service: service,
+            executor: executor,
+            docker: docker,
+        }
+    }
+
+    /// Only serves requests made with one of `keys`
+    fn with_keys(mut self, keys: Keys) -> Self {
+        self.service = self.service.clone().with_keys(keys);
+        self
+    }
+
+    /// Reports not being ready once `max_queue` submissions are queued
+    fn with_max_queue(mut self, max_queue: usize) -> Self {
+        self.service = self.service.clone().with_max_queue(max_queue);
+        self
+    }
+
+    /// Keeps the submissions in memory for as long as `retention` says
+    fn with_history(mut self, retention: Retention) -> Self {
+        let history = History::in_memory(retention).unwrap();
+        self.service = self.service.clone().with_history(history);
+        self
+    }
+
+    /// Answers submissions which were executed before from a cache
+    fn with_cache(mut self) -> Self {
+        let cache = ResultCache::new(CacheLimits::default());
+        self.service = self.service.clone().with_cache(cache);
+        self
+    }
+
+    /// Turns away requests larger than `limits`
+    fn with_limits(mut self, limits: RequestLimits) -> Self {
+        self.service = self.service.clone().with_limits(limits);
+        self
+    }
+
+    fn send(&mut self, method: Method, path: &str, body: &str) -> (StatusCode, String) {
+        let mut request = Request::new(method, path.parse().unwrap());
+        request.set_body(body.to_owned());
+        let (status, _, body) = self.request(request);
+        (status, body)
+    }
+
+    /// Sends `request`, returns the response with its headers
+    fn request(&mut self, request: Request) -> (StatusCode, Headers, String) {
+        let response = self.service.call(request).and_then(|response| {
+            let status = response.status();
+            let headers = response.headers().clone();
+            response.body().concat2().map(move |body| (status, headers, body))
+        });
+        let (status, headers, body) = self.core.run(response).unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Sends a request like a client going away `after` the daemon got a
+    /// request to a path ending with `until`, then lets the cleanup run
+    fn abandon(&mut self, path: &str, body: &str, until: &str, after: Duration) {
+        let mut request = Request::new(Method::Post, path.parse().unwrap());
+        request.set_body(body.to_owned());
+        let mut response = self.service.call(request);
+        let docker = &self.docker;
+        let received = future::poll_fn(|| {
+            if let Async::Ready(_) = response.poll().map_err(|e| panic!("{:?}", e))? {
+                panic!("{} wasn't given up on", until);
+            }
+            let received = docker.received().iter().any(|received| received.path.ends_with(until));
+            Ok::<_, ()>(if received { Async::Ready(()) } else { Async::NotReady })
+        });
+        self.core.run(received).unwrap();
+        let waited = Timeout::new(after, &self.core.handle()).unwrap();
+        match self.core.run(response.select2(waited)) {
+            Err(future::Either::A(_)) | Ok(future::Either::A(_)) => {
+                panic!("{} wasn't given up on", until)
+            }
+            _ => (),
+        }
+        let cleanup = Timeout::new(Duration::from_millis(50), &self.core.handle()).unwrap();
+        self.core.run(cleanup).unwrap();
+    }
+
+    /// Executes `submission`, returns how many images were built so far
+    fn builds_after(&mut self, submission: &json::Value) -> usize {
+        let (status, body) = self.send(Method::Post, "/execute", &submission.to_string());
+        assert_eq!(status, StatusCode::Ok, "body: {}", body);
+        self.docker
+            .received()
+            .into_iter()
+            .filter(|received| received.path == "/build")
+            .count()
+    }
+
+    /// Opens a Python session, returns its id
+    fn open(&mut self) -> String {
+        let (status, body) = self.send(Method::Post, "/sessions", r#"{"lang": "python3"}"#);
+        assert_eq!(status, StatusCode::Ok, "body: {}", body);
+        let info: json::Value = json::from_str(&body).unwrap();
+        info["id"].as_str().unwrap().to_owned()
+    }
+
+    /// Runs `code` in the session specified by `id`
+    fn eval(&mut self, id: &str, code: &str) -> (StatusCode, String) {
+        let body = json!({ "code": code }).to_string();
+        self.send(Method::Post, &format!("/sessions/{}/eval", id), &body)
+    }
+}
+
+/// Length framed cell, as the REPL driver reads it
+fn cell(code: &str) -> Action {
+    Action::Expect(format!("{}\n{}", code.len(), code).into_bytes())
+}
+
+/// The end of a cell, as the REPL driver writes it
+fn cell_end(status: &str) -> Vec<Action> {
+    vec![
+        Action::Write(2, b"\0\n".to_vec()),
+        Action::Write(1, format!("\0{}\n", status).into_bytes()),
+    ]
+}
+
+#[test]
+fn keeps_interpreter_across_cells() {
+    let mut attach = vec![cell("x = 41")];
+    attach.extend(cell_end("ok"));
+    attach.push(cell("x + 1"));
+    attach.push(Action::Write(1, b"42\n".to_vec()));
+    attach.extend(cell_end("ok"));
+    attach.push(cell("1 / 0"));
+    attach.push(Action::Write(2, b"ZeroDivisionError\n".to_vec()));
+    attach.extend(cell_end("error"));
+    let script = Script {
+        attach: attach,
+        ..Script::default()
+    };
+    let mut client = Client::new(script, SessionLimits::default());
+    let id = client.open();
+    let eval = |client: &mut Client, code| {
+        let (status, body) = client.eval(&id, code);
+        assert_eq!(status, StatusCode::Ok, "body: {}", body);
+        json::from_str::<json::Value>(&body).unwrap()
+    };
+    assert_eq!(
+        eval(&mut client, "x = 41"),
+        json!({"stdout": "", "stderr": "", "status": "ok"})
+    );
+    assert_eq!(
+        eval(&mut client, "x + 1"),
+        json!({"stdout": "42\n", "stderr": "", "status": "ok"})
+    );
+    assert_eq!(
+        eval(&mut client, "1 / 0"),
+        json!({"stdout": "", "stderr": "ZeroDivisionError\n", "status": "error"})
+    );
+    let files = unpack(&client.docker.last("/build").unwrap().body);
+    assert!(files.iter().any(|&(ref path, _)| path == "src/repl.py"));
+    let config = client.docker.last("/containers/create").unwrap().json();
+    assert_eq!(
+        config["Cmd"],
+        json!(["sh", "-c", "exec 3<&0 </dev/null; exec \"$@\"", "sh", "python", "/src/repl.py"])
+    );
+    assert_eq!(config["HostConfig"]["Memory"], 268435456);
+    assert_eq!(config["HostConfig"]["AutoRemove"], false);
+    let (status, _) = client.send(Method::Delete, &format!("/sessions/{}", id), "");
+    assert_eq!(status, StatusCode::NoContent);
+    assert_eq!(
+        client.docker.last("/containers/e90e34656806").unwrap().method,
+        Method::Delete
+    );
+    let (status, _) = client.eval(&id, "x");
+    assert_eq!(status, StatusCode::NotFound);
+}
+
+#[test]
+fn limits_sessions() {
+    let limits = SessionLimits {
+        sessions: 1,
+        ..SessionLimits::default()
+    };
+    let mut client = Client::new(Script::default(), limits);
+    let (status, body) = client.send(Method::Post, "/sessions", r#"{"lang": "python3", "memory": 1073741824}"#);
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body, "Invalid submission: memory 1073741824 isn't allowed");
+    let (status, _) = client.send(Method::Post, "/sessions", r#"{"lang": "c"}"#);
+    assert_eq!(status, StatusCode::BadRequest);
+    client.open();
+    let (status, body) = client.send(Method::Post, "/sessions", r#"{"lang": "python3"}"#);
+    assert_eq!(status, StatusCode::ServiceUnavailable);
+    assert_eq!(body, "Too many sessions");
+}
+
+#[test]
+fn releases_sessions_given_up_on() {
+    let limits = SessionLimits {
+        sessions: 1,
+        ..SessionLimits::default()
+    };
+    let mut client = Client::new(Script::default(), limits);
+    client.abandon("/sessions", r#"{"lang": "python3"}"#, "/attach", Duration::from_millis(0));
+    let removed = client.docker.last("/containers/e90e34656806").unwrap();
+    assert_eq!(removed.method, Method::Delete);
+    let id = client.open();
+    assert_eq!(id.len(), 32);
+    assert!(id.chars().all(|c| c.is_ascii_hexdigit()), "{}", id);
+}
+
+#[test]
+fn closes_sessions_given_up_on_mid_cell() {
+    let script = Script {
+        // never sends the end of the cell
+        attach: vec![cell("while True: pass"), Action::Expect(b"more".to_vec())],
+        ..Script::default()
+    };
+    let limits = SessionLimits {
+        sessions: 1,
+        ..SessionLimits::default()
+    };
+    let mut client = Client::new(script, limits);
+    let id = client.open();
+    let path = format!("/sessions/{}/eval", id);
+    let cell = json!({"code": "while True: pass"}).to_string();
+    client.abandon(&path, &cell, "/attach", Duration::from_millis(50));
+    let removed = client.docker.last("/containers/e90e34656806").unwrap();
+    assert_eq!(removed.method, Method::Delete);
+    let (status, _) = client.eval(&id, "1");
+    assert_eq!(status, StatusCode::NotFound);
+    client.open();
+}
+
+#[test]
+fn stops_notebooks_given_up_on() {
+    let script = Script {
+        // never sends the end of the cell
+        attach: vec![cell("while True: pass"), Action::Expect(b"more".to_vec())],
+        ..Script::default()
+    };
+    let limits = SessionLimits {
+        sessions: 1,
+        ..SessionLimits::default()
+    };
+    let mut client = Client::new(script, limits);
+    let notebook = json!({"lang": "python3", "cells": ["while True: pass"]}).to_string();
+    client.abandon("/notebook", &notebook, "/start", Duration::from_millis(50));
+    let removed = client.docker.last("/containers/e90e34656806").unwrap();
+    assert_eq!(removed.method, Method::Delete);
+    client.open();
+}
+
+#[test]
+fn truncates_chatty_cells() {
+    let mut attach = vec![cell("print('x' * 2**21)"), Action::Write(1, vec![b'x'; 2 << 20])];
+    attach.extend(cell_end("ok"));
+    let script = Script {
+        attach: attach,
+        ..Script::default()
+    };
+    let mut client = Client::new(script, SessionLimits::default());
+    let id = client.open();
+    let (_, body) = client.eval(&id, "print('x' * 2**21)");
+    let output: json::Value = json::from_str(&body).unwrap();
+    assert_eq!(output["status"], "ok");
+    assert_eq!(output["stdout"].as_str().unwrap().len(), 1048576);
+}
+
+#[test]
+fn closes_session_when_interpreter_exits() {
+    let script = Script {
+        attach: vec![cell("exit()"), Action::Write(1, b"bye".to_vec())],
+        ..Script::default()
+    };
+    let mut client = Client::new(script, SessionLimits::default());
+    let id = client.open();
+    let (_, body) = client.eval(&id, "exit()");
+    assert_eq!(
+        json::from_str::<json::Value>(&body).unwrap(),
+        json!({"stdout": "bye", "stderr": "", "status": "exited"})
+    );
+    let (status, _) = client.eval(&id, "x");
+    assert_eq!(status, StatusCode::NotFound);
+}
+
+#[test]
+fn times_out_cells() {
+    let script = Script {
+        // never sends the end of the cell
+        attach: vec![cell("while True: pass"), Action::Expect(b"more".to_vec())],
+        ..Script::default()
+    };
+    let limits = SessionLimits {
+        eval: Duration::from_millis(100),
+        ..SessionLimits::default()
+    };
+    let mut client = Client::new(script, limits);
+    let id = client.open();
+    let (_, body) = client.eval(&id, "while True: pass");
+    assert_eq!(
+        json::from_str::<json::Value>(&body).unwrap(),
+        json!({"stdout": "", "stderr": "", "status": "timeout"})
+    );
+    let (status, _) = client.eval(&id, "x");
+    assert_eq!(status, StatusCode::NotFound);
+}
+
+#[test]
+fn removes_containers_left_on_shutdown() {
+    let mut client = Client::new(Script::default(), SessionLimits::default());
+    client.open();
+    let removed = client.core.run(client.executor.remove_containers()).unwrap();
+    assert_eq!(removed, 1);
+    let removal = client.docker.last("/containers/e90e34656806").unwrap();
+    assert_eq!(removal.method, Method::Delete);
+    assert_eq!(params(&removal), vec!["force=true"]);
+    let removed = client.core.run(client.executor.remove_containers()).unwrap();
+    assert_eq!(removed, 0);
+}
+
+#[test]
+fn forgets_containers_of_done_submissions() {
+    let mut client = Client::new(Script::default(), SessionLimits::default());
+    let (status, body) = client.send(Method::Post, "/execute", &hello_c().to_string());
+    assert_eq!(status, StatusCode::Ok, "body: {}", body);
+    let id = client.open();
+    let (status, _) = client.send(Method::Delete, &format!("/sessions/{}", id), "");
+    assert_eq!(status, StatusCode::NoContent);
+    let removed = client.core.run(client.executor.remove_containers()).unwrap();
+    assert_eq!(removed, 0);
+}
+
+#[test]
+fn counts_requests_in_flight() {
+    let mut client = Client::new(Script::default(), SessionLimits::default());
+    let mut request = Request::new(Method::Post, "/execute".parse().unwrap());
+    request.set_body(hello_c().to_string());
+    let response = client.service.call(request);
+    assert_eq!(client.service.clone().in_flight(), 1);
+    client.core.run(response).unwrap();
+    assert_eq!(client.service.in_flight(), 0);
+    // hanging up counts too
+    let response = client.service.call(Request::new(Method::Get, "/readyz".parse().unwrap()));
+    assert_eq!(client.service.in_flight(), 1);
+    drop(response);
+    assert_eq!(client.service.in_flight(), 0);
+}
+
+/// Runs `cells` as a notebook of a fake interpreter doing `attach`
+fn notebook(attach: Vec<Action>, cells: &[&str], on_error: &str) -> (json::Value, FakeDocker) {
+    let mut client = Client::new(
+        Script {
+            attach: attach,
+            ..Script::default()
+        },
+        SessionLimits::default(),
+    );
+    let request = json!({"lang": "python3", "cells": cells, "on_error": on_error});
+    let (status, body) = client.send(Method::Post, "/notebook", &request.to_string());
+    assert_eq!(status, StatusCode::Ok, "body: {}", body);
+    (json::from_str(&body).unwrap(), client.docker)
+}
+
+/// A notebook whose second of three cells fails
+fn failing_cells() -> Vec<Action> {
+    let mut attach = vec![cell("x = 1")];
+    attach.extend(cell_end("ok"));
+    attach.push(cell("1 / 0"));
+    attach.push(Action::Write(2, b"ZeroDivisionError\n".to_vec()));
+    attach.extend(cell_end("error"));
+    attach.push(cell("x"));
+    attach.push(Action::Write(1, b"1\n".to_vec()));
+    attach.extend(cell_end("ok"));
+    attach
+}
+
+#[test]
+fn runs_notebook_cells_in_one_interpreter() {
+    let (output, docker) = notebook(failing_cells(), &["x = 1", "1 / 0", "x"], "continue");
+    assert_eq!(
+        output,
+        json!({"cells": [
+            {"stdout": "", "stderr": "", "status": "ok"},
+            {"stdout": "", "stderr": "ZeroDivisionError\n", "status": "error"},
+            {"stdout": "1\n", "stderr": "", "status": "ok"},
+        ]})
+    );
+    let created = docker
+        .received()
+        .into_iter()
+        .filter(|received| received.path == "/containers/create")
+        .count();
+    assert_eq!(created, 1);
+    assert_eq!(
+        docker.last("/containers/e90e34656806").unwrap().method,
+        Method::Delete
+    );
+}
+
+#[test]
+fn stops_notebook_after_failing_cell() {
+    let (output, docker) = notebook(failing_cells(), &["x = 1", "1 / 0", "x"], "stop");
+    assert_eq!(output["cells"].as_array().unwrap().len(), 2);
+    assert_eq!(output["cells"][1]["status"], "error");
+    assert_eq!(
+        docker.last("/containers/e90e34656806").unwrap().method,
+        Method::Delete
+    );
+}
+
+/// Hash of the key `secret`
+const SECRET: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+/// Hash of the key `other`
+const OTHER: &str = "d9298a10d1b0735837dc4bd85dac641b0f3cef27a47e5d53a54f2f3f5b2fcffa";
+
+/// The key `secret`, with `quota`, and the key `other`
+fn keys(quota: Quota) -> Keys {
+    Keys::new(vec![
+        ApiKey {
+            name: "tutoring".to_owned(),
+            sha256: SECRET.to_owned(),
+            quota: quota,
+        },
+        ApiKey {
+            name: "grading".to_owned(),
+            sha256: OTHER.to_owned(),
+            quota: Quota::default(),
+        },
+    ])
+}
+
+/// A client whose keys are `secret`, with `quota`, and `other`
+fn keyed(quota: Quota) -> Client {
+    Client::new(Script::default(), SessionLimits::default()).with_keys(keys(quota))
+}
+
+/// A request with `key` to `path`
+fn keyed_request(key: &str, method: Method, path: &str, body: &str) -> Request {
+    let mut request = Request::new(method, path.parse().unwrap());
+    request.headers_mut().set(Authorization(Bearer { token: key.to_owned() }));
+    request.set_body(body.to_owned());
+    request
+}
+
+/// A request to execute `hello_c` with `key`
+fn keyed_execute(key: &str) -> Request {
+    keyed_request(key, Method::Post, "/execute", &hello_c().to_string())
+}
+
+/// Returns the value of the header `name`
+fn header(headers: &Headers, name: &str) -> String {
+    String::from_utf8(headers.get_raw(name).unwrap().one().unwrap().to_vec()).unwrap()
+}
+
+#[test]
+fn requires_api_key() {
+    let mut client = keyed(Quota::default());
+    let (status, headers, body) = client.request(Request::new(Method::Get, "/languages".parse().unwrap()));
+    assert_eq!(status, StatusCode::Unauthorized);
+    assert_eq!(body, "Invalid API key");
+    assert_eq!(header(&headers, "WWW-Authenticate"), "Bearer");
+    let (status, _, _) = client.request(keyed_execute("guess"));
+    assert_eq!(status, StatusCode::Unauthorized);
+    let mut request = Request::new(Method::Get, "/languages".parse().unwrap());
+    request.headers_mut().set_raw("X-API-Key", "secret");
+    let (status, _, _) = client.request(request);
+    assert_eq!(status, StatusCode::Ok);
+    let (status, headers, body) = client.request(keyed_execute("secret"));
+    assert_eq!(status, StatusCode::Ok, "body: {}", body);
+    assert_eq!(header(&headers, "X-RateLimit-Limit"), "60");
+    assert_eq!(header(&headers, "X-RateLimit-Remaining"), "59");
+}
+
+#[test]
+fn limits_jobs_per_minute() {
+    let mut client = keyed(Quota {
+        per_minute: 2,
+        ..Quota::default()
+    });
+    for remaining in &["1", "0"] {
+        let (status, headers, _) = client.request(keyed_execute("secret"));
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(header(&headers, "X-RateLimit-Remaining"), *remaining);
+    }
+    let (status, headers, body) = client.request(keyed_execute("secret"));
+    assert_eq!(status, StatusCode::TooManyRequests);
+    assert_eq!(body, "Quota exceeded: too many jobs this minute");
+    let retry_after = header(&headers, "Retry-After").parse::<u64>().unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+}
+
+#[test]
+fn limits_concurrent_jobs() {
+    let mut client = keyed(Quota {
+        concurrent: 1,
+        ..Quota::default()
+    });
+    let running = client.service.call(keyed_execute("secret"));
+    let (status, _, body) = client.request(keyed_execute("secret"));
+    assert_eq!(status, StatusCode::TooManyRequests);
+    assert_eq!(body, "Quota exceeded: too many jobs running");
+    drop(running);
+    let (status, _, _) = client.request(keyed_execute("secret"));
+    assert_eq!(status, StatusCode::Ok);
+}
+
+#[test]
+fn limits_run_time_per_day() {
+    let mut client = keyed(Quota {
+        run_seconds_per_day: 0.0,
+        ..Quota::default()
+    });
+    let (status, headers, body) = client.request(keyed_execute("secret"));
+    assert_eq!(status, StatusCode::TooManyRequests);
+    assert_eq!(body, "Quota exceeded: run time used up for today");
+    assert_eq!(header(&headers, "X-Run-Quota-Remaining"), "0.000");
+}
+
+#[test]
+fn charges_cells() {
+    let mut attach = vec![cell("x = 1")];
+    attach.extend(cell_end("ok"));
+    let script = Script {
+        attach: attach,
+        ..Script::default()
+    };
+    let quota = Quota {
+        run_seconds_per_day: 1e-9,
+        ..Quota::default()
+    };
+    let mut client = Client::new(script, SessionLimits::default()).with_keys(keys(quota));
+    let (status, _, body) = client.request(keyed_request("secret", Method::Post, "/sessions", r#"{"lang": "python3"}"#));
+    assert_eq!(status, StatusCode::Ok, "body: {}", body);
+    let id = json::from_str::<json::Value>(&body).unwrap()["id"].as_str().unwrap().to_owned();
+    let eval = format!("/sessions/{}/eval", id);
+    let (status, _, _) = client.request(keyed_request("secret", Method::Post, &eval, r#"{"code": "x = 1"}"#));
+    assert_eq!(status, StatusCode::Ok);
+    let (status, _, body) = client.request(keyed_request("secret", Method::Post, &eval, r#"{"code": "x"}"#));
+    assert_eq!(status, StatusCode::TooManyRequests);
+    assert_eq!(body, "Quota exceeded: run time used up for today");
+}
+
+#[test]
+fn keeps_sessions_to_their_key() {
+    let mut attach = vec![cell("x = 1")];
+    attach.extend(cell_end("ok"));
+    let script = Script {
+        attach: attach,
+        ..Script::default()
+    };
+    let mut client = Client::new(script, SessionLimits::default()).with_keys(keys(Quota::default()));
+    let (_, _, body) = client.request(keyed_request("secret", Method::Post, "/sessions", r#"{"lang": "python3"}"#));
+    let id = json::from_str::<json::Value>(&body).unwrap()["id"].as_str().unwrap().to_owned();
+    let eval = format!("/sessions/{}/eval", id);
+    let (status, _, _) = client.request(keyed_request("other", Method::Post, &eval, r#"{"code": "x = 1"}"#));
+    assert_eq!(status, StatusCode::NotFound);
+    let session = format!("/sessions/{}", id);
+    let (status, _, _) = client.request(keyed_request("other", Method::Delete, &session, ""));
+    assert_eq!(status, StatusCode::NotFound);
+    let (status, _, _) = client.request(keyed_request("secret", Method::Post, &eval, r#"{"code": "x = 1"}"#));
+    assert_eq!(status, StatusCode::Ok);
+    let (status, _, _) = client.request(keyed_request("secret", Method::Delete, &session, ""));
+    assert_eq!(status, StatusCode::NoContent);
+}
+
+/// A client turning away bodies over 1024 bytes and code over 128 bytes
+fn limited() -> Client {
+    let limits = RequestLimits {
+        body: 1024,
+        code: 128,
+        stdin: 16,
+    };
+    Client::new(Script::default(), SessionLimits::default()).with_limits(limits)
+}
+
+#[test]
+fn rejects_large_content_length() {
+    let mut client = limited();
+    let mut request = Request::new(Method::Post, "/execute".parse().unwrap());
+    request.headers_mut().set(ContentLength(1 << 30));
+    request.set_body(hello_c().to_string());
+    let (status, _, body) = client.request(request);
+    assert_eq!(status, StatusCode::PayloadTooLarge);
+    assert_eq!(body, "Request too large: the body takes more than 1024 bytes");
+    assert!(client.docker.last("/build").is_none());
+}
+
+#[test]
+fn stops_reading_large_body() {
+    let mut client = limited();
+    let body = format!("{{\"lang\": \"c\", \"code\": \"{}\"}}", "x".repeat(2048));
+    let (status, body) = client.send(Method::Post, "/execute", &body);
+    assert_eq!(status, StatusCode::PayloadTooLarge);
+    assert_eq!(body, "Request too large: the body takes more than 1024 bytes");
+}
+
+#[test]
+fn limits_code_and_stdin() {
+    let mut client = limited();
+    let mut submission = hello_c();
+    submission["code"] = json!("x".repeat(200));
+    let (status, body) = client.send(Method::Post, "/execute", &submission.to_string());
+    assert_eq!(status, StatusCode::PayloadTooLarge);
+    assert_eq!(body, "Request too large: source takes 200 bytes, more than 128");
+    let mut submission = hello_c();
+    submission["files"] = json!({"lib.h": "x".repeat(60), "lib.c": "x".repeat(60)});
+    submission["archive"] = json!("x".repeat(60));
+    let (status, body) = client.send(Method::Post, "/execute", &submission.to_string());
+    assert_eq!(status, StatusCode::PayloadTooLarge);
+    assert!(body.starts_with("Request too large: source takes"), "{}", body);
+    let mut submission = hello_c();
+    submission["stdin"] = json!("1 2 3 4 5 6 7 8 9");
+    let (status, body) = client.send(Method::Post, "/execute", &submission.to_string());
+    assert_eq!(status, StatusCode::PayloadTooLarge);
+    assert_eq!(body, "Request too large: stdin takes 17 bytes, more than 16");
+    let id = client.open();
+    let (status, _) = client.eval(&id, &"x".repeat(129));
+    assert_eq!(status, StatusCode::PayloadTooLarge);
+}
+
+#[test]
+fn exposes_metrics() {
+    let script = Script {
+        create: Reply::json(
+            StatusCode::InternalServerError,
+            json!({"message": "no space left on device"}),
+        ),
+        ..Script::default()
+    };
+    let mut client = Client::new(Script::default(), SessionLimits::default());
+    let (status, _) = client.send(Method::Post, "/execute", &hello_c().to_string());
+    assert_eq!(status, StatusCode::Ok);
+    let mut failing = Client::new(script, SessionLimits::default());
+    failing.send(Method::Post, "/execute", &hello_c().to_string());
+    let (status, headers, body) = client.request(Request::new(Method::Get, "/metrics".parse().unwrap()));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(header(&headers, "Content-Type"), "text/plain; version=0.0.4");
+    for metric in &[
+        "code_executor_submissions_total{language=\"c\",verdict=\"ok\"}",
+        "code_executor_submissions_total{language=\"c\",verdict=\"error\"}",
+        "code_executor_image_build_seconds_count{language=\"c\"}",
+        "code_executor_container_start_seconds_count",
+        "code_executor_run_seconds_count",
+        "code_executor_queue_depth",
+        "code_executor_active_containers",
+        "code_executor_docker_errors_total{error=\"internal_server_error\"}",
+    ] {
+        assert!(body.contains(metric), "no {} in {}", metric, body);
+    }
+}
+
+/// Asks `client` whether it is ready
+fn readyz(client: &mut Client) -> (StatusCode, json::Value) {
+    let (status, body) = client.send(Method::Get, "/readyz", "");
+    (status, json::from_str(&body).unwrap_or_else(|_| panic!("not json: {}", body)))
+}
+
+#[test]
+fn answers_probes_without_key() {
+    let mut client = keyed(Quota::default());
+    let (status, body) = client.send(Method::Get, "/healthz", "");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "OK");
+    let (status, readiness) = readyz(&mut client);
+    assert_eq!(status, StatusCode::Ok, "readiness: {}", readiness);
+    assert_eq!(readiness["ready"], json!(true));
+    assert_eq!(readiness["docker"], json!(true));
+    assert_eq!(readiness["missing_images"], json!([]));
+    assert!(client.docker.last("/_ping").is_some());
+    assert!(client.docker.last("/images/python:3.12-alpine/json").is_some());
+}
+
+#[test]
+fn isnt_ready_without_images() {
+    let script = Script {
+        image: Reply::json(StatusCode::NotFound, json!({"message": "No such image"})),
+        ..Script::default()
+    };
+    let mut client = Client::new(script, SessionLimits::default());
+    let (status, readiness) = readyz(&mut client);
+    assert_eq!(status, StatusCode::ServiceUnavailable);
+    assert_eq!(readiness["ready"], json!(false));
+    assert_eq!(readiness["docker"], json!(true));
+    let missing = readiness["missing_images"].as_array().unwrap();
+    assert!(missing.contains(&json!("gcc:13")));
+    assert!(missing.contains(&json!("frolvlad/alpine-python2")));
+}
+
+#[test]
+fn isnt_ready_when_engine_is_down() {
+    let script = Script {
+        ping: Reply::new(StatusCode::InternalServerError),
+        ..Script::default()
+    };
+    let mut client = Client::new(script, SessionLimits::default());
+    let (status, readiness) = readyz(&mut client);
+    assert_eq!(status, StatusCode::ServiceUnavailable);
+    assert_eq!(readiness["docker"], json!(false));
+    assert!(client.docker.last("/json").is_none());
+}
+
+#[test]
+fn isnt_ready_when_queue_is_full() {
+    let mut client = Client::new(Script::default(), SessionLimits::default()).with_max_queue(0);
+    let (status, readiness) = readyz(&mut client);
+    assert_eq!(status, StatusCode::ServiceUnavailable);
+    assert_eq!(readiness["docker"], json!(true));
+    assert_eq!(readiness["max_queue"], json!(0));
+}
+
+#[test]
+fn labels_containers_with_request_id() {
+    let mut client = Client::new(Script::default(), SessionLimits::default());
+    let mut request = Request::new(Method::Post, "/execute".parse().unwrap());
+    request.headers_mut().set_raw("X-Request-Id", "grader-42");
+    request.set_body(hello_c().to_string());
+    let (status, headers, _) = client.request(request);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(header(&headers, "X-Request-Id"), "grader-42");
+    let query = client.docker.last("/build").unwrap().query.unwrap();
+    assert!(
+        query.contains("labels=%7B%22code-executor.request-id%22%3A%22grader-42%22%7D"),
+        "query: {}",
+        query
+    );
+    let config = client.docker.last("/containers/create").unwrap().json();
+    assert_eq!(config["Labels"], json!({"code-executor.request-id": "grader-42"}));
+}
+
+#[test]
+fn assigns_request_ids() {
+    let mut client = Client::new(Script::default(), SessionLimits::default());
+    let mut ids = Vec::new();
+    for sent in &[None, Some("not an id")] {
+        let mut request = Request::new(Method::Get, "/languages".parse().unwrap());
+        if let Some(sent) = *sent {
+            request.headers_mut().set_raw("X-Request-Id", sent);
+        }
+        let (_, headers, _) = client.request(request);
+        let id = header(&headers, "X-Request-Id");
+        assert_eq!(id.len(), 32);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        ids.push(id);
+    }
+    assert_ne!(ids[0], ids[1]);
+}
+
+/// Sends a GET request to `path`, returns the status with the JSON body
+fn get_json(client: &mut Client, path: &str) -> (StatusCode, json::Value) {
+    let (status, body) = client.send(Method::Get, path, "");
+    let body = json::from_str(&body).unwrap_or_else(|_| json!(body));
+    (status, body)
+}
+
+#[test]
+fn records_submissions() {
+    let mut client =
+        Client::new(Script::default(), SessionLimits::default()).with_history(Retention::default());
+    let (status, executed) = client.send(Method::Post, "/execute", &hello_c().to_string());
+    assert_eq!(status, StatusCode::Ok);
+    let problem = json!({"code": hello_c()["code"], "lang": "c", "expected": "Hello world"});
+    let (status, _) = client.send(Method::Post, "/judge", &problem.to_string());
+    assert_eq!(status, StatusCode::Ok);
+    let (status, page) = get_json(&mut client, "/submissions");
+    assert_eq!(status, StatusCode::Ok);
+    let entries = page["submissions"].as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(page["next"], json!(null));
+    assert_eq!(entries[0]["endpoint"], "judge");
+    assert_eq!(entries[0]["verdict"], "accepted");
+    let entry = &entries[1];
+    assert_eq!(entry["endpoint"], "execute");
+    assert_eq!(entry["language"], "c");
+    assert_eq!(entry["version"], "13");
+    assert_eq!(entry["verdict"], "ok");
+    assert_eq!(entry["request_id"].as_str().unwrap().len(), 32);
+    assert_eq!(entry["requester"], json!(null));
+    assert_eq!(entry["code_sha256"], entries[0]["code_sha256"]);
+    assert_eq!(entry["code_sha256"].as_str().unwrap().len(), 64);
+    assert_eq!(
+        entry["limits"],
+        json!({"backend": "docker", "memory": 1073741824u64, "cpu_seconds": 1})
+    );
+    assert!(entry["duration_ms"].as_f64().unwrap() >= 0.0);
+    assert!(entry.get("result").is_none());
+    let (status, replay) = get_json(&mut client, &format!("/submissions/{}", entry["id"]));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(replay["result"], json::from_str::<json::Value>(&executed).unwrap());
+    assert_eq!(replay["verdict"], "ok");
+}
+
+#[test]
+fn records_failed_submissions() {
+    let script = Script {
+        create: Reply::json(
+            StatusCode::InternalServerError,
+            json!({"message": "no space left on device"}),
+        ),
+        ..Script::default()
+    };
+    let mut client = Client::new(script, SessionLimits::default()).with_history(Retention::default());
+    client.send(Method::Post, "/execute", &hello_c().to_string());
+    let (_, page) = get_json(&mut client, "/submissions?verdict=error");
+    let id = &page["submissions"][0]["id"];
+    let (status, replay) = get_json(&mut client, &format!("/submissions/{}", id));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(replay["result"], json!(null));
+}
+
+#[test]
+fn filters_and_pages_submissions() {
+    let mut client =
+        Client::new(Script::default(), SessionLimits::default()).with_history(Retention::default());
+    for _ in 0..3 {
+        client.send(Method::Post, "/execute", &hello_c().to_string());
+    }
+    let (_, first) = get_json(&mut client, "/submissions?limit=2");
+    assert_eq!(first["submissions"].as_array().unwrap().len(), 2);
+    let next = first["next"].as_i64().unwrap();
+    assert_eq!(first["submissions"][1]["id"], json!(next));
+    let (_, second) = get_json(&mut client, &format!("/submissions?limit=2&before={}", next));
+    assert_eq!(second["submissions"].as_array().unwrap().len(), 1);
+    assert_eq!(second["next"], json!(null));
+    let (_, page) = get_json(&mut client, "/submissions?language=c&verdict=ok&since=0");
+    assert_eq!(page["submissions"].as_array().unwrap().len(), 3);
+    let (_, page) = get_json(&mut client, "/submissions?verdict=compile_error");
+    assert_eq!(page["submissions"], json!([]));
+    let (_, page) = get_json(&mut client, "/submissions?until=0");
+    assert_eq!(page["submissions"], json!([]));
+    let (status, body) = get_json(&mut client, "/submissions?limit=many");
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body, "Invalid query: limit isn't a number");
+    let (status, _) = get_json(&mut client, "/submissions?colour=red");
+    assert_eq!(status, StatusCode::BadRequest);
+    for path in &["/submissions/100", "/submissions/latest"] {
+        let (status, _) = get_json(&mut client, path);
+        assert_eq!(status, StatusCode::NotFound);
+    }
+}
+
+#[test]
+fn keeps_submissions_within_retention() {
+    let retention = Retention {
+        max_entries: 2,
+        prune_every: 1,
+        ..Retention::default()
+    };
+    let mut client = Client::new(Script::default(), SessionLimits::default()).with_history(retention);
+    for _ in 0..3 {
+        client.send(Method::Post, "/execute", &hello_c().to_string());
+    }
+    let (_, page) = get_json(&mut client, "/submissions");
+    let ids = page["submissions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["id"].as_i64().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![3, 2]);
+    let retention = Retention {
+        max_age: Duration::from_secs(0),
+        prune_every: 1,
+        ..Retention::default()
+    };
+    let mut client = Client::new(Script::default(), SessionLimits::default()).with_history(retention);
+    client.send(Method::Post, "/execute", &hello_c().to_string());
+    // entries made in the current second aren't older than no time
+    let (_, page) = get_json(&mut client, "/submissions");
+    assert_eq!(page["submissions"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn shows_requesters_their_submissions() {
+    let keys = Keys::new(vec![
+        ApiKey {
+            name: "tutoring".to_owned(),
+            sha256: SECRET.to_owned(),
+            quota: Quota::default(),
+        },
+        ApiKey {
+            name: "grading".to_owned(),
+            // hash of the key `other`
+            sha256: "d9298a10d1b0735837dc4bd85dac641b0f3cef27a47e5d53a54f2f3f5b2fcffa".to_owned(),
+            quota: Quota::default(),
+        },
+    ]);
+    let mut client = Client::new(Script::default(), SessionLimits::default())
+        .with_keys(keys)
+        .with_history(Retention::default());
+    let (status, _, _) = client.request(keyed_execute("secret"));
+    assert_eq!(status, StatusCode::Ok);
+    let listed = |client: &mut Client, key: &str, path: &str| {
+        let mut request = Request::new(Method::Get, path.parse().unwrap());
+        request.headers_mut().set(Authorization(Bearer { token: key.to_owned() }));
+        let (status, _, body) = client.request(request);
+        (status, json::from_str::<json::Value>(&body).unwrap_or(json!(body)))
+    };
+    let (_, page) = listed(&mut client, "secret", "/submissions");
+    assert_eq!(page["submissions"][0]["requester"], "tutoring");
+    let (status, _) = listed(&mut client, "secret", "/submissions/1");
+    assert_eq!(status, StatusCode::Ok);
+    let (_, page) = listed(&mut client, "other", "/submissions?requester=tutoring");
+    assert_eq!(page["submissions"], json!([]));
+    let (status, _) = listed(&mut client, "other", "/submissions/1");
+    assert_eq!(status, StatusCode::NotFound);
+}
+
+#[test]
+fn serves_history_only_if_kept() {
+    let mut client = Client::new(Script::default(), SessionLimits::default());
+    let (status, _) = get_json(&mut client, "/submissions");
+    assert_eq!(status, StatusCode::NotFound);
+}
+
+#[test]
+fn answers_repeated_submissions_from_cache() {
+    let mut client = Client::new(Script::default(), SessionLimits::default()).with_cache();
+    let (_, first) = client.send(Method::Post, "/execute", &hello_c().to_string());
+    let (status, again) = client.send(Method::Post, "/execute", &hello_c().to_string());
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(again, first);
+    assert_eq!(client.builds_after(&hello_c()), 1);
+    let mut other_input = hello_c();
+    other_input["stdin"] = json!("42");
+    assert_eq!(client.builds_after(&other_input), 2);
+    assert_eq!(client.builds_after(&other_input), 2);
+    let mut other_version = hello_c();
+    other_version["version"] = json!("9");
+    assert_eq!(client.builds_after(&other_version), 3);
+    let mut bypassed = hello_c();
+    bypassed["no_cache"] = json!(true);
+    assert_eq!(client.builds_after(&bypassed), 4);
+}
+
+#[test]
+fn skips_cache_for_nondeterministic_languages() {
+    let mut client = Client::new(Script::default(), SessionLimits::default()).with_cache();
+    let python = json!({"code": "print({'a', 'b'})", "lang": "python3"});
+    assert_eq!(client.builds_after(&python), 1);
+    assert_eq!(client.builds_after(&python), 2);
+}
+
+#[test]
+fn doesnt_cache_failed_runs() {
+    let script = Script {
+        create: Reply::json(
+            StatusCode::InternalServerError,
+            json!({"message": "no space left on device"}),
+        ),
+        ..Script::default()
+    };
+    let mut client = Client::new(script, SessionLimits::default()).with_cache();
+    assert_eq!(client.builds_after(&hello_c()), 1);
+    assert_eq!(client.builds_after(&hello_c()), 2);
+}
+
+#[test]
+fn doesnt_cache_runs_killed_for_limits() {
+    for &exit_code in &[137, 152] {
+        let script = Script {
+            wait: Reply::json(StatusCode::Ok, json!({ "StatusCode": exit_code })),
+            ..Script::default()
+        };
+        let mut client = Client::new(script, SessionLimits::default()).with_cache();
+        assert_eq!(client.builds_after(&hello_c()), 1);
+        assert_eq!(client.builds_after(&hello_c()), 2, "{}", exit_code);
+    }
+}
+
+#[test]
+fn doesnt_cache_failed_builds() {
+    let mut client = Client::new(failed_pull(), SessionLimits::default()).with_cache();
+    for _ in 0..2 {
+        let (status, _) = client.send(Method::Post, "/execute", &hello_c().to_string());
+        assert_eq!(status, StatusCode::InternalServerError);
+    }
+    let builds = client.docker.received().into_iter().filter(|received| received.path == "/build");
+    assert_eq!(builds.count(), 2);
+}
+
+#[test]
+fn runs_every_submission_without_cache() {
+    let mut client = Client::new(Script::default(), SessionLimits::default());
+    assert_eq!(client.builds_after(&hello_c()), 1);
+    assert_eq!(client.builds_after(&hello_c()), 2);
+}
+
+/// Returns the input uploaded to the container by the last request of `docker`
+fn uploaded_input(docker: &FakeDocker) -> String {
+    let upload = docker.last("/containers/e90e34656806/archive").unwrap();
+    assert_eq!(upload.method, Method::Put);
+    assert_eq!(params(&upload), vec!["path=%2F"]);
+    let files = unpack(&upload.body);
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].0, "input");
+    files[0].1.clone()
+}
+
+#[test]
+fn reuses_compiled_images() {
+    let mut client = Client::compiling(Script::default(), 1000);
+    let mut submission = hello_c();
+    submission["stdin"] = json!("1");
+    assert_eq!(client.builds_after(&submission), 1);
+    let build = client.docker.last("/build").unwrap();
+    let files = unpack(&build.body);
+    assert!(files.contains(&("input".to_owned(), String::new())));
+    // labelled by what it is built from rather than by the request
+    let labels = params(&build).into_iter().find(|param| param.starts_with("labels=")).unwrap();
+    assert!(labels.starts_with("labels=%7B%22code-executor.image%22%3A%22"), "{}", labels);
+    assert_eq!(uploaded_input(&client.docker), "1");
+    submission["stdin"] = json!("2");
+    let (status, body) = client.send(Method::Post, "/execute", &submission.to_string());
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(
+        json::from_str::<json::Value>(&body).unwrap(),
+        json!({"output": {"stdout": "Hello world", "stderr": ""}})
+    );
+    assert_eq!(uploaded_input(&client.docker), "2");
+    let paths = client
+        .docker
+        .received()
+        .into_iter()
+        .map(|received| received.path)
+        .skip_while(|path| path != "/containers/e90e34656806/logs")
+        .skip(1)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        vec![
+            "/containers/create",
+            "/containers/e90e34656806/archive",
+            "/containers/e90e34656806/wait",
+            "/containers/e90e34656806/start",
+            "/containers/e90e34656806/logs",
+        ]
+    );
+    submission["compiler_flags"] = json!(["-O2"]);
+    assert_eq!(client.builds_after(&submission), 2);
+    submission["version"] = json!("9");
+    assert_eq!(client.builds_after(&submission), 3);
+    submission["code"] = json!("int main(void) { return 0; }");
+    assert_eq!(client.builds_after(&submission), 4);
+}
+
+#[test]
+fn removes_least_recently_used_images() {
+    // each image takes up 100 bytes
+    let mut client = Client::compiling(Script::default(), 250);
+    let programs = (0..3)
+        .map(|exit| json!({"code": format!("int main(void) {{ return {}; }}", exit), "lang": "c"}))
+        .collect::<Vec<_>>();
+    assert_eq!(client.builds_after(&programs[0]), 1);
+    assert_eq!(client.builds_after(&programs[1]), 2);
+    let second = client.docker.last("/containers/create").unwrap().json()["Image"].clone();
+    let second = format!("/images/{}", second.as_str().unwrap());
+    assert_eq!(client.builds_after(&programs[0]), 2);
+    assert!(client.docker.last(&second).is_none());
+    // the second is used least recently
+    assert_eq!(client.builds_after(&programs[2]), 3);
+    let removal = client.docker.last(&second).unwrap();
+    assert_eq!(removal.method, Method::Delete);
+    assert_eq!(params(&removal), vec!["force=true"]);
+    assert_eq!(client.builds_after(&programs[0]), 3);
+    assert_eq!(client.builds_after(&programs[1]), 4);
+}
+
+#[test]
+fn removes_images_compiled_before() {
+    let script = Script {
+        images: Reply::json(StatusCode::Ok, json!([{ "Id": "sha256:3c2b1a0f9e8d" }])),
+        ..Script::default()
+    };
+    let mut client = Client::compiling(script, 1000);
+    let removed = client.core.run(client.executor.remove_images()).unwrap();
+    assert_eq!(removed, 1);
+    let listing = client.docker.last("/images/json").unwrap();
+    assert_eq!(params(&listing), vec!["filters=%7B%22label%22%3A%5B%22code-executor.image%22%5D%7D"]);
+    let removal = client.docker.last("/images/sha256:3c2b1a0f9e8d").unwrap();
+    assert_eq!(removal.method, Method::Delete);
+}
+
+/// Echoes its input after "Hello ", it is what the fake compiler outputs
+const ECHO_WAT: &str = r#"
+    (module
+        (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+        (memory (export "memory") 1)
+        (data (i32.const 100) "Hello ")
+        (func (export "_start")
+            (i32.store (i32.const 0) (i32.const 106))
+            (i32.store (i32.const 4) (i32.const 100))
+            (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (i32.store (i32.const 0) (i32.const 100))
+            (i32.store (i32.const 4) (i32.add (i32.const 6) (i32.load (i32.const 8))))
+            (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (call $proc_exit (i32.const 0))))
+"#;
+
+/// Executes `submission` on an `APIService` routing to either backend
+fn execute_on_backends(submission: json::Value) -> (StatusCode, String, FakeDocker) {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let docker = FakeDocker::start(&handle, Script::default());
+    let executor = Executor::new(
+        UnixConnector::new(handle.clone()),
+        docker.socket().to_str().unwrap(),
+        handle.clone(),
+    );
+    core.run(executor.detect_flavor()).unwrap();
+    let service = APIService::new(Backends::new(executor, WasmExecutor::new(Default::default())));
+    let mut request = Request::new(Method::Post, "/execute".parse().unwrap());
+    request.set_body(submission.to_string());
+    let response = service.call(request).and_then(|response| {
+        let status = response.status();
+        response.body().concat2().map(move |body| (status, body))
+    });
+    let (status, body) = core.run(response).unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap(), docker)
+}
+
+#[test]
+fn runs_on_wasm() {
+    // a wasi-sdk whose clang outputs the echoing module
+    let sdk = tempdir::TempDir::new("wasi-sdk").unwrap();
+    fs::create_dir(sdk.path().join("bin")).unwrap();
+    fs::write(sdk.path().join("echo.wasm"), wat::parse_str(ECHO_WAT).unwrap()).unwrap();
+    let clang = sdk.path().join("bin").join("clang");
+    fs::write(
+        &clang,
+        "#!/bin/sh\nwhile [ $# -gt 0 ]; do\n  if [ \"$1\" = -o ]; then cp \"$(dirname \"$0\")/../echo.wasm\" \"$2\"; fi\n  shift\ndone\n",
+    ).unwrap();
+    fs::set_permissions(&clang, fs::Permissions::from_mode(0o755)).unwrap();
+    env::set_var("WASI_SDK_PATH", sdk.path());
+    // there is no bubblewrap to jail the compiler in here
+    env::set_var("WASM_JAIL", "");
+    let mut submission = hello_c();
+    submission["backend"] = json!("wasm");
+    submission["stdin"] = json!("wasm");
+    let (status, body, docker) = execute_on_backends(submission);
+    assert_eq!(status, StatusCode::Ok, "body: {}", body);
+    assert_eq!(
+        json::from_str::<json::Value>(&body).unwrap(),
+        json!({"output": {"stdout": "Hello wasm", "stderr": ""}})
+    );
+    assert!(docker.last("/build").is_none());
+}
+
+#[test]
+fn rejects_transcript_on_wasm() {
+    let mut submission = hello_c();
+    submission["backend"] = json!("wasm");
+    submission["transcript"] = json!(true);
+    let (status, body, _) = execute_on_backends(submission);
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body, "Invalid submission: transcript can't be used with this backend");
+}
@@ -0,0 +1,532 @@
+//! An in-process fake of the Docker daemon for integration tests.
+//!
+//! It serves the endpoints used by the executor on a unix socket in a
+//! temporary directory, answering each of them with a scripted `Reply`
+//! and recording every request it receives.
+
+#![allow(dead_code)]
+
+use futures::sync::mpsc::SendError;
+use futures::{stream, Async, Future, Poll, Sink, Stream};
+use hyper::server::{Http, Request, Response, Service};
+use hyper::{self, Body, Chunk, Method, StatusCode};
+use json;
+use tempdir::TempDir;
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_exact, write_all};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_uds::{UnixListener, UnixStream};
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// A scripted response for one endpoint
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub status: StatusCode,
+    /// The body, each element is sent as a separate chunk
+    pub chunks: Vec<Vec<u8>>,
+}
+
+impl Reply {
+    /// A reply with an empty body
+    pub fn new(status: StatusCode) -> Self {
+        Reply {
+            status: status,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// A reply with `value` as its body
+    pub fn json(status: StatusCode, value: json::Value) -> Self {
+        Reply::new(status).chunk(json::to_vec(&value).unwrap())
+    }
+
+    /// Appends a chunk to the body
+    pub fn chunk<B: Into<Vec<u8>>>(mut self, bytes: B) -> Self {
+        self.chunks.push(bytes.into());
+        self
+    }
+}
+
+/// Encodes a message of the `/build` output stream
+pub fn build_message(value: json::Value) -> Vec<u8> {
+    let mut line = json::to_vec(&value).unwrap();
+    line.extend_from_slice(b"\r\n");
+    line
+}
+
+/// Encodes a length-delimited protobuf field
+fn protobuf_field(number: u8, bytes: &[u8]) -> Vec<u8> {
+    let mut field = vec![number << 3 | 2];
+    let mut len = bytes.len();
+    while len >= 0x80 {
+        field.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    field.push(len as u8);
+    field.extend_from_slice(bytes);
+    field
+}
+
+/// Encodes a `moby.buildkit.trace` message of the `/build` output stream
+/// # Arguments
+/// * `vertex` - Digest of the build step
+/// * `log` - Output of the step
+/// * `error` - Error of the step, if it failed
+pub fn buildkit_trace(vertex: &str, log: &[u8], error: Option<&str>) -> Vec<u8> {
+    let mut vertex_message = protobuf_field(1, vertex.as_bytes());
+    vertex_message.extend(protobuf_field(3, b"[3/3] RUN gcc code.c -o code"));
+    if let Some(error) = error {
+        vertex_message.extend(protobuf_field(7, error.as_bytes()));
+    }
+    let mut log_message = protobuf_field(1, vertex.as_bytes());
+    log_message.extend(protobuf_field(4, log));
+    let mut status = protobuf_field(1, &vertex_message);
+    status.extend(protobuf_field(3, &log_message));
+    build_message(json!({
+        "id": "moby.buildkit.trace",
+        "aux": base64::encode(&status),
+    }))
+}
+
+/// Encodes a frame of a multiplexed log stream, as sent with `timestamps=true`
+/// # Arguments
+/// * `stream` - 0 for stdin, 1 for stdout, 2 for stderr
+/// * `timestamp` - The time the data was written at
+/// * `data` - The data written by the program
+pub fn timestamped_frame(stream: u8, timestamp: &str, data: &[u8]) -> Vec<u8> {
+    let mut message = timestamp.as_bytes().to_vec();
+    message.push(b' ');
+    message.extend_from_slice(data);
+    frame(stream, &message)
+}
+
+/// Encodes a frame of a multiplexed stream, as sent to attached clients
+pub fn frame(stream: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![stream, 0, 0, 0];
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Encodes a frame of a multiplexed log stream written at a fixed time
+pub fn log_frame(stream: u8, data: &[u8]) -> Vec<u8> {
+    timestamped_frame(stream, "2018-02-14T10:00:00.000000000Z", data)
+}
+
+/// Id of containers created with a `Cmd` taking the expected
+/// output, which run checkers and interactors
+pub const CHECKER_ID: &str = "c4ec6e7c0000";
+
+/// A step of a program attached to by the executor
+#[derive(Clone, Debug)]
+pub enum Action {
+    /// Writes `data` to stdout (1) or stderr (2)
+    Write(u8, Vec<u8>),
+    /// Reads from stdin, panics if it isn't `data`
+    Expect(Vec<u8>),
+}
+
+/// Replies for every endpoint of the fake daemon. The default
+/// script runs a program printing "Hello world" on Docker.
+#[derive(Clone, Debug)]
+pub struct Script {
+    pub version: Reply,
+    pub ping: Reply,
+    /// Inspection of any image
+    pub image: Reply,
+    /// Layers of any image, the ones on top of the tagged one take up 100 bytes
+    pub history: Reply,
+    /// Images having the label asked for
+    pub images: Reply,
+    pub build: Reply,
+    pub remove_image: Reply,
+    pub create: Reply,
+    pub start: Reply,
+    pub logs: Reply,
+    pub wait: Reply,
+    pub kill: Reply,
+    pub archive: Reply,
+    /// A tar of a directory in the container
+    pub download: Reply,
+    pub remove: Reply,
+    /// Logs of the checker container
+    pub checker_logs: Reply,
+    /// Exit code of the checker container
+    pub checker_wait: Reply,
+    /// What the container does once attached to
+    pub attach: Vec<Action>,
+    /// What the checker container does once attached to
+    pub checker_attach: Vec<Action>,
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Script {
+            version: Reply::json(
+                StatusCode::Ok,
+                json!({
+                    "Version": "17.12.0-ce",
+                    "ApiVersion": "1.35",
+                }),
+            ),
+            ping: Reply::new(StatusCode::Ok).chunk("OK"),
+            image: Reply::json(StatusCode::Ok, json!({ "Id": "sha256:4a1e8a4ab5ff", "Size": 100 })),
+            history: Reply::json(
+                StatusCode::Ok,
+                json!([
+                    { "Id": "sha256:4a1e8a4ab5ff", "Size": 60, "Tags": null },
+                    { "Id": "<missing>", "Size": 40, "Tags": [] },
+                    { "Id": "sha256:9b7c1a2e5d3f", "Size": 1000, "Tags": ["gcc:latest"] },
+                    { "Id": "<missing>", "Size": 7000, "Tags": null },
+                ]),
+            ),
+            images: Reply::json(StatusCode::Ok, json!([])),
+            build: Reply::new(StatusCode::Ok).chunk(build_message(json!({
+                "stream": "sha256:4a1e8a4ab5ff\n"
+            }))),
+            remove_image: Reply::json(StatusCode::Ok, json!([{ "Deleted": "sha256:4a1e8a4ab5ff" }])),
+            create: Reply::json(
+                StatusCode::Created,
+                json!({
+                    "Id": "e90e34656806",
+                    "Warnings": [],
+                }),
+            ),
+            start: Reply::new(StatusCode::NoContent),
+            logs: Reply::new(StatusCode::Ok).chunk(log_frame(1, b"Hello world")),
+            wait: Reply::json(StatusCode::Ok, json!({ "StatusCode": 0 })),
+            kill: Reply::new(StatusCode::NoContent),
+            archive: Reply::new(StatusCode::Ok),
+            download: Reply::new(StatusCode::NotFound),
+            remove: Reply::new(StatusCode::NoContent),
+            checker_logs: Reply::new(StatusCode::Ok).chunk(log_frame(2, b"ok\n")),
+            checker_wait: Reply::json(StatusCode::Ok, json!({ "StatusCode": 0 })),
+            attach: Vec::new(),
+            checker_attach: Vec::new(),
+        }
+    }
+}
+
+/// A request received by the fake daemon
+#[derive(Clone, Debug)]
+pub struct Received {
+    pub method: Method,
+    /// The path, without the API version prefix
+    pub path: String,
+    pub query: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Received {
+    /// Parses the body as JSON
+    pub fn json(&self) -> json::Value {
+        json::from_slice(&self.body).expect("body isn't json")
+    }
+}
+
+/// Handle to a running fake daemon, which stops
+/// accepting connections once the event loop is dropped
+pub struct FakeDocker {
+    dir: TempDir,
+    received: Rc<RefCell<Vec<Received>>>,
+}
+
+impl FakeDocker {
+    /// Starts serving `script` on the event loop behind `handle`
+    pub fn start(handle: &Handle, script: Script) -> FakeDocker {
+        let dir = TempDir::new("fake-docker").expect("can't create socket directory");
+        let listener = UnixListener::bind(dir.path().join("docker.sock"), handle)
+            .expect("can't bind socket");
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let service = FakeService {
+            script: Rc::new(script),
+            received: received.clone(),
+            handle: handle.clone(),
+        };
+        let handle2 = handle.clone();
+        let server = listener
+            .incoming()
+            .for_each(move |(stream, _)| {
+                let service = service.clone();
+                let connection = Head {
+                    stream: Some(stream),
+                    buf: Vec::new(),
+                }.and_then(move |(stream, head)| -> Box<Future<Item = (), Error = io::Error>> {
+                    // hyper can't hand over connections, so attach requests are handled here
+                    let line = String::from_utf8_lossy(&head).lines().next().unwrap_or("").to_owned();
+                    if line.contains("/attach") {
+                        return service.attach(stream, &line);
+                    }
+                    let stream = Rewind {
+                        prefix: head,
+                        stream: stream,
+                    };
+                    let connection = Http::<Chunk>::new()
+                        .serve_connection(stream, service)
+                        .map(|_| ())
+                        .map_err(io::Error::other);
+                    Box::new(connection)
+                })
+                    // the executor may hang up on us, that's fine
+                    .map_err(|_| ());
+                handle2.spawn(connection);
+                Ok(())
+            })
+            .map_err(|e| panic!("fake docker can't accept: {:?}", e));
+        handle.spawn(server);
+        FakeDocker {
+            dir: dir,
+            received: received,
+        }
+    }
+
+    /// Path of the socket the daemon listens on
+    pub fn socket(&self) -> PathBuf {
+        self.dir.path().join("docker.sock")
+    }
+
+    /// All requests received so far, in order
+    pub fn received(&self) -> Vec<Received> {
+        self.received.borrow().clone()
+    }
+
+    /// The last request received whose path ends with `suffix`
+    pub fn last(&self, suffix: &str) -> Option<Received> {
+        self.received
+            .borrow()
+            .iter()
+            .rev()
+            .find(|received| received.path.ends_with(suffix))
+            .cloned()
+    }
+}
+
+#[derive(Clone)]
+struct FakeService {
+    script: Rc<Script>,
+    received: Rc<RefCell<Vec<Received>>>,
+    handle: Handle,
+}
+
+impl FakeService {
+    /// Picks the scripted reply for a request, `None` if there's no such endpoint
+    fn reply(&self, received: &Received) -> Option<Reply> {
+        let script = &self.script;
+        let segments = received.path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+        let reply = match (&received.method, &segments[..]) {
+            (&Method::Get, &["version"]) => &script.version,
+            (&Method::Get, &["_ping"]) => &script.ping,
+            (&Method::Get, &["images", "json"]) => &script.images,
+            (&Method::Get, &["images", .., "json"]) => &script.image,
+            (&Method::Get, &["images", _, "history"]) => &script.history,
+            (&Method::Post, &["build"]) => match cached_image(received) {
+                // Docker's ids also differ with what images are built from
+                Some(id) => {
+                    let stream = format!("sha256:{}\n", id);
+                    return Some(Reply::new(StatusCode::Ok).chunk(build_message(json!({ "stream": stream }))));
+                }
+                None => &script.build,
+            },
+            (&Method::Delete, &["images", _]) => &script.remove_image,
+            (&Method::Post, &["containers", "create"]) if runs_checker(&received.json()) => {
+                return Some(Reply::json(
+                    StatusCode::Created,
+                    json!({"Id": CHECKER_ID, "Warnings": []}),
+                ))
+            }
+            (&Method::Post, &["containers", "create"]) => &script.create,
+            (&Method::Post, &["containers", _, "start"]) => &script.start,
+            (&Method::Get, &["containers", CHECKER_ID, "logs"]) => &script.checker_logs,
+            (&Method::Get, &["containers", _, "logs"]) => &script.logs,
+            (&Method::Post, &["containers", CHECKER_ID, "wait"]) => &script.checker_wait,
+            (&Method::Post, &["containers", _, "wait"]) => &script.wait,
+            (&Method::Post, &["containers", _, "kill"]) => &script.kill,
+            (&Method::Put, &["containers", _, "archive"]) => &script.archive,
+            (&Method::Get, &["containers", _, "archive"]) => &script.download,
+            (&Method::Delete, &["containers", _]) => &script.remove,
+            _ => return None,
+        };
+        Some(reply.clone())
+    }
+}
+
+impl FakeService {
+    /// Answers an attach request on `stream` with the `Action`s of the
+    /// container, hanging up once they are done
+    fn attach(&self, stream: UnixStream, line: &str) -> Box<Future<Item = (), Error = io::Error>> {
+        let uri = line.split_whitespace().nth(1).unwrap_or("");
+        let (path, query) = match uri.find('?') {
+            Some(index) => (&uri[..index], Some(uri[index + 1..].to_owned())),
+            None => (uri, None),
+        };
+        let path = unversioned(path);
+        let checker = path.contains(CHECKER_ID);
+        self.received.borrow_mut().push(Received {
+            method: Method::Post,
+            path: path,
+            query: query,
+            body: Vec::new(),
+        });
+        let actions = if checker {
+            self.script.checker_attach.clone()
+        } else {
+            self.script.attach.clone()
+        };
+        let response = b"HTTP/1.1 101 UPGRADED\r\n\
+            Content-Type: application/vnd.docker.raw-stream\r\n\
+            Connection: Upgrade\r\n\
+            Upgrade: tcp\r\n\r\n"
+            .to_vec();
+        let program = write_all(stream, response).and_then(|(stream, _)| {
+            stream::iter_ok(actions).fold(stream, |stream, action| -> Box<Future<Item = UnixStream, Error = io::Error>> {
+                match action {
+                    Action::Write(fd, data) => {
+                        Box::new(write_all(stream, frame(fd, &data)).map(|(stream, _)| stream))
+                    }
+                    Action::Expect(data) => {
+                        let buf = vec![0; data.len()];
+                        Box::new(read_exact(stream, buf).map(move |(stream, buf)| {
+                            assert_eq!(
+                                String::from_utf8_lossy(&buf),
+                                String::from_utf8_lossy(&data),
+                                "unexpected stdin"
+                            );
+                            stream
+                        }))
+                    }
+                }
+            })
+        });
+        Box::new(program.map(|_| ()))
+    }
+}
+
+/// Whether a container created with `config` runs a checker or interactor
+fn runs_checker(config: &json::Value) -> bool {
+    config["Cmd"]
+        .as_array()
+        .is_some_and(|cmd| cmd.iter().any(|arg| arg == "/expected"))
+}
+
+/// The id of an image built for the image cache, made from the key it is
+/// labelled with, `None` if the build isn't for the cache
+fn cached_image(build: &Received) -> Option<String> {
+    const LABEL: &str = "labels=%7B%22code-executor.image%22%3A%22";
+    let query = build.query.as_ref()?;
+    let key = query.split('&').find_map(|param| param.strip_prefix(LABEL))?;
+    Some(key.chars().take(12).collect())
+}
+
+/// Reads the head of the first request on a connection
+struct Head {
+    stream: Option<UnixStream>,
+    buf: Vec<u8>,
+}
+
+impl Future for Head {
+    type Item = (UnixStream, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while !self.buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            let mut chunk = [0; 1024];
+            match self.stream.as_mut().unwrap().read(&mut chunk) {
+                // hung up, let hyper deal with it
+                Ok(0) => break,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        }
+        let buf = ::std::mem::take(&mut self.buf);
+        Ok(Async::Ready((self.stream.take().unwrap(), buf)))
+    }
+}
+
+/// A stream which returns `prefix` before what is left to be read
+struct Rewind {
+    prefix: Vec<u8>,
+    stream: UnixStream,
+}
+
+impl Read for Rewind {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.stream.read(buf);
+        }
+        let n = buf.len().min(self.prefix.len());
+        buf[..n].copy_from_slice(&self.prefix[..n]);
+        self.prefix.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Rewind {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for Rewind {}
+
+impl AsyncWrite for Rewind {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+/// Strips the `/v1.xx` prefix from a path
+fn unversioned(path: &str) -> String {
+    let path = path.trim_start_matches('/');
+    match path.find('/') {
+        Some(index) if path.starts_with('v') => path[index..].to_owned(),
+        _ => format!("/{}", path),
+    }
+}
+
+impl Service for FakeService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Response, Error = hyper::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let (method, uri, _, _, body) = req.deconstruct();
+        let service = self.clone();
+        let response = body.concat2().map(move |body| {
+            let received = Received {
+                method: method,
+                path: unversioned(uri.path()),
+                query: uri.query().map(String::from),
+                body: body.to_vec(),
+            };
+            let reply = service.reply(&received);
+            service.received.borrow_mut().push(received);
+            let reply = match reply {
+                Some(reply) => reply,
+                None => return Response::new().with_status(StatusCode::NotFound),
+            };
+            let (sender, body) = Body::pair();
+            let chunks = reply
+                .chunks
+                .into_iter()
+                .map(|chunk| Ok(Chunk::from(chunk)));
+            service.handle.spawn(
+                sender
+                    .send_all(stream::iter_ok::<_, SendError<_>>(chunks))
+                    .map(|_| ())
+                    .map_err(|_| ()),
+            );
+            Response::new().with_status(reply.status).with_body(body)
+        });
+        Box::new(response)
+    }
+}